@@ -1,16 +1,19 @@
+use crate::scalar::Scalar;
 use crate::scheduler::Task;
 use crate::storage::Element;
 use crate::{trace_print, trace_println};
+use serde::{Deserialize, Serialize};
 use std::mem;
 
-pub struct Multiplier {
-    a_eles: Option<Vec<Element>>,
-    b: Option<Element>,
-    c: Option<Element>,
+#[derive(Serialize, Deserialize)]
+pub struct Multiplier<T = f64> {
+    a_eles: Option<Vec<Element<T>>>,
+    b: Option<Element<T>>,
+    c: Option<Element<T>>,
 }
 
-impl Multiplier {
-    pub fn new() -> Multiplier {
+impl<T: Scalar> Multiplier<T> {
+    pub fn new() -> Multiplier<T> {
         Multiplier {
             a_eles: None,
             b: None,
@@ -18,11 +21,11 @@ impl Multiplier {
         }
     }
 
-    pub fn set_as(&mut self, a_elements: Option<Vec<Element>>) {
+    pub fn set_as(&mut self, a_elements: Option<Vec<Element<T>>>) {
         self.a_eles = a_elements;
     }
 
-    pub fn set_b(&mut self, b: Option<Element>) {
+    pub fn set_b(&mut self, b: Option<Element<T>>) {
         if b.is_none() || b.is_some() && b.as_ref().unwrap().idx == [usize::MAX; 2] {
             self.b = None;
         } else {
@@ -30,7 +33,7 @@ impl Multiplier {
         }
     }
 
-    pub fn retrieve_c(&mut self) -> Option<Element> {
+    pub fn retrieve_c(&mut self) -> Option<Element<T>> {
         return self.c.clone();
     }
 
@@ -47,7 +50,7 @@ impl Multiplier {
                 .find(|a| a.idx[1] == b.idx[0])
             {
                 Some(a) => {
-                    self.c = Some(Element::new([a.idx[0], b.idx[1]], a.value * b.value));
+                    self.c = Some(Element::new([a.idx[0], b.idx[1]], a.value.times(b.value)));
                 }
                 None => {
                     panic!("Mistach index b: {:?} a: {:?}", &b, &self.a_eles);
@@ -59,23 +62,35 @@ impl Multiplier {
     pub fn idle(&self) -> bool {
         return self.a_eles.is_none() || self.b.is_none();
     }
+
+    // The B element and the product of the last multiply.
+    pub fn last_product(&self) -> Option<(&Element<T>, &Element<T>)> {
+        Some((self.b.as_ref()?, self.c.as_ref()?))
+    }
 }
 
-pub struct Adder {
-    cur: Option<Element>,
+#[derive(Serialize, Deserialize)]
+pub struct Adder<T = f64> {
+    cur: Option<Element<T>>,
+    pub add_count: usize,
 }
 
-impl Adder {
-    pub fn new() -> Adder {
-        Adder { cur: None }
+impl<T: Scalar> Adder<T> {
+    pub fn new() -> Adder<T> {
+        Adder {
+            cur: None,
+            add_count: 0,
+        }
     }
 
-    pub fn add(&mut self, input: Option<Element>) -> Option<Element> {
+    pub fn add(&mut self, input: Option<Element<T>>) -> Option<Element<T>> {
         if self.cur.is_some()
             && input.is_some()
             && self.cur.as_ref().unwrap().idx == input.as_ref().unwrap().idx
         {
-            self.cur.as_mut().unwrap().value += input.as_ref().unwrap().value;
+            let cur = self.cur.as_mut().unwrap();
+            cur.value = cur.value.plus(input.as_ref().unwrap().value);
+            self.add_count += 1;
             None
         } else {
             mem::replace(&mut self.cur, input)
@@ -87,15 +102,16 @@ impl Adder {
     }
 }
 
-pub struct MergeTree {
+#[derive(Serialize, Deserialize)]
+pub struct MergeTree<T = f64> {
     pub tree_width: usize,
     pub tree_depth: usize,
-    pub merge_tree: Vec<Vec<Option<Element>>>,
+    pub merge_tree: Vec<Vec<Option<Element<T>>>>,
     pub drained: Vec<Vec<bool>>,
 }
 
-impl MergeTree {
-    pub fn new(tree_width: usize) -> MergeTree {
+impl<T: Scalar> MergeTree<T> {
+    pub fn new(tree_width: usize) -> MergeTree<T> {
         let mut layer_width = 1;
         let mut tree_depth = 0;
         let mut tree = vec![];
@@ -124,7 +140,7 @@ impl MergeTree {
         }
     }
 
-    pub fn push_element(&mut self, leaf_idx: usize, element: Option<Element>) {
+    pub fn push_element(&mut self, leaf_idx: usize, element: Option<Element<T>>) {
         if element.is_some() {
             if element.as_ref().unwrap().idx == [usize::MAX; 2] {
                 self.drained[self.tree_depth][leaf_idx] = true;
@@ -142,7 +158,7 @@ impl MergeTree {
         }
     }
 
-    pub fn update(&mut self) -> Option<Element> {
+    pub fn update(&mut self) -> Option<Element<T>> {
         let popped = self.merge_tree[0][0].take();
         for lvl in 1..self.tree_depth + 1 {
             for left_node in (0..2usize.pow(lvl as u32)).step_by(2) {
@@ -175,7 +191,7 @@ impl MergeTree {
         lvl: usize,
         left_node: usize,
         right_node: usize,
-    ) -> Option<Element> {
+    ) -> Option<Element<T>> {
         let le = &self.merge_tree[lvl][left_node];
         let re = &self.merge_tree[lvl][right_node];
         if re.is_none()
@@ -222,18 +238,30 @@ impl MergeTree {
     }
 }
 
-pub struct AdderTree {
+// Cycles to reduce `operand_num` operands with a pipelined tree of `fan_in` input adders.
+pub fn adder_tree_latency(operand_num: usize, fan_in: usize, stage_latency: usize) -> usize {
+    let mut stage_num = 0;
+    let mut reach = 1;
+    while reach < operand_num {
+        reach *= fan_in;
+        stage_num += 1;
+    }
+    stage_num * stage_latency
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AdderTree<T = f64> {
     pub pe_idx: usize,
     pub tree_width: usize,
-    pub merge_tree: MergeTree,
-    pub multiplier: Multiplier,
-    pub adder: Adder,
-    pub task: Option<Task>,
+    pub merge_tree: MergeTree<T>,
+    pub multiplier: Multiplier<T>,
+    pub adder: Adder<T>,
+    pub task: Option<Task<T>>,
     pub mem_finish_cycle: Option<usize>,
 }
 
-impl AdderTree {
-    pub fn new(pe_idx: usize, tree_width: usize) -> AdderTree {
+impl<T: Scalar> AdderTree<T> {
+    pub fn new(pe_idx: usize, tree_width: usize) -> AdderTree<T> {
         AdderTree {
             pe_idx,
             tree_width,
@@ -250,7 +278,7 @@ impl AdderTree {
         return is_idle;
     }
 
-    pub fn set_task(&mut self, task: Option<(usize, Task)>) -> usize {
+    pub fn set_task(&mut self, task: Option<(usize, Task<T>)>) -> usize {
         if task.is_none() {
             self.multiplier.set_as(None);
             self.task = None;
@@ -1,6 +1,7 @@
 use crate::trace_println;
-use std::cmp::{max, min};
+use serde::{Deserialize, Serialize};
 
+#[derive(Serialize, Deserialize)]
 pub struct BlockTopoTracker {
     pub row_s_list: Vec<usize>,
     pub col_s_list: Vec<Vec<usize>>,
@@ -34,14 +35,15 @@ impl BlockTopoTracker {
     }
 
     pub fn find_left(&self, cur_block: [usize; 2]) -> Option<(usize, [usize; 2])> {
-        // Find the most near left in recorded blocks.
+        // Find the most near left in recorded blocks, searching up from the row of the block or
+        // the row above it, never a row below.
         if self.row_s_list.len() == 0 {
             return None;
         }
-        let mut cur_row_pos = self.row_s_list.binary_search(&cur_block[0]).map_or_else(
-            |x| min(max(self.row_s_list.len() as i32 - 1, 0), x as i32),
-            |x| x as i32,
-        );
+        let mut cur_row_pos = self
+            .row_s_list
+            .binary_search(&cur_block[0])
+            .map_or_else(|x| x as i32 - 1, |x| x as i32);
         while cur_row_pos >= 0 {
             trace_println!("cur_block: {:?} cur_row_pos: {}", cur_block, cur_row_pos);
             let row_pos = cur_row_pos as usize;
@@ -67,19 +69,21 @@ impl BlockTopoTracker {
         if self.row_s_list.len() == 0 {
             return None;
         }
-        let cur_row_pos = self.row_s_list.binary_search(&cur_block[0]).map_or_else(
-            |x| x as i32,
-            |x| min(max(self.row_s_list.len() as i32 - 1, 0), x as i32),
-        );
+        let cur_row_pos = match self.row_s_list.binary_search(&cur_block[0]) {
+            Ok(r) | Err(r) => r,
+        };
         if cur_row_pos == 0 {
             return None;
         }
 
-        let row_pos = (cur_row_pos - 1) as usize;
+        // The nearest block of the row above, the left one if both neighbors are as near.
+        let row_pos = cur_row_pos - 1;
         let col_pos = match self.col_s_list[row_pos].binary_search(&cur_block[1]) {
             Ok(c) => c,
             Err(c) => {
-                if c == self.col_s_list[row_pos].len() {
+                if c == 0 {
+                    c
+                } else if c == self.col_s_list[row_pos].len() {
                     c - 1
                 } else {
                     if self.col_s_list[row_pos][c] - cur_block[1]
@@ -99,3 +103,68 @@ impl BlockTopoTracker {
         return Some((token, [row_idx, col_idx]));
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Blocks at rows 0, 4 and 8, each token made of its anchor.
+    fn tracker() -> BlockTopoTracker {
+        let mut tracker = BlockTopoTracker::new();
+        for anchor in [[4, 16], [0, 4], [4, 0], [8, 2], [0, 12], [4, 8]].iter() {
+            tracker.add_block(anchor[0] * 100 + anchor[1], *anchor);
+        }
+        tracker
+    }
+
+    fn found(anchor: [usize; 2]) -> Option<(usize, [usize; 2])> {
+        Some((anchor[0] * 100 + anchor[1], anchor))
+    }
+
+    #[test]
+    fn no_neighbor_without_blocks() {
+        let tracker = BlockTopoTracker::new();
+        assert_eq!(tracker.find_left([4, 4]), None);
+        assert_eq!(tracker.find_above([4, 4]), None);
+    }
+
+    #[test]
+    fn left_edge() {
+        let tracker = tracker();
+        // Nothing left of column 0 in the own row or the rows above.
+        assert_eq!(tracker.find_left([4, 0]), None);
+        assert_eq!(tracker.find_above([4, 0]), found([0, 4]));
+        assert_eq!(tracker.find_above([0, 4]), None);
+    }
+
+    #[test]
+    fn exact_match() {
+        let tracker = tracker();
+        assert_eq!(tracker.find_left([4, 8]), found([4, 0]));
+        assert_eq!(tracker.find_above([8, 8]), found([4, 8]));
+    }
+
+    #[test]
+    fn equidistant_tie_picks_left() {
+        let tracker = tracker();
+        // Column 8 is 4 away from both columns 4 and 12 of row 0.
+        assert_eq!(tracker.find_above([4, 8]), found([0, 4]));
+        assert_eq!(tracker.find_above([4, 9]), found([0, 12]));
+    }
+
+    #[test]
+    fn past_last_column() {
+        let tracker = tracker();
+        assert_eq!(tracker.find_left([4, 20]), found([4, 16]));
+        assert_eq!(tracker.find_above([8, 20]), found([4, 16]));
+    }
+
+    #[test]
+    fn unrecorded_own_row_searches_up() {
+        let tracker = tracker();
+        // Row 6 is not recorded, the search starts from row 4 and never from row 8 below.
+        assert_eq!(tracker.find_left([6, 10]), found([4, 8]));
+        assert_eq!(tracker.find_left([6, 3]), found([4, 0]));
+        assert_eq!(tracker.find_above([6, 10]), found([4, 8]));
+    }
+}
@@ -1,8 +1,11 @@
 use crate::block_topo_tracker::BlockTopoTracker;
-use std::cmp::max;
+use crate::scalar::Scalar;
+use crate::storage::CsrMatStorage;
+use serde::{Deserialize, Serialize};
+use std::cmp::{max, min};
 use std::collections::HashMap;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ColwiseIrrBlockInfo {
     pub a_ele_num: usize,
     pub miss_size: usize,
@@ -19,28 +22,53 @@ impl ColwiseIrrBlockInfo {
     }
 }
 
+#[derive(Serialize, Deserialize)]
 pub struct ColwiseIrrBlockAdjustTracker {
     pub block_info: HashMap<usize, ColwiseIrrBlockInfo>, // block token -> block info
     pub lane_num: usize,
+    #[serde(with = "crate::util::pair_map")]
     pub block_shape: HashMap<[usize; 2], [usize; 2]>, // block anchor -> block shape
     pub group_size: usize,
-    pub block_width: usize,
     pub group_shape: HashMap<usize, [usize; 2]>, // group no -> group block shape
+    pub b_target_size: usize,
+    pub a_row_b_sizes: Vec<Vec<usize>>, // a row -> prefix sum of touched B fiber sizes
 }
 
 impl ColwiseIrrBlockAdjustTracker {
-    pub fn new(
+    pub fn new<T: Scalar>(
         lane_num: usize,
         group_size: usize,
-        block_width: usize,
+        b_target_size: usize,
+        operands: Option<(&CsrMatStorage<T>, &CsrMatStorage<T>)>,
     ) -> ColwiseIrrBlockAdjustTracker {
+        // The B working set is only meaningful when the rows of A index the rows of B.
+        let a_row_b_sizes = match operands {
+            Some((a_matrix, b_matrix)) => (0..a_matrix.row_num())
+                .map(|rowid| {
+                    let rawidx = if a_matrix.remapped {
+                        a_matrix.row_remap[&rowid]
+                    } else {
+                        rowid
+                    };
+                    let mut prefix = vec![0];
+                    for col in a_matrix.row_entries(rawidx).0.iter() {
+                        prefix.push(
+                            prefix.last().unwrap() + b_matrix.get_ele_num(*col, *col + 1) * 2,
+                        );
+                    }
+                    prefix
+                })
+                .collect::<Vec<Vec<usize>>>(),
+            None => vec![],
+        };
         ColwiseIrrBlockAdjustTracker {
             block_info: HashMap::new(),
             lane_num,
             block_shape: HashMap::new(),
             group_size,
-            block_width,
             group_shape: HashMap::new(),
+            b_target_size,
+            a_row_b_sizes,
         }
     }
 
@@ -53,60 +81,126 @@ impl ColwiseIrrBlockAdjustTracker {
         // Irregular colwise block adjust scheme allows irregular block size.
         // Irregular colwise block adjust only adjust on the top blocks shape
         // and only in a degraded way.
+        let blk_h = if block_anchor[0] % self.group_size == 0 {
+            self.adjust_block_height(block_anchor, a_row_num, block_topo)
+        } else {
+            self.group_shape[&(block_anchor[0] / self.group_size)][0]
+        };
+        // The block width varies with the B fibers touched by the block.
+        let block_shape = [
+            blk_h,
+            self.adjust_block_width(block_anchor, blk_h, block_topo),
+        ];
+        self.block_shape.insert(block_anchor, block_shape);
         if block_anchor[0] % self.group_size == 0 {
-            let n1_tk_acr = block_topo.find_left(block_anchor);
-            if n1_tk_acr.is_none() {
-                let mut blk_h = self.lane_num;
-                while block_anchor[0] + blk_h > a_row_num {
-                    blk_h = max(1, blk_h / 2);
-                }
-                let block_shape = [blk_h, self.block_width];
-                self.block_shape.insert(block_anchor, block_shape);
-                self.group_shape
-                    .insert(block_anchor[0] / self.group_size, block_shape);
-                return block_shape;
-            }
-            let (n1_token, n1_block) = n1_tk_acr.unwrap();
-            let n2_tk_acr = block_topo.find_left(n1_block);
-            if n2_tk_acr.is_none() {
-                let mut blk_h = self.lane_num / 2;
-                while block_anchor[0] + blk_h > a_row_num {
-                    blk_h = max(1, blk_h / 2);
-                }
-                let block_shape = [blk_h, self.block_width];
-                self.block_shape.insert(block_anchor, block_shape);
-                self.group_shape
-                    .insert(block_anchor[0] / self.group_size, block_shape);
-                return block_shape;
-            }
-            let (n2_token, _) = n2_tk_acr.unwrap();
-            let n1_block_info = self.block_info.get(&n1_token).unwrap();
-            let n1_ele_size = n1_block_info.a_ele_num;
-            let n1_cost = (n1_block_info.miss_size + n1_block_info.psum_rw_size[0]) * 100
-                + n1_block_info.psum_rw_size[1];
-            let n1_blk_h = self.block_shape[&n1_block][0];
-            let n2_block_info = self.block_info.get(&n2_token).unwrap();
-            let n2_ele_size = n2_block_info.a_ele_num;
-            let n2_cost = (n2_block_info.miss_size + n2_block_info.psum_rw_size[0]) * 100
-                + n2_block_info.psum_rw_size[1];
+            self.group_shape
+                .insert(block_anchor[0] / self.group_size, block_shape);
+        }
+        return block_shape;
+    }
 
-            let mut blk_h =
-                if (n1_cost as f32 / n1_ele_size as f32) < (n2_cost as f32 / n2_ele_size as f32) {
-                    max(1, n1_blk_h / 2)
-                } else {
-                    n1_blk_h
-                };
+    fn adjust_block_height(
+        &self,
+        block_anchor: [usize; 2],
+        a_row_num: usize,
+        block_topo: &BlockTopoTracker,
+    ) -> usize {
+        let n1_tk_acr = block_topo.find_left(block_anchor);
+        if n1_tk_acr.is_none() {
+            let mut blk_h = self.lane_num;
             while block_anchor[0] + blk_h > a_row_num {
                 blk_h = max(1, blk_h / 2);
             }
-            let block_shape = [blk_h, self.block_width];
-            self.block_shape.insert(block_anchor, block_shape);
-            self.group_shape
-                .insert(block_anchor[0] / self.group_size, block_shape);
-            return block_shape;
+            return blk_h;
+        }
+        let (n1_token, n1_block) = n1_tk_acr.unwrap();
+        let n2_tk_acr = block_topo.find_left(n1_block);
+        if n2_tk_acr.is_none() {
+            let mut blk_h = self.lane_num / 2;
+            while block_anchor[0] + blk_h > a_row_num {
+                blk_h = max(1, blk_h / 2);
+            }
+            return blk_h;
+        }
+        let (n2_token, _) = n2_tk_acr.unwrap();
+        let n1_blk_h = self.block_shape[&n1_block][0];
+
+        let mut blk_h = if self.cost_per_ele(n1_token) < self.cost_per_ele(n2_token) {
+            max(1, n1_blk_h / 2)
         } else {
-            return self.group_shape[&(block_anchor[0] / self.group_size)];
+            n1_blk_h
+        };
+        while block_anchor[0] + blk_h > a_row_num {
+            blk_h = max(1, blk_h / 2);
+        }
+        return blk_h;
+    }
+
+    fn adjust_block_width(
+        &self,
+        block_anchor: [usize; 2],
+        blk_h: usize,
+        block_topo: &BlockTopoTracker,
+    ) -> usize {
+        let row_t = min(block_anchor[0] + blk_h, self.a_row_b_sizes.len());
+        let touched_size = |width: usize| -> usize {
+            (block_anchor[0]..row_t)
+                .map(|rowid| {
+                    let prefix = &self.a_row_b_sizes[rowid];
+                    let col_s = min(block_anchor[1], prefix.len() - 1);
+                    let col_t = min(block_anchor[1] + width, prefix.len() - 1);
+                    prefix[col_t] - prefix[col_s]
+                })
+                .sum()
+        };
+        let max_width = (block_anchor[0]..row_t)
+            .map(|rowid| self.a_row_b_sizes[rowid].len() - 1)
+            .max()
+            .unwrap_or(0)
+            .saturating_sub(block_anchor[1]);
+
+        // Grow the width until the touched B fibers cross the target size.
+        let (mut lo, mut hi) = (1, max(1, max_width));
+        while lo < hi {
+            let mid = (lo + hi + 1) / 2;
+            if touched_size(mid) <= self.b_target_size {
+                lo = mid;
+            } else {
+                hi = mid - 1;
+            }
         }
+        let mut width = lo;
+
+        // Bias toward the width of the cheaper neighbor block.
+        let neighbor = match (
+            block_topo.find_left(block_anchor),
+            block_topo.find_above(block_anchor),
+        ) {
+            (Some(left), Some(above)) => {
+                if self.cost_per_ele(left.0) <= self.cost_per_ele(above.0) {
+                    Some(left)
+                } else {
+                    Some(above)
+                }
+            }
+            (left, above) => left.or(above),
+        };
+        if let Some((_, n_block)) = neighbor {
+            if let Some(n_shape) = self.block_shape.get(&n_block) {
+                width = (width + n_shape[1]) / 2;
+            }
+        }
+
+        // A block should hold whole windows.
+        let window_width = max(1, self.lane_num / blk_h);
+        max(1, (width + window_width - 1) / window_width) * window_width
+    }
+
+    fn cost_per_ele(&self, token: usize) -> f32 {
+        let block_info = self.block_info.get(&token).unwrap();
+        let cost =
+            (block_info.miss_size + block_info.psum_rw_size[0]) * 100 + block_info.psum_rw_size[1];
+        cost as f32 / block_info.a_ele_num as f32
     }
 
     pub fn adjust_window_shape(&mut self, block_shape: [usize; 2]) -> [usize; 2] {
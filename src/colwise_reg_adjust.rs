@@ -1,8 +1,9 @@
 use crate::block_topo_tracker::BlockTopoTracker;
+use serde::{Deserialize, Serialize};
 use std::cmp::{max, min};
 use std::collections::HashMap;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ColwiseRegBlockInfo {
     pub a_ele_num: usize,
     pub miss_size: usize,
@@ -19,6 +20,7 @@ impl ColwiseRegBlockInfo {
     }
 }
 
+#[derive(Serialize, Deserialize)]
 pub struct ColwiseRegBlockAdjustTracker {
     pub block_info: HashMap<usize, ColwiseRegBlockInfo>, // block token -> block info
     pub lane_num: usize,
@@ -0,0 +1,94 @@
+use crate::frontend::OmegaConfig;
+use crate::stats::{EnergyStats, SimulationStats};
+use std::cmp::{max, min};
+
+// Traffic and MACs of the same GEMM as if both operands were dense. The output stationary tiling
+// keeps a square tile of A, B and C each in the cache, so C is written once while A is read once
+// per column tile of C and B once per row tile of C.
+pub struct DenseTrafficModel {
+    // [rows of A, cols of A, cols of B].
+    pub shape: [usize; 3],
+    pub tile: usize,
+    pub mult_num: usize,
+    pub words_per_cycle: f32,
+}
+
+impl DenseTrafficModel {
+    pub fn new(spada_config: &OmegaConfig, shape: [usize; 3]) -> DenseTrafficModel {
+        let cache_capability = spada_config.cache_size / spada_config.word_byte;
+        let tile = max((((cache_capability / 3) as f64).sqrt()) as usize, 1);
+        DenseTrafficModel {
+            shape,
+            tile,
+            mult_num: spada_config.pe_num * spada_config.mult_lane_num(),
+            words_per_cycle: spada_config.bandwidth_per_channel * spada_config.channel as f32
+                / spada_config.freq
+                / spada_config.word_byte as f32,
+        }
+    }
+
+    pub fn get_mac_count(&self) -> usize {
+        self.shape[0] * self.shape[1] * self.shape[2]
+    }
+
+    pub fn get_stats(&self, workload: &str, spada_config: &OmegaConfig) -> SimulationStats {
+        let [m, k, n] = self.shape;
+        let tile_m = min(self.tile, max(m, 1));
+        let tile_n = min(self.tile, max(n, 1));
+        let a_read = m * k * ((n + tile_n - 1) / tile_n);
+        let b_read = k * n * ((m + tile_m - 1) / tile_m);
+        let c_write = m * n;
+        let mac_count = self.get_mac_count();
+        // Bound by either the multipliers or the off-chip bandwidth.
+        let compute_cycle = (mac_count + self.mult_num - 1) / self.mult_num;
+        let memory_cycle = ((a_read + b_read + c_write) as f32 / self.words_per_cycle) as usize;
+        let word_byte = spada_config.word_byte;
+        SimulationStats {
+            workload: workload.to_string(),
+            accelerator: "Dense".to_string(),
+            seed: 0,
+            truncated: false,
+            exec_count: max(compute_cycle, memory_cycle),
+            a_count: [a_read, 0],
+            b_count: [b_read, 0],
+            c_count: [0, c_write],
+            a_bytes: [a_read * word_byte, 0],
+            b_bytes: [b_read * word_byte, 0],
+            c_bytes: [0, c_write * word_byte],
+            merge_count: [0; 3],
+            op_count: [mac_count, 0],
+            dense_mac_count: mac_count,
+            steal_count: 0,
+            cache_count: [0; 2],
+            cache_miss_count: [0; 3],
+            prefetch_count: None,
+            channel_conflict_cycles: None,
+            psum_bank_conflict_cycles: None,
+            bandwidth_stall_cycles: None,
+            row_buffer_count: None,
+            l1_count: None,
+            mem_latency_stat: None,
+            broadcast_hit_count: None,
+            warm_b_fibers: None,
+            pinned_fibers: None,
+            peak_occupancy: None,
+            channel_bytes: None,
+            adder_tree_busy: None,
+            line_miss_count: None,
+            symmetric_storage_bytes: None,
+            reuse: None,
+            energy: spada_config.energy.as_ref().map(|energy_config| {
+                EnergyStats::new(
+                    energy_config,
+                    [a_read + b_read, c_write],
+                    [0; 2],
+                    [mac_count, 0],
+                )
+            }),
+            drain_cycles: vec![],
+            drain: None,
+            psum_addr_count: None,
+            pe_stats: vec![],
+        }
+    }
+}
@@ -0,0 +1,175 @@
+use crate::frontend::OmegaConfig;
+use crate::gemm::GEMM;
+use crate::scalar::Scalar;
+use crate::stats::{EnergyStats, SimulationStats};
+use crate::storage::fiber_bytes;
+use std::cmp::{max, min};
+
+// Closed-form estimate of a sparse GEMM without simulating it. The compute is bound by the MACs
+// spread over all multiplier lanes, the memory by the compulsory traffic: A read once, each B
+// fiber referenced by A read once unless a reuse factor is assumed, and C written once.
+pub struct DryRunModel {
+    pub mac_count: usize,
+    pub dense_mac_count: usize,
+    // Element numbers of A and of the B fibers referenced by A.
+    pub a_ele_num: usize,
+    pub b_ele_num: usize,
+    pub a_row_num: usize,
+    pub b_row_num: usize,
+    // Upper bound of the output elements, a row has at most the sum of its B fibers.
+    pub c_ele_num: usize,
+    pub mult_num: usize,
+    pub words_per_cycle: f32,
+}
+
+impl DryRunModel {
+    pub fn new<T: Scalar>(spada_config: &OmegaConfig, gemm: &GEMM<T>) -> DryRunModel {
+        let b_row_lens = gemm
+            .b
+            .outer_iterator()
+            .map(|b_row| b_row.nnz())
+            .collect::<Vec<usize>>();
+        let mut referenced = vec![false; b_row_lens.len()];
+        let mut mac_count = 0;
+        let mut c_ele_num = 0;
+        for a_row in gemm.a.outer_iterator() {
+            let row_macs = a_row
+                .indices()
+                .iter()
+                .map(|col| b_row_lens[*col])
+                .sum::<usize>();
+            for col in a_row.indices() {
+                referenced[*col] = true;
+            }
+            mac_count += row_macs;
+            c_ele_num += min(row_macs, gemm.b.cols());
+        }
+        let (b_ele_num, b_row_num) = referenced
+            .iter()
+            .zip(b_row_lens.iter())
+            .filter(|(r, _)| **r)
+            .fold((0, 0), |(ele_num, row_num), (_, len)| {
+                (ele_num + len, row_num + 1)
+            });
+        DryRunModel {
+            mac_count,
+            dense_mac_count: gemm.a.rows() * gemm.a.cols() * gemm.b.cols(),
+            a_ele_num: gemm.a.nnz(),
+            b_ele_num,
+            a_row_num: gemm.a.rows(),
+            b_row_num,
+            c_ele_num,
+            mult_num: spada_config.pe_num * spada_config.mult_lane_num(),
+            words_per_cycle: spada_config.bandwidth_per_channel * spada_config.channel as f32
+                / spada_config.freq
+                / spada_config.word_byte as f32,
+        }
+    }
+
+    pub fn get_stats(
+        &self,
+        workload: &str,
+        accelerator: &str,
+        spada_config: &OmegaConfig,
+        reuse_factor: Option<f32>,
+    ) -> SimulationStats {
+        // Each A element uses a B fiber, which is fetched once per reuse_factor uses but never
+        // less than once.
+        let b_ele_read = match reuse_factor {
+            Some(reuse_factor) => max(
+                self.b_ele_num,
+                (self.mac_count as f32 / reuse_factor.max(1.0)) as usize,
+            ),
+            None => self.b_ele_num,
+        };
+        let a_read = 2 * self.a_ele_num;
+        let b_read = 2 * b_ele_read;
+        let c_write = 2 * self.c_ele_num;
+        let compute_cycle = (self.mac_count + self.mult_num - 1) / max(self.mult_num, 1);
+        let memory_cycle = ((a_read + b_read + c_write) as f32 / self.words_per_cycle) as usize;
+        let [word_byte, index_byte] = [spada_config.word_byte, spada_config.index_byte];
+        SimulationStats {
+            workload: workload.to_string(),
+            accelerator: format!("{} dry run", accelerator),
+            seed: 0,
+            truncated: false,
+            exec_count: max(compute_cycle, memory_cycle),
+            a_count: [a_read, 0],
+            b_count: [b_read, 0],
+            c_count: [0, c_write],
+            a_bytes: [
+                fiber_bytes(self.a_ele_num, word_byte, index_byte, false)
+                    + self.a_row_num * index_byte,
+                0,
+            ],
+            b_bytes: [
+                fiber_bytes(b_ele_read, word_byte, index_byte, false) + self.b_row_num * index_byte,
+                0,
+            ],
+            c_bytes: [
+                0,
+                fiber_bytes(self.c_ele_num, word_byte, index_byte, false)
+                    + self.a_row_num * index_byte,
+            ],
+            merge_count: [0; 3],
+            op_count: [self.mac_count, 0],
+            dense_mac_count: self.dense_mac_count,
+            steal_count: 0,
+            cache_count: [0; 2],
+            cache_miss_count: [0; 3],
+            prefetch_count: None,
+            channel_conflict_cycles: None,
+            psum_bank_conflict_cycles: None,
+            bandwidth_stall_cycles: None,
+            row_buffer_count: None,
+            l1_count: None,
+            mem_latency_stat: None,
+            broadcast_hit_count: None,
+            warm_b_fibers: None,
+            pinned_fibers: None,
+            peak_occupancy: None,
+            channel_bytes: None,
+            adder_tree_busy: None,
+            line_miss_count: None,
+            symmetric_storage_bytes: None,
+            reuse: None,
+            energy: spada_config.energy.as_ref().map(|energy_config| {
+                EnergyStats::new(
+                    energy_config,
+                    [a_read + b_read, c_write],
+                    [0; 2],
+                    [self.mac_count, 0],
+                )
+            }),
+            drain_cycles: vec![],
+            drain: None,
+            psum_addr_count: None,
+            pe_stats: vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::*;
+    use sprs::CsMat;
+
+    #[test]
+    fn mac_estimate_is_the_sum_of_the_fiber_products() {
+        // Row 0 of A uses B fibers 0 and 1, row 1 uses fiber 2.
+        let a = CsMat::new((2, 3), vec![0, 2, 3], vec![0, 1, 2], vec![1.0; 3]);
+        let b = csr_with_row_lens(&[2, 1, 3], 3);
+        let gemm = GEMM::try_new("dry_run", a, b).unwrap();
+        let config = test_config();
+        let model = DryRunModel::new(&config, &gemm);
+        assert_eq!(model.mac_count, (2 + 1) + 3);
+        let stats = model.get_stats(&gemm.name, "Spada", &config, None);
+        assert_eq!(stats.op_count, [6, 0]);
+        assert_eq!(stats.dense_mac_count, 2 * 3 * 3);
+        // Every referenced fiber is read once.
+        assert_eq!(stats.b_count, [2 * 6, 0]);
+        let mult_num = config.pe_num * config.mult_lane_num();
+        assert!(stats.exec_count >= (6 + mult_num - 1) / mult_num);
+    }
+}
@@ -1,25 +1,451 @@
-use serde::Deserialize;
+use crate::info_println;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::env;
 use std::error::Error;
+use std::fmt;
 use std::fs::File;
-use std::io::BufReader;
+use std::io::{BufRead, BufReader};
 use std::path::Path;
 use structopt::{clap::arg_enum, StructOpt};
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct OmegaConfig {
     pub ss_filepath: String,
     pub nn_filepath: String,
+    // Directory of the scipy .npz workloads, stored as <name>_a.npz and <name>_b.npz.
+    #[serde(default)]
+    pub npz_filepath: String,
     pub pe_num: usize,
     pub at_num: usize,
     pub lane_num: usize,
     pub cache_size: usize,
     pub word_byte: usize,
+    // Scalar type of the accelerator. Overrides word_byte and packs narrower MACs into each
+    // multiplier lane if set, the values are still simulated as f64.
+    pub data_type: Option<DataType>,
+    #[serde(default = "default_index_byte")]
+    pub index_byte: usize,
     pub block_shape: [usize; 2],
-    pub mem_latency: usize,
+    pub mem_latency: MemLatency,
     pub cache_latency: usize,
     pub freq: f32,
     pub channel: usize,
     pub bandwidth_per_channel: f32,
+    // Number of ways of the fiber cache, fully associative if not set. The sets are sized to hold
+    // `ways` B fibers of the average length each, so their number follows the loaded B.
+    pub set_associative: Option<usize>,
+    // Bytes of a fiber cache line. A fiber takes whole lines and loses its tail lines first on
+    // an eviction. The cache holds whole fibers if not set.
+    pub line_size: Option<usize>,
+    // Only write back dirty psum lines on eviction.
+    #[serde(default)]
+    pub write_back: bool,
+    // Private per PE cache size in bytes, 0 for a single-level cache.
+    #[serde(default)]
+    pub l1_cache_size: usize,
+    // Prefetch the next B fiber on a demand miss.
+    #[serde(default)]
+    pub prefetch: bool,
+    // How the fiber cache picks its victims.
+    #[serde(default)]
+    pub replacement_policy: ReplacementPolicy,
+    // Fraction of the fiber cache the pinned B fibers may take, the rest is left to the working
+    // set.
+    #[serde(default = "default_pin_budget")]
+    pub pin_budget: f32,
+    // Never evict from the fiber cache for a lower bound of the off-chip traffic. A cache_size
+    // of 0 bypasses the cache for the upper bound instead.
+    #[serde(default)]
+    pub infinite_cache: bool,
+    // Number of psums merged per output row in a merge task.
+    #[serde(default = "default_merge_radix")]
+    pub merge_radix: usize,
+    // When to schedule merge tasks before A is fully traversed.
+    #[serde(default)]
+    pub merge_policy: MergePolicy,
+    // Outputs with at most this many columns, e.g. SpMV, accumulate in a dense vector without
+    // psum addresses or merges. Disabled if 0.
+    #[serde(default)]
+    pub spmv_threshold: usize,
+    // Fraction of the cache occupied past which a merge is forced between blocks, to reclaim
+    // psum space before the evictions start.
+    pub merge_watermark: Option<f32>,
+    // Which rows with pending psums are merged first.
+    #[serde(default)]
+    pub merge_order: MergeOrder,
+    // How row length groups are split for the rowwise adjustment.
+    #[serde(default)]
+    pub group_split: GroupSplit,
+    // Sample the block row num of a wide row group again once the cost per element of the chosen
+    // one drifts from its sampled cost by more than this fraction. Rowwise strategy only, the
+    // row num is kept for the rest of the group if not set.
+    pub resample_threshold: Option<f32>,
+    // Bounds on the blocks of the adaptive strategies: the width is raised to at least this
+    // fraction of the mean remaining length of the block rows, and the rows are capped so that a
+    // window stays this many lanes wide. Not clamped if not set.
+    pub min_block_width: Option<f32>,
+    pub min_window_width: Option<usize>,
+    // Psum addresses live at once, like a bounded accumulator storage. The addresses of the merged
+    // psums are handed out again, and the PEs merge before taking windows once the space runs
+    // low. Unbounded and never reused if not set.
+    pub psum_addr_space: Option<usize>,
+    // Serialize memory accesses that hit the same DRAM channel.
+    #[serde(default)]
+    pub channel_conflict: bool,
+    // Cap the off-chip bytes per cycle at channel * bandwidth_per_channel, stalling on the excess.
+    #[serde(default)]
+    pub bandwidth_limit: bool,
+    // Fibers per DRAM row of the open-page row buffer model, disabled if 0.
+    #[serde(default)]
+    pub row_buffer_fibers: usize,
+    #[serde(default = "default_bank_num")]
+    pub bank_num: usize,
+    // Extra latency to close the open DRAM row on a row buffer conflict.
+    #[serde(default)]
+    pub precharge_latency: usize,
+    // Banks of the psum SRAM, psum accesses are not serialized if 0.
+    #[serde(default)]
+    pub psum_banks: usize,
+    // Fan-in of the PE merge tree adders, a fixed merge latency is used if not set.
+    pub adder_fan_in: Option<usize>,
+    #[serde(default = "default_adder_stage_latency")]
+    pub adder_stage_latency: usize,
+    // MACs a PE issues per cycle, shared by its lanes so that a sparse window with few busy lanes
+    // finishes faster. The fixed cost of one B element per lane per cycle is kept if not set.
+    pub macs_per_cycle: Option<usize>,
+    // Per event energy costs, no energy is reported if not set.
+    pub energy: Option<EnergyConfig>,
+}
+
+impl OmegaConfig {
+    // Number of MACs issued per PE per cycle.
+    pub fn mult_lane_num(&self) -> usize {
+        self.lane_num * self.data_type.map_or(1, |data_type| data_type.pack_num())
+    }
+
+    // The data type sets the word size of the traffic accounting if configured.
+    pub fn apply_data_type(&mut self) {
+        if let Some(data_type) = self.data_type {
+            self.word_byte = data_type.word_byte();
+        }
+    }
+
+    // Check the invariants the simulator relies on, naming the first field that breaks one.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let positive_fields = [
+            ("pe_num", self.pe_num as f64),
+            ("lane_num", self.lane_num as f64),
+            ("word_byte", self.word_byte as f64),
+            ("block_shape[0]", self.block_shape[0] as f64),
+            ("block_shape[1]", self.block_shape[1] as f64),
+            ("freq", self.freq as f64),
+            ("channel", self.channel as f64),
+            ("bandwidth_per_channel", self.bandwidth_per_channel as f64),
+            ("set_associative", self.set_associative.unwrap_or(1) as f64),
+        ];
+        for (field, value) in positive_fields.iter() {
+            if !(*value > 0.0) {
+                return Err(ConfigError::NotPositive(field));
+            }
+        }
+        if self.cache_size > 0 && self.cache_size < self.word_byte + self.index_byte {
+            return Err(ConfigError::Inconsistent(
+                "cache_size",
+                format!(
+                    "must be 0 or hold one element of {} bytes",
+                    self.word_byte + self.index_byte
+                ),
+            ));
+        }
+        if self.block_shape[0] > self.mult_lane_num() {
+            return Err(ConfigError::Inconsistent(
+                "block_shape[0]",
+                format!("must not exceed the {} lanes of a PE", self.mult_lane_num()),
+            ));
+        }
+        if self.merge_radix < 2 || self.lane_num % self.merge_radix != 0 {
+            return Err(ConfigError::Inconsistent(
+                "merge_radix",
+                format!("must be at least 2 and divide lane_num {}", self.lane_num),
+            ));
+        }
+        if let Some(line_size) = self.line_size {
+            if line_size == 0 || line_size % self.word_byte != 0 {
+                return Err(ConfigError::Inconsistent(
+                    "line_size",
+                    format!(
+                        "must be a positive multiple of word_byte {}",
+                        self.word_byte
+                    ),
+                ));
+            }
+            if self.cache_size > 0 && line_size > self.cache_size {
+                return Err(ConfigError::Inconsistent(
+                    "line_size",
+                    format!("must not exceed cache_size {}", self.cache_size),
+                ));
+            }
+        }
+        match self.mem_latency {
+            MemLatency::Uniform { min, max } if min > max => {
+                return Err(ConfigError::Inconsistent(
+                    "mem_latency",
+                    format!("min {} must not exceed max {}", min, max),
+                ));
+            }
+            MemLatency::TwoPoint { open_rate, .. } if !(0.0..=1.0).contains(&open_rate) => {
+                return Err(ConfigError::Inconsistent(
+                    "mem_latency",
+                    "open_rate must be in [0, 1]".to_owned(),
+                ));
+            }
+            _ => {}
+        }
+        if self.row_buffer_fibers > 0 && self.bank_num == 0 {
+            return Err(ConfigError::Inconsistent(
+                "bank_num",
+                "must be positive with the row buffer model".to_owned(),
+            ));
+        }
+        if self.adder_fan_in.map_or(false, |fan_in| fan_in < 2) {
+            return Err(ConfigError::Inconsistent(
+                "adder_fan_in",
+                "must be at least 2".to_owned(),
+            ));
+        }
+        if self
+            .resample_threshold
+            .map_or(false, |threshold| !(threshold > 0.0))
+        {
+            return Err(ConfigError::Inconsistent(
+                "resample_threshold",
+                "must be positive".to_owned(),
+            ));
+        }
+        if self
+            .min_block_width
+            .map_or(false, |ratio| !(ratio > 0.0 && ratio <= 1.0))
+        {
+            return Err(ConfigError::Inconsistent(
+                "min_block_width",
+                "must be in (0, 1]".to_owned(),
+            ));
+        }
+        if let Some(width) = self.min_window_width {
+            let lane_num = self.mult_lane_num();
+            if width == 0 || width > lane_num || lane_num % width != 0 {
+                return Err(ConfigError::Inconsistent(
+                    "min_window_width",
+                    format!("must divide the {} lanes", lane_num),
+                ));
+            }
+        }
+        // A window takes up to a lane's worth of addresses, and as much is kept to merge.
+        if let Some(space) = self.psum_addr_space {
+            let lane_num = self.mult_lane_num();
+            if space < 2 * lane_num {
+                return Err(ConfigError::Inconsistent(
+                    "psum_addr_space",
+                    format!("must be at least twice the {} lanes", lane_num),
+                ));
+            }
+        }
+        if let Some(macs) = self.macs_per_cycle {
+            let lane_num = self.mult_lane_num();
+            if macs < lane_num {
+                return Err(ConfigError::Inconsistent(
+                    "macs_per_cycle",
+                    format!("must be at least the {} lanes", lane_num),
+                ));
+            }
+        }
+        if !(self.pin_budget > 0.0 && self.pin_budget < 1.0) {
+            return Err(ConfigError::Inconsistent(
+                "pin_budget",
+                "must be in (0, 1)".to_owned(),
+            ));
+        }
+        if let Some(watermark) = self.merge_watermark {
+            if !(watermark > 0.0 && watermark <= 1.0) {
+                return Err(ConfigError::Inconsistent(
+                    "merge_watermark",
+                    "must be in (0, 1]".to_owned(),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfigError {
+    NotPositive(&'static str),
+    // The field and the constraint it breaks.
+    Inconsistent(&'static str, String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::NotPositive(field) => {
+                write!(f, "Invalid config: {} must be positive", field)
+            }
+            ConfigError::Inconsistent(field, constraint) => {
+                write!(f, "Invalid config: {} {}", field, constraint)
+            }
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+// Energy per event in pJ. Memory and cache costs are per accessed word.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EnergyConfig {
+    pub dram_read: f64,
+    pub dram_write: f64,
+    pub cache_read: f64,
+    pub cache_write: f64,
+    pub mac: f64,
+    pub merge_add: f64,
+}
+
+fn default_index_byte() -> usize {
+    4
+}
+
+fn default_merge_radix() -> usize {
+    2
+}
+
+fn default_pin_budget() -> f32 {
+    0.5
+}
+
+fn default_bank_num() -> usize {
+    8
+}
+
+fn default_adder_stage_latency() -> usize {
+    1
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum MergePolicy {
+    // Merge only full groups of psums, and the rest after A is traversed.
+    Deferred,
+    // Also merge when pending psums risk overflowing the cache or a produced row waits on them.
+    CostBased,
+}
+
+impl Default for MergePolicy {
+    fn default() -> Self {
+        MergePolicy::Deferred
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum MergeOrder {
+    // Seeded random order.
+    Random,
+    // Ascending row id.
+    RowId,
+    // Rows with the most psums first, to free the cache fastest.
+    LongestFirst,
+    // Rows whose oldest psum was produced first.
+    OldestFirst,
+}
+
+impl Default for MergeOrder {
+    fn default() -> Self {
+        MergeOrder::Random
+    }
+}
+
+// Off-chip access latency in cycles, either a plain number or a distribution sampled per access.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum MemLatency {
+    Fixed(usize),
+    Uniform {
+        min: usize,
+        max: usize,
+    },
+    // An open page hit with probability open_rate, a closed page access otherwise.
+    TwoPoint {
+        open: usize,
+        closed: usize,
+        open_rate: f32,
+    },
+}
+
+impl MemLatency {
+    // Expected latency, used where a single estimate is needed.
+    pub fn mean(&self) -> usize {
+        match *self {
+            MemLatency::Fixed(latency) => latency,
+            MemLatency::Uniform { min, max } => (min + max) / 2,
+            MemLatency::TwoPoint {
+                open,
+                closed,
+                open_rate,
+            } => (open as f32 * open_rate + closed as f32 * (1.0 - open_rate)).round() as usize,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ReplacementPolicy {
+    // Evict the line whose last use is the earliest A row.
+    Lru,
+    // Evict the coldest line large enough to make room alone, fall back to Lru.
+    SizeAware,
+}
+
+impl Default for ReplacementPolicy {
+    fn default() -> Self {
+        ReplacementPolicy::Lru
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum DataType {
+    F64,
+    F32,
+    F16,
+    Int8,
+}
+
+impl DataType {
+    pub fn word_byte(&self) -> usize {
+        match self {
+            DataType::F64 => 8,
+            DataType::F32 => 4,
+            DataType::F16 => 2,
+            DataType::Int8 => 1,
+        }
+    }
+
+    // MACs packed into a multiplier lane sized for f64.
+    pub fn pack_num(&self) -> usize {
+        8 / self.word_byte()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum GroupSplit {
+    // Split when a row differs from the previous one by more than var_factor.
+    Constant,
+    // Scale var_factor by the coefficient of variation of recent rows, so noisy rows stay in one
+    // group while sharp jumps still split.
+    Adaptive,
+}
+
+impl Default for GroupSplit {
+    fn default() -> Self {
+        GroupSplit::Constant
+    }
 }
 
 arg_enum! {
@@ -28,11 +454,12 @@ arg_enum! {
         AccurateSimu,
         TrafficModel,
         BReuseCounter,
+        DenseModel,
     }
 }
 
 arg_enum! {
-    #[derive(Debug, Clone, PartialEq)]
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
     pub enum Accelerator {
         Ip,
         Op,
@@ -41,11 +468,117 @@ arg_enum! {
     }
 }
 
+impl Default for Accelerator {
+    fn default() -> Self {
+        Accelerator::Spada
+    }
+}
+
+arg_enum! {
+    #[derive(Debug, Clone, Copy)]
+    pub enum LogLevel {
+        Off,
+        Info,
+        Trace,
+    }
+}
+
+arg_enum! {
+    #[derive(Debug)]
+    pub enum StatsFormat {
+        Human,
+        Json,
+        Csv,
+    }
+}
+
 arg_enum! {
     #[derive(Debug)]
     pub enum WorkloadCate {
         SS,
         NN,
+        Npz,
+        Synthetic,
+    }
+}
+
+arg_enum! {
+    #[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+    pub enum BlockStrategy {
+        Fixed,
+        Rowwise,
+        ColwiseRegular,
+        ColwiseIrregular,
+        RowwiseLatency,
+    }
+}
+
+impl Default for BlockStrategy {
+    fn default() -> Self {
+        BlockStrategy::RowwiseLatency
+    }
+}
+
+arg_enum! {
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum Reorder {
+        None,
+        Asc,
+        Desc,
+        Rcm,
+    }
+}
+
+impl Default for Reorder {
+    fn default() -> Self {
+        Reorder::None
+    }
+}
+
+arg_enum! {
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum ColReorder {
+        None,
+        FirstTouch,
+        BLength,
+    }
+}
+
+impl Default for ColReorder {
+    fn default() -> Self {
+        ColReorder::None
+    }
+}
+
+arg_enum! {
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum WarmB {
+        None,
+        Fit,
+        All,
+    }
+}
+
+impl Default for WarmB {
+    fn default() -> Self {
+        WarmB::None
+    }
+}
+
+arg_enum! {
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum ScalarType {
+        F64,
+        I64,
+        Complex,
+    }
+}
+
+arg_enum! {
+    #[derive(Debug, Clone)]
+    pub enum RowDistribution {
+        Uniform,
+        PowerLaw,
     }
 }
 
@@ -63,23 +596,428 @@ pub struct Cli {
     #[structopt(possible_values=&WorkloadCate::variants(), case_insensitive=true)]
     pub category: WorkloadCate,
 
-    /// The workload name.
+    /// The workload name, or a comma-separated list of names to run as a batch.
     pub workload: String,
 
     /// Configuration file path.
     pub configuration: String,
 
-    /// Preprocessing.
+    /// File listing extra workload names to run in the batch, one per line.
+    #[structopt(long)]
+    pub workload_list: Option<String>,
+
+    /// Rows of the synthetic A matrix.
+    #[structopt(long, default_value = "1024")]
+    pub synth_rows: usize,
+
+    /// Cols of the synthetic A matrix.
+    #[structopt(long, default_value = "1024")]
+    pub synth_cols: usize,
+
+    /// Density of the synthetic matrices.
+    #[structopt(long, default_value = "0.01")]
+    pub synth_density: f64,
+
+    /// Seed of the synthetic matrix generator.
+    #[structopt(long, default_value = "0")]
+    pub synth_seed: u64,
+
+    /// Row length distribution of the synthetic matrices.
+    #[structopt(
+        long,
+        possible_values=&RowDistribution::variants(),
+        case_insensitive=true,
+        default_value="uniform"
+    )]
+    pub synth_distribution: RowDistribution,
+
+    /// Stop the cycle-accurate simulation after this many cycles and report the partial stats.
+    #[structopt(long)]
+    pub max_cycles: Option<usize>,
+
+    /// Save the simulation state to this file if the run stops at the cycle limit.
+    #[structopt(long)]
+    pub checkpoint: Option<String>,
+
+    /// Resume the cycle-accurate simulation from a checkpoint file, the cycle limit still counts
+    /// from the start of the run.
+    #[structopt(long)]
+    pub resume: Option<String>,
+
+    /// Record the block and window shapes chosen by the scheduler to a JSON lines file.
+    #[structopt(long)]
+    pub record_schedule: Option<String>,
+
+    /// Replay the block and window shapes recorded by --record-schedule instead of adjusting
+    /// them, to compare configs under the same schedule.
+    #[structopt(long)]
+    pub replay_schedule: Option<String>,
+
+    /// Write a CSV report of the shapes, fiber sizes, reuse ratios and cost of every block.
+    #[structopt(long)]
+    pub block_report: Option<String>,
+
+    /// Draw a progress bar of the cycle-accurate simulation on stderr.
+    #[structopt(long)]
+    pub progress: bool,
+
+    /// Report the hit rates of every power of two cache size from the reuse distances of the run.
+    #[structopt(long)]
+    pub reuse_analysis: bool,
+
+    /// Stream the scheduling and cache events of the cycle-accurate simulation to a JSON lines
+    /// file.
+    #[structopt(long)]
+    pub trace_out: Option<String>,
+
+    /// Write the block and window tokens of the task of every PE and adder tree at every cycle to a
+    /// CSV file.
+    #[structopt(long)]
+    pub pe_log: Option<String>,
+
+    /// Write the fiber cache occupancy of the B fibers and the psums at every cycle to a CSV file.
+    #[structopt(long)]
+    pub occupancy_out: Option<String>,
+
+    /// Write the bytes fetched through every DRAM channel in each epoch to a CSV file.
+    #[structopt(long)]
+    pub channel_util_out: Option<String>,
+
+    /// Cycles of an epoch of the channel utilization CSV.
+    #[structopt(long, default_value = "1000")]
+    pub channel_epoch: usize,
+
+    /// Check the consistency of the psum bookkeeping after every cycle, and panic with the
+    /// tracker states on the first violation.
+    #[structopt(long)]
+    pub debug_checks: bool,
+
+    /// Sum the products of each result element in ascending column order of A, so that the
+    /// result is bitwise the same for any schedule. Keeps every product until the end of the run.
+    #[structopt(long)]
+    pub stable_reduce: bool,
+
+    /// How Spada adapts the block and window shapes, the other accelerators keep them fixed.
+    #[structopt(
+        long,
+        possible_values=&BlockStrategy::variants(),
+        case_insensitive=true,
+        default_value="rowwiselatency"
+    )]
+    pub block_strategy: BlockStrategy,
+
+    /// Seed of the scheduling decisions, runs with the same seed are reproducible.
+    #[structopt(long, default_value = "0")]
+    pub seed: u64,
+
+    /// Preprocessing, same as `--reorder asc`.
     #[structopt(short, long)]
     pub preprocess: bool,
+
+    /// Reorder the rows of A by ascending or descending length, or by Reverse Cuthill-McKee.
+    #[structopt(
+        long,
+        possible_values=&Reorder::variants(),
+        case_insensitive=true,
+        default_value="none"
+    )]
+    pub reorder: Reorder,
+
+    /// Renumber the columns of A and rows of B, by first touch in the A row order or by
+    /// descending B row length.
+    #[structopt(
+        long,
+        possible_values=&ColReorder::variants(),
+        case_insensitive=true,
+        default_value="none"
+    )]
+    pub reorder_col: ColReorder,
+
+    /// Serve B fibers from the A rows already streamed in, for a workload multiplied by itself.
+    #[structopt(long)]
+    pub self_multiply: bool,
+
+    /// Start with B in the cache, the most reused fibers that fit or all of it.
+    #[structopt(
+        long,
+        possible_values=&WarmB::variants(),
+        case_insensitive=true,
+        default_value="none"
+    )]
+    pub warm_b: WarmB,
+
+    /// Pin this many B fibers referenced by the most A rows in the cache, they are never evicted
+    /// once loaded.
+    #[structopt(long)]
+    pub pin_hot: Option<usize>,
+
+    /// Pin the B fibers whose row ids are listed in a file, one per line.
+    #[structopt(long)]
+    pub pin_file: Option<String>,
+
+    /// Store the symmetric operands as their lower triangle, mirroring the upper one on reads.
+    #[structopt(long)]
+    pub symmetric_storage: bool,
+
+    /// Print the row length profile of A and B before simulating.
+    #[structopt(long)]
+    pub profile: bool,
+
+    /// Run the workload on each of these comma-separated accelerators and compare them side by
+    /// side, instead of on the accelerator argument.
+    #[structopt(
+        long,
+        use_delimiter=true,
+        possible_values=&Accelerator::variants(),
+        case_insensitive=true
+    )]
+    pub compare: Vec<Accelerator>,
+
+    /// Multiply the product of the workload by each of these comma-separated SS matrices in turn,
+    /// e.g. `--chain c` runs A*B and then (A*B)*C. The matrices are read natively.
+    #[structopt(long, use_delimiter = true)]
+    pub chain: Vec<String>,
+
+    /// Only estimate the cycles and the compulsory traffic analytically, without simulating.
+    #[structopt(long)]
+    pub dry_run: bool,
+
+    /// Uses of a fetched B fiber assumed by the dry run, every fiber is fetched once if not set.
+    #[structopt(long)]
+    pub reuse_factor: Option<f32>,
+
+    /// Read Matrix Market workloads natively instead of through Python.
+    #[structopt(long)]
+    pub native_mm: bool,
+
+    /// Sort the rows of the loaded matrices whose indices are not increasing, instead of
+    /// rejecting them.
+    #[structopt(long)]
+    pub sort_indices: bool,
+
+    /// Value type of the matrix elements, only f64 for the workloads not read natively.
+    #[structopt(
+        long,
+        possible_values=&ScalarType::variants(),
+        case_insensitive=true,
+        default_value="f64"
+    )]
+    pub scalar: ScalarType,
+
+    /// Write the result matrix to a Matrix Market file.
+    #[structopt(long)]
+    pub output: Option<String>,
+
+    /// Check the result against a reference product.
+    #[structopt(long)]
+    pub verify: bool,
+
+    /// Format of the collected statistics.
+    #[structopt(
+        long,
+        possible_values=&StatsFormat::variants(),
+        case_insensitive=true,
+        default_value="human"
+    )]
+    pub stats_format: StatsFormat,
+
+    /// Write the statistics to a file, keeping the human readable ones on stdout.
+    #[structopt(long)]
+    pub stats_out: Option<String>,
+
+    /// Log verbosity, tracing needs the trace_exec feature.
+    #[structopt(
+        long,
+        possible_values=&LogLevel::variants(),
+        case_insensitive=true,
+        default_value="off"
+    )]
+    pub verbosity: LogLevel,
+}
+
+pub fn parse_workloads(cli: &Cli) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut workloads = cli
+        .workload
+        .split(',')
+        .map(|w| w.trim().to_owned())
+        .filter(|w| !w.is_empty())
+        .collect::<Vec<String>>();
+    if let Some(workload_list) = cli.workload_list.as_ref() {
+        let reader = BufReader::new(File::open(workload_list)?);
+        for line in reader.lines() {
+            let line = line?;
+            let line = line.trim();
+            // Skip blank lines and comments.
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            workloads.push(line.to_owned());
+        }
+    }
+    if workloads.is_empty() {
+        return Err("No workload to run".into());
+    }
+    Ok(workloads)
 }
 
 pub fn parse_config(config_fp: &str) -> Result<OmegaConfig, Box<dyn Error>> {
-    println!("{}", config_fp);
+    info_println!("{}", config_fp);
     let config_fp = Path::new(config_fp);
     let file = File::open(config_fp)?;
     let reader = BufReader::new(file);
 
-    let omega_config = serde_json::from_reader(reader)?;
+    let mut omega_config: OmegaConfig = serde_json::from_reader(reader)?;
+    omega_config = apply_env_overrides(omega_config, env::vars())?;
+    omega_config.apply_data_type();
     Ok(omega_config)
 }
+
+// Environment variables named after a config field, e.g. SPADA_PE_NUM=4 or
+// SPADA_BLOCK_SHAPE=[2,64], take precedence over the config file. A value that is not valid
+// JSON is taken as a string.
+const ENV_PREFIX: &str = "SPADA_";
+
+fn apply_env_overrides<I: Iterator<Item = (String, String)>>(
+    omega_config: OmegaConfig,
+    vars: I,
+) -> Result<OmegaConfig, Box<dyn Error>> {
+    let mut overrides = vars
+        .filter(|(key, _)| key.starts_with(ENV_PREFIX))
+        .collect::<Vec<(String, String)>>();
+    if overrides.is_empty() {
+        return Ok(omega_config);
+    }
+    overrides.sort();
+    let mut config_value = serde_json::to_value(&omega_config)?;
+    for (key, raw_value) in overrides {
+        let field = key[ENV_PREFIX.len()..].to_lowercase();
+        if config_value.get(&field).is_none() {
+            return Err(format!("{} does not name a config field", key).into());
+        }
+        let value = serde_json::from_str(&raw_value).unwrap_or(Value::String(raw_value.clone()));
+        // Check each override on its own so that the error names the variable.
+        let mut overridden = config_value.clone();
+        overridden[&field] = value;
+        if let Err(err) = serde_json::from_value::<OmegaConfig>(overridden.clone()) {
+            return Err(format!("Invalid {}={}: {}", key, raw_value, err).into());
+        }
+        info_println!("Config {} overridden by {}={}", field, key, raw_value);
+        config_value = overridden;
+    }
+    Ok(serde_json::from_value(config_value)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::*;
+    use serde_json::json;
+
+    fn rejected_field(fields: Value) -> &'static str {
+        match config_with(fields).validate() {
+            Err(ConfigError::NotPositive(field)) => field,
+            Err(ConfigError::Inconsistent(field, _)) => field,
+            Ok(()) => "",
+        }
+    }
+
+    #[test]
+    fn test_config_is_valid() {
+        assert_eq!(test_config().validate(), Ok(()));
+    }
+
+    #[test]
+    fn non_positive_fields_are_rejected() {
+        let cases = [
+            (json!({"pe_num": 0}), "pe_num"),
+            (json!({"lane_num": 0}), "lane_num"),
+            (json!({"word_byte": 0}), "word_byte"),
+            (json!({"block_shape": [0, 16]}), "block_shape[0]"),
+            (json!({"block_shape": [1, 0]}), "block_shape[1]"),
+            (json!({"freq": 0.0}), "freq"),
+            (json!({"channel": 0}), "channel"),
+            (
+                json!({"bandwidth_per_channel": 0.0}),
+                "bandwidth_per_channel",
+            ),
+            (json!({"set_associative": 0}), "set_associative"),
+        ];
+        for (fields, field) in cases.iter() {
+            assert_eq!(rejected_field(fields.clone()), *field);
+        }
+    }
+
+    #[test]
+    fn inconsistent_fields_are_rejected() {
+        let cases = [
+            (json!({"cache_size": 4}), "cache_size"),
+            (json!({"block_shape": [16, 16]}), "block_shape[0]"),
+            (json!({"merge_radix": 3}), "merge_radix"),
+            (json!({"merge_radix": 16}), "merge_radix"),
+            (json!({"line_size": 12}), "line_size"),
+            (json!({"cache_size": 64, "line_size": 128}), "line_size"),
+            (
+                json!({"mem_latency": {"min": 40, "max": 20}}),
+                "mem_latency",
+            ),
+            (
+                json!({"mem_latency": {"open": 20, "closed": 40, "open_rate": 1.5}}),
+                "mem_latency",
+            ),
+            (json!({"row_buffer_fibers": 4, "bank_num": 0}), "bank_num"),
+            (json!({"adder_fan_in": 1}), "adder_fan_in"),
+            (json!({"resample_threshold": 0.0}), "resample_threshold"),
+            (json!({"min_block_width": 1.5}), "min_block_width"),
+            (json!({"min_window_width": 3}), "min_window_width"),
+            (json!({"psum_addr_space": 8}), "psum_addr_space"),
+            (json!({"macs_per_cycle": 4}), "macs_per_cycle"),
+            (json!({"pin_budget": 1.0}), "pin_budget"),
+            (json!({"merge_watermark": 0.0}), "merge_watermark"),
+        ];
+        for (fields, field) in cases.iter() {
+            assert_eq!(rejected_field(fields.clone()), *field);
+        }
+    }
+
+    fn env_vars(vars: &[(&str, &str)]) -> impl Iterator<Item = (String, String)> {
+        vars.iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect::<Vec<(String, String)>>()
+            .into_iter()
+    }
+
+    #[test]
+    fn env_overrides_apply() {
+        let vars = env_vars(&[
+            ("SPADA_PE_NUM", "4"),
+            ("SPADA_BLOCK_SHAPE", "[2, 64]"),
+            ("HOME", "/root"),
+        ]);
+        let config = apply_env_overrides(test_config(), vars).unwrap();
+        assert_eq!(config.pe_num, 4);
+        assert_eq!(config.block_shape, [2, 64]);
+        assert_eq!(config.lane_num, test_config().lane_num);
+        assert_eq!(config.validate(), Ok(()));
+    }
+
+    #[test]
+    fn invalid_env_overrides_are_rejected() {
+        let cases = [
+            ("SPADA_NO_SUCH_FIELD", "1"),
+            ("SPADA_PE_NUM", "four"),
+            ("SPADA_BLOCK_SHAPE", "[2]"),
+        ];
+        for (key, value) in cases.iter() {
+            let err = apply_env_overrides(test_config(), env_vars(&[(key, value)])).unwrap_err();
+            assert!(err.to_string().contains(key), "{}", err);
+        }
+    }
+
+    #[test]
+    fn env_overrides_are_validated() {
+        // The override parses, the config check of each GEMM rejects it.
+        let vars = env_vars(&[("SPADA_PE_NUM", "0")]);
+        let config = apply_env_overrides(test_config(), vars).unwrap();
+        assert_eq!(config.validate(), Err(ConfigError::NotPositive("pe_num")));
+    }
+}
@@ -1,6 +1,10 @@
+use crate::frontend::RowDistribution;
+use crate::scalar::Scalar;
 use pyo3::prelude::*;
+use rand::{rngs::StdRng, seq::index::sample, seq::SliceRandom, Rng, SeedableRng};
 use sprs::CsMat;
 use std::cmp::min;
+use std::error::Error;
 use std::fmt;
 
 #[derive(FromPyObject, Debug)]
@@ -23,22 +27,95 @@ pub struct CsrTuple(
     pub Vec<f64>,
 );
 
-pub struct GEMM {
+#[derive(Clone)]
+pub struct GEMM<T = f64> {
     pub name: String,
-    pub a: CsMat<f64>,
-    pub b: CsMat<f64>,
+    pub a: CsMat<T>,
+    pub b: CsMat<T>,
 }
 
+// A and B of a GEMM whose inner dimensions differ.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShapeMismatch {
+    pub name: String,
+    pub a_shape: (usize, usize),
+    pub b_shape: (usize, usize),
+}
+
+impl fmt::Display for ShapeMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Inner dimensions of {} do not match: A is {}x{} but B is {}x{}",
+            self.name, self.a_shape.0, self.a_shape.1, self.b_shape.0, self.b_shape.1
+        )
+    }
+}
+
+impl Error for ShapeMismatch {}
+
 impl GEMM {
-    pub fn new(gn: &str, grt: GEMMRawTuple) -> GEMM {
-        GEMM {
-            name: gn.to_owned(),
-            a: CsMat::new(grt.0, grt.1, grt.2, grt.3),
-            b: CsMat::new(grt.4, grt.5, grt.6, grt.7),
+    pub fn new(gn: &str, grt: GEMMRawTuple) -> Result<GEMM, ShapeMismatch> {
+        GEMM::try_new(
+            gn,
+            CsMat::new(grt.0, grt.1, grt.2, grt.3),
+            CsMat::new(grt.4, grt.5, grt.6, grt.7),
+        )
+    }
+
+    // Generate A (rows x cols) and B (cols x rows) with the given density, reproducible by seed.
+    pub fn random(
+        rows: usize,
+        cols: usize,
+        density: f64,
+        seed: u64,
+        distribution: RowDistribution,
+    ) -> Result<GEMM, String> {
+        if rows == 0 || cols == 0 {
+            return Err(format!("Invalid synthetic matrix shape: {}x{}", rows, cols));
+        }
+        if !(density > 0.0 && density <= 1.0) {
+            return Err(format!(
+                "Invalid synthetic matrix density: {}, expected in (0, 1]",
+                density
+            ));
+        }
+        let mut rng = StdRng::seed_from_u64(seed);
+        let a = random_csr(&mut rng, [rows, cols], density, &distribution);
+        let b = random_csr(&mut rng, [cols, rows], density, &distribution);
+        Ok(GEMM {
+            name: format!("{}_{}x{}_{}_{}", distribution, rows, cols, density, seed).to_lowercase(),
+            a,
+            b,
+        })
+    }
+}
+
+impl<T: Scalar> GEMM<T> {
+    // A (m x k) times B (k x n), the result is m x n.
+    pub fn try_new(name: &str, a: CsMat<T>, b: CsMat<T>) -> Result<GEMM<T>, ShapeMismatch> {
+        let gemm = GEMM {
+            name: name.to_owned(),
+            a,
+            b,
+        };
+        gemm.check_shape()?;
+        Ok(gemm)
+    }
+
+    // The fields are public, so the GEMMs built directly are checked again before a simulation.
+    pub fn check_shape(&self) -> Result<(), ShapeMismatch> {
+        if self.a.cols() != self.b.rows() {
+            return Err(ShapeMismatch {
+                name: self.name.clone(),
+                a_shape: self.a.shape(),
+                b_shape: self.b.shape(),
+            });
         }
+        Ok(())
     }
 
-    pub fn from_mat(mn: &str, mat: CsMat<f64>) -> GEMM {
+    pub fn from_mat(mn: &str, mat: CsMat<T>) -> GEMM<T> {
         // If the matrix is square, use A * A, otherwise A * AT.
         let b_mat = if mat.shape().0 == mat.shape().1 {
             mat.clone()
@@ -53,7 +130,53 @@ impl GEMM {
     }
 }
 
-impl fmt::Display for GEMM {
+fn random_csr(
+    rng: &mut StdRng,
+    shape: [usize; 2],
+    density: f64,
+    distribution: &RowDistribution,
+) -> CsMat<f64> {
+    let [rows, cols] = shape;
+    let total = density * (rows * cols) as f64;
+    let row_lens: Vec<f64> = match distribution {
+        RowDistribution::Uniform => vec![total / rows as f64; rows],
+        RowDistribution::PowerLaw => {
+            // Zipf weights, filled from the heaviest row so that the nnz a full row cannot
+            // hold is spread over the remaining ones.
+            let weights = (1..=rows).map(|r| 1.0 / r as f64).collect::<Vec<f64>>();
+            let mut weight_sum = weights.iter().sum::<f64>();
+            let mut remain = total;
+            let mut lens = vec![];
+            for w in weights.iter() {
+                let len = (remain * w / weight_sum).min(cols as f64);
+                remain -= len;
+                weight_sum -= w;
+                lens.push(len);
+            }
+            lens.shuffle(rng);
+            lens
+        }
+    };
+
+    let mut indptr = vec![0];
+    let mut indices = vec![];
+    let mut data = vec![];
+    for len in row_lens {
+        // Round the fractional part randomly to keep the expected nnz.
+        let mut row_len = len.floor() as usize;
+        if rng.gen::<f64>() < len.fract() {
+            row_len += 1;
+        }
+        let mut cols_idx = sample(rng, cols, min(row_len, cols)).into_vec();
+        cols_idx.sort_unstable();
+        data.extend(cols_idx.iter().map(|_| rng.gen_range(-1.0..1.0)));
+        indices.extend(cols_idx);
+        indptr.push(indices.len());
+    }
+    CsMat::new((rows, cols), indptr, indices, data)
+}
+
+impl<T: Scalar> fmt::Display for GEMM<T> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "---- {} ----\n", self.name)?;
         write!(f, "--A: {:?}\n", self.a.shape())?;
@@ -90,3 +213,55 @@ impl fmt::Display for GEMM {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn random_gemm_is_reproducible() {
+        for distribution in [RowDistribution::Uniform, RowDistribution::PowerLaw].iter() {
+            let gemm = GEMM::random(40, 30, 0.2, 7, distribution.clone()).unwrap();
+            let again = GEMM::random(40, 30, 0.2, 7, distribution.clone()).unwrap();
+            assert_eq!(gemm.name, again.name);
+            assert_eq!(gemm.a, again.a);
+            assert_eq!(gemm.b, again.b);
+            assert_eq!(gemm.a.shape(), (40, 30));
+            assert_eq!(gemm.b.shape(), (30, 40));
+            let other = GEMM::random(40, 30, 0.2, 8, distribution.clone()).unwrap();
+            assert_ne!(gemm.a, other.a);
+        }
+    }
+
+    #[test]
+    fn invalid_random_gemm_is_rejected() {
+        for density in [0.0, -0.5, 1.5, f64::NAN].iter() {
+            assert!(GEMM::random(8, 8, *density, 0, RowDistribution::Uniform).is_err());
+        }
+        assert!(GEMM::random(0, 8, 0.5, 0, RowDistribution::Uniform).is_err());
+        assert!(GEMM::random(8, 0, 0.5, 0, RowDistribution::Uniform).is_err());
+        assert!(GEMM::random(8, 8, 1.0, 0, RowDistribution::Uniform).is_ok());
+    }
+
+    #[test]
+    fn mismatched_inner_dimensions_are_rejected() {
+        let mat = |rows, cols| CsMat::<f64>::zero((rows, cols));
+        let gemm = GEMM::try_new("rectangular", mat(3, 4), mat(4, 2)).unwrap();
+        assert_eq!((gemm.a.rows(), gemm.b.cols()), (3, 2));
+        let err = GEMM::try_new("mismatched", mat(3, 4), mat(5, 2))
+            .err()
+            .unwrap();
+        assert_eq!((err.a_shape, err.b_shape), ((3, 4), (5, 2)));
+        assert_eq!(
+            err.to_string(),
+            "Inner dimensions of mismatched do not match: A is 3x4 but B is 5x2"
+        );
+        // The GEMMs built directly are checked as well.
+        let gemm = GEMM {
+            name: "direct".to_owned(),
+            a: mat(3, 4),
+            b: mat(2, 3),
+        };
+        assert!(gemm.check_shape().is_err());
+    }
+}
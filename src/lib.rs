@@ -0,0 +1,1287 @@
+#![feature(drain_filter)]
+#![feature(hash_drain_filter)]
+
+mod adder_tree;
+mod block_topo_tracker;
+mod colwise_irr_adjust;
+mod colwise_reg_adjust;
+mod dense_traffic_model;
+mod dry_run_model;
+pub mod frontend;
+pub mod gemm;
+pub mod mm_reader;
+mod preprocessing;
+pub mod py2rust;
+mod rowwise_adjust;
+mod rowwise_perf_adjust;
+pub mod scalar;
+mod scheduler;
+mod simulator;
+pub mod stats;
+pub mod storage;
+pub mod util;
+
+#[cfg(test)]
+mod test_util;
+
+use crate::dense_traffic_model::DenseTrafficModel;
+use crate::dry_run_model::DryRunModel;
+use crate::frontend::{Accelerator, BlockStrategy, ColReorder, OmegaConfig, Reorder, WarmB};
+use crate::gemm::GEMM;
+use crate::preprocessing::{
+    first_touch_col_order, rcm_ordering, sort_by_length, sort_cols_by_b_length,
+};
+use crate::scalar::Scalar;
+use crate::simulator::Simulator;
+use crate::stats::SimulationStats;
+use crate::storage::{CsrMatStorage, CsrRow, VectorStorage, VictimPolicy};
+use crate::util::{compare_results, csr_rows_to_mat, reference_spgemm};
+use serde_json::json;
+use sprs::CsMat;
+use std::cmp::max;
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+
+pub use crate::simulator::{ProgressCallback, ProgressInfo};
+
+// Run options that do not come from the config file.
+#[derive(Debug, Clone, Default)]
+pub struct SimulationOptions {
+    pub accelerator: Accelerator,
+    pub block_strategy: BlockStrategy,
+    pub reorder: Reorder,
+    pub reorder_col: ColReorder,
+    pub verify: bool,
+    pub seed: u64,
+    pub max_cycles: Option<usize>,
+    // State file written when the run is truncated, and the one to resume from.
+    pub checkpoint: Option<String>,
+    pub resume: Option<String>,
+    pub reuse_analysis: bool,
+    // Share the fibers of A and B, which must be the same matrix.
+    pub self_multiply: bool,
+    pub warm_b: WarmB,
+    // B fibers never evicted once loaded: this many referenced by the most A rows, and the row ids
+    // listed in a file.
+    pub pin_hot: Option<usize>,
+    pub pin_file: Option<String>,
+    // Keep only the lower triangle of the symmetric operands.
+    pub symmetric_storage: bool,
+    // JSON lines files of the block and window shape decisions.
+    pub record_schedule: Option<String>,
+    pub replay_schedule: Option<String>,
+    // CSV report of the allocated blocks.
+    pub block_report: Option<String>,
+    // JSON lines event trace, appended to if the file exists.
+    pub trace_out: Option<String>,
+    // CSV of the task of every PE at every cycle, appended to if the file exists.
+    pub pe_log: Option<String>,
+    // CSV of the cache occupancy at every cycle, appended to if the file exists.
+    pub occupancy_out: Option<String>,
+    // CSV of the bytes per DRAM channel in every epoch of channel_epoch cycles, appended to if the
+    // file exists.
+    pub channel_util_out: Option<String>,
+    pub channel_epoch: usize,
+    // Check the psum bookkeeping after every cycle.
+    pub debug_checks: bool,
+    // Sum the simulated products of each result element in column order of A, and compare the
+    // results of accelerators exactly.
+    pub stable_reduce: bool,
+    // Builds the victim chooser of the fiber cache instead of the configured replacement policy,
+    // fresh for every run.
+    pub victim_policy: Option<fn() -> Box<dyn VictimPolicy>>,
+}
+
+pub struct SimulationReport<T = f64> {
+    pub stats: SimulationStats,
+    pub result: Vec<CsrRow<T>>,
+    pub shape: [usize; 2],
+    // The (row, col, expected, got) entries that differ from the reference product if verified.
+    pub mismatches: Option<Vec<(usize, usize, T, T)>>,
+}
+
+pub struct ComparisonReport<T = f64> {
+    pub reports: Vec<SimulationReport<T>>,
+    // The entries of each result that differ from the result of the first accelerator.
+    pub result_mismatches: Vec<Vec<(usize, usize, T, T)>>,
+}
+
+pub struct ChainReport<T = f64> {
+    pub stages: Vec<SimulationReport<T>>,
+    // The counts summed over the stages.
+    pub stats: SimulationStats,
+    // The entries of the last result that differ from the reference chained product if verified.
+    pub mismatches: Option<Vec<(usize, usize, T, T)>>,
+}
+
+// Simulate one GEMM on the cycle-accurate simulator.
+pub fn run_simulation<T: Scalar>(
+    spada_config: &OmegaConfig,
+    gemm: GEMM<T>,
+    options: &SimulationOptions,
+) -> Result<SimulationReport<T>, Box<dyn Error>> {
+    run_simulation_with_progress(spada_config, gemm, options, None)
+}
+
+// Same as run_simulation, reporting the progress every interval cycles.
+pub fn run_simulation_with_progress<T: Scalar>(
+    spada_config: &OmegaConfig,
+    gemm: GEMM<T>,
+    options: &SimulationOptions,
+    progress: Option<(usize, ProgressCallback)>,
+) -> Result<SimulationReport<T>, Box<dyn Error>> {
+    let PreparedGemm {
+        workload,
+        c_shape,
+        reference,
+        mut dram_a,
+        mut dram_b,
+        mut dram_psum,
+        output_base_addr,
+        default_block_shape,
+    } = prepare_gemm(spada_config, gemm, options)?;
+    let mut cycle_simu = Simulator::new(
+        &spada_config,
+        output_base_addr,
+        default_block_shape,
+        &mut dram_a,
+        &mut dram_b,
+        &mut dram_psum,
+        options.accelerator.clone(),
+        options.block_strategy,
+        options.seed,
+    );
+    run_prepared(
+        &mut cycle_simu,
+        options,
+        workload,
+        c_shape,
+        reference,
+        progress,
+    )
+}
+
+// Simulate the GEMMs one after another on one simulator, which is reset in between instead of
+// being built again for every GEMM.
+pub fn run_simulation_batch<T: Scalar>(
+    spada_config: &OmegaConfig,
+    gemms: Vec<GEMM<T>>,
+    options: &SimulationOptions,
+) -> Vec<Result<SimulationReport<T>, Box<dyn Error>>> {
+    let mut reports = vec![];
+    let mut gemms = gemms.into_iter();
+    // The first GEMM that can be prepared builds the simulator.
+    let first = loop {
+        match gemms
+            .next()
+            .map(|gemm| prepare_gemm(spada_config, gemm, options))
+        {
+            Some(Ok(prepared)) => break prepared,
+            Some(Err(e)) => reports.push(Err(e)),
+            None => return reports,
+        }
+    };
+    let PreparedGemm {
+        workload,
+        c_shape,
+        reference,
+        mut dram_a,
+        mut dram_b,
+        mut dram_psum,
+        output_base_addr,
+        default_block_shape,
+    } = first;
+    let mut cycle_simu = Simulator::new(
+        &spada_config,
+        output_base_addr,
+        default_block_shape,
+        &mut dram_a,
+        &mut dram_b,
+        &mut dram_psum,
+        options.accelerator.clone(),
+        options.block_strategy,
+        options.seed,
+    );
+    reports.push(run_prepared(
+        &mut cycle_simu,
+        options,
+        workload,
+        c_shape,
+        reference,
+        None,
+    ));
+    for gemm in gemms {
+        let prepared = match prepare_gemm(spada_config, gemm, options) {
+            Ok(prepared) => prepared,
+            Err(e) => {
+                reports.push(Err(e));
+                continue;
+            }
+        };
+        cycle_simu.reset(
+            &spada_config,
+            prepared.output_base_addr,
+            prepared.default_block_shape,
+            prepared.dram_a,
+            prepared.dram_b,
+            prepared.dram_psum,
+            options.accelerator.clone(),
+            options.block_strategy,
+            options.seed,
+        );
+        reports.push(run_prepared(
+            &mut cycle_simu,
+            options,
+            prepared.workload,
+            prepared.c_shape,
+            prepared.reference,
+            None,
+        ));
+    }
+    reports
+}
+
+// Simulate the same GEMM on each accelerator, every run on a fresh simulator, and compare the
+// results, which are the same product.
+pub fn run_comparison<T: Scalar>(
+    spada_config: &OmegaConfig,
+    gemm: &GEMM<T>,
+    options: &SimulationOptions,
+    accelerators: &[Accelerator],
+) -> Result<ComparisonReport<T>, Box<dyn Error>> {
+    let mut reports: Vec<SimulationReport<T>> = vec![];
+    for accelerator in accelerators.iter() {
+        let options = SimulationOptions {
+            accelerator: accelerator.clone(),
+            ..options.clone()
+        };
+        reports.push(run_simulation(spada_config, gemm.clone(), &options)?);
+    }
+    // A truncated result misses the unfinished rows, so it is not compared.
+    let epsilon = if options.stable_reduce { 0.0 } else { 1e-9 };
+    let result_mismatches = reports
+        .iter()
+        .map(|report| {
+            if report.stats.truncated || reports[0].stats.truncated {
+                vec![]
+            } else {
+                compare_results(&reports[0].result, &report.result, epsilon)
+            }
+        })
+        .collect();
+    Ok(ComparisonReport {
+        reports,
+        result_mismatches,
+    })
+}
+
+// Simulate the GEMM and then multiply its product by each operand in turn, e.g. A·B·C. Every stage
+// is a full run on a fresh simulator, which reads the product of the previous stage as its A.
+pub fn run_chain<T: Scalar>(
+    spada_config: &OmegaConfig,
+    gemm: GEMM<T>,
+    operands: Vec<(String, CsMat<T>)>,
+    options: &SimulationOptions,
+) -> Result<ChainReport<T>, Box<dyn Error>> {
+    if options.resume.is_some() || options.checkpoint.is_some() {
+        return Err("A chained product can not be checkpointed".into());
+    }
+    let mut reference = if options.verify {
+        Some(reference_spgemm(&gemm.a, &gemm.b))
+    } else {
+        None
+    };
+    let mut workload = gemm.name.clone();
+    let mut stages = vec![run_simulation(spada_config, gemm, options)?];
+    for (name, operand) in operands.into_iter() {
+        let prev = stages.last().unwrap();
+        // A partial product would silently drop the unfinished rows from the next stages.
+        if prev.stats.truncated {
+            return Err(format!("Stage {} is truncated by the cycle limit", workload).into());
+        }
+        if let Some(reference) = reference.as_mut() {
+            let prev_reference = csr_rows_to_mat(reference, prev.shape);
+            *reference = reference_spgemm(&prev_reference, &operand);
+        }
+        workload = format!("{}*{}", workload, name);
+        let a = csr_rows_to_mat(&prev.result, prev.shape);
+        let gemm = GEMM::try_new(&workload, a, operand)?;
+        stages.push(run_simulation(spada_config, gemm, options)?);
+    }
+    let stage_stats = stages
+        .iter()
+        .map(|stage| stage.stats.clone())
+        .collect::<Vec<SimulationStats>>();
+    let mismatches = reference.map(|reference| {
+        let epsilon = if options.stable_reduce { 0.0 } else { 1e-9 };
+        compare_results(&reference, &stages.last().unwrap().result, epsilon)
+    });
+    Ok(ChainReport {
+        stages,
+        stats: SimulationStats::chain(&workload, &stage_stats),
+        mismatches,
+    })
+}
+
+// The DRAM storages of a GEMM after the preprocessing.
+struct PreparedGemm<T> {
+    workload: String,
+    c_shape: [usize; 2],
+    reference: Option<Vec<CsrRow<T>>>,
+    dram_a: CsrMatStorage<T>,
+    dram_b: CsrMatStorage<T>,
+    dram_psum: VectorStorage<T>,
+    output_base_addr: usize,
+    default_block_shape: [usize; 2],
+}
+
+fn prepare_gemm<T: Scalar>(
+    spada_config: &OmegaConfig,
+    gemm: GEMM<T>,
+    options: &SimulationOptions,
+) -> Result<PreparedGemm<T>, Box<dyn Error>> {
+    spada_config.validate()?;
+    let workload = gemm.name.clone();
+    gemm.check_shape()?;
+    if options.self_multiply {
+        if gemm.a != gemm.b {
+            return Err("Self multiply needs the same matrix for A and B".into());
+        }
+        // The renumbered A columns would no longer match the B fibers.
+        if options.reorder_col != ColReorder::None {
+            return Err("Self multiply does not support column reordering".into());
+        }
+    }
+    let c_shape = [gemm.a.rows(), gemm.b.cols()];
+    let reference = if options.verify {
+        Some(reference_spgemm(&gemm.a, &gemm.b))
+    } else {
+        None
+    };
+    let (mut dram_a, mut dram_b) =
+        CsrMatStorage::init_with_gemm(gemm, spada_config.word_byte, spada_config.index_byte);
+    let dram_psum = VectorStorage::new(spada_config.word_byte, spada_config.index_byte);
+    if options.symmetric_storage {
+        for (name, mat) in [("A", &mut dram_a), ("B", &mut dram_b)] {
+            if mat.to_half_storage() {
+                info_println!("{} is symmetric, stored as its lower triangle", name);
+            }
+        }
+    }
+
+    // Preprocessing.
+    let rowmap = match options.reorder {
+        Reorder::None => None,
+        Reorder::Asc => Some(sort_by_length(&mut dram_a, false)),
+        Reorder::Desc => Some(sort_by_length(&mut dram_a, true)),
+        Reorder::Rcm => Some(rcm_ordering(&dram_a)),
+    };
+    if let Some(rowmap) = rowmap {
+        dram_a.reorder_row(rowmap);
+    }
+    let colmap = match options.reorder_col {
+        ColReorder::None => None,
+        ColReorder::FirstTouch => Some(first_touch_col_order(&dram_a, dram_b.row_num())),
+        ColReorder::BLength => Some(sort_cols_by_b_length(&dram_b)),
+    };
+    if let Some(colmap) = colmap {
+        dram_a.reorder_col(&mut dram_b, colmap);
+    }
+
+    let output_base_addr = dram_b.indptr.len();
+    // Determine the default window & block shape.
+    let default_block_shape = match options.accelerator {
+        Accelerator::Ip => spada_config.block_shape,
+        Accelerator::MultiRow => [spada_config.block_shape[0], spada_config.block_shape[1]],
+        // A whole column of A per block, its B row is fetched once and broadcast to the lanes.
+        Accelerator::Op => [1, max(c_shape[0], 1)],
+        Accelerator::Spada => spada_config.block_shape,
+    };
+
+    Ok(PreparedGemm {
+        workload,
+        c_shape,
+        reference,
+        dram_a,
+        dram_b,
+        dram_psum,
+        output_base_addr,
+        default_block_shape,
+    })
+}
+
+fn run_prepared<T: Scalar>(
+    cycle_simu: &mut Simulator<T>,
+    options: &SimulationOptions,
+    workload: String,
+    c_shape: [usize; 2],
+    reference: Option<Vec<CsrRow<T>>>,
+    progress: Option<(usize, ProgressCallback)>,
+) -> Result<SimulationReport<T>, Box<dyn Error>> {
+    cycle_simu.max_cycles = options.max_cycles;
+    if options.reuse_analysis {
+        cycle_simu.enable_reuse_analysis();
+    }
+    cycle_simu.shared_operand = options.self_multiply;
+    cycle_simu.debug_checks = options.debug_checks;
+    cycle_simu.stable_reduce = options.stable_reduce;
+    if let Some(trace_out) = options.trace_out.as_ref() {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(trace_out)?;
+        let mut writer = BufWriter::new(file);
+        writeln!(
+            writer,
+            "{}",
+            json!({"event": "start", "workload": workload, "cycle": 0})
+        )?;
+        cycle_simu.trace_out = Some(writer);
+    }
+    if let Some(pe_log) = options.pe_log.as_ref() {
+        let file = OpenOptions::new().create(true).append(true).open(pe_log)?;
+        let is_empty = file.metadata()?.len() == 0;
+        let mut writer = BufWriter::new(file);
+        if is_empty {
+            writeln!(
+                writer,
+                "workload,cycle,unit,idx,block_token,window_token,merge_mode,a_ele_num"
+            )?;
+        }
+        cycle_simu.pe_log = Some((workload.clone(), writer));
+    }
+    if let Some(occupancy_out) = options.occupancy_out.as_ref() {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(occupancy_out)?;
+        let is_empty = file.metadata()?.len() == 0;
+        let mut writer = BufWriter::new(file);
+        if is_empty {
+            writeln!(
+                writer,
+                "workload,cycle,occupancy,b_occupancy,psum_occupancy,capability"
+            )?;
+        }
+        cycle_simu.occupancy_out = Some((workload.clone(), writer));
+    }
+    if let Some(channel_util_out) = options.channel_util_out.as_ref() {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(channel_util_out)?;
+        let is_empty = file.metadata()?.len() == 0;
+        let mut writer = BufWriter::new(file);
+        if is_empty {
+            writeln!(writer, "workload,epoch_start,channel,bytes")?;
+        }
+        cycle_simu.channel_util_out = Some((workload.clone(), writer));
+        cycle_simu.channel_epoch = max(options.channel_epoch, 1);
+    }
+    if let Some(victim_policy) = options.victim_policy {
+        cycle_simu.set_victim_policy(victim_policy());
+    }
+    if options.record_schedule.is_some() {
+        cycle_simu.record_schedule();
+    }
+    if let Some(replay_schedule) = options.replay_schedule.as_ref() {
+        cycle_simu.replay_schedule(replay_schedule)?;
+    }
+    if let Some((interval, callback)) = progress {
+        cycle_simu.set_progress_callback(interval, callback);
+    }
+    match options.resume.as_ref() {
+        Some(resume) => {
+            cycle_simu.load_state(resume)?;
+            cycle_simu.max_cycles = options.max_cycles;
+            cycle_simu.truncated = false;
+            cycle_simu.resume();
+        }
+        None => {
+            if options.pin_hot.is_some() || options.pin_file.is_some() {
+                let mut rowids = match options.pin_file.as_ref() {
+                    Some(pin_file) => read_rowids(pin_file)?,
+                    None => vec![],
+                };
+                if let Some(pin_hot) = options.pin_hot {
+                    rowids.extend(cycle_simu.hot_b_fibers(pin_hot));
+                }
+                cycle_simu.pin_b(&rowids);
+            }
+            if options.warm_b != WarmB::None {
+                cycle_simu.warm_b(options.warm_b == WarmB::All);
+            }
+            cycle_simu.execute();
+        }
+    }
+    if let Some(checkpoint) = options.checkpoint.as_ref().filter(|_| cycle_simu.truncated) {
+        cycle_simu.save_state(checkpoint)?;
+    }
+    if let Some(record_schedule) = options.record_schedule.as_ref() {
+        cycle_simu.save_schedule(record_schedule)?;
+    }
+    if let Some(block_report) = options.block_report.as_ref() {
+        cycle_simu.save_block_report(block_report)?;
+    }
+
+    let mut result = cycle_simu.get_exec_result();
+    let stats = cycle_simu.get_stats(&workload);
+    // A truncated result misses the unfinished rows, so it is not compared.
+    let mismatches = reference
+        .as_ref()
+        .filter(|_| options.verify && !stats.truncated)
+        .map(|reference| compare_results(reference, &result, 1e-9));
+    // The sums of the simulated merges are still verified above, so that a lost or repeated psum
+    // is reported, only the reported values are summed again in column order.
+    if options.stable_reduce {
+        cycle_simu.apply_stable_reduce(&mut result);
+    }
+
+    Ok(SimulationReport {
+        stats,
+        result,
+        shape: c_shape,
+        mismatches,
+    })
+}
+
+// Row ids listed one per line, the blank lines are skipped.
+fn read_rowids(path: &str) -> Result<Vec<usize>, Box<dyn Error>> {
+    let mut rowids = vec![];
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        if !line.trim().is_empty() {
+            rowids.push(line.trim().parse::<usize>()?);
+        }
+    }
+    Ok(rowids)
+}
+
+// Off-chip traffic and MACs of the GEMM if both operands were dense.
+pub fn run_dense_model<T: Scalar>(spada_config: &OmegaConfig, gemm: &GEMM<T>) -> SimulationStats {
+    let model = DenseTrafficModel::new(spada_config, [gemm.a.rows(), gemm.a.cols(), gemm.b.cols()]);
+    model.get_stats(&gemm.name, spada_config)
+}
+
+// Analytical estimate of the cycles and the compulsory traffic, without simulating.
+pub fn run_dry_run<T: Scalar>(
+    spada_config: &OmegaConfig,
+    gemm: &GEMM<T>,
+    accelerator: &Accelerator,
+    reuse_factor: Option<f32>,
+) -> SimulationStats {
+    let model = DryRunModel::new(spada_config, gemm);
+    model.get_stats(
+        &gemm.name,
+        &accelerator.to_string(),
+        spada_config,
+        reuse_factor,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::*;
+    use num_complex::Complex;
+    use std::cell::RefCell;
+    use std::collections::HashSet;
+    use std::rc::Rc;
+
+    #[test]
+    fn non_square_a_matches_reference() {
+        let config = test_config();
+        for accelerator in ACCELERATORS.iter() {
+            let report = run_simulation(
+                &config,
+                test_gemm(64, 48, 0.1, 1),
+                &test_options(accelerator.clone()),
+            )
+            .unwrap();
+            assert_eq!(report.mismatches.unwrap().len(), 0, "{}", accelerator);
+        }
+        let mut options = test_options(Accelerator::Spada);
+        options.block_strategy = BlockStrategy::ColwiseIrregular;
+        let report = run_simulation(&config, test_gemm(48, 64, 0.1, 2), &options).unwrap();
+        assert_eq!(report.mismatches.unwrap().len(), 0);
+    }
+
+    #[test]
+    fn outer_product_ranks_b_by_its_rows() {
+        // A has more rows than B, and the outer product walks the transposed A.
+        let config = test_config();
+        let mut warm_fibers = vec![];
+        for accelerator in [Accelerator::Ip, Accelerator::Op].iter() {
+            let mut options = test_options(accelerator.clone());
+            options.warm_b = WarmB::All;
+            options.pin_hot = Some(4);
+            let report = run_simulation(&config, test_gemm(64, 48, 0.1, 3), &options).unwrap();
+            assert_eq!(report.mismatches.unwrap().len(), 0);
+            assert_eq!(report.stats.pinned_fibers.unwrap()[0], 4);
+            warm_fibers.push(report.stats.warm_b_fibers.unwrap());
+        }
+        assert_eq!(warm_fibers[0], warm_fibers[1]);
+    }
+
+    #[test]
+    fn pinned_fibers_leave_room_for_psums() {
+        let config = config_with(serde_json::json!({"cache_size": 2048, "pin_budget": 0.99}));
+        for accelerator in ACCELERATORS.iter() {
+            let mut options = test_options(accelerator.clone());
+            options.pin_hot = Some(48);
+            let report = run_simulation(&config, test_gemm(64, 48, 0.1, 4), &options).unwrap();
+            assert_eq!(report.mismatches.unwrap().len(), 0, "{}", accelerator);
+        }
+    }
+
+    #[test]
+    fn macs_per_cycle_speeds_up_sparse_windows() {
+        // Short A rows leave most lanes of a window idle, while each busy lane streams a long B row.
+        let a = test_gemm(64, 64, 0.03, 10).a;
+        let b = test_gemm(64, 64, 0.5, 11).b;
+        let gemm = GEMM::try_new("sparse_window", a, b).unwrap();
+        let reference = reference_spgemm(&gemm.a, &gemm.b);
+        let options = test_options(Accelerator::Spada);
+        let compute_cycles = |config: &OmegaConfig| {
+            let report = run_simulation(config, gemm.clone(), &options).unwrap();
+            assert_eq!(report.mismatches.unwrap().len(), 0);
+            assert!(compare_results(&reference, &report.result, 1e-9).is_empty());
+            report
+                .stats
+                .pe_stats
+                .iter()
+                .map(|pe| pe.compute_cycle)
+                .sum::<usize>()
+        };
+        let fixed = compute_cycles(&test_config());
+        let shared = compute_cycles(&config_with(serde_json::json!({"macs_per_cycle": 8})));
+        assert!(shared < fixed, "{} >= {}", shared, fixed);
+    }
+
+    #[test]
+    fn idle_pes_take_windows_of_a_dense_row() {
+        // The first row of A is full, so its block holds many more windows than the others.
+        let mut row_lens = vec![2; 32];
+        row_lens[0] = 256;
+        let a = csr_with_row_lens(&row_lens, 256);
+        let b = test_gemm(32, 256, 0.05, 12).b;
+        let gemm = GEMM::try_new("dense_row", a, b).unwrap();
+        let reference = reference_spgemm(&gemm.a, &gemm.b);
+        let report =
+            run_simulation(&test_config(), gemm, &test_options(Accelerator::Spada)).unwrap();
+        assert!(report.stats.steal_count > 0);
+        // A window taken twice would add its products twice.
+        assert_eq!(report.mismatches.unwrap().len(), 0);
+        assert!(compare_results(&reference, &report.result, 1e-9).is_empty());
+    }
+
+    #[test]
+    fn integer_and_complex_gemms_match_reference() {
+        let gemm = test_gemm(40, 32, 0.15, 13);
+        // Large integers so that the products and their sums wrap around.
+        let to_int = |v: f64| (v * 4e18) as i64;
+        let (a, b) = (map_values(&gemm.a, to_int), map_values(&gemm.b, to_int));
+        let int_gemm = GEMM::try_new("int", a, b).unwrap();
+        let to_complex = |v: f64| Complex::new(v, 1.0 - v);
+        let (a, b) = (
+            map_values(&gemm.a, to_complex),
+            map_values(&gemm.b, to_complex),
+        );
+        let complex_gemm = GEMM::try_new("complex", a, b).unwrap();
+        for accelerator in ACCELERATORS.iter() {
+            let options = test_options(accelerator.clone());
+            let reference = reference_spgemm(&int_gemm.a, &int_gemm.b);
+            let report = run_simulation(&test_config(), int_gemm.clone(), &options).unwrap();
+            assert_eq!(report.mismatches.unwrap().len(), 0, "{}", accelerator);
+            assert!(compare_results(&reference, &report.result, 0.0).is_empty());
+            let reference = reference_spgemm(&complex_gemm.a, &complex_gemm.b);
+            let report = run_simulation(&test_config(), complex_gemm.clone(), &options).unwrap();
+            assert_eq!(report.mismatches.unwrap().len(), 0, "{}", accelerator);
+            assert!(compare_results(&reference, &report.result, 1e-9).is_empty());
+        }
+    }
+
+    #[test]
+    fn data_type_scales_traffic_and_compute() {
+        let gemm = test_gemm(64, 64, 0.1, 14);
+        let options = test_options(Accelerator::Spada);
+        // An infinite cache reads each B fiber once whatever the word size.
+        let run = |data_type: &str| {
+            let config = config_with(serde_json::json!({
+                "data_type": data_type,
+                "infinite_cache": true,
+            }));
+            let report = run_simulation(&config, gemm.clone(), &options).unwrap();
+            assert_eq!(report.mismatches.unwrap().len(), 0, "{}", data_type);
+            report.stats
+        };
+        let (f64_stats, f16_stats, int8_stats) = (run("F64"), run("F16"), run("Int8"));
+        // An element takes a value and an index word. With 4 byte indices its 12 bytes halve to
+        // 6, the row pointers are read as before.
+        assert_eq!(f16_stats.b_count, f64_stats.b_count);
+        let ele_num = f64_stats.b_count[0] / 2;
+        assert_eq!(f64_stats.b_bytes[0] - f16_stats.b_bytes[0], ele_num * 6);
+        // Int8 packs 8 MACs into each multiplier lane.
+        let compute_cycle = |stats: &SimulationStats| {
+            stats
+                .pe_stats
+                .iter()
+                .map(|pe| pe.compute_cycle)
+                .sum::<usize>()
+        };
+        assert!(compute_cycle(&int8_stats) < compute_cycle(&f64_stats));
+    }
+
+    #[test]
+    fn report_holds_the_run_stats() {
+        let gemm = test_gemm(24, 16, 0.25, 4);
+        let mac_num = gemm
+            .a
+            .indices()
+            .iter()
+            .map(|col| gemm.b.outer_view(*col).unwrap().nnz())
+            .sum::<usize>();
+        let options = SimulationOptions {
+            seed: 3,
+            ..test_options(Accelerator::Spada)
+        };
+        let report = run_simulation(&test_config(), gemm.clone(), &options).unwrap();
+        assert_eq!(report.shape, [24, 24]);
+        assert_eq!(report.result.len(), 24);
+        assert_eq!(report.mismatches.unwrap().len(), 0);
+        let stats = report.stats;
+        assert_eq!(stats.workload, gemm.name);
+        assert_eq!(stats.accelerator, "Spada");
+        assert_eq!(stats.seed, 3);
+        assert!(!stats.truncated);
+        assert!(stats.exec_count > 0);
+        assert_eq!(stats.op_count[0], mac_num);
+        // A is read once, an element takes a value and an index word.
+        assert_eq!(stats.a_count[0], 2 * gemm.a.nnz());
+        assert_eq!(stats.pe_stats.len(), 2);
+    }
+
+    #[test]
+    fn symmetric_half_storage_keeps_the_result() {
+        let a = test_gemm(32, 32, 0.15, 6).a;
+        let a = &a + &a.transpose_view().to_csr();
+        let gemm = GEMM::try_new("symmetric", a.clone(), a).unwrap();
+        for accelerator in ACCELERATORS.iter() {
+            let run = |symmetric_storage: bool| {
+                let options = SimulationOptions {
+                    symmetric_storage,
+                    ..test_options(accelerator.clone())
+                };
+                run_simulation(&test_config(), gemm.clone(), &options).unwrap()
+            };
+            let (full, half) = (run(false), run(true));
+            assert_eq!(half.mismatches.unwrap().len(), 0, "{}", accelerator);
+            assert!(
+                compare_results(&full.result, &half.result, 0.0).is_empty(),
+                "{}",
+                accelerator
+            );
+            // The mirrored reads are charged like the stored ones.
+            assert_eq!(half.stats.a_count, full.stats.a_count, "{}", accelerator);
+            let [stored, materialized] = half.stats.symmetric_storage_bytes.unwrap();
+            assert!(stored < materialized, "{}", accelerator);
+        }
+    }
+
+    #[test]
+    fn dense_model_counts_every_mac() {
+        // A is 12 x 20 and B is 20 x 12.
+        let stats = run_dense_model(&test_config(), &test_gemm(12, 20, 0.1, 6));
+        assert_eq!(stats.op_count[0], 12 * 12 * 20);
+        assert_eq!(stats.dense_mac_count, stats.op_count[0]);
+        assert_eq!(stats.accelerator, "Dense");
+    }
+
+    #[test]
+    fn cycle_limit_truncates_the_run() {
+        let options = SimulationOptions {
+            max_cycles: Some(10),
+            ..test_options(Accelerator::Spada)
+        };
+        let report = run_simulation(&test_config(), test_gemm(32, 32, 0.3, 8), &options).unwrap();
+        assert!(report.stats.truncated);
+        assert_eq!(report.stats.exec_count, 10);
+        // The unfinished rows are missing, so the result is not verified.
+        assert!(report.mismatches.is_none());
+    }
+
+    #[test]
+    fn assigned_windows_finish_in_the_trace() {
+        let trace_out = temp_path("trace.jsonl");
+        let options = SimulationOptions {
+            trace_out: Some(trace_out.clone()),
+            ..test_options(Accelerator::Spada)
+        };
+        run_simulation(&test_config(), test_gemm(32, 32, 0.2, 11), &options).unwrap();
+        let events = std::fs::read_to_string(&trace_out)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect::<Vec<serde_json::Value>>();
+        std::fs::remove_file(&trace_out).unwrap();
+        assert_eq!(events[0]["event"], "start");
+        let unit_window =
+            |e: &serde_json::Value| (e["unit"].clone(), e["idx"].clone(), e["window"].clone());
+        let mut assign_num = 0;
+        for (pos, event) in events.iter().enumerate() {
+            if event["event"] != "assign" {
+                continue;
+            }
+            assign_num += 1;
+            assert!(
+                events[pos + 1..]
+                    .iter()
+                    .any(|e| e["event"] == "finish" && unit_window(e) == unit_window(event)),
+                "{}",
+                event
+            );
+        }
+        assert!(assign_num > 0);
+    }
+
+    #[test]
+    fn every_block_is_in_the_pe_log() {
+        let (block_report, pe_log) = (temp_path("blocks.csv"), temp_path("pe_log.csv"));
+        // The tokens in the given CSV column, without the header and the idle rows.
+        let tokens = |path: &str, column: usize| {
+            let csv = std::fs::read_to_string(path).unwrap();
+            std::fs::remove_file(path).unwrap();
+            csv.lines()
+                .skip(1)
+                .filter_map(|line| line.split(',').nth(column).unwrap().parse().ok())
+                .collect::<HashSet<usize>>()
+        };
+        for accelerator in ACCELERATORS.iter() {
+            let options = SimulationOptions {
+                block_report: Some(block_report.clone()),
+                pe_log: Some(pe_log.clone()),
+                ..test_options(accelerator.clone())
+            };
+            run_simulation(&test_config(), test_gemm(16, 16, 0.2, 13), &options).unwrap();
+            let (blocks, logged) = (tokens(&block_report, 0), tokens(&pe_log, 4));
+            assert!(!blocks.is_empty(), "{}", accelerator);
+            assert!(blocks.is_subset(&logged), "{}", accelerator);
+        }
+    }
+
+    #[test]
+    fn peak_occupancy_is_the_logged_maximum() {
+        // The 4 column blocks leave psums of every row to merge in the cache.
+        let config = config_with(serde_json::json!({"block_shape": [1, 4]}));
+        let occupancy_out = temp_path("occupancy.csv");
+        let options = SimulationOptions {
+            occupancy_out: Some(occupancy_out.clone()),
+            ..test_options(Accelerator::Spada)
+        };
+        let report = run_simulation(&config, test_gemm(32, 32, 0.3, 16), &options).unwrap();
+        let csv = std::fs::read_to_string(&occupancy_out).unwrap();
+        std::fs::remove_file(&occupancy_out).unwrap();
+        let mut logged_peak = [0; 3];
+        for line in csv.lines().skip(1) {
+            let fields = line.split(',').collect::<Vec<_>>();
+            for (peak, field) in logged_peak.iter_mut().zip(fields[2..5].iter()) {
+                *peak = (*peak).max(field.parse().unwrap());
+            }
+        }
+        assert_eq!(report.stats.peak_occupancy, Some(logged_peak));
+        assert!(logged_peak[2] > 0);
+    }
+
+    #[test]
+    fn chain_matches_the_triple_product() {
+        let gemm = test_gemm(20, 16, 0.25, 22);
+        let c = test_gemm(20, 12, 0.25, 23).a;
+        // The reference takes the sprs product of A and B.
+        let ab = &gemm.a * &gemm.b;
+        let reference = reference_spgemm(&ab, &c);
+        for accelerator in ACCELERATORS.iter() {
+            let options = test_options(accelerator.clone());
+            let report = run_chain(
+                &test_config(),
+                gemm.clone(),
+                vec![("c".to_owned(), c.clone())],
+                &options,
+            )
+            .unwrap();
+            assert_eq!(report.stages.len(), 2);
+            let result = &report.stages[1].result;
+            assert_eq!(report.stages[1].shape, [20, 12]);
+            assert!(
+                compare_results(&reference, result, 1e-9).is_empty(),
+                "{}",
+                accelerator
+            );
+            assert_eq!(report.mismatches.unwrap().len(), 0);
+            assert_eq!(report.stats.workload, format!("{}*c", gemm.name));
+        }
+    }
+
+    #[test]
+    fn psum_addrs_are_reused_within_the_space() {
+        let gemm = test_gemm(32, 32, 0.3, 24);
+        let run = |psum_addr_space: Option<usize>| {
+            let config = config_with(serde_json::json!({ "psum_addr_space": psum_addr_space }));
+            let report =
+                run_simulation(&config, gemm.clone(), &test_options(Accelerator::Spada)).unwrap();
+            assert_eq!(report.mismatches.unwrap().len(), 0);
+            report.stats.psum_addr_count.unwrap()
+        };
+        // Without a space the addresses only grow.
+        let [unbounded_peak, unbounded_reused] = run(None);
+        assert!(unbounded_peak > 32);
+        assert_eq!(unbounded_reused, 0);
+        let [peak, reused] = run(Some(32));
+        assert!(peak <= 32);
+        assert!(reused > 0);
+    }
+
+    #[test]
+    fn every_block_strategy_verifies() {
+        let gemm = test_gemm(48, 48, 0.15, 12);
+        // A strategy that stops making progress ends truncated instead of hanging the test.
+        for name in BlockStrategy::variants().iter() {
+            let options = SimulationOptions {
+                block_strategy: name.parse().unwrap(),
+                max_cycles: Some(1000000),
+                ..test_options(Accelerator::Spada)
+            };
+            let report = run_simulation(&test_config(), gemm.clone(), &options).unwrap();
+            assert!(!report.stats.truncated, "{}", name);
+            assert_eq!(report.mismatches.unwrap().len(), 0, "{}", name);
+        }
+    }
+
+    #[test]
+    fn self_multiply_reads_less_of_b() {
+        let a = test_gemm(48, 48, 0.15, 13).a;
+        let gemm = GEMM::try_new("a_squared", a.clone(), a).unwrap();
+        let b_reads = |self_multiply: bool| {
+            let options = SimulationOptions {
+                self_multiply,
+                ..test_options(Accelerator::Spada)
+            };
+            let report = run_simulation(&test_config(), gemm.clone(), &options).unwrap();
+            assert_eq!(report.mismatches.unwrap().len(), 0, "{}", self_multiply);
+            report.stats.b_count[0]
+        };
+        assert!(b_reads(true) < b_reads(false));
+    }
+
+    #[test]
+    fn column_major_psums_match_the_row_psums() {
+        let gemm = test_gemm(32, 32, 0.2, 15);
+        // The inner product splits the A rows over windows of 4 and merges their psums by row.
+        let config = config_with(serde_json::json!({"block_shape": [1, 4]}));
+        let run = |accelerator: Accelerator| {
+            let options = test_options(accelerator);
+            let report = run_simulation(&config, gemm.clone(), &options).unwrap();
+            assert_eq!(
+                report.mismatches.unwrap().len(),
+                0,
+                "{}",
+                options.accelerator
+            );
+            (report.result, report.stats.merge_count[0])
+        };
+        let (row_result, row_merge_tasks) = run(Accelerator::Ip);
+        let (csc_result, csc_merge_tasks) = run(Accelerator::Op);
+        assert!(compare_results(&row_result, &csc_result, 1e-9).is_empty());
+        // The column-major psums are accumulated as they are written, so nothing is merged.
+        assert!(row_merge_tasks > 0);
+        assert_eq!(csc_merge_tasks, 0);
+    }
+
+    #[test]
+    fn progress_is_reported_in_order() {
+        let infos = Rc::new(RefCell::new(vec![]));
+        let callback_infos = infos.clone();
+        let callback: ProgressCallback =
+            Box::new(move |info| callback_infos.borrow_mut().push(info));
+        run_simulation_with_progress(
+            &test_config(),
+            test_gemm(32, 32, 0.2, 16),
+            &test_options(Accelerator::Spada),
+            Some((50, callback)),
+        )
+        .unwrap();
+        let infos = infos.borrow();
+        assert!(infos.len() > 1);
+        for pair in infos.windows(2) {
+            assert!(pair[0].cycle < pair[1].cycle);
+            assert!(pair[0].a_rows_traversed <= pair[1].a_rows_traversed);
+        }
+        // The last report comes once A is traversed.
+        let last = infos.last().unwrap();
+        assert_eq!(last.a_rows_traversed, last.a_row_num);
+    }
+
+    #[test]
+    fn replayed_schedule_only_changes_the_cache_stats() {
+        let gemm = test_gemm(48, 48, 0.15, 17);
+        let (schedule, replayed_schedule) = (
+            temp_path("schedule.jsonl"),
+            temp_path("replayed_schedule.jsonl"),
+        );
+        let config = config_with(serde_json::json!({"cache_size": 4096}));
+        let options = SimulationOptions {
+            block_strategy: BlockStrategy::RowwiseLatency,
+            ..test_options(Accelerator::Spada)
+        };
+        let record_options = SimulationOptions {
+            record_schedule: Some(schedule.clone()),
+            ..options.clone()
+        };
+        let recorded = run_simulation(&config, gemm.clone(), &record_options).unwrap();
+        let replay_options = SimulationOptions {
+            replay_schedule: Some(schedule.clone()),
+            record_schedule: Some(replayed_schedule.clone()),
+            ..options
+        };
+        let replayed = run_simulation(&config, gemm.clone(), &replay_options).unwrap();
+        assert_eq!(
+            serde_json::to_value(&recorded.stats).unwrap(),
+            serde_json::to_value(&replayed.stats).unwrap()
+        );
+
+        let larger_config = config_with(serde_json::json!({"cache_size": 16384}));
+        let larger = run_simulation(&larger_config, gemm, &replay_options).unwrap();
+        assert_eq!(larger.mismatches.unwrap().len(), 0);
+        // The replay takes the same shapes, the tokens follow the timing of the cache.
+        let shapes = |path: &str| {
+            std::fs::read_to_string(path)
+                .unwrap()
+                .lines()
+                .map(|line| {
+                    let decision: serde_json::Value = serde_json::from_str(line).unwrap();
+                    (
+                        decision["kind"].clone(),
+                        decision["anchor"].clone(),
+                        decision["shape"].clone(),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(shapes(&schedule), shapes(&replayed_schedule));
+        let (stats, larger_stats) = (recorded.stats, larger.stats);
+        assert!(larger_stats.cache_miss_count[0] < stats.cache_miss_count[0]);
+        assert_eq!(larger_stats.a_count, stats.a_count);
+        assert_eq!(larger_stats.op_count, stats.op_count);
+        assert_eq!(larger_stats.merge_count[0], stats.merge_count[0]);
+        std::fs::remove_file(&schedule).unwrap();
+        std::fs::remove_file(&replayed_schedule).unwrap();
+    }
+
+    #[test]
+    fn dense_output_writes_fewer_psums() {
+        // A matrix times a vector of ones.
+        let a = test_gemm(32, 32, 0.3, 18).a;
+        let gemm = GEMM::try_new("spmv", a, csr_with_row_lens(&[1; 32], 1)).unwrap();
+        let run = |spmv_threshold: usize| {
+            let config = config_with(serde_json::json!({ "spmv_threshold": spmv_threshold }));
+            let report =
+                run_simulation(&config, gemm.clone(), &test_options(Accelerator::Spada)).unwrap();
+            assert_eq!(report.mismatches.unwrap().len(), 0, "{}", spmv_threshold);
+            // The cache writes are the B fills, alike on both paths, and the psums.
+            (report.result, report.stats.cache_count[1])
+        };
+        let (result, psum_writes) = run(0);
+        let (dense_result, dense_psum_writes) = run(1);
+        assert!(compare_results(&result, &dense_result, 1e-9).is_empty());
+        assert!(dense_psum_writes < psum_writes);
+    }
+
+    #[test]
+    fn cache_modes_bound_b_reads() {
+        let gemm = test_gemm(32, 32, 0.2, 21);
+        let b_row_nnz = |row: usize| gemm.b.outer_view(row).unwrap().nnz();
+        // An element of B takes a value and an index word.
+        let distinct_words = gemm
+            .a
+            .indices()
+            .iter()
+            .collect::<HashSet<&usize>>()
+            .into_iter()
+            .map(|col| 2 * b_row_nnz(*col))
+            .sum::<usize>();
+        let touched_words = gemm
+            .a
+            .indices()
+            .iter()
+            .map(|col| 2 * b_row_nnz(*col))
+            .sum::<usize>();
+        let b_reads = |fields: serde_json::Value| {
+            let report = run_simulation(
+                &config_with(fields),
+                gemm.clone(),
+                &test_options(Accelerator::Spada),
+            )
+            .unwrap();
+            assert_eq!(report.mismatches.unwrap().len(), 0);
+            report.stats.b_count[0]
+        };
+        assert_eq!(
+            b_reads(serde_json::json!({"infinite_cache": true})),
+            distinct_words
+        );
+        assert_eq!(b_reads(serde_json::json!({"cache_size": 0})), touched_words);
+    }
+
+    #[test]
+    fn same_seed_gives_the_same_run() {
+        let gemm = test_gemm(48, 48, 0.2, 9);
+        for accelerator in ACCELERATORS.iter() {
+            let options = SimulationOptions {
+                seed: 7,
+                ..test_options(accelerator.clone())
+            };
+            let run = || {
+                run_simulation(&test_config(), gemm.clone(), &options)
+                    .unwrap()
+                    .stats
+            };
+            let (first, second) = (run(), run());
+            assert_eq!(first.seed, 7);
+            assert_eq!(first.cache_count, second.cache_count, "{}", accelerator);
+            assert_eq!(first.exec_count, second.exec_count, "{}", accelerator);
+        }
+    }
+
+    #[test]
+    fn merge_radixes_match_reference() {
+        for merge_radix in [2, 4, 8].iter() {
+            let config = config_with(serde_json::json!({ "merge_radix": merge_radix }));
+            let report = run_simulation(
+                &config,
+                test_gemm(32, 32, 0.6, 14),
+                &test_options(Accelerator::Spada),
+            )
+            .unwrap();
+            assert_eq!(report.mismatches.unwrap().len(), 0, "{}", merge_radix);
+            assert!(report.stats.merge_count[0] > 0);
+        }
+    }
+
+    #[test]
+    fn invalid_config_is_rejected() {
+        let config = config_with(serde_json::json!({"merge_radix": 3}));
+        let gemm = test_gemm(16, 16, 0.2, 5);
+        let options = test_options(Accelerator::Spada);
+        assert!(run_simulation(&config, gemm.clone(), &options).is_err());
+        let reports = run_simulation_batch(&config, vec![gemm.clone(), gemm], &options);
+        assert!(reports.iter().all(|report| report.is_err()));
+    }
+
+    #[test]
+    fn reset_simulator_matches_fresh_one() {
+        let config = test_config();
+        let gemms = vec![
+            test_gemm(32, 24, 0.2, 6),
+            test_gemm(48, 48, 0.1, 7),
+            test_gemm(16, 40, 0.3, 8),
+        ];
+        for accelerator in ACCELERATORS.iter() {
+            let options = test_options(accelerator.clone());
+            let batch = run_simulation_batch(&config, gemms.clone(), &options);
+            for (gemm, reset) in gemms.iter().zip(batch.into_iter()) {
+                let reset = reset.unwrap();
+                let fresh = run_simulation(&config, gemm.clone(), &options).unwrap();
+                assert_eq!(
+                    serde_json::to_value(&reset.stats).unwrap(),
+                    serde_json::to_value(&fresh.stats).unwrap(),
+                    "{} {}",
+                    accelerator,
+                    gemm.name
+                );
+                assert!(compare_results(&fresh.result, &reset.result, 0.0).is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn compared_accelerators_give_the_same_result() {
+        let gemm = test_gemm(24, 24, 0.2, 15);
+        let accelerators = ACCELERATORS.to_vec();
+        let report = run_comparison(
+            &test_config(),
+            &gemm,
+            &test_options(Accelerator::Spada),
+            &accelerators,
+        )
+        .unwrap();
+        assert_eq!(report.reports.len(), accelerators.len());
+        for (accelerator, run) in accelerators.iter().zip(report.reports.iter()) {
+            assert_eq!(run.stats.accelerator, accelerator.to_string());
+            assert!(
+                compare_results(&report.reports[0].result, &run.result, 1e-9).is_empty(),
+                "{}",
+                accelerator
+            );
+        }
+        assert!(report.result_mismatches.iter().all(|m| m.is_empty()));
+    }
+
+    #[test]
+    fn stable_reduce_matches_reference_exactly() {
+        let config = test_config();
+        let gemm = test_gemm(80, 80, 0.15, 9);
+        let reference = reference_spgemm(&gemm.a, &gemm.b);
+        for reorder in [Reorder::None, Reorder::Desc].iter() {
+            for reorder_col in [ColReorder::None, ColReorder::BLength].iter() {
+                let mut options = test_options(Accelerator::Spada);
+                options.stable_reduce = true;
+                options.reorder = *reorder;
+                options.reorder_col = *reorder_col;
+                let accelerators = ACCELERATORS.to_vec();
+                let report = run_comparison(&config, &gemm, &options, &accelerators).unwrap();
+                for (accelerator, report) in accelerators.iter().zip(report.reports.iter()) {
+                    assert_eq!(report.mismatches.as_ref().unwrap().len(), 0);
+                    assert!(
+                        compare_results(&reference, &report.result, 0.0).is_empty(),
+                        "{} {} {}",
+                        accelerator,
+                        reorder,
+                        reorder_col
+                    );
+                }
+                assert!(report.result_mismatches.iter().all(|m| m.is_empty()));
+            }
+        }
+    }
+
+    #[test]
+    fn resumed_run_matches_uninterrupted_one() {
+        let config = test_config();
+        let gemm = test_gemm(48, 48, 0.2, 16);
+        let checkpoint = temp_path("checkpoint.json");
+        for accelerator in ACCELERATORS.iter() {
+            let options = test_options(accelerator.clone());
+            let full = run_simulation(&config, gemm.clone(), &options).unwrap();
+            let truncated_options = SimulationOptions {
+                max_cycles: Some(full.stats.exec_count / 2),
+                checkpoint: Some(checkpoint.clone()),
+                ..options.clone()
+            };
+            let truncated = run_simulation(&config, gemm.clone(), &truncated_options).unwrap();
+            assert!(truncated.stats.truncated);
+            let resume_options = SimulationOptions {
+                resume: Some(checkpoint.clone()),
+                ..options.clone()
+            };
+            let resumed = run_simulation(&config, gemm.clone(), &resume_options).unwrap();
+            assert_eq!(
+                serde_json::to_value(&resumed.stats).unwrap(),
+                serde_json::to_value(&full.stats).unwrap(),
+                "{}",
+                accelerator
+            );
+            assert!(compare_results(&full.result, &resumed.result, 0.0).is_empty());
+        }
+        std::fs::remove_file(&checkpoint).unwrap();
+    }
+}
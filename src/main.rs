@@ -1,121 +1,500 @@
-#![feature(drain_filter)]
-#![feature(hash_drain_filter)]
-
-mod adder_tree;
-mod block_topo_tracker;
-mod colwise_irr_adjust;
-mod colwise_reg_adjust;
-mod frontend;
-mod gemm;
-mod preprocessing;
-mod py2rust;
-mod rowwise_adjust;
-mod rowwise_perf_adjust;
-mod scheduler;
-mod simulator;
-mod storage;
-mod util;
-
-use std::cmp::min;
-
-use gemm::GEMM;
-
-use crate::frontend::{parse_config, Accelerator, Cli, Mode, WorkloadCate};
-use crate::preprocessing::sort_by_length;
-use crate::py2rust::{load_mm_mat, load_pickled_gemms};
-use crate::simulator::Simulator;
-use crate::storage::{CsrMatStorage, VectorStorage};
+use std::error::Error;
+use std::fs;
+use std::io::{self, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+
+use num_complex::Complex;
+use pyo3::{GILGuard, Python};
+use spada_sim::frontend::{
+    parse_config, parse_workloads, Cli, ColReorder, LogLevel, Mode, OmegaConfig, Reorder,
+    ScalarType, StatsFormat, WorkloadCate,
+};
+use spada_sim::gemm::GEMM;
+use spada_sim::mm_reader::{load_mm_mat_native, write_mm_result};
+use spada_sim::py2rust::{load_mm_mat, load_npz_gemm, load_pickled_gemms};
+use spada_sim::scalar::Scalar;
+use spada_sim::stats::{format_batch, format_comparison, MatrixProfile, SimulationStats};
+use spada_sim::storage::CsrRow;
+use spada_sim::util::set_log_level;
+use spada_sim::{
+    run_chain, run_comparison, run_dense_model, run_dry_run, run_simulation_with_progress,
+    ProgressCallback, ProgressInfo, SimulationOptions,
+};
+use std::thread;
 use structopt::StructOpt;
 
 fn main() {
     let cli: Cli = Cli::from_args();
-    let spada_config = parse_config(&cli.configuration).unwrap();
-    let gemm: GEMM;
-    match cli.category {
+    set_log_level(cli.verbosity);
+    if matches!(cli.verbosity, LogLevel::Trace) && !cfg!(feature = "trace_exec") {
+        eprintln!("Tracing is compiled out, rebuild with the trace_exec feature.");
+    }
+    let spada_config = match parse_config(&cli.configuration) {
+        Ok(spada_config) => spada_config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = spada_config.validate() {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+    let workloads = match parse_workloads(&cli) {
+        Ok(workloads) => workloads,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    // Start a fresh trace and PE log, the workloads of a batch append to them.
+    for path in cli
+        .trace_out
+        .iter()
+        .chain(cli.pe_log.iter())
+        .chain(cli.occupancy_out.iter())
+        .chain(cli.channel_util_out.iter())
+    {
+        fs::File::create(path).unwrap();
+    }
+
+    let (records, failed) = run_batch(&cli, &spada_config, &workloads);
+
+    let output = format_batch(&records, &cli.stats_format);
+    match cli.stats_out.as_ref() {
+        Some(stats_out) => fs::write(stats_out, output).unwrap(),
+        None if !matches!(cli.stats_format, StatsFormat::Human) => print!("{}", output),
+        None => {}
+    }
+
+    if workloads.len() > 1 {
+        println!(
+            "-----Batch finished: {} passed {} failed",
+            workloads.len() - failed.len(),
+            failed.len()
+        );
+        for workload in failed.iter() {
+            println!("Failed workload: {}", workload);
+        }
+    }
+    if failed.len() > 0 {
+        std::process::exit(1);
+    }
+}
+
+// Run each workload of the batch in turn, returning the records of the passed workloads and the
+// names of the failed ones.
+fn run_batch(
+    cli: &Cli,
+    spada_config: &OmegaConfig,
+    workloads: &[String],
+) -> (Vec<SimulationStats>, Vec<String>) {
+    // The GIL is acquired on the first Python load and then kept for the whole batch.
+    let mut gil: Option<GILGuard> = None;
+    let mut records = vec![];
+    let mut failed = vec![];
+    for workload in workloads.iter() {
+        // Report a failed workload and move on to the next one.
+        let outcome = match cli.scalar {
+            ScalarType::F64 => load_gemm(cli, spada_config, workload, &mut gil)
+                .map(|gemm| catch_workload(cli, spada_config, workload, gemm)),
+            ScalarType::I64 => load_native_gemm::<i64>(cli, spada_config, workload)
+                .map(|gemm| catch_workload(cli, spada_config, workload, gemm)),
+            ScalarType::Complex => load_native_gemm::<Complex<f64>>(cli, spada_config, workload)
+                .map(|gemm| catch_workload(cli, spada_config, workload, gemm)),
+        };
+        let outcome = match outcome {
+            Ok(outcome) => outcome,
+            Err(e) => {
+                println!("-----Skip workload {}: {}", workload, e);
+                failed.push(workload.clone());
+                continue;
+            }
+        };
+        match outcome {
+            Ok(Ok(stats)) => records.extend(stats),
+            Ok(Err(e)) => {
+                println!("-----Workload {} failed: {}", workload, e);
+                failed.push(workload.clone());
+            }
+            Err(_) => {
+                println!("-----Workload {} panicked", workload);
+                failed.push(workload.clone());
+            }
+        }
+    }
+
+    (records, failed)
+}
+
+fn load_gemm(
+    cli: &Cli,
+    spada_config: &OmegaConfig,
+    workload: &str,
+    gil: &mut Option<GILGuard>,
+) -> Result<GEMM, Box<dyn Error>> {
+    let gemm = match cli.category {
         WorkloadCate::NN => {
-            gemm = load_pickled_gemms(&spada_config.nn_filepath, &cli.workload).unwrap();
+            let py = gil.get_or_insert_with(Python::acquire_gil).python();
+            load_pickled_gemms(py, &spada_config.nn_filepath, workload, cli.sort_indices)?
+        }
+        WorkloadCate::Npz => {
+            let py = gil.get_or_insert_with(Python::acquire_gil).python();
+            load_npz_gemm(py, &spada_config.npz_filepath, workload, cli.sort_indices)?
         }
         WorkloadCate::SS => {
-            let mat = load_mm_mat(&spada_config.ss_filepath, &cli.workload).unwrap();
-            gemm = GEMM::from_mat(&cli.workload, mat);
+            let mat = if cli.native_mm {
+                load_mm_mat_native(&spada_config.ss_filepath, workload)?
+            } else {
+                let py = gil.get_or_insert_with(Python::acquire_gil).python();
+                load_mm_mat(py, &spada_config.ss_filepath, workload, cli.sort_indices)?
+            };
+            GEMM::from_mat(workload, mat)
         }
+        WorkloadCate::Synthetic => GEMM::random(
+            cli.synth_rows,
+            cli.synth_cols,
+            cli.synth_density,
+            cli.synth_seed,
+            cli.synth_distribution.clone(),
+        )?,
     };
+    Ok(gemm)
+}
 
-    let a_avg_row_len = gemm.a.nnz() / gemm.a.rows();
-    let b_avg_row_len = gemm.b.nnz() / gemm.b.rows();
-    println!("Get GEMM {}", gemm.name);
-    println!("{}", &gemm);
-    println!(
-        "Avg row len of A: {}, Avg row len of B: {}",
-        a_avg_row_len, b_avg_row_len
-    );
+// The other value types are only read by the native Matrix Market loader.
+fn load_native_gemm<T: Scalar>(
+    cli: &Cli,
+    spada_config: &OmegaConfig,
+    workload: &str,
+) -> Result<GEMM<T>, Box<dyn Error>> {
+    match cli.category {
+        WorkloadCate::SS if cli.native_mm => Ok(GEMM::from_mat(
+            workload,
+            load_mm_mat_native(&spada_config.ss_filepath, workload)?,
+        )),
+        _ => Err("Only the --native-mm SS workloads are loaded as non-f64 values".into()),
+    }
+}
+
+fn catch_workload<T: Scalar>(
+    cli: &Cli,
+    spada_config: &OmegaConfig,
+    workload: &str,
+    gemm: GEMM<T>,
+) -> thread::Result<Result<Vec<SimulationStats>, Box<dyn Error>>> {
+    panic::catch_unwind(AssertUnwindSafe(|| {
+        run_workload(cli, spada_config, workload, gemm)
+    }))
+}
+
+fn simulation_options(cli: &Cli) -> SimulationOptions {
+    SimulationOptions {
+        accelerator: cli.accelerator.clone(),
+        block_strategy: cli.block_strategy,
+        reorder: if cli.preprocess {
+            Reorder::Asc
+        } else {
+            cli.reorder
+        },
+        reorder_col: cli.reorder_col,
+        verify: cli.verify,
+        seed: cli.seed,
+        max_cycles: cli.max_cycles,
+        checkpoint: cli.checkpoint.clone(),
+        resume: cli.resume.clone(),
+        reuse_analysis: cli.reuse_analysis,
+        self_multiply: cli.self_multiply,
+        warm_b: cli.warm_b,
+        pin_hot: cli.pin_hot,
+        pin_file: cli.pin_file.clone(),
+        symmetric_storage: cli.symmetric_storage,
+        record_schedule: cli.record_schedule.clone(),
+        replay_schedule: cli.replay_schedule.clone(),
+        block_report: cli.block_report.clone(),
+        trace_out: cli.trace_out.clone(),
+        pe_log: cli.pe_log.clone(),
+        occupancy_out: cli.occupancy_out.clone(),
+        channel_util_out: cli.channel_util_out.clone(),
+        channel_epoch: cli.channel_epoch,
+        debug_checks: cli.debug_checks,
+        stable_reduce: cli.stable_reduce,
+        victim_policy: None,
+    }
+}
+
+// Cycles between two redraws of the progress bar.
+const PROGRESS_INTERVAL: usize = 1000;
+const PROGRESS_WIDTH: usize = 40;
+
+// Redraw a one-line bar of the traversed A rows and the cache occupancy on stderr.
+fn progress_bar(workload: &str) -> ProgressCallback {
+    let workload = workload.to_owned();
+    Box::new(move |info: ProgressInfo| {
+        let ratio = info.a_rows_traversed as f64 / info.a_row_num.max(1) as f64;
+        let filled = (ratio * PROGRESS_WIDTH as f64) as usize;
+        eprint!(
+            "\r{} [{}{}] {:>3}% rows {}/{} cycle {} cache {:.0}%",
+            workload,
+            "#".repeat(filled),
+            ".".repeat(PROGRESS_WIDTH - filled),
+            (ratio * 100.0) as usize,
+            info.a_rows_traversed,
+            info.a_row_num,
+            info.cycle,
+            info.cache_occupancy as f64 * 100.0 / info.cache_capability.max(1) as f64
+        );
+    })
+}
+
+fn run_workload<T: Scalar>(
+    cli: &Cli,
+    spada_config: &OmegaConfig,
+    workload: &str,
+    gemm: GEMM<T>,
+) -> Result<Vec<SimulationStats>, Box<dyn Error>> {
+    write_gemm_header(&mut io::stdout(), &gemm)?;
+    if cli.profile {
+        for (name, mat) in [("A", &gemm.a), ("B", &gemm.b)].iter() {
+            println!("-----Profile of {}", name);
+            print!(
+                "{}",
+                MatrixProfile::new(mat.indptr().as_slice().unwrap(), mat.cols())
+            );
+        }
+    }
 
     match cli.simulator {
+        Mode::AccurateSimu if cli.dry_run => {
+            let mut stats = run_dry_run(spada_config, &gemm, &cli.accelerator, cli.reuse_factor);
+            stats.workload = workload.to_owned();
+            if cli.stats_out.is_some() || matches!(cli.stats_format, StatsFormat::Human) {
+                print!("{}", stats);
+            }
+            Ok(vec![stats])
+        }
+
+        Mode::AccurateSimu if cli.compare.len() > 0 => {
+            let comparison =
+                run_comparison(spada_config, &gemm, &simulation_options(cli), &cli.compare)?;
+            let mut records = vec![];
+            for report in comparison.reports.iter() {
+                let mut stats = report.stats.clone();
+                stats.workload = workload.to_owned();
+                records.push(stats);
+            }
+            if cli.stats_out.is_some() || matches!(cli.stats_format, StatsFormat::Human) {
+                print!("{}", format_comparison(&records));
+            }
+
+            let mut failures = vec![];
+            for (report, result_mismatches) in comparison
+                .reports
+                .iter()
+                .zip(comparison.result_mismatches.iter())
+            {
+                let accelerator = &report.stats.accelerator;
+                if result_mismatches.len() > 0 {
+                    println!(
+                        "-----{} result differs from {} in {} entries",
+                        accelerator,
+                        cli.compare[0],
+                        result_mismatches.len()
+                    );
+                    failures.push(accelerator.clone());
+                }
+                match report.mismatches.as_ref() {
+                    Some(mismatches) if mismatches.len() > 0 => {
+                        println!(
+                            "-----{} verify failed with {} mismatches",
+                            accelerator,
+                            mismatches.len()
+                        );
+                        failures.push(accelerator.clone());
+                    }
+                    Some(_) => println!("-----{} verify passed", accelerator),
+                    None => {}
+                }
+            }
+            if failures.len() > 0 {
+                return Err(format!("Mismatching results of {}", failures.join(",")).into());
+            }
+
+            Ok(records)
+        }
+
+        Mode::AccurateSimu if cli.chain.len() > 0 => {
+            let mut operands = vec![];
+            for name in cli.chain.iter() {
+                operands.push((
+                    name.clone(),
+                    load_mm_mat_native(&spada_config.ss_filepath, name)?,
+                ));
+            }
+            let report = run_chain(spada_config, gemm, operands, &simulation_options(cli))?;
+            let mut records = vec![];
+            for (idx, stage) in report.stages.iter().enumerate() {
+                if cli.stats_out.is_some() || matches!(cli.stats_format, StatsFormat::Human) {
+                    println!("-----Chain stage {}: {}", idx, stage.stats.workload);
+                    print!("{}", stage.stats);
+                }
+                records.push(stage.stats.clone());
+            }
+            if cli.stats_out.is_some() || matches!(cli.stats_format, StatsFormat::Human) {
+                println!("-----Chain total: {}", report.stats.workload);
+                print!("{}", report.stats);
+            }
+            records.push(report.stats);
+
+            let last = report.stages.last().unwrap();
+            if let Some(output) = cli.output.as_ref() {
+                write_mm_result(Path::new(output), &last.result, last.shape)?;
+                println!("Write result to {}", output);
+            }
+            if let Some(mismatches) = report.mismatches.as_ref() {
+                print_mismatches(mismatches)?;
+            }
+
+            Ok(records)
+        }
+
         Mode::AccurateSimu => {
             // Cycle-accurate simulator.
-            let (mut dram_a, mut dram_b) = CsrMatStorage::init_with_gemm(gemm);
-            let mut dram_psum = VectorStorage::new();
-
-            // Preprocessing.
-            if cli.preprocess {
-                let rowmap = sort_by_length(&mut dram_a);
-                dram_a.reorder_row(rowmap);
-            }
-
-            let output_base_addr = dram_b.indptr.len();
-            // Determine the default window & block shape.
-            let default_block_shape = match cli.accelerator {
-                Accelerator::Ip => spada_config.block_shape,
-                Accelerator::MultiRow => [spada_config.block_shape[0], spada_config.block_shape[1]],
-                Accelerator::Op => [spada_config.lane_num, 1],
-                Accelerator::Spada => spada_config.block_shape,
+            let options = simulation_options(cli);
+            let progress = if cli.progress {
+                Some((PROGRESS_INTERVAL, progress_bar(workload)))
+            } else {
+                None
             };
+            let report = run_simulation_with_progress(spada_config, gemm, &options, progress)?;
+            if cli.progress {
+                eprintln!();
+            }
+            let mut stats = report.stats;
+            stats.workload = workload.to_owned();
+            // Machine readable records are emitted once the batch is done.
+            if cli.stats_out.is_some() || matches!(cli.stats_format, StatsFormat::Human) {
+                print!("{}", stats);
+            }
 
-            let mut cycle_simu = Simulator::new(
-                spada_config.pe_num,
-                spada_config.at_num,
-                spada_config.lane_num,
-                spada_config.cache_size,
-                spada_config.word_byte,
-                output_base_addr,
-                default_block_shape,
-                &mut dram_a,
-                &mut dram_b,
-                &mut dram_psum,
-                cli.accelerator.clone(),
-                spada_config.mem_latency,
-                spada_config.cache_latency,
-                spada_config.freq,
-                spada_config.channel,
-                spada_config.bandwidth_per_channel,
-            );
+            write_result_preview(&mut io::stdout(), &report.result)?;
 
-            cycle_simu.execute();
-
-            let result = cycle_simu.get_exec_result();
-            let a_count = cycle_simu.get_a_mat_stat();
-            let b_count = cycle_simu.get_b_mat_stat();
-            let c_count = cycle_simu.get_c_mat_stat();
-            let exec_count = cycle_simu.get_exec_cycle();
-            let cache_count = cycle_simu.get_cache_stat();
-
-            println!("-----Result-----");
-            println!("-----Access count");
-            println!("Execution count: {}", exec_count);
-            println!("A matrix count: read {} write {}", a_count[0], a_count[1]);
-            println!("B matrix count: read {} write {}", b_count[0], b_count[1]);
-            println!("C matrix count: read {} write {}", c_count[0], c_count[1]);
-            println!(
-                "Cache count: read {} write {}",
-                cache_count[0], cache_count[1]
-            );
+            if let Some(output) = cli.output.as_ref() {
+                write_mm_result(Path::new(output), &report.result, report.shape)?;
+                println!("Write result to {}", output);
+            }
+
+            if let Some(mismatches) = report.mismatches.as_ref() {
+                print_mismatches(mismatches)?;
+            } else if cli.verify && stats.truncated {
+                println!("-----Verify skipped on the truncated run");
+            }
 
-            println!("-----Output product matrix");
-            for idx in 0..min(result.len(), 10) {
-                println!("{}", &result[idx]);
+            Ok(vec![stats])
+        }
+
+        Mode::DenseModel => {
+            // Dense baseline of the same dimensions.
+            let mut stats = run_dense_model(spada_config, &gemm);
+            stats.workload = workload.to_owned();
+            if cli.stats_out.is_some() || matches!(cli.stats_format, StatsFormat::Human) {
+                print!("{}", stats);
             }
+            Ok(vec![stats])
         }
 
-        _ => panic!("Unimplemented simulator {}", cli.simulator),
+        _ => Err(format!("Unimplemented simulator {}", cli.simulator).into()),
+    }
+}
+
+// The baseline lines of every workload, printed at any verbosity.
+fn write_gemm_header<T: Scalar>(out: &mut impl Write, gemm: &GEMM<T>) -> io::Result<()> {
+    let a_avg_row_len = gemm.a.nnz() / gemm.a.rows();
+    let b_avg_row_len = gemm.b.nnz() / gemm.b.rows();
+    writeln!(out, "Get GEMM {}", gemm.name)?;
+    writeln!(out, "{}", gemm)?;
+    writeln!(
+        out,
+        "Avg row len of A: {}, Avg row len of B: {}",
+        a_avg_row_len, b_avg_row_len
+    )
+}
+
+fn write_result_preview<T: Scalar>(out: &mut impl Write, result: &[CsrRow<T>]) -> io::Result<()> {
+    writeln!(out, "-----Output product matrix")?;
+    for csrrow in result.iter().take(10) {
+        writeln!(out, "{}", csrrow)?;
+    }
+    Ok(())
+}
+
+fn print_mismatches<T: Scalar>(mismatches: &[(usize, usize, T, T)]) -> Result<(), Box<dyn Error>> {
+    if mismatches.len() == 0 {
+        println!("-----Verify passed");
+        return Ok(());
+    }
+    println!("-----Verify failed with {} mismatches", mismatches.len());
+    for (row, col, expected, got) in mismatches.iter().take(10) {
+        println!(
+            "({}, {}): expected {} got {}",
+            row,
+            col,
+            expected.fmt_mm(),
+            got.fmt_mm()
+        );
+    }
+    Err(format!("{} mismatches", mismatches.len()).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use spada_sim::frontend::RowDistribution;
+
+    #[test]
+    fn baseline_lines_print_when_logging_is_off() {
+        set_log_level(LogLevel::Off);
+        let gemm = GEMM::random(8, 8, 0.5, 0, RowDistribution::Uniform).unwrap();
+        let result = (0..12)
+            .map(CsrRow::<f64>::new)
+            .collect::<Vec<CsrRow<f64>>>();
+        let mut out = vec![];
+        write_gemm_header(&mut out, &gemm).unwrap();
+        write_result_preview(&mut out, &result).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with(&format!("Get GEMM {}\n", gemm.name)));
+        assert!(out.contains("Avg row len of A: "));
+        // The preview stops at the first 10 rows.
+        let preview = out.split("-----Output product matrix\n").nth(1).unwrap();
+        assert_eq!(preview.lines().count(), 10);
+    }
+
+    #[test]
+    fn batch_keeps_the_record_of_each_workload() {
+        let cli = Cli::from_iter(&[
+            "spada-sim",
+            "accuratesimu",
+            "spada",
+            "synthetic",
+            "first,second",
+            "config/config_1mb_row1.json",
+            "--synth-rows",
+            "16",
+            "--synth-cols",
+            "16",
+            "--synth-density",
+            "0.2",
+            "--verify",
+        ]);
+        let spada_config = parse_config(&cli.configuration).unwrap();
+        let workloads = parse_workloads(&cli).unwrap();
+        let (records, failed) = run_batch(&cli, &spada_config, &workloads);
+        assert!(failed.is_empty());
+        let names = records
+            .iter()
+            .map(|s| s.workload.as_str())
+            .collect::<Vec<&str>>();
+        assert_eq!(names, vec!["first", "second"]);
     }
 }
@@ -0,0 +1,392 @@
+use crate::scalar::Scalar;
+use crate::storage::CsrRow;
+use flate2::bufread::MultiGzDecoder;
+use sprs::{CsMat, TriMat};
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum MmField {
+    Real,
+    Complex,
+    Pattern,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum MmSymmetry {
+    General,
+    Symmetric,
+    SkewSymmetric,
+}
+
+pub fn load_mm_mat_native<T: Scalar>(
+    dir_path: &str,
+    mat_nm: &str,
+) -> Result<CsMat<T>, Box<dyn Error>> {
+    let mut mat_path = Path::new(dir_path).join(format!("{}.mtx", mat_nm));
+    // Fall back to the compressed file as SuiteSparse ships it.
+    let gz_path = Path::new(dir_path).join(format!("{}.mtx.gz", mat_nm));
+    if !mat_path.exists() && gz_path.exists() {
+        mat_path = gz_path;
+    }
+    stream_mm_mat(&mat_path)
+}
+
+// Gzip files are recognized by their magic bytes and decompressed while they are read.
+fn open_mm(mat_path: &Path) -> io::Result<Box<dyn BufRead>> {
+    let mut reader = BufReader::new(File::open(mat_path)?);
+    if reader.fill_buf()?.starts_with(&[0x1f, 0x8b]) {
+        Ok(Box::new(BufReader::new(MultiGzDecoder::new(reader))))
+    } else {
+        Ok(Box::new(reader))
+    }
+}
+
+pub fn read_mm_mat<T: Scalar>(mat_path: &Path) -> Result<CsMat<T>, Box<dyn Error>> {
+    parse_mm_mat(open_mm(mat_path)?)
+}
+
+pub fn parse_mm_mat<T: Scalar, R: BufRead>(reader: R) -> Result<CsMat<T>, Box<dyn Error>> {
+    let mut lines = reader.lines();
+    let header = parse_mm_header(&mut lines)?;
+    let [rows, cols, nnz] = header.shape;
+    let mut trimat = TriMat::with_capacity((rows, cols), nnz);
+    let mut entry_num = 0;
+    for line in lines {
+        if let Some((row, col, value)) = parse_mm_entry(&line?, &header)? {
+            // Expand the symmetric entries to the full matrix.
+            trimat.add_triplet(row, col, value);
+            if let Some(value) = header.mirror(row, col, value) {
+                trimat.add_triplet(col, row, value);
+            }
+            entry_num += 1;
+        }
+    }
+    check_entry_num(nnz, entry_num)?;
+    Ok(trimat.to_csr())
+}
+
+// Build the CSR arrays in two passes over the file, the first one counting the row lengths, so
+// that the entries are not held a second time as triplets. The result is the same as read_mm_mat,
+// the duplicate entries are summed in file order.
+pub fn stream_mm_mat<T: Scalar>(mat_path: &Path) -> Result<CsMat<T>, Box<dyn Error>> {
+    // Count the entries of each row.
+    let mut lines = open_mm(mat_path)?.lines();
+    let header = parse_mm_header(&mut lines)?;
+    let [rows, cols, nnz] = header.shape;
+    let mut indptr = vec![0; rows + 1];
+    let mut entry_num = 0;
+    for line in lines {
+        if let Some((row, col, value)) = parse_mm_entry::<T>(&line?, &header)? {
+            indptr[row + 1] += 1;
+            if header.mirror(row, col, value).is_some() {
+                indptr[col + 1] += 1;
+            }
+            entry_num += 1;
+        }
+    }
+    check_entry_num(nnz, entry_num)?;
+    for rowid in 0..rows {
+        indptr[rowid + 1] += indptr[rowid];
+    }
+
+    // Place the entries in their rows.
+    let mut indices = vec![0; indptr[rows]];
+    let mut data = vec![T::zero(); indptr[rows]];
+    let mut cursor = indptr[..rows].to_vec();
+    let mut lines = open_mm(mat_path)?.lines();
+    parse_mm_header(&mut lines)?;
+    for line in lines {
+        if let Some((row, col, value)) = parse_mm_entry(&line?, &header)? {
+            indices[cursor[row]] = col;
+            data[cursor[row]] = value;
+            cursor[row] += 1;
+            if let Some(value) = header.mirror(row, col, value) {
+                indices[cursor[col]] = row;
+                data[cursor[col]] = value;
+                cursor[col] += 1;
+            }
+        }
+    }
+
+    // Sort each row by column and sum the duplicates, compacting the rows in place.
+    let mut slot = 0;
+    for rowid in 0..rows {
+        let (s, t) = (indptr[rowid], indptr[rowid + 1]);
+        let mut row = indices[s..t]
+            .iter()
+            .cloned()
+            .zip(data[s..t].iter().cloned())
+            .collect::<Vec<(usize, T)>>();
+        row.sort_by_key(|e| e.0);
+        indptr[rowid] = slot;
+        for (idx, (col, value)) in row.into_iter().enumerate() {
+            if idx > 0 && indices[slot - 1] == col {
+                data[slot - 1] = data[slot - 1].plus(value);
+            } else {
+                indices[slot] = col;
+                data[slot] = value;
+                slot += 1;
+            }
+        }
+    }
+    indptr[rows] = slot;
+    indices.truncate(slot);
+    data.truncate(slot);
+    Ok(CsMat::new((rows, cols), indptr, indices, data))
+}
+
+struct MmHeader {
+    field: MmField,
+    symmetry: MmSymmetry,
+    shape: [usize; 3],
+}
+
+impl MmHeader {
+    // The value of the entry mirrored across the diagonal, if it is stored only once.
+    fn mirror<T: Scalar>(&self, row: usize, col: usize, value: T) -> Option<T> {
+        match self.symmetry {
+            _ if row == col => None,
+            MmSymmetry::General => None,
+            MmSymmetry::Symmetric => Some(value),
+            MmSymmetry::SkewSymmetric => Some(value.negate()),
+        }
+    }
+}
+
+// Parse the banner and the size line after the comments.
+fn parse_mm_header<I: Iterator<Item = io::Result<String>>>(
+    lines: &mut I,
+) -> Result<MmHeader, Box<dyn Error>> {
+    let banner = match lines.next() {
+        Some(line) => line?.to_lowercase(),
+        None => return Err("Empty Matrix Market file".into()),
+    };
+    let banner = banner.split_whitespace().collect::<Vec<&str>>();
+    if banner.len() != 5 || banner[0] != "%%matrixmarket" || banner[1] != "matrix" {
+        return Err(format!("Invalid Matrix Market banner: {}", banner.join(" ")).into());
+    }
+    if banner[2] != "coordinate" {
+        return Err(format!("Unsupported Matrix Market format: {}", banner[2]).into());
+    }
+    let field = match banner[3] {
+        "real" | "integer" | "double" => MmField::Real,
+        "complex" => MmField::Complex,
+        "pattern" => MmField::Pattern,
+        _ => return Err(format!("Unsupported Matrix Market field: {}", banner[3]).into()),
+    };
+    let symmetry = match banner[4] {
+        "general" => MmSymmetry::General,
+        "symmetric" => MmSymmetry::Symmetric,
+        "skew-symmetric" => MmSymmetry::SkewSymmetric,
+        _ => return Err(format!("Unsupported Matrix Market symmetry: {}", banner[4]).into()),
+    };
+
+    for line in lines {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('%') {
+            continue;
+        }
+        let tokens = line.split_whitespace().collect::<Vec<&str>>();
+        if tokens.len() != 3 {
+            return Err(format!("Invalid Matrix Market size line: {}", line).into());
+        }
+        let shape = [
+            tokens[0].parse::<usize>()?,
+            tokens[1].parse::<usize>()?,
+            tokens[2].parse::<usize>()?,
+        ];
+        return Ok(MmHeader {
+            field,
+            symmetry,
+            shape,
+        });
+    }
+    Err("Missing Matrix Market size line".into())
+}
+
+// Parse an entry line into the zero-based row, col and value, skipping comments and blank lines.
+fn parse_mm_entry<T: Scalar>(
+    line: &str,
+    header: &MmHeader,
+) -> Result<Option<(usize, usize, T)>, Box<dyn Error>> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('%') {
+        return Ok(None);
+    }
+    let [rows, cols, _] = header.shape;
+    let tokens = line.split_whitespace().collect::<Vec<&str>>();
+    let value_num = match header.field {
+        MmField::Real => 1,
+        MmField::Complex => 2,
+        MmField::Pattern => 0,
+    };
+    if tokens.len() < 2 + value_num {
+        return Err(format!("Invalid Matrix Market entry: {}", line).into());
+    }
+    let row = tokens[0].parse::<usize>()?;
+    let col = tokens[1].parse::<usize>()?;
+    if row == 0 || row > rows || col == 0 || col > cols {
+        return Err(format!("Matrix Market entry out of bound: {}", line).into());
+    }
+    let value = match header.field {
+        MmField::Pattern => T::one(),
+        _ => T::parse_mm(&tokens[2..2 + value_num])?,
+    };
+    Ok(Some((row - 1, col - 1, value)))
+}
+
+fn check_entry_num(nnz: usize, entry_num: usize) -> Result<(), Box<dyn Error>> {
+    if nnz != entry_num {
+        return Err(format!(
+            "Matrix Market entry number mismatch: expect {} get {}",
+            nnz, entry_num
+        )
+        .into());
+    }
+    Ok(())
+}
+
+pub fn write_mm_result<T: Scalar>(
+    mat_path: &Path,
+    result: &Vec<CsrRow<T>>,
+    shape: [usize; 2],
+) -> Result<(), Box<dyn Error>> {
+    let mut writer = BufWriter::new(File::create(mat_path)?);
+    let nnz = result.iter().map(|r| r.len()).sum::<usize>();
+    writeln!(
+        writer,
+        "%%MatrixMarket matrix coordinate {} general",
+        T::MM_FIELD
+    )?;
+    writeln!(writer, "{} {} {}", shape[0], shape[1], nnz)?;
+    // Empty rows only count in the dimensions.
+    for csrrow in result.iter().filter(|r| r.len() > 0) {
+        for (col, value) in csrrow.indptr.iter().zip(csrrow.data.iter()) {
+            writeln!(
+                writer,
+                "{} {} {}",
+                csrrow.rowptr + 1,
+                col + 1,
+                value.fmt_mm()
+            )?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{temp_path, test_gemm};
+    use crate::util::{csr_rows_to_mat, reference_spgemm};
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::fs;
+    use std::process;
+
+    const MM_TEXT: &str = "%%MatrixMarket matrix coordinate real symmetric
+% A small symmetric matrix.
+4 4 5
+1 1 1.5
+2 1 -2.0
+3 2 0.25
+4 4 3.0
+4 3 1.0
+";
+
+    #[test]
+    fn symmetric_entries_are_mirrored() {
+        let mat: CsMat<f64> = parse_mm_mat(MM_TEXT.as_bytes()).unwrap();
+        assert_eq!(mat.shape(), (4, 4));
+        // The 2 diagonal entries once, the 3 others on both sides.
+        assert_eq!(mat.nnz(), 8);
+        for (row, col, value) in [(0, 0, 1.5), (1, 0, -2.0), (2, 1, 0.25), (3, 2, 1.0)].iter() {
+            assert_eq!(mat.get(*row, *col), Some(value));
+            assert_eq!(mat.get(*col, *row), Some(value));
+        }
+        assert_eq!(mat.get(3, 3), Some(&3.0));
+    }
+
+    #[test]
+    fn general_entries_are_sorted_into_rows() {
+        let text = "%%MatrixMarket matrix coordinate real general
+2 3 3
+2 2 4.5
+1 3 2.0
+2 1 -1.0
+";
+        let mat: CsMat<f64> = parse_mm_mat(text.as_bytes()).unwrap();
+        let expected = CsMat::new((2, 3), vec![0, 1, 3], vec![2, 0, 1], vec![2.0, -1.0, 4.5]);
+        assert_eq!(mat, expected);
+    }
+
+    #[test]
+    fn written_result_reads_back() {
+        let gemm = test_gemm(12, 10, 0.3, 18);
+        let result = reference_spgemm(&gemm.a, &gemm.b);
+        let path = temp_path("result.mtx");
+        write_mm_result(Path::new(&path), &result, [12, 12]).unwrap();
+        let read = read_mm_mat::<f64>(Path::new(&path));
+        fs::remove_file(&path).unwrap();
+        assert_eq!(read.unwrap(), csr_rows_to_mat(&result, [12, 12]));
+    }
+
+    #[test]
+    fn gzip_file_parses_as_plain_text() {
+        let expected: CsMat<f64> = parse_mm_mat(MM_TEXT.as_bytes()).unwrap();
+        let mut encoder = GzEncoder::new(vec![], Compression::default());
+        encoder.write_all(MM_TEXT.as_bytes()).unwrap();
+        let gz_bytes = encoder.finish().unwrap();
+        assert!(gz_bytes.starts_with(&[0x1f, 0x8b]));
+
+        // Only the compressed file exists, so the native loader falls back to it.
+        let dir = std::env::temp_dir().join(format!("spada_mm_gz_{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let gz_path = dir.join("small.mtx.gz");
+        fs::write(&gz_path, &gz_bytes).unwrap();
+        let loaded = load_mm_mat_native::<f64>(dir.to_str().unwrap(), "small");
+        let read = read_mm_mat::<f64>(&gz_path);
+        // The magic bytes are checked, not the extension.
+        let plain_path = dir.join("plain.mtx.gz");
+        fs::write(&plain_path, MM_TEXT).unwrap();
+        let plain = stream_mm_mat::<f64>(&plain_path);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(loaded.unwrap(), expected);
+        assert_eq!(read.unwrap(), expected);
+        assert_eq!(plain.unwrap(), expected);
+    }
+
+    #[test]
+    fn streamed_matrix_equals_the_full_load() {
+        // A medium general matrix with its entries out of order and a duplicate to sum.
+        let mat = test_gemm(60, 50, 0.1, 4).a;
+        let mut entries = mat
+            .iter()
+            .map(|(value, (row, col))| format!("{} {} {}", row + 1, col + 1, value))
+            .collect::<Vec<_>>();
+        entries.reverse();
+        entries.push(entries[0].clone());
+        let text = format!(
+            "%%MatrixMarket matrix coordinate real general\n60 50 {}\n{}\n",
+            entries.len(),
+            entries.join("\n")
+        );
+        let path = temp_path("streamed.mtx");
+        fs::write(&path, text).unwrap();
+        let streamed = stream_mm_mat::<f64>(Path::new(&path)).unwrap();
+        let full = read_mm_mat::<f64>(Path::new(&path)).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(streamed.nnz(), mat.nnz());
+        assert_eq!(streamed.indptr(), full.indptr());
+        assert_eq!(streamed.indices(), full.indices());
+        let bits = |m: &CsMat<f64>| m.data().iter().map(|v| v.to_bits()).collect::<Vec<_>>();
+        assert_eq!(bits(&streamed), bits(&full));
+    }
+}
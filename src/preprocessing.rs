@@ -1,20 +1,21 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 
 use priority_queue::PriorityQueue;
 use sprs::vec;
 
+use crate::scalar::Scalar;
 use crate::storage::CsrMatStorage;
-use crate::trace_println;
+use crate::{info_println, trace_println};
 
 pub type RowMap = HashMap<usize, usize>;
 
-pub fn affinity_based_row_reordering(
-    amat: &mut CsrMatStorage,
+pub fn affinity_based_row_reordering<T: Scalar>(
+    amat: &mut CsrMatStorage<T>,
     cache_size: usize,
     a_avg_row_len: usize,
     b_avg_row_len: usize,
 ) -> Option<RowMap> {
-    println!("---Affinity based row reordering---");
+    info_println!("---Affinity based row reordering---");
     // Calculate the window size.
     let w = cache_size / (a_avg_row_len * b_avg_row_len);
     let mut pq = PriorityQueue::new();
@@ -35,7 +36,7 @@ pub fn affinity_based_row_reordering(
     }
 
     for i in 0..rows.len() {
-        println!("reorder row {}", &i);
+        trace_println!("reorder row {}", &i);
         for u in amat.read_row(rowmap[&i]).unwrap().indptr.iter() {
             for r in find_contain_rows(amat, *u).iter() {
                 if let Some((item, priority)) = pq.get(r).map(|(x, y)| (x.clone(), y.clone())) {
@@ -62,10 +63,10 @@ pub fn affinity_based_row_reordering(
     Some(rowmap)
 }
 
-fn find_contain_rows(amat: &CsrMatStorage, colid: usize) -> Vec<usize> {
+fn find_contain_rows<T: Scalar>(amat: &CsrMatStorage<T>, colid: usize) -> Vec<usize> {
     let mut result = vec![];
     for ipid in 0..amat.indptr.len() - 1 {
-        if amat.indices[amat.indptr[ipid]..amat.indptr[ipid + 1]].contains(&colid) {
+        if amat.row_entries(ipid).0.contains(&colid) {
             result.push(ipid);
         }
     }
@@ -73,13 +74,20 @@ fn find_contain_rows(amat: &CsrMatStorage, colid: usize) -> Vec<usize> {
     result
 }
 
-pub fn sort_by_length(amat: &mut CsrMatStorage) -> HashMap<usize, usize> {
+pub fn sort_by_length<T: Scalar>(
+    amat: &mut CsrMatStorage<T>,
+    descending: bool,
+) -> HashMap<usize, usize> {
     trace_println!("---Sort A rows by length---");
     let mut id_len_vector = vec![];
     for idx in 0..amat.row_num() {
-        id_len_vector.push([idx, amat.rowptr(idx + 1) - amat.rowptr(idx)]);
+        id_len_vector.push([idx, amat.get_ele_num(idx, idx + 1)]);
+    }
+    if descending {
+        id_len_vector.sort_by(|a, b| b[1].cmp(&a[1]));
+    } else {
+        id_len_vector.sort_by(|a, b| a[1].cmp(&b[1]));
     }
-    id_len_vector.sort_by(|a, b| a[1].cmp(&b[1]));
     let remap: HashMap<usize, usize> = id_len_vector
         .iter()
         .enumerate()
@@ -87,3 +95,157 @@ pub fn sort_by_length(amat: &mut CsrMatStorage) -> HashMap<usize, usize> {
         .collect();
     return remap;
 }
+
+// Reverse Cuthill-McKee on the row graph, where rows sharing a column are adjacent. The degree
+// of a row is its number of distinct neighbor rows, and each disconnected component starts from
+// its lowest degree row.
+pub fn rcm_ordering<T: Scalar>(amat: &CsrMatStorage<T>) -> RowMap {
+    trace_println!("---RCM ordering of A rows---");
+    let row_num = amat.row_num();
+    let mut col_rows: HashMap<usize, Vec<usize>> = HashMap::new();
+    for rowid in 0..row_num {
+        for colid in amat.row_entries(rowid).0.iter() {
+            col_rows.entry(*colid).or_insert(vec![]).push(rowid);
+        }
+    }
+
+    // Count the distinct neighbors of each row, seen_by marks the rows already counted for it.
+    let mut degree = vec![0; row_num];
+    let mut seen_by = vec![usize::MAX; row_num];
+    for rowid in 0..row_num {
+        seen_by[rowid] = rowid;
+        for colid in amat.row_entries(rowid).0.iter() {
+            for r in col_rows[colid].iter() {
+                if seen_by[*r] != rowid {
+                    seen_by[*r] = rowid;
+                    degree[rowid] += 1;
+                }
+            }
+        }
+    }
+
+    let mut starts = (0..row_num).collect::<Vec<usize>>();
+    starts.sort_by_key(|rowid| (degree[*rowid], *rowid));
+    let mut visited = vec![false; row_num];
+    let mut order = Vec::with_capacity(row_num);
+    let mut queue = VecDeque::new();
+    for start in starts {
+        if visited[start] {
+            continue;
+        }
+        visited[start] = true;
+        queue.push_back(start);
+        while let Some(rowid) = queue.pop_front() {
+            order.push(rowid);
+            // All the rows of an expanded column are visited from then on, so each column is
+            // only walked once.
+            let mut neighbors = vec![];
+            for colid in amat.row_entries(rowid).0.iter() {
+                for r in col_rows.remove(colid).unwrap_or_default() {
+                    if !visited[r] {
+                        visited[r] = true;
+                        neighbors.push(r);
+                    }
+                }
+            }
+            neighbors.sort_by_key(|r| (degree[*r], *r));
+            queue.extend(neighbors);
+        }
+    }
+    order.reverse();
+
+    order.into_iter().enumerate().collect()
+}
+
+// Number the columns in the order the (reordered) A rows first touch them, so that the B rows
+// used together get neighbouring addresses.
+pub fn first_touch_col_order<T: Scalar>(amat: &CsrMatStorage<T>, col_num: usize) -> RowMap {
+    let mut touched = vec![false; col_num];
+    let mut order = vec![];
+    for rowid in 0..amat.row_num() {
+        let rawid = if amat.remapped {
+            amat.row_remap[&rowid]
+        } else {
+            rowid
+        };
+        for colid in amat.row_entries(rawid).0.iter() {
+            if !touched[*colid] {
+                touched[*colid] = true;
+                order.push(*colid);
+            }
+        }
+    }
+    // Untouched columns keep their relative order at the end.
+    order.extend((0..col_num).filter(|colid| !touched[*colid]));
+
+    order.into_iter().enumerate().collect()
+}
+
+pub fn sort_cols_by_b_length<T: Scalar>(bmat: &CsrMatStorage<T>) -> RowMap {
+    let mut order = (0..bmat.row_num()).collect::<Vec<usize>>();
+    order.sort_by(|a, b| {
+        let a_len = bmat.row_len(*a);
+        let b_len = bmat.row_len(*b);
+        b_len.cmp(&a_len)
+    });
+
+    order.into_iter().enumerate().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::*;
+    use sprs::CsMat;
+
+    // The mean distance between the first columns of neighboring rows once the rows are
+    // reordered, the rows only move so the band can come out in either direction.
+    fn avg_bandwidth(amat: &CsrMatStorage<f64>, rowmap: &RowMap) -> f64 {
+        let first_cols = (0..amat.row_num())
+            .map(|rowid| amat.row_entries(rowmap[&rowid]).0[0] as isize)
+            .collect::<Vec<isize>>();
+        let dist_sum = first_cols
+            .windows(2)
+            .map(|c| (c[1] - c[0]).abs())
+            .sum::<isize>();
+        dist_sum as f64 / (amat.row_num() - 1) as f64
+    }
+
+    #[test]
+    fn rcm_starts_from_the_lowest_degree_row() {
+        // Rows 0, 2 and 1 form a path over columns 1 and 2, row 3 is alone.
+        let a = CsMat::new(
+            (4, 4),
+            vec![0, 2, 3, 5, 6],
+            vec![0, 1, 2, 1, 2, 3],
+            vec![1.0; 6],
+        );
+        let rowmap = rcm_ordering(&a_storage(a));
+        let order = (0..4).map(|rowid| rowmap[&rowid]).collect::<Vec<usize>>();
+        assert_eq!(order, vec![1, 2, 0, 3]);
+    }
+
+    #[test]
+    fn rcm_reduces_the_bandwidth_of_shuffled_band() {
+        let row_num: usize = 64;
+        // A tridiagonal matrix whose rows are shuffled by a fixed stride.
+        let mut indptr = vec![0];
+        let mut indices = vec![];
+        for rowid in 0..row_num {
+            let band_row = rowid * 37 % row_num;
+            indices.extend(band_row.saturating_sub(1)..std::cmp::min(band_row + 2, row_num));
+            indptr.push(indices.len());
+        }
+        let data = vec![1.0; indices.len()];
+        let amat = a_storage(CsMat::new((row_num, row_num), indptr, indices, data));
+        let rowmap = rcm_ordering(&amat);
+
+        let mut rows = rowmap.values().cloned().collect::<Vec<usize>>();
+        rows.sort_unstable();
+        assert_eq!(rows, (0..row_num).collect::<Vec<usize>>());
+        let identity = (0..row_num).map(|rowid| (rowid, rowid)).collect::<RowMap>();
+        let before = avg_bandwidth(&amat, &identity);
+        let after = avg_bandwidth(&amat, &rowmap);
+        assert!(after <= 1.0 && after < before / 4.0, "{} {}", before, after);
+    }
+}
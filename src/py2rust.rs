@@ -1,97 +1,296 @@
-use crate::gemm::{CsrTuple, GEMMRawTuple, GEMM};
+use crate::frontend::LogLevel;
+use crate::gemm::GEMM;
+use crate::util::{log_enabled, sort_csr_indices, unsorted_csr_rows};
+use pyo3::exceptions::PyValueError;
 use pyo3::{prelude::*, types::PyModule};
 use sprs::CsMat;
+use std::cmp::min;
 
-pub fn load_pickled_gemms(gemm_fp: &str, gemm_nm: &str) -> PyResult<GEMM> {
+// Elements copied from a numpy array at a time, so that the Python list of a chunk stays small.
+const COPY_CHUNK: usize = 1 << 20;
+
+const COPY_CSR_CODE: &str = r#"
+def csr_shape(mats, mat_idx):
+    return mats[mat_idx].shape + (mats[mat_idx].nnz,)
+
+def csr_chunk(mats, mat_idx, attr, s, e):
+    return getattr(mats[mat_idx], attr)[s:e].tolist()
+
+def release(mats, mat_idx):
+    mats[mat_idx] = None
+"#;
+
+// Copy the CSR matrices of a Python list one by one, chunk by chunk, and drop each of them from
+// the list once copied, so that a matrix is held twice at most while it is copied. The rows must
+// have strictly increasing indices, unsorted ones are sorted if sort_indices is set.
+fn copy_csr_mats(py: Python, mats: &PyAny, sort_indices: bool) -> PyResult<Vec<CsMat<f64>>> {
+    let copy_csr = PyModule::from_code(py, COPY_CSR_CODE, "copy_csr.py", "copy_csr")?;
+    let mut csrmats = vec![];
+    for mat_idx in 0..mats.len()? {
+        let (rows, cols, nnz): (usize, usize, usize) = copy_csr
+            .getattr("csr_shape")?
+            .call1((mats, mat_idx))?
+            .extract()?;
+        let indptr = copy_chunked(py, copy_csr, mats, mat_idx, "indptr", rows + 1)?;
+        let mut indices = copy_chunked(py, copy_csr, mats, mat_idx, "indices", nnz)?;
+        let mut data = copy_chunked(py, copy_csr, mats, mat_idx, "data", nnz)?;
+        copy_csr.getattr("release")?.call1((mats, mat_idx))?;
+        let unsorted_num = unsorted_csr_rows(&indptr, &indices);
+        if unsorted_num > 0 {
+            if !sort_indices {
+                return Err(PyValueError::new_err(format!(
+                    "{} rows of matrix {} have unsorted indices, rerun with --sort-indices",
+                    unsorted_num, mat_idx
+                )));
+            }
+            let sorted_num = sort_csr_indices(&indptr, &mut indices, &mut data);
+            eprintln!(
+                "Sorted the indices of {} rows of matrix {}",
+                sorted_num, mat_idx
+            );
+            // Sorting leaves the repeated indices in place.
+            let repeated_num = unsorted_csr_rows(&indptr, &indices);
+            if repeated_num > 0 {
+                return Err(PyValueError::new_err(format!(
+                    "{} rows of matrix {} have repeated indices",
+                    repeated_num, mat_idx
+                )));
+            }
+        }
+        csrmats.push(CsMat::new((rows, cols), indptr, indices, data));
+    }
+    Ok(csrmats)
+}
+
+fn pop_mat(csrmats: &mut Vec<CsMat<f64>>) -> PyResult<CsMat<f64>> {
+    csrmats
+        .pop()
+        .ok_or_else(|| PyValueError::new_err("Too few matrices returned from Python"))
+}
+
+fn copy_chunked<T>(
+    py: Python,
+    copy_csr: &PyModule,
+    mats: &PyAny,
+    mat_idx: usize,
+    attr: &str,
+    len: usize,
+) -> PyResult<Vec<T>>
+where
+    T: for<'p> FromPyObject<'p>,
+{
+    let csr_chunk = copy_csr.getattr("csr_chunk")?;
+    let mut values = Vec::with_capacity(len);
+    for s in (0..len).step_by(COPY_CHUNK) {
+        // Safety: the chunk list does not outlive the pool, which frees it right after the copy
+        // instead of when the GIL held for the batch is released.
+        let pool = unsafe { py.new_pool() };
+        let chunk: Vec<T> = csr_chunk
+            .call1((mats, mat_idx, attr, s, min(s + COPY_CHUNK, len)))?
+            .extract()?;
+        values.extend(chunk);
+        drop(pool);
+    }
+    Ok(values)
+}
+
+pub fn load_pickled_gemms(
+    py: Python,
+    gemm_fp: &str,
+    gemm_nm: &str,
+    sort_indices: bool,
+) -> PyResult<GEMM> {
     let code = r#"
-def retrieve_pickled_csr(pickle_gemm_fp, pickle_gemm_name):
-    print('---- Python Interface ----')
-    import pickle
+def to_csr(operand, mat):
     import numpy as np
     from scipy.sparse import coo_matrix, csr_matrix, csc_matrix
-    print(f'% Load {pickle_gemm_name} from', pickle_gemm_fp)
+    if isinstance(mat, (csc_matrix, coo_matrix)):
+        return mat.tocsr()
+    elif isinstance(mat, (np.ndarray)):
+        return csr_matrix(mat)
+    elif not isinstance(mat, csr_matrix):
+        raise TypeError('Unsupported matrix type of {}: {}'.format(operand, type(mat)))
+    return mat
+
+def retrieve_pickled_csr(pickle_gemm_fp, pickle_gemm_name, verbose):
+    log = print if verbose else (lambda *args: None)
+    log('---- Python Interface ----')
+    import pickle
+    log(f'% Load {pickle_gemm_name} from', pickle_gemm_fp)
     with open(pickle_gemm_fp, 'rb') as f:
         gemms = pickle.load(f)
 
-        A, B = gemms[pickle_gemm_name]
-        if isinstance(A, (csc_matrix, coo_matrix)):
-            A = A.tocsr()
-        elif isinstance(A, (np.ndarray)):
-            A = csr_matrix(A)
-        elif not isinstance(A, csr_matrix):
-            raise TypeError('Unsupported matrix type: {}'.format(type(A)))
-
-        if isinstance(B, (csc_matrix, coo_matrix)):
-            B = B.tocsr()
-        elif isinstance(B, (np.ndarray)):
-            B = csr_matrix(B)
-        elif not isinstance(B, csr_matrix):
-            raise TypeError('Unsupported matrix type: {}'.format(type(B)))
-
-        shape_A, shape_B = A.shape, B.shape
-        data_A, data_B = A.data, B.data
-        indices_A, indices_B = A.indices, B.indices
-        indptr_A, indptr_B = A.indptr, B.indptr
-
-        print(f'% -- A --')
-        print(f'% shape: {shape_A} data: {data_A[:5]}... indices: {indices_A[:5]}... indptr: {indptr_A[:5]}...')
-        print(f'% -- B --')
-        print(f'% shape: {shape_B} data: {data_B[:5]}... indices: {indices_B[:5]}... indptr: {indptr_B[:5]}...')
-    print('--- Return from Python Interface ---\n')
-    return (shape_A, indptr_A, indices_A, data_A, shape_B, indptr_B, indices_B, data_B)
+        if pickle_gemm_name not in gemms:
+            raise KeyError(f'No GEMM {pickle_gemm_name} in {pickle_gemm_fp}')
+        operands = gemms[pickle_gemm_name]
+        if len(operands) != 2:
+            raise ValueError(f'GEMM {pickle_gemm_name} has {len(operands)} operands instead of 2')
+        A = to_csr('A', operands[0])
+        B = to_csr('B', operands[1])
+
+        log(f'% -- A --')
+        log(f'% shape: {A.shape} data: {A.data[:5]}... indices: {A.indices[:5]}... indptr: {A.indptr[:5]}...')
+        log(f'% -- B --')
+        log(f'% shape: {B.shape} data: {B.data[:5]}... indices: {B.indices[:5]}... indptr: {B.indptr[:5]}...')
+    log('--- Return from Python Interface ---\n')
+    return [A, B]
     "#;
 
     let file_name = "retrieve_pickled_csr.py";
     let module_name = "retrieve_pickled_csr";
 
-    Python::with_gil(|py| {
-        let load_gemm_from_path = PyModule::from_code(py, code, file_name, module_name).unwrap();
-        let csr_tuple: GEMMRawTuple = load_gemm_from_path
-            .getattr("retrieve_pickled_csr")
-            .unwrap()
-            .call1((gemm_fp, gemm_nm))
-            .unwrap()
-            .extract()
-            .unwrap();
-        // println!("csr_tuple:\n {:#?}", csr_tuple);
-        let gemm = GEMM::new(gemm_nm, csr_tuple);
-        Ok(gemm)
-    })
+    // The caller holds the GIL so that a batch of workloads shares it.
+    let load_gemm_from_path = PyModule::from_code(py, code, file_name, module_name)?;
+    let mats = load_gemm_from_path
+        .getattr("retrieve_pickled_csr")?
+        .call1((gemm_fp, gemm_nm, log_enabled(LogLevel::Info)))?;
+    let mut csrmats = copy_csr_mats(py, mats, sort_indices)?;
+    let b = pop_mat(&mut csrmats)?;
+    let a = pop_mat(&mut csrmats)?;
+    GEMM::try_new(gemm_nm, a, b).map_err(|e| PyValueError::new_err(e.to_string()))
 }
 
-pub fn load_mm_mat(dir_path: &str, gemm_nm: &str) -> PyResult<CsMat<f64>> {
+pub fn load_npz_gemm(
+    py: Python,
+    dir_path: &str,
+    gemm_nm: &str,
+    sort_indices: bool,
+) -> PyResult<GEMM> {
     let code = r#"
-def retrieve_mm_mat(dir_fp, mat_name):
-    print('---- Python Interface ----')
+def retrieve_npz_csr(dir_fp, gemm_name, verbose):
+    log = print if verbose else (lambda *args: None)
+    log('---- Python Interface ----')
+    import os
+    from scipy import sparse
+    mats = []
+    for operand in ('a', 'b'):
+        mat_path = os.path.join(dir_fp, f'{gemm_name}_{operand}.npz')
+        log(f'% Load {operand.upper()} from', mat_path)
+        mat = sparse.load_npz(mat_path).tocsr()
+        # The sprs CSR matrices need sorted indices without duplicates.
+        mat.sum_duplicates()
+        log(f'% shape: {mat.shape} data: {mat.data[:5]}... indices: {mat.indices[:5]}... indptr: {mat.indptr[:5]}...')
+        mats.append(mat)
+    log('--- Return from Python Interface ---\n')
+    return mats
+    "#;
+
+    let file_name = "retrieve_npz_csr.py";
+    let module_name = "retrieve_npz_csr";
+
+    let load_npz_from_path = PyModule::from_code(py, code, file_name, module_name)?;
+    let mats = load_npz_from_path.getattr("retrieve_npz_csr")?.call1((
+        dir_path,
+        gemm_nm,
+        log_enabled(LogLevel::Info),
+    ))?;
+    let mut csrmats = copy_csr_mats(py, mats, sort_indices)?;
+    let b = pop_mat(&mut csrmats)?;
+    let a = pop_mat(&mut csrmats)?;
+    GEMM::try_new(gemm_nm, a, b).map_err(|e| PyValueError::new_err(e.to_string()))
+}
+
+pub fn load_mm_mat(
+    py: Python,
+    dir_path: &str,
+    gemm_nm: &str,
+    sort_indices: bool,
+) -> PyResult<CsMat<f64>> {
+    let code = r#"
+def retrieve_mm_mat(dir_fp, mat_name, verbose):
+    log = print if verbose else (lambda *args: None)
+    log('---- Python Interface ----')
     import os
     import pickle
     import numpy as np
     from scipy import io as spio
     from scipy.sparse import csr_matrix
-    print(f'% Load {mat_name} from {dir_fp}')
+    log(f'% Load {mat_name} from {dir_fp}')
     mat_path = os.path.join(dir_fp, mat_name + '.mtx')
     with open(mat_path, 'r') as f:
-        matrix = spio.mmread(f).tocsr()
-        shape = matrix.shape
-        data = matrix.data
-        indices = matrix.indices
-        indptr = matrix.indptr
-        return (shape, indptr, indices, data)
+        return [spio.mmread(f).tocsr()]
     "#;
 
     let file_name = "retrieve_mm_mat.py";
     let module_name = "retrieve_mm_mat";
 
-    Python::with_gil(|py| {
-        let load_mm_from_path = PyModule::from_code(py, code, file_name, module_name).unwrap();
-        let ct: CsrTuple = load_mm_from_path
-            .getattr("retrieve_mm_mat")
-            .unwrap()
-            .call1((dir_path, gemm_nm))
-            .unwrap()
-            .extract()
-            .unwrap();
-        let csrmat = CsMat::new(ct.0, ct.1, ct.2, ct.3);
-        Ok(csrmat)
-    })
+    let load_mm_from_path = PyModule::from_code(py, code, file_name, module_name)?;
+    let mats = load_mm_from_path.getattr("retrieve_mm_mat")?.call1((
+        dir_path,
+        gemm_nm,
+        log_enabled(LogLevel::Info),
+    ))?;
+    pop_mat(&mut copy_csr_mats(py, mats, sort_indices)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_path;
+    use pyo3::exceptions::PyKeyError;
+    use pyo3::types::IntoPyDict;
+    use std::fs;
+
+    // A is 2 x 3 and B is 3 x 2, the bad B is 2 x 3.
+    const NPZ_FIXTURE_CODE: &str = r#"
+import os
+import numpy as np
+from scipy import sparse
+a = sparse.csr_matrix(np.array([[1.0, 0.0, 2.0], [0.0, 0.0, 3.0]]))
+b = sparse.csr_matrix(np.array([[0.0, 4.0], [5.0, 0.0], [0.0, 6.0]]))
+sparse.save_npz(os.path.join(dir_fp, 'fixture_a.npz'), a)
+sparse.save_npz(os.path.join(dir_fp, 'fixture_b.npz'), b)
+sparse.save_npz(os.path.join(dir_fp, 'mismatch_a.npz'), a)
+sparse.save_npz(os.path.join(dir_fp, 'mismatch_b.npz'), a)
+"#;
+
+    // A GEMM of a CSR A and a dense B, which is converted to CSR.
+    const PICKLE_FIXTURE_CODE: &str = r#"
+import pickle
+import numpy as np
+from scipy import sparse
+a = sparse.csr_matrix(np.array([[1.0, 0.0, 2.0], [0.0, 0.0, 3.0]]))
+b = np.array([[0.0, 4.0], [5.0, 0.0], [0.0, 6.0]])
+with open(gemm_fp, 'wb') as f:
+    pickle.dump({'fixture': [a, b]}, f)
+"#;
+
+    #[test]
+    fn npz_fixture_loads_as_csr() {
+        let dir = temp_path("npz");
+        fs::create_dir_all(&dir).unwrap();
+        Python::with_gil(|py| {
+            let locals = [("dir_fp", dir.as_str())].into_py_dict(py);
+            py.run(NPZ_FIXTURE_CODE, None, Some(locals)).unwrap();
+            let gemm = load_npz_gemm(py, &dir, "fixture", false);
+            let mismatch = load_npz_gemm(py, &dir, "mismatch", false);
+            fs::remove_dir_all(&dir).unwrap();
+
+            let gemm = gemm.unwrap();
+            assert_eq!(gemm.name, "fixture");
+            let a = CsMat::new((2, 3), vec![0, 2, 3], vec![0, 2, 2], vec![1.0, 2.0, 3.0]);
+            let b = CsMat::new((3, 2), vec![0, 1, 2, 3], vec![1, 0, 1], vec![4.0, 5.0, 6.0]);
+            assert_eq!(gemm.a, a);
+            assert_eq!(gemm.b, b);
+            assert!(mismatch.is_err());
+        });
+    }
+
+    #[test]
+    fn pickle_fixture_loads_only_its_gemms() {
+        let gemm_fp = temp_path("gemms.pkl");
+        Python::with_gil(|py| {
+            let locals = [("gemm_fp", gemm_fp.as_str())].into_py_dict(py);
+            py.run(PICKLE_FIXTURE_CODE, None, Some(locals)).unwrap();
+            let gemm = load_pickled_gemms(py, &gemm_fp, "fixture", false);
+            let missing = load_pickled_gemms(py, &gemm_fp, "missing", false);
+            fs::remove_file(&gemm_fp).unwrap();
+
+            let gemm = gemm.unwrap();
+            assert_eq!(gemm.a.shape(), (2, 3));
+            let b = CsMat::new((3, 2), vec![0, 1, 2, 3], vec![1, 0, 1], vec![4.0, 5.0, 6.0]);
+            assert_eq!(gemm.b, b);
+            assert!(missing.err().unwrap().is_instance::<PyKeyError>(py));
+        });
+    }
 }
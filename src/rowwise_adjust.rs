@@ -1,18 +1,23 @@
 use crate::block_topo_tracker::BlockTopoTracker;
+use crate::frontend::GroupSplit;
+use crate::scalar::Scalar;
 use crate::scheduler::BlockTracker;
 use crate::storage::CsrMatStorage;
 use crate::trace_println;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 use std::cmp::{max, min};
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::iter;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GroupInfo {
     pub row_range: [usize; 2],
     pub avg_row_len: usize,
     pub cost_num: HashMap<usize, [usize; 2]>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GroupTracker {
     pub groups: Vec<GroupInfo>,
     pub rgmap: HashMap<usize, usize>,
@@ -35,49 +40,137 @@ impl GroupTracker {
     }
 }
 
-pub fn parse_group(matrix: &CsrMatStorage, var_factor: f32) -> GroupTracker {
-    let mut gt = GroupTracker::new();
-    let mut prev_row_len = usize::MAX;
-    let mut row_s = 0;
+// Number of recent rows tracked by the adaptive split.
+const ADAPTIVE_WINDOW: usize = 16;
+// Multiple of the sampled element number checked at once for a cost drift, shorter windows mistake
+// the noise of a few blocks for a drift.
+const RESAMPLE_WINDOW: usize = 8;
 
-    // Parse matrix.
-    for idx in 0..matrix.row_num() + 1 {
-        if idx == matrix.row_num() {
-            // Finish the last group.
-            let gi = GroupInfo {
-                row_range: [row_s, idx],
-                avg_row_len: (matrix.get_ele_num(row_s, idx)) / (idx - row_s),
-                cost_num: HashMap::new(),
-            };
-            gt.add_group(gi);
-        } else {
-            let row_len = matrix.get_ele_num(idx, idx + 1);
-            if row_len == 0 {
-                continue;
-            } else if prev_row_len == usize::MAX {
-                // Init the first group.
-                prev_row_len = row_len;
-            } else if prev_row_len as f32 * var_factor < row_len as f32
-                || prev_row_len as f32 > var_factor * row_len as f32
-            {
-                // Encounter a new group. Save the current one.
-                let gi = GroupInfo {
-                    row_range: [row_s, idx],
-                    avg_row_len: (matrix.get_ele_num(row_s, idx)) / (idx - row_s),
-                    cost_num: HashMap::new(),
+#[derive(Serialize, Deserialize)]
+pub struct GroupSplitter {
+    var_factor: f32,
+    group_split: GroupSplit,
+    recent_lens: VecDeque<usize>,
+}
+
+impl GroupSplitter {
+    pub fn new(var_factor: f32, group_split: GroupSplit) -> GroupSplitter {
+        GroupSplitter {
+            var_factor,
+            group_split,
+            recent_lens: VecDeque::new(),
+        }
+    }
+
+    // Check whether a non-empty row starts a new group and track its length.
+    pub fn is_new_group(&mut self, row_len: usize) -> bool {
+        let is_new = match self.recent_lens.back() {
+            None => false,
+            Some(prev_row_len) => {
+                let factor = match self.group_split {
+                    GroupSplit::Constant => self.var_factor,
+                    GroupSplit::Adaptive => {
+                        // Widen the factor by the coefficient of variation of recent rows.
+                        let num = self.recent_lens.len() as f32;
+                        let mean = self.recent_lens.iter().sum::<usize>() as f32 / num;
+                        let var = self
+                            .recent_lens
+                            .iter()
+                            .map(|l| (*l as f32 - mean).powi(2))
+                            .sum::<f32>()
+                            / num;
+                        self.var_factor * (1.0 + var.sqrt() / mean)
+                    }
                 };
-                gt.add_group(gi);
-                prev_row_len = row_len;
-                row_s = idx;
-            } else {
-                prev_row_len = row_len;
+                *prev_row_len as f32 * factor < row_len as f32
+                    || *prev_row_len as f32 > factor * row_len as f32
             }
+        };
+        self.recent_lens.push_back(row_len);
+        if self.recent_lens.len() > ADAPTIVE_WINDOW {
+            self.recent_lens.pop_front();
         }
+        return is_new;
+    }
+}
+
+// Rows scanned by one task of the parallel group parsing.
+const PARSE_CHUNK_ROWS: usize = 4096;
+
+// Find the rows in row_s..row_t that start a new group. The splitter is first fed the non-empty
+// rows before row_s it would still track, so a chunk splits exactly where a serial scan does.
+fn chunk_group_splits<T: Scalar>(
+    matrix: &CsrMatStorage<T>,
+    var_factor: f32,
+    group_split: GroupSplit,
+    row_s: usize,
+    row_t: usize,
+) -> Vec<usize> {
+    let mut splitter = GroupSplitter::new(var_factor, group_split);
+    let mut history = vec![];
+    let mut idx = row_s;
+    while idx > 0 && history.len() < ADAPTIVE_WINDOW {
+        idx -= 1;
+        let row_len = matrix.get_ele_num(idx, idx + 1);
+        if row_len > 0 {
+            history.push(row_len);
+        }
+    }
+    for row_len in history.into_iter().rev() {
+        splitter.is_new_group(row_len);
+    }
+
+    (row_s..row_t)
+        .filter(|idx| {
+            let row_len = matrix.get_ele_num(*idx, idx + 1);
+            row_len > 0 && splitter.is_new_group(row_len)
+        })
+        .collect()
+}
+
+// The rows starting a new group, found over chunks of rows in parallel.
+pub fn parse_group_splits<T: Scalar>(
+    matrix: &CsrMatStorage<T>,
+    var_factor: f32,
+    group_split: GroupSplit,
+) -> Vec<usize> {
+    let row_num = matrix.row_num();
+    let chunk_num = (row_num + PARSE_CHUNK_ROWS - 1) / PARSE_CHUNK_ROWS;
+    (0..chunk_num)
+        .into_par_iter()
+        .map(|chunk_idx| {
+            let row_s = chunk_idx * PARSE_CHUNK_ROWS;
+            let row_t = min(row_s + PARSE_CHUNK_ROWS, row_num);
+            chunk_group_splits(matrix, var_factor, group_split, row_s, row_t)
+        })
+        .collect::<Vec<Vec<usize>>>()
+        .concat()
+}
+
+pub fn parse_group<T: Scalar>(
+    matrix: &CsrMatStorage<T>,
+    var_factor: f32,
+    group_split: GroupSplit,
+) -> GroupTracker {
+    let mut gt = GroupTracker::new();
+    let mut row_s = 0;
+
+    // Stitch the groups between the split rows, the last one ends with the matrix.
+    let splits = parse_group_splits(matrix, var_factor, group_split);
+    for row_t in splits.into_iter().chain(iter::once(matrix.row_num())) {
+        let gi = GroupInfo {
+            row_range: [row_s, row_t],
+            avg_row_len: (matrix.get_ele_num(row_s, row_t)) / (row_t - row_s),
+            cost_num: HashMap::new(),
+        };
+        gt.add_group(gi);
+        row_s = row_t;
     }
 
     return gt;
 }
 
+#[derive(Serialize, Deserialize)]
 pub struct RowwiseBlockInfo {
     pub a_ele_num: usize,
     pub miss_size: usize,
@@ -94,6 +187,7 @@ impl RowwiseBlockInfo {
     }
 }
 
+#[derive(Serialize, Deserialize)]
 pub struct RowwiseAdjustTracker {
     pub block_info: HashMap<usize, RowwiseBlockInfo>, // block_token -> rowwise block info
     pub a_group: GroupTracker,
@@ -102,24 +196,65 @@ pub struct RowwiseAdjustTracker {
     pub sampling_bounds: Vec<usize>,
     pub set_row_num: usize,
     pub lane_num: usize,
+    pub resample_threshold: Option<f32>,
+    // The sampled cost per element of set_row_num and the element num it was sampled on.
+    pub sampled_cost: Option<(f32, usize)>,
+    // The cost_num of set_row_num when the current check window started.
+    pub cost_base: [usize; 2],
 }
 
 impl RowwiseAdjustTracker {
-    pub fn new(
+    pub fn new<T: Scalar>(
         lane_num: usize,
-        a_matrix: &CsrMatStorage,
-        b_matrix: &CsrMatStorage,
+        a_matrix: &CsrMatStorage<T>,
+        b_matrix: &CsrMatStorage<T>,
         var_factor: f32,
+        group_split: GroupSplit,
+        resample_threshold: Option<f32>,
     ) -> RowwiseAdjustTracker {
         RowwiseAdjustTracker {
             block_info: HashMap::new(),
-            a_group: parse_group(a_matrix, var_factor),
-            b_group: parse_group(b_matrix, var_factor),
+            a_group: parse_group(a_matrix, var_factor, group_split),
+            b_group: parse_group(b_matrix, var_factor, group_split),
             row_group: usize::MAX,
             sampling_bounds: vec![],
             set_row_num: usize::MAX,
             lane_num,
+            resample_threshold,
+            sampled_cost: None,
+            cost_base: [0, 0],
+        }
+    }
+
+    // Sample the row nums from row_s on, doubling the row num every sample_num blocks.
+    fn start_sampling(&mut self, row_s: usize, sample_num: usize) {
+        let mut cur_row = row_s + 1;
+        let mut i = 1;
+        self.sampling_bounds.clear();
+        while i <= self.lane_num {
+            cur_row += sample_num * i;
+            self.sampling_bounds.push(cur_row);
+            i *= 2;
         }
+        self.set_row_num = usize::MAX;
+        self.sampled_cost = None;
+    }
+
+    // Whether the blocks run with set_row_num cost more per element than sampled. They are
+    // checked in windows of RESAMPLE_WINDOW times the elements the samples covered.
+    fn is_cost_drifted(&mut self, threshold: f32) -> bool {
+        let (sampled, sampled_ele_num) = match self.sampled_cost {
+            Some(sampled_cost) => sampled_cost,
+            None => return false,
+        };
+        let cost_num = self.a_group.groups[self.row_group].cost_num[&self.set_row_num];
+        let cost = cost_num[0] - self.cost_base[0];
+        let ele_num = cost_num[1] - self.cost_base[1];
+        if sampled_ele_num == 0 || ele_num < RESAMPLE_WINDOW * sampled_ele_num {
+            return false;
+        }
+        self.cost_base = cost_num;
+        cost as f32 / ele_num as f32 > (1.0 + threshold) * sampled
     }
 
     pub fn adjust_block_shape(
@@ -143,14 +278,7 @@ impl RowwiseAdjustTracker {
             self.row_group = self.a_group.rgmap[&row_s];
             let cur_gi = &self.a_group.groups[self.row_group];
             if cur_gi.row_range[1] - cur_gi.row_range[0] > group_diviser {
-                let mut cur_row = row_s + 1;
-                let mut i = 1;
-                self.sampling_bounds.clear();
-                while i <= self.lane_num {
-                    cur_row += sample_num * i;
-                    self.sampling_bounds.push(cur_row);
-                    i *= 2;
-                }
+                self.start_sampling(row_s, sample_num);
             }
             self.set_row_num = usize::MAX;
             return block_shape;
@@ -175,12 +303,15 @@ impl RowwiseAdjustTracker {
                             if div_cost < min_cost {
                                 min_cost = div_cost;
                                 self.set_row_num = cur_row_num;
+                                self.sampled_cost = Some((div_cost, cost_num[1]));
+                                self.cost_base = *cost_num;
                             }
                         } else {
                             self.a_group.groups[self.row_group]
                                 .cost_num
                                 .insert(cur_row_num, [0, 0]);
                             self.set_row_num = cur_row_num;
+                            self.sampled_cost = None;
                             break;
                         }
                         cur_row_num *= 2;
@@ -190,6 +321,18 @@ impl RowwiseAdjustTracker {
                     {
                         cur_row_num /= 2;
                     }
+                } else if let Some(threshold) = self.resample_threshold {
+                    // The rows of the group may have changed density since the sampling, sample
+                    // again from scratch for the rest of the group.
+                    if cur_gi.row_range[1] - row_s > group_diviser
+                        && self.is_cost_drifted(threshold)
+                    {
+                        trace_println!("---Resampling from row {}", row_s);
+                        self.a_group.groups[self.row_group].cost_num.clear();
+                        self.start_sampling(row_s, sample_num);
+                        block_shape[0] = 1;
+                        return block_shape;
+                    }
                 }
                 min_row_num = self.set_row_num;
             } else {
@@ -279,3 +422,152 @@ impl RowwiseAdjustTracker {
         return [block_shape[0], self.lane_num / block_shape[0]];
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::*;
+    use std::collections::HashSet;
+    use std::ops::Range;
+
+    // The groups a single splitter finds scanning all rows in order.
+    fn serial_group(matrix: &CsrMatStorage<f64>, group_split: GroupSplit) -> GroupTracker {
+        let mut splitter = GroupSplitter::new(1.5, group_split);
+        let mut gt = GroupTracker::new();
+        let mut row_s = 0;
+        for row_t in 0..matrix.row_num() {
+            let row_len = matrix.get_ele_num(row_t, row_t + 1);
+            if row_len > 0 && splitter.is_new_group(row_len) {
+                gt.add_group(GroupInfo {
+                    row_range: [row_s, row_t],
+                    avg_row_len: matrix.get_ele_num(row_s, row_t) / (row_t - row_s),
+                    cost_num: HashMap::new(),
+                });
+                row_s = row_t;
+            }
+        }
+        let row_t = matrix.row_num();
+        gt.add_group(GroupInfo {
+            row_range: [row_s, row_t],
+            avg_row_len: matrix.get_ele_num(row_s, row_t) / (row_t - row_s),
+            cost_num: HashMap::new(),
+        });
+        gt
+    }
+
+    #[test]
+    fn parallel_groups_match_serial_scan() {
+        // Noisy short rows, a few empty rows before the first chunk edge, then longer rows from
+        // the edge on.
+        let row_lens = (0..3 * PARSE_CHUNK_ROWS / 2)
+            .map(|r| match r {
+                r if r < PARSE_CHUNK_ROWS - 4 => 2 + r % 3 + (r / 100 % 2) * 4,
+                r if r < PARSE_CHUNK_ROWS => 0,
+                r => 20 + r % 5,
+            })
+            .collect::<Vec<usize>>();
+        let matrix = a_storage(csr_with_row_lens(&row_lens, 32));
+        for group_split in [GroupSplit::Constant, GroupSplit::Adaptive].iter() {
+            let parallel = parse_group(&matrix, 1.5, *group_split);
+            let serial = serial_group(&matrix, *group_split);
+            assert!(serial.groups.len() > 2, "{:?}", group_split);
+            assert!(serial
+                .groups
+                .iter()
+                .any(|g| g.row_range[1] == PARSE_CHUNK_ROWS));
+            assert_eq!(
+                parallel.groups.len(),
+                serial.groups.len(),
+                "{:?}",
+                group_split
+            );
+            for (p, s) in parallel.groups.iter().zip(serial.groups.iter()) {
+                assert_eq!(p.row_range, s.row_range, "{:?}", group_split);
+                assert_eq!(p.avg_row_len, s.avg_row_len, "{:?}", group_split);
+            }
+            assert_eq!(parallel.rgmap, serial.rgmap, "{:?}", group_split);
+        }
+    }
+
+    // The rows of the sequence that start a new group.
+    fn split_rows(row_lens: &[usize], group_split: GroupSplit) -> Vec<usize> {
+        let mut splitter = GroupSplitter::new(1.5, group_split);
+        (0..row_lens.len())
+            .filter(|r| splitter.is_new_group(row_lens[*r]))
+            .collect()
+    }
+
+    #[test]
+    fn adaptive_split_keeps_noisy_rows_together() {
+        // Rows alternating around a stable mean by up to 1.6x, after a gentle start.
+        let mut row_lens = vec![14, 15, 13, 16, 12, 17, 11, 18];
+        for _ in 0..8 {
+            row_lens.extend([11, 18, 12, 17, 11, 18, 12, 18].iter());
+        }
+        assert!(split_rows(&row_lens, GroupSplit::Adaptive).is_empty());
+        assert!(split_rows(&row_lens, GroupSplit::Constant).len() > 8);
+        // A real shift of the row lengths still splits.
+        let noisy_num = row_lens.len();
+        row_lens.extend([60; 4].iter());
+        assert_eq!(split_rows(&row_lens, GroupSplit::Adaptive), vec![noisy_num]);
+    }
+
+    // The block row nums the tracker picks over a single wide group. The blocks cost 1 per element
+    // at 8 rows and 1 more per halving in the first half of the group, and the other way round in
+    // the second half.
+    fn sampled_row_nums(resample_threshold: Option<f32>) -> Vec<(usize, usize)> {
+        let row_num = 2048;
+        let matrix = a_storage(csr_with_row_lens(&vec![4; row_num], 8));
+        let mut tracker = RowwiseAdjustTracker::new(
+            8,
+            &matrix,
+            &matrix,
+            1.5,
+            GroupSplit::Constant,
+            resample_threshold,
+        );
+        let block_topo = BlockTopoTracker::new();
+        let a_row_lens = vec![4; row_num];
+        let mut row_nums = vec![];
+        let mut block_shape = [1, 8];
+        let mut row_s = 0;
+        while row_s < row_num {
+            block_shape =
+                tracker.adjust_block_shape([0, 0], row_s, block_shape, &block_topo, &a_row_lens);
+            let block_rows = block_shape[0];
+            let steps = block_rows.trailing_zeros() as usize;
+            let cost = if row_s < row_num / 2 {
+                4 - steps
+            } else {
+                1 + steps
+            };
+            let ele_num = 4 * block_rows;
+            let cost_num = tracker.a_group.groups[0]
+                .cost_num
+                .entry(block_rows)
+                .or_insert([0, 0]);
+            cost_num[0] += cost * ele_num;
+            cost_num[1] += ele_num;
+            row_nums.push((row_s, block_rows));
+            row_s += block_rows;
+        }
+        row_nums
+    }
+
+    #[test]
+    fn density_shift_resamples_the_row_num() {
+        let row_nums_in = |resample_threshold, rows: Range<usize>| {
+            sampled_row_nums(resample_threshold)
+                .into_iter()
+                .filter(|(row_s, _)| rows.contains(row_s))
+                .map(|(_, block_rows)| block_rows)
+                .collect::<HashSet<usize>>()
+        };
+        let only = |block_rows: usize| iter::once(block_rows).collect::<HashSet<usize>>();
+        assert_eq!(row_nums_in(Some(1.0), 128..1024), only(8));
+        // Well after the shift and before the group end shrinks the blocks, the row num chosen on
+        // the first half stays unless it is sampled again.
+        assert_eq!(row_nums_in(None, 1536..1920), only(8));
+        assert_eq!(row_nums_in(Some(1.0), 1536..1920), only(1));
+    }
+}
@@ -1,16 +1,21 @@
+use crate::frontend::GroupSplit;
+use crate::rowwise_adjust::parse_group_splits;
+use crate::scalar::Scalar;
 use crate::scheduler::BlockTracker;
 use crate::storage::CsrMatStorage;
 use crate::trace_println;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::iter;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GroupInfo {
     pub row_range: [usize; 2],
     pub avg_row_len: usize,
     pub latency_num: HashMap<usize, [usize; 2]>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GroupTracker {
     pub groups: Vec<GroupInfo>,
     pub rgmap: HashMap<usize, usize>,
@@ -33,49 +38,30 @@ impl GroupTracker {
     }
 }
 
-pub fn parse_group(matrix: &CsrMatStorage, var_factor: f32) -> GroupTracker {
+pub fn parse_group<T: Scalar>(
+    matrix: &CsrMatStorage<T>,
+    var_factor: f32,
+    group_split: GroupSplit,
+) -> GroupTracker {
     let mut gt = GroupTracker::new();
-    let mut prev_row_len = usize::MAX;
     let mut row_s = 0;
 
-    // Parse matrix.
-    for idx in 0..matrix.row_num() + 1 {
-        if idx == matrix.row_num() {
-            // Finish the last group.
-            let gi = GroupInfo {
-                row_range: [row_s, idx],
-                avg_row_len: (matrix.get_ele_num(row_s, idx)) / (idx - row_s),
-                latency_num: HashMap::new(),
-            };
-            gt.add_group(gi);
-        } else {
-            let row_len = matrix.get_ele_num(idx, idx + 1);
-            if row_len == 0 {
-                continue;
-            } else if prev_row_len == usize::MAX {
-                // Init the first group.
-                prev_row_len = row_len;
-            } else if prev_row_len as f32 * var_factor < row_len as f32
-                || prev_row_len as f32 > var_factor * row_len as f32
-            {
-                // Encounter a new group. Save the current one.
-                let gi = GroupInfo {
-                    row_range: [row_s, idx],
-                    avg_row_len: (matrix.get_ele_num(row_s, idx)) / (idx - row_s),
-                    latency_num: HashMap::new(),
-                };
-                gt.add_group(gi);
-                prev_row_len = row_len;
-                row_s = idx;
-            } else {
-                prev_row_len = row_len;
-            }
-        }
+    // Stitch the groups between the split rows, the last one ends with the matrix.
+    let splits = parse_group_splits(matrix, var_factor, group_split);
+    for row_t in splits.into_iter().chain(iter::once(matrix.row_num())) {
+        let gi = GroupInfo {
+            row_range: [row_s, row_t],
+            avg_row_len: (matrix.get_ele_num(row_s, row_t)) / (row_t - row_s),
+            latency_num: HashMap::new(),
+        };
+        gt.add_group(gi);
+        row_s = row_t;
     }
 
     return gt;
 }
 
+#[derive(Serialize, Deserialize)]
 pub struct RowwiseLatencyBlockInfo {
     pub a_ele_num: usize,
     pub latency: usize,
@@ -90,6 +76,7 @@ impl RowwiseLatencyBlockInfo {
     }
 }
 
+#[derive(Serialize, Deserialize)]
 pub struct RowwiseLatencyAdjustTracker {
     pub block_info: HashMap<usize, RowwiseLatencyBlockInfo>, // block_token -> rowwise block info
     pub a_group: GroupTracker,
@@ -101,16 +88,17 @@ pub struct RowwiseLatencyAdjustTracker {
 }
 
 impl RowwiseLatencyAdjustTracker {
-    pub fn new(
+    pub fn new<T: Scalar>(
         lane_num: usize,
-        a_matrix: &CsrMatStorage,
-        b_matrix: &CsrMatStorage,
+        a_matrix: &CsrMatStorage<T>,
+        b_matrix: &CsrMatStorage<T>,
         var_factor: f32,
+        group_split: GroupSplit,
     ) -> RowwiseLatencyAdjustTracker {
         RowwiseLatencyAdjustTracker {
             block_info: HashMap::new(),
-            a_group: parse_group(a_matrix, var_factor),
-            b_group: parse_group(b_matrix, var_factor),
+            a_group: parse_group(a_matrix, var_factor, group_split),
+            b_group: parse_group(b_matrix, var_factor, group_split),
             row_group: usize::MAX,
             sampling_bounds: vec![],
             set_row_num: usize::MAX,
@@ -209,7 +197,10 @@ impl RowwiseLatencyAdjustTracker {
                 block_row_num *= 2;
             } else {
                 let mut min_div_latency = f32::MAX;
-                for (row_num, latency_num) in cur_gi.latency_num.iter() {
+                // Break the ties by the smaller row num.
+                let mut latency_nums = cur_gi.latency_num.iter().collect::<Vec<_>>();
+                latency_nums.sort_unstable_by_key(|(row_num, _)| **row_num);
+                for (row_num, latency_num) in latency_nums {
                     let div_latency = latency_num[0] as f32 / (latency_num[1] as f32 + 0.0001);
                     if div_latency < min_div_latency {
                         min_div_latency = div_latency;
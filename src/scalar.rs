@@ -0,0 +1,157 @@
+use num_complex::Complex;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::error::Error;
+use std::fmt::Debug;
+use std::ops::Add;
+
+// The value type of the matrix elements. The simulator only needs the additive identity, the
+// sum and the product, the timing does not depend on the type. Add is kept for sprs, which
+// sums the duplicate triplets with it.
+pub trait Scalar:
+    Copy
+    + Debug
+    + Default
+    + PartialEq
+    + Add<Output = Self>
+    + Serialize
+    + DeserializeOwned
+    + Send
+    + Sync
+    + 'static
+{
+    // Matrix Market field written for the results.
+    const MM_FIELD: &'static str;
+
+    fn zero() -> Self;
+    fn one() -> Self;
+    fn plus(self, other: Self) -> Self;
+    fn times(self, other: Self) -> Self;
+    fn negate(self) -> Self;
+    // Distance used to compare against the reference product.
+    fn distance(self, other: Self) -> f64;
+    // Parse the value tokens of a Matrix Market entry.
+    fn parse_mm(tokens: &[&str]) -> Result<Self, Box<dyn Error>>;
+    fn fmt_mm(&self) -> String;
+}
+
+impl Scalar for f64 {
+    const MM_FIELD: &'static str = "real";
+
+    fn zero() -> f64 {
+        0.0
+    }
+
+    fn one() -> f64 {
+        1.0
+    }
+
+    fn plus(self, other: f64) -> f64 {
+        self + other
+    }
+
+    fn times(self, other: f64) -> f64 {
+        self * other
+    }
+
+    fn negate(self) -> f64 {
+        -self
+    }
+
+    fn distance(self, other: f64) -> f64 {
+        (self - other).abs()
+    }
+
+    fn parse_mm(tokens: &[&str]) -> Result<f64, Box<dyn Error>> {
+        match tokens {
+            [re] => Ok(re.parse::<f64>()?),
+            _ => Err("Complex entries need the complex scalar".into()),
+        }
+    }
+
+    fn fmt_mm(&self) -> String {
+        self.to_string()
+    }
+}
+
+// The integer sums and products wrap on overflow like a fixed width datapath, instead of
+// panicking in the middle of a simulation.
+impl Scalar for i64 {
+    const MM_FIELD: &'static str = "integer";
+
+    fn zero() -> i64 {
+        0
+    }
+
+    fn one() -> i64 {
+        1
+    }
+
+    fn plus(self, other: i64) -> i64 {
+        self.wrapping_add(other)
+    }
+
+    fn times(self, other: i64) -> i64 {
+        self.wrapping_mul(other)
+    }
+
+    fn negate(self) -> i64 {
+        self.wrapping_neg()
+    }
+
+    fn distance(self, other: i64) -> f64 {
+        (self as i128 - other as i128).abs() as f64
+    }
+
+    fn parse_mm(tokens: &[&str]) -> Result<i64, Box<dyn Error>> {
+        match tokens {
+            [re] => Ok(re.parse::<i64>()?),
+            _ => Err("Complex entries need the complex scalar".into()),
+        }
+    }
+
+    fn fmt_mm(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl Scalar for Complex<f64> {
+    const MM_FIELD: &'static str = "complex";
+
+    fn zero() -> Complex<f64> {
+        Complex::new(0.0, 0.0)
+    }
+
+    fn one() -> Complex<f64> {
+        Complex::new(1.0, 0.0)
+    }
+
+    fn plus(self, other: Complex<f64>) -> Complex<f64> {
+        self + other
+    }
+
+    fn times(self, other: Complex<f64>) -> Complex<f64> {
+        self * other
+    }
+
+    fn negate(self) -> Complex<f64> {
+        -self
+    }
+
+    fn distance(self, other: Complex<f64>) -> f64 {
+        (self - other).norm()
+    }
+
+    // Real entries read as complex ones with a zero imaginary part.
+    fn parse_mm(tokens: &[&str]) -> Result<Complex<f64>, Box<dyn Error>> {
+        match tokens {
+            [re] => Ok(Complex::new(re.parse::<f64>()?, 0.0)),
+            [re, im] => Ok(Complex::new(re.parse::<f64>()?, im.parse::<f64>()?)),
+            _ => Err(format!("Invalid complex value: {}", tokens.join(" ")).into()),
+        }
+    }
+
+    fn fmt_mm(&self) -> String {
+        format!("{} {}", self.re, self.im)
+    }
+}
@@ -1,39 +1,45 @@
-use std::cmp::{max, min};
-use std::collections::{HashMap, HashSet};
+use std::cmp::{max, min, Reverse};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::mem;
 use std::ops::AddAssign;
 
+use rand::{seq::SliceRandom, SeedableRng};
+use rand_chacha::ChaCha12Rng;
+use serde::{Deserialize, Serialize};
+
 use crate::adder_tree::AdderTree;
 use crate::block_topo_tracker::BlockTopoTracker;
 use crate::colwise_irr_adjust::{ColwiseIrrBlockAdjustTracker, ColwiseIrrBlockInfo};
 use crate::colwise_reg_adjust::{ColwiseRegBlockAdjustTracker, ColwiseRegBlockInfo};
-use crate::frontend::Accelerator;
+use crate::frontend::{Accelerator, BlockStrategy, GroupSplit, MergeOrder, MergePolicy};
+use crate::info_println;
 use crate::rowwise_adjust::{RowwiseAdjustTracker, RowwiseBlockInfo};
 use crate::rowwise_perf_adjust::{RowwiseLatencyAdjustTracker, RowwiseLatencyBlockInfo};
+use crate::scalar::Scalar;
 use crate::simulator::PE;
 use crate::storage::LatencyPriorityCache;
 use crate::storage::{CsrMatStorage, Element};
 
-#[derive(Debug, Clone)]
-pub struct Task {
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Task<T = f64> {
     pub block_token: usize,
     pub window_token: usize,
     pub group_size: usize,
     pub merge_mode: bool,
-    pub a_eles: Vec<Option<Element>>,
+    pub a_eles: Vec<Option<Element<T>>>,
     pub memory_traffic: usize,
     pub start_cycle: usize,
 }
 
-impl Task {
+impl<T: Scalar> Task<T> {
     pub fn new(
         block_token: usize,
         window_token: usize,
         group_size: usize,
         merge_mode: bool,
-        a_eles: Vec<Option<Element>>,
+        a_eles: Vec<Option<Element<T>>>,
         start_cycle: usize,
-    ) -> Task {
+    ) -> Task<T> {
         Task {
             block_token,
             window_token,
@@ -46,6 +52,26 @@ impl Task {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum DecisionKind {
+    // Block shape at a new row position, no block is set if the rows are done.
+    BlockRow,
+    Block,
+    // First window of a block, the later windows keep its shape.
+    Window,
+}
+
+// A shape decision of the scheduler, recorded to replay the same schedule on another config.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScheduleDecision {
+    pub kind: DecisionKind,
+    pub block_token: Option<usize>,
+    pub window_token: Option<usize>,
+    pub anchor: [usize; 2],
+    pub shape: [usize; 2],
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct Token {
     token: usize,
 }
@@ -66,6 +92,69 @@ impl Token {
     }
 }
 
+// Hands out the psum addresses from the output base address on. In a bounded address space the
+// addresses of the merged psums are handed out again.
+#[derive(Serialize, Deserialize)]
+pub struct PsumAddrAllocator {
+    token: Token,
+    space: Option<usize>,
+    free: Vec<usize>,
+    // Addresses handed out and neither merged away nor holding a finished row.
+    pub live: usize,
+    pub peak_live: usize,
+    pub reused: usize,
+    // Tasks whose addresses are not collected yet.
+    pub pending_tasks: usize,
+}
+
+impl PsumAddrAllocator {
+    pub fn new(output_base_addr: usize, space: Option<usize>) -> PsumAddrAllocator {
+        PsumAddrAllocator {
+            token: Token::new_from(output_base_addr),
+            space,
+            free: vec![],
+            live: 0,
+            peak_live: 0,
+            reused: 0,
+            pending_tasks: 0,
+        }
+    }
+
+    pub fn alloc(&mut self) -> usize {
+        self.live += 1;
+        self.peak_live = max(self.peak_live, self.live);
+        match self.free.pop() {
+            Some(addr) => {
+                self.reused += 1;
+                addr
+            }
+            None => self.token.tik(),
+        }
+    }
+
+    // Returns whether the address is recycled, so that its stale state must be dropped.
+    pub fn release(&mut self, addr: usize) -> bool {
+        self.live -= 1;
+        if self.space.is_some() {
+            self.free.push(addr);
+        }
+        self.space.is_some()
+    }
+
+    // The address keeps the finished row in memory, so it is not recycled.
+    pub fn finish(&mut self) {
+        self.live -= 1;
+    }
+
+    // Exceeds the space only when no running task could release an address, which would hang.
+    pub fn fits(&self, num: usize) -> bool {
+        self.space.map_or(true, |space| {
+            self.live + num <= space || self.pending_tasks == 0
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct BlockTracker {
     // Config.
     pub token: usize,
@@ -76,8 +165,14 @@ pub struct BlockTracker {
     pub a_cols_assigned: Vec<usize>,
     pub a_cols_num: Vec<usize>,
     pub window_tokens: Vec<usize>,
+    // The PE that opened the block.
+    pub owner_pe: usize,
     // Merge related.
     pub is_tail: Vec<bool>,
+    // Reuse related, in elements of the B fibers fetched and the psums produced.
+    pub touched_fiber_size: usize,
+    pub dedup_fiber_size: usize,
+    pub output_fiber_size: usize,
 }
 
 impl BlockTracker {
@@ -97,11 +192,28 @@ impl BlockTracker {
             a_cols_assigned: vec![0; a_cols_num.len()],
             a_cols_num,
             window_tokens: vec![],
+            owner_pe: usize::MAX,
             is_tail,
+            touched_fiber_size: 0,
+            dedup_fiber_size: 0,
+            output_fiber_size: 0,
         }
     }
+
+    // Products reduced into each output element, normalized by the window width.
+    pub fn c_reuse(&self, window_shape: [usize; 2]) -> f64 {
+        self.touched_fiber_size as f64
+            / (self.output_fiber_size as f64 * window_shape[1] as f64 + 0.00001)
+    }
+
+    // Rows of the window sharing each fetched B fiber, normalized by the window height.
+    pub fn b_reuse(&self, window_shape: [usize; 2]) -> f64 {
+        self.touched_fiber_size as f64
+            / (self.dedup_fiber_size as f64 * window_shape[0] as f64 + 0.00001)
+    }
 }
 
+#[derive(Serialize, Deserialize)]
 pub struct WindowTracker {
     // Config.
     pub token: usize,
@@ -136,10 +248,17 @@ impl WindowTracker {
     }
 }
 
-pub struct Scheduler {
+#[derive(Serialize, Deserialize)]
+pub struct Scheduler<T = f64> {
     // Config.
     pub a_traversed: bool,
     lane_num: usize,
+    merge_radix: usize,
+    merge_policy: MergePolicy,
+    merge_watermark: Option<f32>,
+    merge_order: MergeOrder,
+    // Without adder trees the PEs also merge psums while A is being traversed.
+    merge_on_pes: bool,
     pub row_s: usize,
     pub col_s: usize,
     block_shape: [usize; 2],
@@ -149,8 +268,11 @@ pub struct Scheduler {
     pub b_row_lens: HashMap<usize, usize>,
     pub mem_latency: usize,
     pub cache_latency: usize,
-    // Adjust scheme.
-    adjust_scheme: usize,
+    // How Spada adapts the block and window shapes.
+    block_strategy: BlockStrategy,
+    // Bounds on the adapted block shapes, see OmegaConfig.
+    min_block_width: Option<f32>,
+    min_window_width: Option<usize>,
     pub rowwise_adjust_tracker: RowwiseAdjustTracker,
     pub rowwise_latency_adjust_tracker: RowwiseLatencyAdjustTracker,
     pub colwise_reg_adjust_tracker: ColwiseRegBlockAdjustTracker,
@@ -160,7 +282,7 @@ pub struct Scheduler {
     pub window_tracker: HashMap<usize, WindowTracker>, // window_token -> WindowTracker
     pub output_tracker: HashMap<usize, Vec<usize>>,  // row idx -> psums
     block_topo_tracker: BlockTopoTracker,
-    output_addr_token: Token,
+    pub psum_addrs: PsumAddrAllocator,
     window_token: Token,
     block_token: Token,
     pub a_tail_produced: HashSet<usize>,
@@ -169,26 +291,63 @@ pub struct Scheduler {
     pub a_cols_assigned: Vec<usize>,
     pub a_cols_produced: HashMap<usize, usize>,
     pub row_rgstr_task: HashMap<usize, usize>,
-    staged_tasks: Vec<Option<Task>>,
+    staged_tasks: Vec<Option<Task<T>>>,
     latest_block_token: usize,
+    pub merge_task_count: usize,
+    pub steal_count: usize,
+    // All arbitrary choices draw from the seeded RNG to keep runs reproducible.
+    pub seed: u64,
+    // The same generator as StdRng, which cannot be checkpointed.
+    rng: ChaCha12Rng,
+    // Shape decisions recorded during the run, or replayed instead of adjusting the shapes.
+    pub schedule_record: Option<Vec<ScheduleDecision>>,
+    pub schedule_replay: Option<VecDeque<ScheduleDecision>>,
 }
 
-impl Scheduler {
+impl<T: Scalar> Scheduler<T> {
     pub fn new(
         pe_num: usize,
         lane_num: usize,
         block_shape: [usize; 2],
         output_base_addr: usize,
-        a_matrix: &CsrMatStorage,
-        b_matrix: &CsrMatStorage,
+        a_matrix: &CsrMatStorage<T>,
+        b_matrix: &CsrMatStorage<T>,
         var_factor: f32,
+        group_split: GroupSplit,
+        resample_threshold: Option<f32>,
         accelerator: Accelerator,
         mem_latency: usize,
         cache_latency: usize,
-    ) -> Scheduler {
+        cache_capability: usize,
+        merge_radix: usize,
+        merge_policy: MergePolicy,
+        merge_watermark: Option<f32>,
+        merge_order: MergeOrder,
+        merge_on_pes: bool,
+        block_strategy: BlockStrategy,
+        min_block_width: Option<f32>,
+        min_window_width: Option<usize>,
+        psum_addr_space: Option<usize>,
+        seed: u64,
+    ) -> Scheduler<T> {
+        assert!(
+            merge_radix >= 2 && lane_num % merge_radix == 0,
+            "Invalid merge radix {} for {} lanes",
+            merge_radix,
+            lane_num
+        );
+        // Only colwise irregular blocks track the B working set, and the rows of the
+        // transposed A of the outer product do not index B.
+        let track_b_sizes =
+            accelerator == Accelerator::Spada && block_strategy == BlockStrategy::ColwiseIrregular;
         Scheduler {
             a_traversed: false,
             lane_num,
+            merge_radix,
+            merge_policy,
+            merge_watermark,
+            merge_order,
+            merge_on_pes,
             row_s: usize::MAX,
             col_s: usize::MAX,
             block_shape,
@@ -200,26 +359,40 @@ impl Scheduler {
             b_row_lens: (0..b_matrix.row_num())
                 .map(|idx| (idx, b_matrix.get_ele_num(idx, idx + 1)))
                 .collect::<HashMap<usize, usize>>(),
-            adjust_scheme: 3,
+            block_strategy,
+            min_block_width,
+            min_window_width,
             block_tracker: HashMap::new(),
             window_tracker: HashMap::new(),
             output_tracker: HashMap::new(),
             block_topo_tracker: BlockTopoTracker::new(),
-            output_addr_token: Token::new_from(output_base_addr),
+            psum_addrs: PsumAddrAllocator::new(output_base_addr, psum_addr_space),
             window_token: Token::new(),
             block_token: Token::new(),
             a_tail_produced: HashSet::new(),
             // a_row_finished: HashSet::new(),
             a_row_finished: HashMap::new(),
             rowwise_adjust_tracker: RowwiseAdjustTracker::new(
-                lane_num, a_matrix, b_matrix, var_factor,
+                lane_num,
+                a_matrix,
+                b_matrix,
+                var_factor,
+                group_split,
+                resample_threshold,
             ),
             rowwise_latency_adjust_tracker: RowwiseLatencyAdjustTracker::new(
-                lane_num, a_matrix, b_matrix, var_factor,
+                lane_num,
+                a_matrix,
+                b_matrix,
+                var_factor,
+                group_split,
             ),
             colwise_reg_adjust_tracker: ColwiseRegBlockAdjustTracker::new(lane_num),
             colwise_irr_adjust_tracker: ColwiseIrrBlockAdjustTracker::new(
-                lane_num, lane_num, lane_num,
+                lane_num,
+                lane_num,
+                cache_capability / pe_num,
+                track_b_sizes.then(|| (a_matrix, b_matrix)),
             ),
             mem_latency,
             cache_latency,
@@ -228,34 +401,60 @@ impl Scheduler {
             row_rgstr_task: HashMap::new(),
             staged_tasks: vec![None; pe_num],
             latest_block_token: usize::MAX,
+            merge_task_count: 0,
+            steal_count: 0,
+            seed,
+            rng: ChaCha12Rng::seed_from_u64(seed),
+            schedule_record: None,
+            schedule_replay: None,
         }
     }
 
     pub fn assign_task(
         &mut self,
-        pe: &mut PE,
-        a_matrix: &mut CsrMatStorage,
+        pe: &mut PE<T>,
+        a_matrix: &mut CsrMatStorage<T>,
+        fiber_cache: &LatencyPriorityCache<T>,
         cur_cycle: usize,
-    ) -> Option<(usize, Task)> {
+    ) -> Option<(usize, Task<T>)> {
+        // Merge to free psum addresses instead of taking more windows once the space runs low. A
+        // lane's worth of addresses is kept for the merges.
+        if !self.psum_addrs.fits(2 * self.lane_num) {
+            return self.merge_task(cur_cycle, true).map(|task| (0, task));
+        }
         // If previous block is finished, try assign the undone latest block, or alloc a new block.
         if pe.task.is_none() || self.is_block_finished(pe.task.as_ref().unwrap().block_token) {
+            // Merge in advance between blocks if it is worth it, unless adder trees do it.
+            if self.merge_on_pes && self.is_merge_due(fiber_cache) {
+                if let Some(task) = self.merge_task(cur_cycle, true) {
+                    return Some((0, task));
+                }
+            }
             if self.latest_block_token != usize::MAX
             // && pe.task.as_ref().unwrap().block_token < self.latest_block_token
             && !self.is_block_finished(self.latest_block_token)
             {
-                return self.next_window(self.latest_block_token, a_matrix, cur_cycle);
+                // Idle PEs take the next window of the latest block, even if another PE opened it.
+                let latency_task = self.next_window(self.latest_block_token, a_matrix, cur_cycle);
+                if latency_task.is_some()
+                    && self.block_tracker[&self.latest_block_token].owner_pe != pe.pe_idx
+                {
+                    self.steal_count += 1;
+                }
+                return latency_task;
             } else {
                 match self.next_block() {
                     None => {
                         self.a_traversed = true;
                         // Check if there are some merge task remained.
-                        if let Some(task) = self.merge_task(cur_cycle) {
+                        if let Some(task) = self.merge_task(cur_cycle, false) {
                             return Some((0, task));
                         } else {
                             return None;
                         }
                     }
                     Some(blk_token) => {
+                        self.block_tracker.get_mut(&blk_token).unwrap().owner_pe = pe.pe_idx;
                         let latency_task = self.next_window(blk_token, a_matrix, cur_cycle);
                         self.latest_block_token = blk_token;
                         return latency_task;
@@ -267,6 +466,31 @@ impl Scheduler {
         }
     }
 
+    // Release the addresses of a finished task: the outputs it left empty, and the psums a merge
+    // consumed. Returns the ones that are recycled.
+    pub fn release_task_addrs(&mut self, window_token: usize, merge_mode: bool) -> Vec<usize> {
+        self.psum_addrs.pending_tasks -= 1;
+        let window_tracker = &self.window_tracker[&window_token];
+        let b_row_lens = &self.b_row_lens;
+        let mut addrs = window_tracker
+            .arow_addr_pairs
+            .iter()
+            .map(|arow_addr| arow_addr[1])
+            .filter(|addr| !b_row_lens.contains_key(addr))
+            .collect::<Vec<usize>>();
+        if merge_mode {
+            addrs.extend(window_tracker.lane2idx.iter().flatten().map(|idx| idx[1]));
+        }
+        let mut recycled = vec![];
+        for addr in addrs {
+            if self.psum_addrs.release(addr) {
+                self.b_row_lens.remove(&addr);
+                recycled.push(addr);
+            }
+        }
+        recycled
+    }
+
     pub fn is_block_finished(&mut self, block_token: usize) -> bool {
         let block_tracker = self.block_tracker.get(&block_token).unwrap();
         for (c, l) in block_tracker
@@ -301,6 +525,13 @@ impl Scheduler {
                 self.col_s = 0;
                 if let Accelerator::Spada = self.accelerator {
                     self.adjust_block_row([self.row_s, self.col_s]);
+                    self.commit_decision(
+                        DecisionKind::BlockRow,
+                        None,
+                        None,
+                        [self.row_s, self.col_s],
+                        self.block_shape,
+                    );
                 }
                 // Get block stats.
                 let token = self.block_token.tik();
@@ -318,6 +549,13 @@ impl Scheduler {
                     })
                     .collect::<Vec<bool>>();
                 // Config trackers.
+                self.commit_decision(
+                    DecisionKind::Block,
+                    Some(token),
+                    None,
+                    [self.row_s, self.col_s],
+                    self.block_shape,
+                );
                 self.set_block(
                     token,
                     [self.row_s, self.col_s],
@@ -354,6 +592,13 @@ impl Scheduler {
                     })
                     .collect::<Vec<bool>>();
                 // Config trackers.
+                self.commit_decision(
+                    DecisionKind::Block,
+                    Some(token),
+                    None,
+                    [self.row_s, self.col_s],
+                    self.block_shape,
+                );
                 self.set_block(
                     token,
                     [self.row_s, self.col_s],
@@ -371,6 +616,13 @@ impl Scheduler {
                 if self.row_s < self.a_row_num {
                     self.col_s = self.a_cols_assigned[self.row_s];
                     self.adjust_block_row([self.row_s, self.col_s]);
+                    self.commit_decision(
+                        DecisionKind::BlockRow,
+                        None,
+                        None,
+                        [self.row_s, self.col_s],
+                        self.block_shape,
+                    );
                 } else {
                     self.col_s = 0;
                 }
@@ -378,57 +630,135 @@ impl Scheduler {
         }
     }
 
-    pub fn merge_task(&mut self, cur_cycle: usize) -> Option<Task> {
-        let mut psums = vec![];
-        let mut pnum = 0;
+    pub fn is_merge_due(&self, fiber_cache: &LatencyPriorityCache<T>) -> bool {
+        (self.merge_policy == MergePolicy::CostBased && self.is_merge_urgent(fiber_cache))
+            || self.is_above_merge_watermark(fiber_cache)
+    }
 
-        // If `lane_num / 2` pairs of psums are found, the a merge block is ready.
+    pub fn is_merge_urgent(&self, fiber_cache: &LatencyPriorityCache<T>) -> bool {
+        // The merged rows need about as much space as the psums to merge.
+        let pending_size = self
+            .output_tracker
+            .values()
+            .filter(|ps| ps.len() > 1)
+            .flatten()
+            .map(|addr| fiber_cache.footprint(2 * self.b_row_lens.get(addr).cloned().unwrap_or(0)))
+            .sum::<usize>();
+        if pending_size > fiber_cache.capability.saturating_sub(fiber_cache.cur_num) {
+            return true;
+        }
+        // Fully produced rows only wait on merging to be swapped out, merge once they fill a task.
+        let produced_num = self
+            .a_tail_produced
+            .iter()
+            .filter(|row| {
+                self.row_rgstr_task.get(row).map_or(true, |r| *r == 0)
+                    && self
+                        .output_tracker
+                        .get(row)
+                        .map_or(false, |ps| ps.len() > 1)
+            })
+            .count();
+        produced_num >= self.lane_num / self.merge_radix
+    }
+
+    pub fn is_above_merge_watermark(&self, fiber_cache: &LatencyPriorityCache<T>) -> bool {
+        self.merge_watermark.map_or(false, |watermark| {
+            fiber_cache.cur_num as f32 >= watermark * fiber_cache.capability as f32
+        })
+    }
+
+    // Order the sorted rows with pending psums by the merge order. Output addrs are allocated
+    // in task order, so the smallest addr of a row is its oldest psum.
+    fn order_merge_rows(&mut self, rows: &mut Vec<usize>) {
+        let output_tracker = &self.output_tracker;
+        match self.merge_order {
+            MergeOrder::Random => rows.shuffle(&mut self.rng),
+            MergeOrder::RowId => {}
+            MergeOrder::LongestFirst => {
+                rows.sort_by_key(|row| Reverse(output_tracker[row].len()));
+            }
+            MergeOrder::OldestFirst => {
+                rows.sort_by_key(|row| output_tracker[row].iter().min().cloned());
+            }
+        }
+    }
+
+    pub fn merge_task(&mut self, cur_cycle: usize, force: bool) -> Option<Task<T>> {
+        let radix = self.merge_radix;
+        let group_num = self.lane_num / radix;
+        if !self.psum_addrs.fits(group_num) {
+            return None;
+        }
+        let mut groups: Vec<Vec<[usize; 2]>> = vec![];
+        let mut gnum = 0;
+
+        // If `lane_num / radix` groups of psums are found, the a merge block is ready.
         for psum_addrs in self.output_tracker.values() {
-            if pnum >= self.lane_num / 2 {
+            if gnum >= group_num {
                 break;
             }
-            pnum += psum_addrs.len() / 2;
+            gnum += psum_addrs.len() / radix;
         }
-        if (self.a_traversed && pnum == 0) || (!self.a_traversed && pnum < self.lane_num / 2) {
+        let has_psums = self.output_tracker.values().any(|ps| ps.len() > 1);
+        if !has_psums || (!self.a_traversed && !force && gnum < group_num) {
             return None;
         }
 
-        for (row, psum_addrs) in self.output_tracker.iter_mut() {
-            while psum_addrs.len() > 1 {
-                if psums.len() == self.lane_num {
-                    break;
-                }
-                for addr in psum_addrs.drain(..2) {
-                    psums.push([*row, addr]);
-                }
+        // Merge up to `radix` psums of the same row in a group, produced rows first if forced.
+        let mut rows = self.output_tracker.keys().cloned().collect::<Vec<usize>>();
+        rows.sort_unstable();
+        if force {
+            // Forced merges take the produced rows first, in row id order unless an order is set.
+            if self.merge_order != MergeOrder::Random {
+                self.order_merge_rows(&mut rows);
             }
+            let a_tail_produced = &self.a_tail_produced;
+            rows.sort_by_key(|row| !a_tail_produced.contains(row));
+        } else {
+            self.order_merge_rows(&mut rows);
         }
+        for row in rows {
+            let psum_addrs = self.output_tracker.get_mut(&row).unwrap();
+            while psum_addrs.len() > 1 && groups.len() < group_num {
+                let merge_num = min(radix, psum_addrs.len());
+                groups.push(
+                    psum_addrs
+                        .drain(..merge_num)
+                        .map(|addr| [row, addr])
+                        .collect(),
+                );
+            }
+        }
+        self.merge_task_count += 1;
 
         let blk_token = self.block_token.tik();
         let win_token = self.window_token.tik();
-        let a_cols_num = (0..self.lane_num / 2)
-            .map(|r_ofst| if r_ofst < psums.len() / 2 { 2 } else { 0 })
-            .collect();
+        let a_cols_num = (0..group_num)
+            .map(|r_ofst| groups.get(r_ofst).map_or(0, |ps| ps.len()))
+            .collect::<Vec<usize>>();
         let mut arow_addr_pairs = vec![];
         let mut a_eles = vec![];
         let mut lane2idx = vec![];
-        for r_ofst in 0..self.lane_num / 2 {
-            if r_ofst < psums.len() / 2 {
-                arow_addr_pairs.push([psums[r_ofst * 2][0], self.output_addr_token.tik()]);
-                a_eles.extend(vec![
-                    Some(Element::new(psums[r_ofst * 2], 1.0)),
-                    Some(Element::new(psums[r_ofst * 2 + 1], 1.0)),
-                ]);
-                lane2idx.extend(vec![Some(psums[r_ofst * 2]), Some(psums[r_ofst * 2 + 1])]);
-            } else {
-                arow_addr_pairs.push([usize::MAX, self.output_addr_token.tik()]);
-                // a_eles.push(None);
-                a_eles.extend(vec![None; 2]);
-                lane2idx.extend(vec![None; 2]);
+        for r_ofst in 0..group_num {
+            match groups.get(r_ofst) {
+                Some(ps) => {
+                    arow_addr_pairs.push([ps[0][0], self.psum_addrs.alloc()]);
+                    for c_ofst in 0..radix {
+                        a_eles.push(ps.get(c_ofst).map(|p| Element::new(*p, T::one())));
+                        lane2idx.push(ps.get(c_ofst).cloned());
+                    }
+                }
+                None => {
+                    arow_addr_pairs.push([usize::MAX, self.psum_addrs.alloc()]);
+                    a_eles.extend(vec![None; radix]);
+                    lane2idx.extend(vec![None; radix]);
+                }
             }
         }
         // Create merge task.
-        let task = Task::new(blk_token, win_token, 2, true, a_eles, cur_cycle);
+        let task = Task::new(blk_token, win_token, radix, true, a_eles, cur_cycle);
+        self.psum_addrs.pending_tasks += 1;
         //Register task in each row.
         for arow_addr in arow_addr_pairs.iter() {
             if arow_addr[0] != usize::MAX {
@@ -444,19 +774,17 @@ impl Scheduler {
             BlockTracker::new(
                 blk_token,
                 [0, 0],
-                [self.lane_num / 2, 2],
+                [group_num, radix],
                 true,
-                a_cols_num,
-                vec![false; self.lane_num / 2],
+                a_cols_num.clone(),
+                vec![false; group_num],
             ),
         );
-        for r_ofst in 0..self.lane_num / 2 {
-            if r_ofst < psums.len() / 2 {
-                self.block_tracker
-                    .get_mut(&blk_token)
-                    .unwrap()
-                    .a_cols_assigned[r_ofst] += 2;
-            }
+        for (r_ofst, col_num) in a_cols_num.into_iter().enumerate() {
+            self.block_tracker
+                .get_mut(&blk_token)
+                .unwrap()
+                .a_cols_assigned[r_ofst] += col_num;
         }
         self.block_tracker
             .get_mut(&blk_token)
@@ -470,7 +798,7 @@ impl Scheduler {
                 win_token,
                 [0, 0],
                 blk_token,
-                [self.lane_num / 2, 2],
+                [group_num, radix],
                 lane2idx,
                 arow_addr_pairs,
             ),
@@ -482,9 +810,9 @@ impl Scheduler {
     pub fn next_window(
         &mut self,
         block_token: usize,
-        a_matrix: &mut CsrMatStorage,
+        a_matrix: &mut CsrMatStorage<T>,
         cur_cycle: usize,
-    ) -> Option<(usize, Task)> {
+    ) -> Option<(usize, Task<T>)> {
         let prev_window = self.block_tracker[&block_token]
             .window_tokens
             .last()
@@ -492,14 +820,28 @@ impl Scheduler {
         let window_shape: [usize; 2];
         let window_token: usize;
         let mut window_anchor: [usize; 2];
-        let block_anchor: [usize; 2];
+        let block_anchor = self.block_tracker[&block_token].anchor;
+        let block_shape = self.block_tracker[&block_token].shape;
         let a_latency: usize;
         if prev_window.is_none() {
             window_shape = self.adjust_window(block_token);
+            // An empty window would never slide past the block.
+            assert!(
+                window_shape[0] > 0 && window_shape[1] > 0,
+                "Empty window shape {:?} in block {}",
+                window_shape,
+                block_token
+            );
             window_token = self.window_token.tik();
-            window_anchor = self.block_tracker.get_mut(&block_token).unwrap().anchor;
-            block_anchor = self.block_tracker.get_mut(&block_token).unwrap().anchor;
+            window_anchor = block_anchor;
             a_latency = self.cache_latency;
+            self.commit_decision(
+                DecisionKind::Window,
+                Some(block_token),
+                Some(window_token),
+                window_anchor,
+                window_shape,
+            );
         } else {
             let prev_window = prev_window.unwrap();
             let blk_tracker = self.block_tracker.get(&block_token).unwrap();
@@ -507,7 +849,6 @@ impl Scheduler {
             window_token = self.window_token.tik();
             window_anchor = window.anchor;
             window_shape = window.shape;
-            block_anchor = blk_tracker.anchor;
             let row_lim = blk_tracker.anchor[0] + blk_tracker.shape[0];
             let col_lim = blk_tracker.anchor[1]
                 + min(
@@ -527,12 +868,8 @@ impl Scheduler {
                 while window_anchor[0] < row_lim {
                     window_anchor[1] = blk_tracker.anchor[1];
                     window_anchor[0] += window_shape[0];
-                    if !self.is_window_valid(
-                        blk_tracker.anchor,
-                        blk_tracker.shape,
-                        window_anchor,
-                        window_shape,
-                    ) {
+                    if !self.is_window_valid(block_anchor, block_shape, window_anchor, window_shape)
+                    {
                         break;
                     }
                 }
@@ -544,32 +881,92 @@ impl Scheduler {
         }
         let mut lane2idx = vec![];
         let mut a_eles = vec![];
-        // let output_addrs = vec![self.output_addr_token.tik(); window_shape[0]];
-        let output_addrs = (0..window_shape[0])
-            .map(|r_offset| [window_anchor[0] + r_offset, self.output_addr_token.tik()])
-            .collect::<Vec<[usize; 2]>>();
-        for r_idx in window_anchor[0]..window_anchor[0] + window_shape[0] {
+        let mut output_addrs = vec![];
+        let group_size;
+        if self.accelerator == Accelerator::Op {
+            // The A matrix is stored by column. Each lane is a group of its own, scaling the B row
+            // of the column by its element into a partial row of the element's A row.
+            let c_idx = window_anchor[0];
             let num = min(
-                max(self.a_row_lens[r_idx], window_anchor[1]),
+                max(self.a_row_lens[c_idx], window_anchor[1]),
                 window_anchor[1] + window_shape[1],
             ) - window_anchor[1];
-            let element = a_matrix.read_scalars(r_idx, window_anchor[1], num).unwrap();
+            let element = a_matrix.read_scalars(c_idx, window_anchor[1], num).unwrap();
             let ele_len = element.len();
-            // Increase assigned a col elements.
             let block_tracker = self.block_tracker.get_mut(&block_token).unwrap();
-            block_tracker.a_cols_assigned[r_idx - block_anchor[0]] += ele_len;
+            block_tracker.a_cols_assigned[0] += ele_len;
             for mut e in element {
-                lane2idx.push(Some(e.idx));
-                e.idx = [window_token, e.idx[1]];
+                output_addrs.push([e.idx[1], self.psum_addrs.alloc()]);
+                lane2idx.push(Some([c_idx, c_idx]));
+                e.idx = [window_token, c_idx];
                 a_eles.push(Some(e));
             }
             for _ in ele_len..window_shape[1] {
+                output_addrs.push([usize::MAX, self.psum_addrs.alloc()]);
                 lane2idx.push(None);
                 a_eles.push(None);
             }
+            group_size = 1;
+        } else {
+            // The last window of a block whose row num is not a multiple of the window height
+            // sticks out of the block, its rows past the block are left as empty lanes.
+            let row_end =
+                self.window_row_end(block_anchor, block_shape, window_anchor, window_shape);
+            for r_idx in window_anchor[0]..window_anchor[0] + window_shape[0] {
+                if r_idx >= row_end {
+                    output_addrs.push([usize::MAX, self.psum_addrs.alloc()]);
+                    lane2idx.extend(vec![None; window_shape[1]]);
+                    a_eles.extend(vec![None; window_shape[1]]);
+                    continue;
+                }
+                output_addrs.push([r_idx, self.psum_addrs.alloc()]);
+                // Nor may it read past the block cols when the block width is not a multiple of
+                // the window width.
+                let num = min(
+                    max(self.a_row_lens[r_idx], window_anchor[1]),
+                    min(
+                        window_anchor[1] + window_shape[1],
+                        block_anchor[1] + block_shape[1],
+                    ),
+                ) - window_anchor[1];
+                let element = a_matrix.read_scalars(r_idx, window_anchor[1], num).unwrap();
+                let ele_len = element.len();
+                // Increase assigned a col elements.
+                let block_tracker = self.block_tracker.get_mut(&block_token).unwrap();
+                block_tracker.a_cols_assigned[r_idx - block_anchor[0]] += ele_len;
+                for mut e in element {
+                    lane2idx.push(Some(e.idx));
+                    e.idx = [window_token, e.idx[1]];
+                    a_eles.push(Some(e));
+                }
+                for _ in ele_len..window_shape[1] {
+                    lane2idx.push(None);
+                    a_eles.push(None);
+                }
+            }
+            group_size = window_shape[1];
         }
+        // Track the B fibers the window touches.
+        let b_rowids = a_eles
+            .iter()
+            .flatten()
+            .map(|e| e.idx[1])
+            .collect::<Vec<usize>>();
+        let touched_fiber_size = b_rowids
+            .iter()
+            .map(|r| self.b_row_lens.get(r).cloned().unwrap_or(0))
+            .sum::<usize>();
+        let dedup_fiber_size = b_rowids
+            .iter()
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .map(|r| self.b_row_lens.get(r).cloned().unwrap_or(0))
+            .sum::<usize>();
+        let block_tracker = self.block_tracker.get_mut(&block_token).unwrap();
+        block_tracker.touched_fiber_size += touched_fiber_size;
+        block_tracker.dedup_fiber_size += dedup_fiber_size;
         // Register task in each row.
-        for arow_addr in output_addrs.iter() {
+        for arow_addr in output_addrs.iter().filter(|a| a[0] != usize::MAX) {
             self.row_rgstr_task
                 .entry(arow_addr[0])
                 .or_default()
@@ -594,10 +991,11 @@ impl Scheduler {
             .window_tokens
             .push(window_token);
         // Config task.
+        self.psum_addrs.pending_tasks += 1;
         let task = Task::new(
             block_token,
             window_token,
-            window_shape[1],
+            group_size,
             false,
             a_eles,
             cur_cycle,
@@ -626,13 +1024,9 @@ impl Scheduler {
         window_anchor: [usize; 2],
         window_shape: [usize; 2],
     ) -> bool {
-        for rowid in window_anchor[0]
-            ..min(
-                window_anchor[0] + window_shape[0],
-                block_anchor[0] + block_shape[0],
-            )
-        {
-            if rowid >= self.a_row_num || window_anchor[1] >= self.a_row_lens[rowid] {
+        let row_end = self.window_row_end(block_anchor, block_shape, window_anchor, window_shape);
+        for rowid in window_anchor[0]..row_end {
+            if window_anchor[1] >= self.a_row_lens[rowid] {
                 continue;
             } else {
                 return false;
@@ -642,6 +1036,20 @@ impl Scheduler {
         return true;
     }
 
+    // End of the window rows clamped to the block and to A.
+    fn window_row_end(
+        &self,
+        block_anchor: [usize; 2],
+        block_shape: [usize; 2],
+        window_anchor: [usize; 2],
+        window_shape: [usize; 2],
+    ) -> usize {
+        min(
+            window_anchor[0] + window_shape[0],
+            min(block_anchor[0] + block_shape[0], self.a_row_num),
+        )
+    }
+
     pub fn is_window_finished(&self, window_token: usize) -> bool {
         // trace_println!("**is_window_finished");
         let window_tracker = self.window_tracker.get(&window_token).unwrap();
@@ -664,91 +1072,164 @@ impl Scheduler {
         return true;
     }
 
-    pub fn adjust_block_row(&mut self, block_anchor: [usize; 2]) {
+    // Only Spada adapts the block and window shapes, the other accelerators keep them fixed.
+    fn block_strategy(&self) -> BlockStrategy {
         match self.accelerator {
-            Accelerator::Ip | Accelerator::MultiRow | Accelerator::Op => {
-                while self.row_s + self.block_shape[0] > self.a_row_num {
-                    self.block_shape[0] = max(1, self.block_shape[0] / 2);
-                }
-            }
-            Accelerator::Spada => {
-                self.block_shape = match self.adjust_scheme {
-                    0 => self.rowwise_adjust_tracker.adjust_block_shape(
-                        block_anchor,
-                        self.row_s,
-                        self.block_shape,
-                        &self.block_topo_tracker,
-                        &self.a_row_lens,
-                    ),
-                    1 => self
-                        .colwise_reg_adjust_tracker
-                        .adjust_block_shape(self.row_s, self.a_row_num),
-                    2 => {
-                        if block_anchor == [0; 2] {
-                            self.colwise_irr_adjust_tracker.adjust_block_shape(
-                                block_anchor,
-                                self.a_row_num,
-                                &&self.block_topo_tracker,
-                            )
-                        } else {
-                            self.block_shape
-                        }
-                    }
-                    3 => self
-                        .rowwise_latency_adjust_tracker
-                        .adjust_block_shape(self.row_s, self.block_shape),
-                    _ => panic!("Invalid merge scheme: {}", self.adjust_scheme),
-                }
-            }
+            Accelerator::Spada => self.block_strategy,
+            Accelerator::Ip | Accelerator::MultiRow | Accelerator::Op => BlockStrategy::Fixed,
         }
     }
 
-    pub fn adjust_block_col(&mut self, block_anchor: [usize; 2]) {
-        match self.accelerator {
-            Accelerator::Ip | Accelerator::MultiRow | Accelerator::Op => {
+    // The shape of the next replayed decision, which must be made at the same anchor.
+    fn replayed_shape(&self, kind: DecisionKind, anchor: [usize; 2]) -> Option<[usize; 2]> {
+        let decision = self.schedule_replay.as_ref()?.front()?;
+        assert!(
+            decision.kind == kind && decision.anchor == anchor,
+            "Replayed schedule diverged: {:?} at {:?}, recorded {:?}",
+            kind,
+            anchor,
+            decision
+        );
+        Some(decision.shape)
+    }
+
+    fn commit_decision(
+        &mut self,
+        kind: DecisionKind,
+        block_token: Option<usize>,
+        window_token: Option<usize>,
+        anchor: [usize; 2],
+        shape: [usize; 2],
+    ) {
+        if self.replayed_shape(kind, anchor).is_some() {
+            self.schedule_replay.as_mut().unwrap().pop_front();
+        }
+        if let Some(schedule_record) = self.schedule_record.as_mut() {
+            schedule_record.push(ScheduleDecision {
+                kind,
+                block_token,
+                window_token,
+                anchor,
+                shape,
+            });
+        }
+    }
+
+    pub fn adjust_block_row(&mut self, block_anchor: [usize; 2]) {
+        if let Some(shape) = self.replayed_shape(DecisionKind::BlockRow, block_anchor) {
+            self.block_shape = shape;
+            return;
+        }
+        self.block_shape = match self.block_strategy() {
+            BlockStrategy::Fixed => {
                 while self.row_s + self.block_shape[0] > self.a_row_num {
                     self.block_shape[0] = max(1, self.block_shape[0] / 2);
                 }
+                self.block_shape
             }
-            Accelerator::Spada => {
-                self.block_shape = match self.adjust_scheme {
-                    0 => self.block_shape,
-                    1 => self.block_shape,
-                    2 => self.colwise_irr_adjust_tracker.adjust_block_shape(
+            BlockStrategy::Rowwise => self.rowwise_adjust_tracker.adjust_block_shape(
+                block_anchor,
+                self.row_s,
+                self.block_shape,
+                &self.block_topo_tracker,
+                &self.a_row_lens,
+            ),
+            BlockStrategy::ColwiseRegular => self
+                .colwise_reg_adjust_tracker
+                .adjust_block_shape(self.row_s, self.a_row_num),
+            BlockStrategy::ColwiseIrregular => {
+                if block_anchor == [0; 2] {
+                    self.colwise_irr_adjust_tracker.adjust_block_shape(
                         block_anchor,
                         self.a_row_num,
                         &&self.block_topo_tracker,
-                    ),
-                    3 => self.block_shape,
-                    _ => panic!("Invalid merge scheme: {}", self.adjust_scheme),
+                    )
+                } else {
+                    self.block_shape
                 }
             }
+            BlockStrategy::RowwiseLatency => self
+                .rowwise_latency_adjust_tracker
+                .adjust_block_shape(self.row_s, self.block_shape),
+        };
+        self.clamp_block(block_anchor);
+    }
+
+    pub fn adjust_block_col(&mut self, block_anchor: [usize; 2]) {
+        if let Some(shape) = self.replayed_shape(DecisionKind::Block, block_anchor) {
+            self.block_shape = shape;
+            return;
+        }
+        self.block_shape =
+            match self.block_strategy() {
+                BlockStrategy::Fixed => {
+                    while self.row_s + self.block_shape[0] > self.a_row_num {
+                        self.block_shape[0] = max(1, self.block_shape[0] / 2);
+                    }
+                    self.block_shape
+                }
+                BlockStrategy::ColwiseIrregular => self
+                    .colwise_irr_adjust_tracker
+                    .adjust_block_shape(block_anchor, self.a_row_num, &&self.block_topo_tracker),
+                BlockStrategy::Rowwise
+                | BlockStrategy::ColwiseRegular
+                | BlockStrategy::RowwiseLatency => self.block_shape,
+            };
+        self.clamp_block(block_anchor);
+    }
+
+    // Keep the adapted block from collapsing into narrow blocks that flood the cache with psums.
+    fn clamp_block(&mut self, block_anchor: [usize; 2]) {
+        if self.block_strategy() == BlockStrategy::Fixed {
+            return;
+        }
+        let mut block_shape = self.block_shape;
+        if let Some(min_window_width) = self.min_window_width {
+            block_shape[0] = min(block_shape[0], max(1, self.lane_num / min_window_width));
+        }
+        if let Some(ratio) = self.min_block_width {
+            let row_t = min(block_anchor[0] + block_shape[0], self.a_row_num);
+            let remaining = self.a_row_lens[block_anchor[0]..row_t]
+                .iter()
+                .map(|len| len.saturating_sub(block_anchor[1]))
+                .sum::<usize>();
+            let row_num = max(1, row_t.saturating_sub(block_anchor[0]));
+            let floor = (ratio * remaining as f32 / row_num as f32).ceil() as usize;
+            block_shape[1] = max(block_shape[1], floor);
+        }
+        if block_shape != self.block_shape {
+            info_println!(
+                "Clamp the block at {:?} from {:?} to {:?}",
+                block_anchor,
+                self.block_shape,
+                block_shape
+            );
+            self.block_shape = block_shape;
         }
     }
 
     pub fn adjust_window(&mut self, block_token: usize) -> [usize; 2] {
-        match self.accelerator {
-            Accelerator::Ip | Accelerator::MultiRow | Accelerator::Op => {
-                return [self.block_shape[0], self.lane_num / self.block_shape[0]];
-            }
-            Accelerator::Spada => match self.adjust_scheme {
-                0 => self
-                    .rowwise_adjust_tracker
-                    .adjust_window_shape(self.block_tracker[&block_token].shape),
-                1 => self.colwise_reg_adjust_tracker.adjust_window_shape(
-                    block_token,
-                    self.block_tracker[&block_token].anchor,
-                    self.block_tracker[&block_token].shape,
-                    &self.block_topo_tracker,
-                ),
-                2 => self
-                    .colwise_irr_adjust_tracker
-                    .adjust_window_shape(self.block_tracker[&block_token].shape),
-                3 => self
-                    .rowwise_latency_adjust_tracker
-                    .adjust_window_shape(self.block_tracker[&block_token].shape),
-                _ => panic!("Invalid adjust scheme: {}", self.adjust_scheme),
-            },
+        let block_anchor = self.block_tracker[&block_token].anchor;
+        if let Some(shape) = self.replayed_shape(DecisionKind::Window, block_anchor) {
+            return shape;
+        }
+        match self.block_strategy() {
+            BlockStrategy::Fixed => [self.block_shape[0], self.lane_num / self.block_shape[0]],
+            BlockStrategy::Rowwise => self
+                .rowwise_adjust_tracker
+                .adjust_window_shape(self.block_tracker[&block_token].shape),
+            BlockStrategy::ColwiseRegular => self.colwise_reg_adjust_tracker.adjust_window_shape(
+                block_token,
+                self.block_tracker[&block_token].anchor,
+                self.block_tracker[&block_token].shape,
+                &self.block_topo_tracker,
+            ),
+            BlockStrategy::ColwiseIrregular => self
+                .colwise_irr_adjust_tracker
+                .adjust_window_shape(self.block_tracker[&block_token].shape),
+            BlockStrategy::RowwiseLatency => self
+                .rowwise_latency_adjust_tracker
+                .adjust_window_shape(self.block_tracker[&block_token].shape),
         }
     }
 
@@ -801,12 +1282,18 @@ impl Scheduler {
 
     pub fn assign_in_cache_merge_task(
         &mut self,
-        adder_tree: &mut AdderTree,
-        fiber_cache: &LatencyPriorityCache,
+        adder_tree: &mut AdderTree<T>,
+        fiber_cache: &LatencyPriorityCache<T>,
         cur_cycle: usize,
-    ) -> Option<(usize, Task)> {
-        // Prior to merge task.
-        if let Some(task) = self.in_cache_merge_task(adder_tree.tree_width, fiber_cache, cur_cycle)
+    ) -> Option<(usize, Task<T>)> {
+        // Prior to merge task. Under cache pressure any row with more than one psum is merged.
+        let min_psum_num = if !self.merge_on_pes && self.is_merge_due(fiber_cache) {
+            2
+        } else {
+            adder_tree.tree_width
+        };
+        if let Some(task) =
+            self.in_cache_merge_task(adder_tree.tree_width, min_psum_num, fiber_cache, cur_cycle)
         {
             if adder_tree.task.is_some() && !adder_tree.task.as_ref().unwrap().merge_mode {
                 self.staged_tasks[adder_tree.pe_idx] = mem::replace(&mut adder_tree.task, None);
@@ -820,22 +1307,32 @@ impl Scheduler {
     pub fn in_cache_merge_task(
         &mut self,
         tree_width: usize,
-        fiber_cache: &LatencyPriorityCache,
+        min_psum_num: usize,
+        fiber_cache: &LatencyPriorityCache<T>,
         cur_cycle: usize,
-    ) -> Option<Task> {
+    ) -> Option<Task<T>> {
+        if !self.psum_addrs.fits(1) {
+            return None;
+        }
         let mut psums = vec![];
-        for (row, psum_addrs) in self.output_tracker.iter_mut() {
-            if psum_addrs.len() >= tree_width
+        let mut rows = self.output_tracker.keys().cloned().collect::<Vec<usize>>();
+        rows.sort_unstable();
+        self.order_merge_rows(&mut rows);
+        for row in rows {
+            let psum_addrs = self.output_tracker.get_mut(&row).unwrap();
+            if psum_addrs.len() >= min_psum_num
                 && psum_addrs.iter().all(|addr| fiber_cache.contains_row(addr))
             {
                 for addr in psum_addrs.drain(..min(tree_width, psum_addrs.len())) {
-                    psums.push([*row, addr]);
+                    psums.push([row, addr]);
                 }
                 break;
             }
         }
         if psums.len() == 0 {
-            for frow in self.a_tail_produced.iter() {
+            let mut frows = self.a_tail_produced.iter().collect::<Vec<&usize>>();
+            frows.sort_unstable();
+            for frow in frows {
                 if !self.output_tracker.contains_key(frow) {
                     continue;
                 }
@@ -863,10 +1360,10 @@ impl Scheduler {
         let blk_token = self.block_token.tik();
         let win_token = self.window_token.tik();
         let a_cols_num = vec![psums.len()];
-        let arow_addr_pairs = vec![[psums[0][0], self.output_addr_token.tik()]];
+        let arow_addr_pairs = vec![[psums[0][0], self.psum_addrs.alloc()]];
         let mut a_eles = psums
             .iter()
-            .map(|p_idx| Some(Element::new(*p_idx, 1.0)))
+            .map(|p_idx| Some(Element::new(*p_idx, T::one())))
             .collect::<Vec<_>>();
         let mut lane2idx = psums.iter().map(|p_idx| Some(*p_idx)).collect::<Vec<_>>();
         for _ in a_eles.len()..tree_width {
@@ -875,6 +1372,7 @@ impl Scheduler {
         }
         // Create merge task.
         let task = Task::new(blk_token, win_token, tree_width, true, a_eles, cur_cycle);
+        self.psum_addrs.pending_tasks += 1;
         // Register task in each row.
         for arow_addr in arow_addr_pairs.iter() {
             if arow_addr[0] != usize::MAX {
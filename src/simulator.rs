@@ -1,20 +1,33 @@
 use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
 use std::ops::AddAssign;
 
-use crate::adder_tree::AdderTree;
-use crate::frontend::Accelerator;
+use crate::adder_tree::{adder_tree_latency, AdderTree};
+use crate::frontend::{Accelerator, BlockStrategy, EnergyConfig, MemLatency, OmegaConfig};
+use crate::scalar::Scalar;
 use crate::scheduler::{Scheduler, Task};
+use crate::stats::{EnergyStats, SimulationStats};
 use crate::storage::{
-    sorted_element_vec_to_csr_row, CsrMatStorage, CsrRow, Element, LatencyPriorityCache,
-    VectorStorage,
+    fiber_bytes, sorted_element_vec_to_csr_row, CscPsumStorage, CsrMatStorage, CsrRow,
+    DenseOutputStorage, DramChannels, Element, L1Cache, LatencyPriorityCache, PsumBanks,
+    ReuseTracker, StorageAPI, VectorStorage, VictimPolicy,
 };
-use crate::{trace_print, trace_println};
+use crate::util::pair_map;
+use crate::{load_state_fields, trace_print, trace_println};
 use std::{
     cmp::{max, min},
-    collections::VecDeque,
+    collections::{BTreeMap, HashMap, VecDeque},
 };
 
-pub fn merge_idx(a: &VecDeque<Element>, b: &VecDeque<Element>, merge_num: usize) -> [usize; 2] {
+pub fn merge_idx<T>(
+    a: &VecDeque<Element<T>>,
+    b: &VecDeque<Element<T>>,
+    merge_num: usize,
+) -> [usize; 2] {
     let mut a_num = 0;
     let mut b_num = 0;
     let mut merge_num = merge_num;
@@ -37,17 +50,17 @@ pub fn merge_idx(a: &VecDeque<Element>, b: &VecDeque<Element>, merge_num: usize)
     return [a_num, b_num];
 }
 
-#[derive(Debug, Clone)]
-pub struct MultiplierArray {
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MultiplierArray<T = f64> {
     multiplier_num: usize,
-    pub a_eles: Vec<Option<Element>>,
-    pub b_eles: Vec<Option<Element>>,
-    pub c_eles: Vec<Option<Element>>,
+    pub a_eles: Vec<Option<Element<T>>>,
+    pub b_eles: Vec<Option<Element<T>>>,
+    pub c_eles: Vec<Option<Element<T>>>,
     row_drained: Vec<bool>,
 }
 
-impl MultiplierArray {
-    pub fn new(multiplier_num: usize) -> MultiplierArray {
+impl<T: Scalar> MultiplierArray<T> {
+    pub fn new(multiplier_num: usize) -> MultiplierArray<T> {
         MultiplierArray {
             multiplier_num,
             a_eles: (0..multiplier_num).map(|_| None).collect_vec(),
@@ -57,7 +70,7 @@ impl MultiplierArray {
         }
     }
 
-    pub fn set_as(&mut self, a_eles: Vec<Option<Element>>) {
+    pub fn set_as(&mut self, a_eles: Vec<Option<Element<T>>>) {
         for (idx, a) in a_eles.into_iter().enumerate() {
             if a.is_some() {
                 self.row_drained[idx] = false;
@@ -68,7 +81,7 @@ impl MultiplierArray {
         }
     }
 
-    pub fn set_bs(&mut self, b_eles: Vec<Option<Element>>) {
+    pub fn set_bs(&mut self, b_eles: Vec<Option<Element<T>>>) {
         for (idx, b) in b_eles.into_iter().enumerate() {
             if b.is_some() && b.as_ref().unwrap().idx == [usize::MAX; 2] {
                 self.row_drained[idx] = true;
@@ -79,7 +92,7 @@ impl MultiplierArray {
         }
     }
 
-    pub fn retrieve_cs(&mut self) -> Vec<Option<Element>> {
+    pub fn retrieve_cs(&mut self) -> Vec<Option<Element<T>>> {
         return self.c_eles.clone();
     }
 
@@ -98,7 +111,7 @@ impl MultiplierArray {
                     let a = self.a_eles[a_idx].as_ref().unwrap();
                     if a.idx[1] == b.idx[0] {
                         self.c_eles[idx] =
-                            Some(Element::new([a.idx[0], b.idx[1]], a.value * b.value));
+                            Some(Element::new([a.idx[0], b.idx[1]], a.value.times(b.value)));
                         matched = true;
                         break;
                     }
@@ -112,20 +125,27 @@ impl MultiplierArray {
     pub fn is_empty(&self, mult_idx: usize) -> bool {
         self.a_eles[mult_idx].is_none() || self.row_drained[mult_idx]
     }
+
+    pub fn reset(&mut self) {
+        self.a_eles.fill(None);
+        self.b_eles.fill(None);
+        self.c_eles.fill(None);
+        self.row_drained.fill(false);
+    }
 }
 
-#[derive(Debug, Clone)]
-pub struct SortingNetwork {
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SortingNetwork<T = f64> {
     // For now we simply assume a one-cycle sorting-network.
-    elements: Vec<Vec<Option<Element>>>,
+    elements: Vec<Vec<Option<Element<T>>>>,
     latency_counter: Vec<usize>,
     group_lane_num: usize,
     ele_per_lane: usize,
     latency: usize,
 }
 
-impl SortingNetwork {
-    pub fn new(group_lane_num: usize, ele_per_lane: usize, latency: usize) -> SortingNetwork {
+impl<T: Scalar> SortingNetwork<T> {
+    pub fn new(group_lane_num: usize, ele_per_lane: usize, latency: usize) -> SortingNetwork<T> {
         SortingNetwork {
             elements: vec![],
             latency_counter: vec![],
@@ -135,12 +155,12 @@ impl SortingNetwork {
         }
     }
 
-    pub fn push_elements(&mut self, elements: Vec<Option<Element>>) {
+    pub fn push_elements(&mut self, elements: Vec<Option<Element<T>>>) {
         self.elements.push(elements);
         self.latency_counter.push(self.latency);
     }
 
-    pub fn pop_elements(&mut self) -> Vec<Vec<Element>> {
+    pub fn pop_elements(&mut self) -> Vec<Vec<Element<T>>> {
         let mut sorted_results = vec![];
         for idx in 0..self.elements.len() {
             if self.latency_counter[idx] > 0 {
@@ -153,10 +173,10 @@ impl SortingNetwork {
             while !elements.is_empty() {
                 let mut g = elements
                     .drain(..self.group_lane_num * self.ele_per_lane)
-                    .collect::<Vec<Option<Element>>>()
+                    .collect::<Vec<Option<Element<T>>>>()
                     .drain_filter(|x| x.is_some())
                     .map(|x| x.unwrap())
-                    .collect::<Vec<Element>>();
+                    .collect::<Vec<Element<T>>>();
                 g.sort_by(|a, b| a.idx[1].cmp(&b.idx[1]));
                 sorted_results.push(g);
             }
@@ -173,30 +193,53 @@ impl SortingNetwork {
     pub fn is_empty(&self) -> bool {
         return self.elements.len() == 0;
     }
+
+    pub fn reset(&mut self, group_lane_num: usize) {
+        self.elements.clear();
+        self.latency_counter.clear();
+        self.group_lane_num = group_lane_num;
+    }
 }
 
-#[derive(Debug, Clone)]
-pub struct MergeTree {
-    elements: Vec<Vec<Vec<Element>>>,
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MergeTree<T = f64> {
+    elements: Vec<Vec<Vec<Element<T>>>>,
     latency_counter: Vec<usize>,
     latency: usize,
+    fan_in: Option<usize>,
+    stage_latency: usize,
+    pub add_count: usize,
 }
 
-impl MergeTree {
-    pub fn new(latency: usize) -> MergeTree {
+impl<T: Scalar> MergeTree<T> {
+    pub fn new(latency: usize, fan_in: Option<usize>, stage_latency: usize) -> MergeTree<T> {
         MergeTree {
             elements: vec![],
             latency_counter: vec![],
             latency,
+            fan_in,
+            stage_latency,
+            add_count: 0,
         }
     }
 
-    pub fn push_elements(&mut self, elements: Vec<Vec<Element>>) {
+    pub fn push_elements(&mut self, elements: Vec<Vec<Element<T>>>) {
+        // The groups are reduced in parallel, so the largest one sets the latency.
+        let latency = match self.fan_in {
+            Some(fan_in) => adder_tree_latency(
+                elements.iter().map(|es| es.len()).max().unwrap_or(0),
+                fan_in,
+                self.stage_latency,
+            ),
+            None => self.latency,
+        };
+        // Keep the batches in order.
+        let latency = max(latency, self.latency_counter.last().cloned().unwrap_or(0));
         self.elements.push(elements);
-        self.latency_counter.push(self.latency);
+        self.latency_counter.push(latency);
     }
 
-    pub fn pop_elements(&mut self) -> Vec<Vec<Element>> {
+    pub fn pop_elements(&mut self) -> Vec<Vec<Element<T>>> {
         let mut merged_results = vec![];
         for idx in 0..self.elements.len() {
             if self.latency_counter[idx] > 0 {
@@ -214,7 +257,9 @@ impl MergeTree {
                         prev_idx = e.idx[1];
                         m.push(e);
                     } else {
-                        m.last_mut().unwrap().value += e.value;
+                        let last = m.last_mut().unwrap();
+                        last.value = last.value.plus(e.value);
+                        self.add_count += 1;
                     }
                 }
                 merged_results.push(m);
@@ -223,7 +268,7 @@ impl MergeTree {
         }
 
         for idx in 0..self.elements.len() {
-            self.latency_counter[idx] -= 1;
+            self.latency_counter[idx] = self.latency_counter[idx].saturating_sub(1);
         }
 
         return merged_results;
@@ -232,23 +277,59 @@ impl MergeTree {
     pub fn is_empty(&self) -> bool {
         return self.elements.len() == 0;
     }
+
+    pub fn reset(&mut self) {
+        self.elements.clear();
+        self.latency_counter.clear();
+        self.add_count = 0;
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PeStats {
+    pub compute_cycle: usize,
+    pub merge_cycle: usize,
+    pub stall_cycle: usize, // Waiting for memory with a task assigned.
+    pub idle_cycle: usize,  // No task assigned.
+}
+
+// The cycles from the assignment of the last A window to the end of the run. Each cycle counts
+// once, as merging if any merge is in flight, else as flushing if psums are evicted to memory,
+// else as writeback if finished rows are swapped out, else as multiplying the last windows.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DrainReport {
+    pub cycles: usize,
+    pub merge_cycles: usize,
+    pub flush_cycles: usize,
+    pub writeback_cycles: usize,
+    pub multiply_cycles: usize,
+    // Whole cycles stalled on the capped bandwidth.
+    pub stall_cycles: usize,
+    // PEs holding a task summed over the drain cycles.
+    pub active_pe_cycles: usize,
+}
+
+impl DrainReport {
+    pub fn mean_active_pes(&self) -> f64 {
+        self.active_pe_cycles as f64 / max(self.cycles, 1) as f64
+    }
 }
 
-#[derive(Debug, Clone)]
-pub struct PE {
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PE<T = f64> {
     // HW components.
-    pub stream_buffers: Vec<VecDeque<Element>>,
-    pub multiplier_array: MultiplierArray,
-    pub psum_buffers: Vec<VecDeque<Element>>,
-    pub sorting_network: SortingNetwork,
-    pub merge_tree: MergeTree,
+    pub stream_buffers: Vec<VecDeque<Element<T>>>,
+    pub multiplier_array: MultiplierArray<T>,
+    pub psum_buffers: Vec<VecDeque<Element<T>>>,
+    pub sorting_network: SortingNetwork<T>,
+    pub merge_tree: MergeTree<T>,
     pub stream_buffer_size: usize,
     pub psum_buffer_size: usize,
     // Config.
     pub pe_idx: usize,
     pub lane_num: usize,
     pub look_aside: bool,
-    pub task: Option<Task>,
+    pub task: Option<Task<T>>,
     // Control.
     pub tail_flags: Vec<usize>,
     pub sb_drained: Vec<bool>,
@@ -257,9 +338,10 @@ pub struct PE {
     // Drain latency.
     pub drain_cycle: Option<usize>,
     pub config_unchanged: bool,
+    pub mac_count: usize,
 }
 
-impl PE {
+impl<T: Scalar> PE<T> {
     pub fn new(
         pe_idx: usize,
         sb_size: usize,
@@ -268,13 +350,15 @@ impl PE {
         pop_num_per_lane: usize,
         sn_latency: usize,
         mt_latency: usize,
-    ) -> PE {
+        adder_fan_in: Option<usize>,
+        adder_stage_latency: usize,
+    ) -> PE<T> {
         PE {
             stream_buffers: vec![VecDeque::new(); lane_num],
             multiplier_array: MultiplierArray::new(lane_num),
             psum_buffers: vec![VecDeque::new(); lane_num],
             sorting_network: SortingNetwork::new(lane_num, pop_num_per_lane, sn_latency),
-            merge_tree: MergeTree::new(mt_latency),
+            merge_tree: MergeTree::new(mt_latency, adder_fan_in, adder_stage_latency),
             stream_buffer_size: sb_size,
             psum_buffer_size: pb_size,
             pe_idx,
@@ -287,9 +371,28 @@ impl PE {
             mem_finish_cycle: None,
             drain_cycle: None,
             config_unchanged: false,
+            mac_count: 0,
         }
     }
 
+    // Back to the state of a new PE, keeping the buffers allocated.
+    pub fn reset(&mut self) {
+        self.stream_buffers.iter_mut().for_each(|sb| sb.clear());
+        self.multiplier_array.reset();
+        self.psum_buffers.iter_mut().for_each(|pb| pb.clear());
+        self.sorting_network.reset(self.lane_num);
+        self.merge_tree.reset();
+        self.look_aside = false;
+        self.task = None;
+        self.tail_flags.fill(0);
+        self.sb_drained.fill(true);
+        self.full_flags.fill(false);
+        self.mem_finish_cycle = None;
+        self.drain_cycle = None;
+        self.config_unchanged = false;
+        self.mac_count = 0;
+    }
+
     pub fn idle(&self) -> bool {
         let is_idle = self
             .stream_buffers
@@ -324,20 +427,20 @@ impl PE {
         }
     }
 
-    pub fn push_stream_buffer(&mut self, lane_idx: usize, elements: Option<Vec<Element>>) {
+    pub fn push_stream_buffer(&mut self, lane_idx: usize, elements: Option<Vec<Element<T>>>) {
         if let Some(es) = elements {
             for e in es {
                 self.stream_buffers[lane_idx].push_back(e);
             }
         } else {
             if !self.sb_drained[lane_idx] {
-                self.stream_buffers[lane_idx].push_back(Element::new([usize::MAX; 2], 0.0));
+                self.stream_buffers[lane_idx].push_back(Element::new([usize::MAX; 2], T::zero()));
                 self.sb_drained[lane_idx] = true;
             }
         }
     }
 
-    pub fn pop_stream_buffer(&mut self, lane_idx: usize) -> Option<Element> {
+    pub fn pop_stream_buffer(&mut self, lane_idx: usize) -> Option<Element<T>> {
         if self.task.is_none() || self.full_flags[lane_idx] {
             return None;
         }
@@ -363,11 +466,11 @@ impl PE {
         }
     }
 
-    pub fn push_psum_buffer(&mut self, lane_idx: usize, element: Element) {
+    pub fn push_psum_buffer(&mut self, lane_idx: usize, element: Element<T>) {
         self.psum_buffers[lane_idx].push_back(element);
     }
 
-    pub fn pop_psum_buffer(&mut self, lane_idx: usize, pop_num: usize) -> Vec<Option<Element>> {
+    pub fn pop_psum_buffer(&mut self, lane_idx: usize, pop_num: usize) -> Vec<Option<Element<T>>> {
         let mut psums = vec![];
         let pb = &mut self.psum_buffers[lane_idx];
         let tf = self.tail_flags[lane_idx];
@@ -381,7 +484,7 @@ impl PE {
         return psums;
     }
 
-    pub fn set_task(&mut self, task: Option<(usize, Task)>) -> usize {
+    pub fn set_task(&mut self, task: Option<(usize, Task<T>)>) -> usize {
         if task.is_none() {
             self.multiplier_array.set_as(vec![None; self.lane_num]);
             // Pb, sn, mt remain the previous configuration.
@@ -407,78 +510,192 @@ impl PE {
     }
 }
 
-pub struct Simulator<'a> {
+// Trace event of a task starting or finishing on a PE or an adder tree.
+fn task_event<T>(event: &str, unit: &str, idx: usize, task: &Option<Task<T>>) -> Value {
+    let task = task.as_ref().unwrap();
+    json!({
+        "event": event,
+        "unit": unit,
+        "idx": idx,
+        "block": task.block_token,
+        "window": task.window_token,
+        "merge": task.merge_mode,
+    })
+}
+
+// Snapshot of a long run handed to the progress callback.
+#[derive(Debug, Clone, Copy)]
+pub struct ProgressInfo {
+    pub cycle: usize,
+    pub a_rows_traversed: usize,
+    pub a_row_num: usize,
+    pub cache_occupancy: usize,
+    pub cache_capability: usize,
+}
+
+pub type ProgressCallback = Box<dyn FnMut(ProgressInfo)>;
+
+#[derive(Serialize)]
+pub struct Simulator<'a, T = f64> {
     pe_num: usize,
     adder_tree_num: usize,
     lane_num: usize,
-    fiber_cache: LatencyPriorityCache<'a>,
-    pes: Vec<PE>,
-    a_matrix: &'a mut CsrMatStorage,
+    fiber_cache: LatencyPriorityCache<'a, T>,
+    // The outer-product psums are accumulated column-wise instead of merged row by row.
+    csc_psums: Option<CscPsumStorage<T>>,
+    // Outputs with few columns are accumulated in place in a dense vector.
+    dense_output: Option<DenseOutputStorage<T>>,
+    l1_caches: Vec<L1Cache<T>>,
+    // MultiRow B fibers shared by all the windows of a block.
+    broadcast_buffers: HashMap<usize, HashMap<usize, CsrRow<T>>>,
+    pub broadcast_hit_count: usize,
+    dram_channels: DramChannels,
+    psum_banks: Option<PsumBanks>,
+    pes: Vec<PE<T>>,
+    a_matrix: &'a mut CsrMatStorage<T>,
+    c_row_num: usize,
     exec_cycle: usize,
-    scheduler: Scheduler,
-    adder_trees: Vec<AdderTree>,
+    scheduler: Scheduler<T>,
+    adder_trees: Vec<AdderTree<T>>,
+    // Cycles each adder tree holds a merge task.
+    pub adder_tree_busy: Vec<usize>,
     // Storage access latency related.
     pub a_pending_cycle: Vec<usize>,
     pub channel: usize,
     pub word_cycle_chan_bw: f32,
     // Debug info.
     pub drain_cycles: Vec<usize>,
+    pub drain_report: DrainReport,
+    // Words of the finished rows swapped out to memory.
+    result_writeback_words: usize,
+    // Additions of the merge of the psums left after the last task.
+    final_merge_add_count: usize,
     pub mult_util: Vec<f32>,
     pub active_cycle: Vec<usize>,
+    pub pe_stats: Vec<PeStats>,
+    pub energy_config: Option<EnergyConfig>,
+    // Stop early after this many cycles, leaving the unfinished rows out of the result.
+    pub max_cycles: Option<usize>,
+    pub truncated: bool,
+    // JSON lines of the scheduling and cache events, streamed as they happen.
+    #[serde(skip)]
+    pub trace_out: Option<BufWriter<File>>,
+    // CSV rows of the task of each PE per cycle, tagged with the workload of a batch.
+    #[serde(skip)]
+    pub pe_log: Option<(String, BufWriter<File>)>,
+    // CSV rows of the cache occupancy per cycle, tagged with the workload of a batch.
+    #[serde(skip)]
+    pub occupancy_out: Option<(String, BufWriter<File>)>,
+    // CSV rows of the bytes per DRAM channel in each epoch of this many cycles.
+    #[serde(skip)]
+    pub channel_util_out: Option<(String, BufWriter<File>)>,
+    #[serde(skip)]
+    pub channel_epoch: usize,
+    #[serde(skip)]
+    channel_epoch_start: usize,
+    // Peak words in the fiber cache in total, of the B fibers and of the psums, each over the
+    // whole run.
+    pub peak_occupancy: [usize; 3],
+    #[serde(skip)]
+    pub debug_checks: bool,
+    // Keep every product with the raw A column it comes from, and sum the products of each
+    // result element in column order at the end, so that the result is the same for any schedule.
+    #[serde(skip)]
+    pub stable_reduce: bool,
+    #[serde(with = "crate::util::pair_map")]
+    stable_products: HashMap<[usize; 2], Vec<(usize, T)>>,
+    // Off-chip bytes per cycle if the bandwidth is capped, and the bytes still queued.
+    pub byte_cycle_bw: Option<f32>,
+    pub bandwidth_backlog: f32,
+    pub bandwidth_stall_cycles: usize,
+    // MACs a PE issues per cycle over all its lanes, one B element per lane per cycle if not set.
+    pub macs_per_cycle: Option<usize>,
+    // A and B are the same matrix, so the A rows streamed in also serve as B fibers.
+    pub shared_operand: bool,
+    // B fibers loaded into the cache before the run.
+    pub warm_b_fibers: Option<usize>,
+    // Pinned B fibers and the requested ones.
+    pub pinned_fibers: Option<[usize; 2]>,
+    // Fraction of the cache the pinned B fibers may take.
+    pub pin_budget: f32,
+    // Called every interval cycles and once at the end of the run.
+    #[serde(skip)]
+    progress_callback: Option<(usize, ProgressCallback)>,
 }
 
-impl<'a> Simulator<'a> {
+impl<'a, T: Scalar> Simulator<'a, T> {
     pub fn new(
-        pe_num: usize,
-        adder_tree_num: usize,
-        lane_num: usize,
-        cache_size: usize,
-        word_byte: usize,
+        spada_config: &OmegaConfig,
         output_base_addr: usize,
         default_block_shape: [usize; 2],
-        a_matrix: &'a mut CsrMatStorage,
-        b_matrix: &'a mut CsrMatStorage,
-        psum_matrix: &'a mut VectorStorage,
+        a_matrix: &'a mut CsrMatStorage<T>,
+        b_matrix: &'a mut CsrMatStorage<T>,
+        psum_matrix: &'a mut VectorStorage<T>,
         accelerator: Accelerator,
-        mem_latency: usize,
-        cache_latency: usize,
-        freq: f32,
-        channel: usize,
-        bandwidth_per_channel: f32,
-    ) -> Simulator<'a> {
-        let var_factor = 1.5;
+        block_strategy: BlockStrategy,
+        seed: u64,
+    ) -> Simulator<'a, T> {
+        let pe_num = spada_config.pe_num;
+        let adder_tree_num = spada_config.at_num;
+        let lane_num = spada_config.mult_lane_num();
+        let mem_latency = spada_config.mem_latency.mean();
+        let cache_latency = spada_config.cache_latency;
         let sb_size = 4;
         let pb_size = 8;
         let pop_num_per_lane = 2;
         let sn_latency = 4;
         let mt_latency = 4;
         let tree_width = 8;
-        let word_cycle_chan_bw = bandwidth_per_channel / freq / word_byte as f32;
+        let word_cycle_chan_bw =
+            spada_config.bandwidth_per_channel / spada_config.freq / spada_config.word_byte as f32;
+        let (c_row_num, dense_output, csc_psums) =
+            Self::bind_outputs(spada_config, a_matrix, b_matrix, psum_matrix, &accelerator);
         Simulator {
-            scheduler: Scheduler::new(
-                pe_num,
-                lane_num,
-                default_block_shape,
+            scheduler: Self::new_scheduler(
+                spada_config,
                 output_base_addr,
+                default_block_shape,
                 a_matrix,
                 b_matrix,
-                var_factor,
                 accelerator,
-                mem_latency,
-                cache_latency,
+                block_strategy,
+                seed,
             ),
             pe_num,
             adder_tree_num,
             lane_num,
             fiber_cache: LatencyPriorityCache::new(
-                cache_size,
-                word_byte,
+                spada_config.cache_size,
+                spada_config.word_byte,
                 output_base_addr,
                 b_matrix,
                 psum_matrix,
                 mem_latency,
                 cache_latency,
+                spada_config.set_associative,
+                spada_config.line_size,
+                spada_config.write_back,
+                spada_config.prefetch,
+                spada_config.infinite_cache,
+                spada_config.replacement_policy,
             ),
+            csc_psums,
+            dense_output,
+            l1_caches: if spada_config.l1_cache_size > 0 {
+                (0..pe_num)
+                    .map(|_| L1Cache::new(spada_config.l1_cache_size, spada_config.word_byte))
+                    .collect_vec()
+            } else {
+                vec![]
+            },
+            broadcast_buffers: HashMap::new(),
+            broadcast_hit_count: 0,
+            psum_banks: if spada_config.psum_banks > 0 {
+                Some(PsumBanks::new(spada_config.psum_banks))
+            } else {
+                None
+            },
+            dram_channels: Self::new_dram_channels(spada_config, seed),
             pes: (0..pe_num)
                 .map(|pe_idx| {
                     PE::new(
@@ -489,26 +706,275 @@ impl<'a> Simulator<'a> {
                         pop_num_per_lane,
                         sn_latency,
                         mt_latency,
+                        spada_config.adder_fan_in,
+                        spada_config.adder_stage_latency,
                     )
                 })
-                .collect::<Vec<PE>>(),
+                .collect::<Vec<PE<T>>>(),
             adder_trees: (0..adder_tree_num)
                 .map(|idx| AdderTree::new(idx, tree_width))
                 .collect_vec(),
+            adder_tree_busy: vec![0; adder_tree_num],
             a_matrix,
+            c_row_num,
             exec_cycle: 0,
             a_pending_cycle: vec![0; pe_num],
-            channel,
+            channel: spada_config.channel,
             word_cycle_chan_bw,
             drain_cycles: vec![0; pe_num],
+            drain_report: DrainReport::default(),
+            result_writeback_words: 0,
+            final_merge_add_count: 0,
             mult_util: vec![0.0; pe_num],
             active_cycle: vec![0; pe_num],
+            pe_stats: vec![PeStats::default(); pe_num],
+            energy_config: spada_config.energy.clone(),
+            max_cycles: None,
+            truncated: false,
+            trace_out: None,
+            pe_log: None,
+            occupancy_out: None,
+            channel_util_out: None,
+            channel_epoch: 0,
+            channel_epoch_start: 0,
+            peak_occupancy: [0; 3],
+            debug_checks: false,
+            stable_reduce: false,
+            stable_products: HashMap::new(),
+            byte_cycle_bw: if spada_config.bandwidth_limit {
+                Some(
+                    spada_config.bandwidth_per_channel * spada_config.channel as f32
+                        / spada_config.freq,
+                )
+            } else {
+                None
+            },
+            bandwidth_backlog: 0.0,
+            bandwidth_stall_cycles: 0,
+            macs_per_cycle: spada_config.macs_per_cycle,
+            shared_operand: false,
+            warm_b_fibers: None,
+            pinned_fibers: None,
+            pin_budget: spada_config.pin_budget,
+            progress_callback: None,
+        }
+    }
+
+    // Rebind the simulator to the DRAM storages of another GEMM and clear every state and counter,
+    // as if it was newly built. The PEs and the cache keep their allocations, so the config must be
+    // the one the simulator was built with.
+    pub fn reset(
+        &mut self,
+        spada_config: &OmegaConfig,
+        output_base_addr: usize,
+        default_block_shape: [usize; 2],
+        a_matrix: CsrMatStorage<T>,
+        b_matrix: CsrMatStorage<T>,
+        psum_matrix: VectorStorage<T>,
+        accelerator: Accelerator,
+        block_strategy: BlockStrategy,
+        seed: u64,
+    ) {
+        assert!(
+            self.pe_num == spada_config.pe_num
+                && self.lane_num == spada_config.mult_lane_num()
+                && self.fiber_cache.cache_size == spada_config.cache_size,
+            "Reset with a different PE, lane or cache size"
+        );
+        // The new storages are moved in behind the references.
+        *self.a_matrix = a_matrix;
+        *self.fiber_cache.b_mem = b_matrix;
+        *self.fiber_cache.psum_mem = psum_matrix;
+        let (c_row_num, dense_output, csc_psums) = Self::bind_outputs(
+            spada_config,
+            self.a_matrix,
+            self.fiber_cache.b_mem,
+            self.fiber_cache.psum_mem,
+            &accelerator,
+        );
+        self.c_row_num = c_row_num;
+        self.dense_output = dense_output;
+        self.csc_psums = csc_psums;
+        self.scheduler = Self::new_scheduler(
+            spada_config,
+            output_base_addr,
+            default_block_shape,
+            self.a_matrix,
+            self.fiber_cache.b_mem,
+            accelerator,
+            block_strategy,
+            seed,
+        );
+        self.fiber_cache.reset(output_base_addr);
+        self.l1_caches.iter_mut().for_each(|l1| l1.reset());
+        self.broadcast_buffers.clear();
+        self.broadcast_hit_count = 0;
+        self.dram_channels = Self::new_dram_channels(spada_config, seed);
+        self.psum_banks = if spada_config.psum_banks > 0 {
+            Some(PsumBanks::new(spada_config.psum_banks))
+        } else {
+            None
+        };
+        self.pes.iter_mut().for_each(|pe| pe.reset());
+        self.adder_trees
+            .iter_mut()
+            .for_each(|at| *at = AdderTree::new(at.pe_idx, at.tree_width));
+        self.adder_tree_busy.fill(0);
+        self.exec_cycle = 0;
+        self.a_pending_cycle.fill(0);
+        self.drain_cycles.fill(0);
+        self.drain_report = DrainReport::default();
+        self.result_writeback_words = 0;
+        self.final_merge_add_count = 0;
+        self.mult_util.fill(0.0);
+        self.active_cycle.fill(0);
+        self.pe_stats.fill(PeStats::default());
+        self.max_cycles = None;
+        self.truncated = false;
+        self.trace_out = None;
+        self.pe_log = None;
+        self.occupancy_out = None;
+        self.channel_util_out = None;
+        self.channel_epoch = 0;
+        self.channel_epoch_start = 0;
+        self.peak_occupancy = [0; 3];
+        self.debug_checks = false;
+        self.stable_reduce = false;
+        self.stable_products.clear();
+        self.bandwidth_backlog = 0.0;
+        self.bandwidth_stall_cycles = 0;
+        self.shared_operand = false;
+        self.warm_b_fibers = None;
+        self.pinned_fibers = None;
+        self.progress_callback = None;
+    }
+
+    // Transpose A for the outer product and pick where the psums are accumulated in place.
+    fn bind_outputs(
+        spada_config: &OmegaConfig,
+        a_matrix: &mut CsrMatStorage<T>,
+        b_matrix: &CsrMatStorage<T>,
+        psum_matrix: &VectorStorage<T>,
+        accelerator: &Accelerator,
+    ) -> (
+        usize,
+        Option<DenseOutputStorage<T>>,
+        Option<CscPsumStorage<T>>,
+    ) {
+        // The outer product walks the columns of A.
+        let c_row_num = a_matrix.row_num();
+        if *accelerator == Accelerator::Op {
+            a_matrix.transpose();
+        }
+        let dense_output = if b_matrix.mat_shape[0] <= spada_config.spmv_threshold {
+            Some(DenseOutputStorage::new(
+                c_row_num,
+                b_matrix.mat_shape[0],
+                psum_matrix.word_byte,
+                psum_matrix.index_byte,
+            ))
+        } else {
+            None
+        };
+        let csc_psums = match accelerator {
+            Accelerator::Op if dense_output.is_none() => Some(CscPsumStorage::new(
+                psum_matrix.word_byte,
+                psum_matrix.index_byte,
+            )),
+            _ => None,
+        };
+        (c_row_num, dense_output, csc_psums)
+    }
+
+    fn new_scheduler(
+        spada_config: &OmegaConfig,
+        output_base_addr: usize,
+        default_block_shape: [usize; 2],
+        a_matrix: &CsrMatStorage<T>,
+        b_matrix: &CsrMatStorage<T>,
+        accelerator: Accelerator,
+        block_strategy: BlockStrategy,
+        seed: u64,
+    ) -> Scheduler<T> {
+        let var_factor = 1.5;
+        let cache_capability = if spada_config.infinite_cache {
+            usize::MAX
+        } else {
+            spada_config.cache_size / spada_config.word_byte
+        };
+        Scheduler::new(
+            spada_config.pe_num,
+            spada_config.mult_lane_num(),
+            default_block_shape,
+            output_base_addr,
+            a_matrix,
+            b_matrix,
+            var_factor,
+            spada_config.group_split,
+            spada_config.resample_threshold,
+            accelerator,
+            spada_config.mem_latency.mean(),
+            spada_config.cache_latency,
+            cache_capability,
+            spada_config.merge_radix,
+            spada_config.merge_policy,
+            spada_config.merge_watermark,
+            spada_config.merge_order,
+            spada_config.at_num == 0,
+            block_strategy,
+            spada_config.min_block_width,
+            spada_config.min_window_width,
+            spada_config.psum_addr_space,
+            seed,
+        )
+    }
+
+    fn new_dram_channels(spada_config: &OmegaConfig, seed: u64) -> DramChannels {
+        DramChannels::new(
+            spada_config.channel,
+            spada_config.mem_latency,
+            seed,
+            spada_config.channel_conflict,
+            spada_config.bank_num,
+            spada_config.row_buffer_fibers,
+            spada_config.cache_latency,
+            spada_config.precharge_latency,
+        )
+    }
+
+    pub fn set_progress_callback(&mut self, interval: usize, callback: ProgressCallback) {
+        self.progress_callback = Some((max(interval, 1), callback));
+    }
+
+    fn report_progress(&mut self) {
+        let a_row_num = self.a_matrix.row_num();
+        let a_rows_traversed = if self.scheduler.a_traversed {
+            a_row_num
+        } else if self.scheduler.row_s == usize::MAX {
+            0
+        } else {
+            min(self.scheduler.row_s, a_row_num)
+        };
+        let info = ProgressInfo {
+            cycle: self.exec_cycle,
+            a_rows_traversed,
+            a_row_num,
+            cache_occupancy: self.fiber_cache.cur_num,
+            cache_capability: self.fiber_cache.capability,
+        };
+        if let Some((_, callback)) = self.progress_callback.as_mut() {
+            callback(info);
         }
     }
 
     pub fn execute(&mut self) {
         // Reset the execution round counter.
         self.exec_cycle = 0;
+        self.resume();
+    }
+
+    // Run from the current cycle, e.g. after loading a checkpoint.
+    pub fn resume(&mut self) {
         loop {
             trace_println!("\n---- cycle {}", self.exec_cycle);
 
@@ -522,8 +988,47 @@ impl<'a> Simulator<'a> {
             let mut prev_cache_rs = vec![0; self.pe_num];
             let mut prev_cache_ws = vec![0; self.pe_num];
 
+            // Stall the whole cycle while the queued transfers exceed the bandwidth.
+            if let Some(byte_cycle_bw) = self.byte_cycle_bw {
+                self.bandwidth_backlog = (self.bandwidth_backlog - byte_cycle_bw).max(0.0);
+                if self.bandwidth_backlog > 0.0 {
+                    trace_println!("bandwidth stall, backlog: {}", self.bandwidth_backlog);
+                    self.bandwidth_stall_cycles += 1;
+                    // Only the PEs holding a task wait on the transfers.
+                    for (pe, pe_stats) in self.pes.iter().zip(self.pe_stats.iter_mut()) {
+                        if pe.task.is_some() {
+                            pe_stats.stall_cycle += 1;
+                        } else {
+                            pe_stats.idle_cycle += 1;
+                        }
+                    }
+                    if self.scheduler.a_traversed {
+                        self.drain_report.stall_cycles += 1;
+                        self.record_drain_cycle();
+                    }
+                    self.log_pes();
+                    self.exec_cycle += 1;
+                    if self.max_cycles.map_or(false, |c| self.exec_cycle >= c) {
+                        self.truncated = true;
+                        break;
+                    }
+                    continue;
+                }
+            }
+            let prev_offchip_bytes = self.get_offchip_bytes();
+            let prev_writes = [
+                self.fiber_cache.psum_mem.write_count,
+                self.result_writeback_words,
+            ];
+
             trace_println!("Psum in memory:");
             self.fiber_cache.print_psums();
+            let prev_cache_events = [
+                self.fiber_cache.read_count,
+                self.fiber_cache.write_count,
+                self.fiber_cache.miss_count,
+                self.fiber_cache.psum_mem.write_count,
+            ];
 
             // Fetch data stage.
             for pe_idx in 0..self.pe_num {
@@ -531,6 +1036,7 @@ impl<'a> Simulator<'a> {
                 // Pending when access a or b matrix.
                 if self.a_pending_cycle[pe_idx] > 0 {
                     self.a_pending_cycle[pe_idx] -= 1;
+                    self.pe_stats[pe_idx].stall_cycle += 1;
                     continue;
                 }
                 // Collect prev exec stats.
@@ -634,10 +1140,13 @@ impl<'a> Simulator<'a> {
                     if self.pes[pe_idx].mem_finish_cycle.is_some()
                         && *self.pes[pe_idx].mem_finish_cycle.as_ref().unwrap() > self.exec_cycle
                     {
+                        self.pe_stats[pe_idx].stall_cycle += 1;
                         continue;
                     }
                     // Collect output psums.
                     if self.pes[pe_idx].task.is_some() {
+                        let event = task_event("finish", "pe", pe_idx, &self.pes[pe_idx].task);
+                        self.trace_event(event);
                         let prev_win_token = self.pes[pe_idx].task.as_ref().unwrap().window_token;
                         for arow_addr in self.scheduler.window_tracker[&prev_win_token]
                             .arow_addr_pairs
@@ -659,6 +1168,13 @@ impl<'a> Simulator<'a> {
                                     .or_insert(vec![arow_addr[1]]);
                             }
                         }
+                        let merge_mode = self.pes[pe_idx].task.as_ref().unwrap().merge_mode;
+                        for addr in self
+                            .scheduler
+                            .release_task_addrs(prev_win_token, merge_mode)
+                        {
+                            self.fiber_cache.purge_psum(addr);
+                        }
                     }
                     // Collect stats of the prev finished task.
                     if self.pes[pe_idx].task.is_some()
@@ -668,6 +1184,7 @@ impl<'a> Simulator<'a> {
                         if self.scheduler.is_block_finished(prev_blk_tk) {
                             // Label finished rows.
                             self.scheduler.label_finished_rows(prev_blk_tk);
+                            self.broadcast_buffers.remove(&prev_blk_tk);
                             match self.scheduler.accelerator {
                                 Accelerator::Spada => {
                                     // Update the rowwise adjust tracker.
@@ -689,10 +1206,18 @@ impl<'a> Simulator<'a> {
                     let task = self.scheduler.assign_task(
                         &mut self.pes[pe_idx],
                         &mut self.a_matrix,
+                        &self.fiber_cache,
                         self.exec_cycle,
                     );
                     let latency = self.pes[pe_idx].set_task(task);
                     self.a_pending_cycle[pe_idx] += latency;
+                    if self.pes[pe_idx].task.is_some() {
+                        let event = task_event("assign", "pe", pe_idx, &self.pes[pe_idx].task);
+                        self.trace_event(event);
+                        if self.shared_operand {
+                            self.share_streamed_rows(pe_idx);
+                        }
+                    }
                 }
                 if self.pes[pe_idx].task.is_some() {
                     let block_token = self.pes[pe_idx].task.as_ref().unwrap().block_token;
@@ -721,6 +1246,8 @@ impl<'a> Simulator<'a> {
                         }
                     }
                 } else {
+                    // PEs left without work after A is traversed are idle rather than stalled.
+                    self.pe_stats[pe_idx].idle_cycle += 1;
                     continue;
                 }
 
@@ -735,43 +1262,88 @@ impl<'a> Simulator<'a> {
                     self.pes[pe_idx].push_stream_buffer(lane_idx, bs);
                 }
 
-                // Production phase.
+                // Production phase. With a MAC throughput set, the MACs of the lanes without a
+                // row serve the busy ones, so a sparse window issues several products per lane
+                // in a cycle.
                 let group_size = self.pes[pe_idx].task.as_ref().unwrap().group_size;
-                let mut bs = vec![];
-                for lane_idx in 0..self.lane_num {
-                    // Update full flag.
-                    if self.pes[pe_idx].psum_buffers[lane_idx].len()
-                        >= self.pes[pe_idx].psum_buffer_size - 1
-                    {
-                        self.pes[pe_idx].full_flags[lane_idx] = true;
-                    } else {
-                        self.pes[pe_idx].full_flags[lane_idx] = false;
+                let merge_mode = self.pes[pe_idx].task.as_ref().unwrap().merge_mode;
+                let issue_num = match self.macs_per_cycle {
+                    Some(macs) if !merge_mode => {
+                        let busy_lanes = (0..self.lane_num)
+                            .filter(|l| !self.pes[pe_idx].multiplier_array.is_empty(*l))
+                            .count();
+                        max(1, macs / max(1, busy_lanes))
                     }
-                    // Pop from stream buffer.
-                    let b = if self.pes[pe_idx].multiplier_array.a_eles[lane_idx].is_some() {
-                        self.pes[pe_idx].pop_stream_buffer(lane_idx)
-                    } else {
-                        None
-                    };
-                    bs.push(b);
-                }
-                // Set bs to multiplier array.
-                let prods = self.pes[pe_idx].multiplier_array.retrieve_cs();
-                self.pes[pe_idx].multiplier_array.set_bs(bs);
-                self.pes[pe_idx].multiplier_array.multiply(group_size);
+                    _ => 1,
+                };
                 let mut mult_in_use = 0;
-                for (lane_idx, prod) in prods.into_iter().enumerate() {
-                    if prod.is_some() {
-                        mult_in_use += 1;
-                        self.pes[pe_idx].push_psum_buffer(lane_idx, prod.unwrap());
+                for issue_idx in 0..issue_num {
+                    // The extra issues stop once a busy lane is down to its last buffered
+                    // element, the merged lanes of a pair pop in order only while both see
+                    // their next element.
+                    if issue_idx > 0
+                        && (0..self.lane_num).any(|l| {
+                            let sb = &self.pes[pe_idx].stream_buffers[l];
+                            !self.pes[pe_idx].multiplier_array.is_empty(l)
+                                && sb.len() < 2
+                                && sb.front().map_or(true, |e| e.idx[0] != usize::MAX)
+                        })
+                    {
+                        break;
+                    }
+                    let mut bs = vec![];
+                    for lane_idx in 0..self.lane_num {
+                        // Update full flag.
+                        if self.pes[pe_idx].psum_buffers[lane_idx].len()
+                            >= self.pes[pe_idx].psum_buffer_size - 1
+                        {
+                            self.pes[pe_idx].full_flags[lane_idx] = true;
+                        } else {
+                            self.pes[pe_idx].full_flags[lane_idx] = false;
+                        }
+                        // Pop from stream buffer.
+                        let b = if self.pes[pe_idx].multiplier_array.a_eles[lane_idx].is_some() {
+                            self.pes[pe_idx].pop_stream_buffer(lane_idx)
+                        } else {
+                            None
+                        };
+                        bs.push(b);
+                    }
+                    // Set bs to multiplier array.
+                    let prods = self.pes[pe_idx].multiplier_array.retrieve_cs();
+                    self.pes[pe_idx].multiplier_array.set_bs(bs);
+                    self.pes[pe_idx].multiplier_array.multiply(group_size);
+                    if self.stable_reduce && !merge_mode {
+                        self.log_lane_products(pe_idx, group_size);
+                    }
+                    // Merge tasks only pass the psums through the multipliers.
+                    if !merge_mode {
+                        self.pes[pe_idx].mac_count += self.pes[pe_idx]
+                            .multiplier_array
+                            .c_eles
+                            .iter()
+                            .filter(|c| c.is_some())
+                            .count();
+                    }
+                    for (lane_idx, prod) in prods.into_iter().enumerate() {
+                        if prod.is_some() {
+                            mult_in_use += 1;
+                            self.pes[pe_idx].push_psum_buffer(lane_idx, prod.unwrap());
+                        }
                     }
                 }
-                let mult_util = mult_in_use as f32 / self.pes[pe_idx].lane_num as f32;
+                let mult_util =
+                    mult_in_use as f32 / self.macs_per_cycle.unwrap_or(self.lane_num) as f32;
                 if !self.pes[pe_idx].idle() && !self.pes[pe_idx].task.as_ref().unwrap().merge_mode {
                     self.mult_util[pe_idx] =
                         (self.mult_util[pe_idx] * self.active_cycle[pe_idx] as f32 + mult_util)
                             / (self.active_cycle[pe_idx] + 1) as f32;
                     self.active_cycle[pe_idx] += 1;
+                    self.pe_stats[pe_idx].compute_cycle += 1;
+                } else if !self.pes[pe_idx].idle() {
+                    self.pe_stats[pe_idx].merge_cycle += 1;
+                } else {
+                    self.pe_stats[pe_idx].stall_cycle += 1;
                 }
 
                 // Collect psum phase.
@@ -837,6 +1409,45 @@ impl<'a> Simulator<'a> {
 
             for idx in 0..self.adder_tree_num {
                 self.adder_tree_exec(idx);
+                if self.adder_trees[idx].task.is_some() {
+                    self.adder_tree_busy[idx] += 1;
+                }
+            }
+            self.log_pes();
+            self.record_occupancy();
+            if self.channel_epoch > 0
+                && self.exec_cycle + 1 - self.channel_epoch_start >= self.channel_epoch
+            {
+                self.log_channel_epoch();
+            }
+            if self.debug_checks {
+                if let Err(err) = self.check_psum_trackers() {
+                    panic!("Debug check failed at cycle {}: {}", self.exec_cycle, err);
+                }
+            }
+            if self.byte_cycle_bw.is_some() {
+                self.bandwidth_backlog += (self.get_offchip_bytes() - prev_offchip_bytes) as f32;
+            }
+
+            if self.trace_out.is_some() {
+                let [read, write, miss, psum_write] = prev_cache_events;
+                let cache_delta = [
+                    self.fiber_cache.read_count - read,
+                    self.fiber_cache.write_count - write,
+                    self.fiber_cache.miss_count - miss,
+                ];
+                if cache_delta.iter().any(|d| *d > 0) {
+                    self.trace_event(json!({
+                        "event": "cache",
+                        "read": cache_delta[0],
+                        "write": cache_delta[1],
+                        "miss": cache_delta[2],
+                    }));
+                }
+                let psum_delta = self.fiber_cache.psum_mem.write_count - psum_write;
+                if psum_delta > 0 {
+                    self.trace_event(json!({"event": "writeback", "words": psum_delta}));
+                }
             }
 
             if self.scheduler.a_traversed
@@ -846,8 +1457,13 @@ impl<'a> Simulator<'a> {
                     .iter()
                     .all(|a| a.idle() && a.task.is_none())
             {
+                self.final_merge();
                 break;
             }
+            if self.scheduler.a_traversed {
+                self.classify_drain_cycle(prev_writes);
+                self.record_drain_cycle();
+            }
 
             trace_println!(
                 "Cache read_count: + {} -> {}, write_count: + {} -> {}",
@@ -886,6 +1502,298 @@ impl<'a> Simulator<'a> {
             );
 
             self.exec_cycle += 1;
+            if self.max_cycles.map_or(false, |c| self.exec_cycle >= c) {
+                self.truncated = true;
+                break;
+            }
+            if let Some((interval, _)) = self.progress_callback.as_ref() {
+                if self.exec_cycle % interval == 0 {
+                    self.report_progress();
+                }
+            }
+        }
+        if self.progress_callback.is_some() {
+            self.report_progress();
+        }
+        if let Some(trace_out) = self.trace_out.as_mut() {
+            trace_out.flush().unwrap();
+        }
+        if let Some((_, pe_log)) = self.pe_log.as_mut() {
+            pe_log.flush().unwrap();
+        }
+        if let Some((_, occupancy_out)) = self.occupancy_out.as_mut() {
+            occupancy_out.flush().unwrap();
+        }
+        // The last epoch is cut short by the end of the run.
+        if self.channel_epoch > 0
+            && (self.channel_epoch_start < self.exec_cycle
+                || self.dram_channels.epoch_bytes.iter().any(|b| *b > 0))
+        {
+            self.log_channel_epoch();
+        }
+        if let Some((_, channel_util_out)) = self.channel_util_out.as_mut() {
+            channel_util_out.flush().unwrap();
+        }
+    }
+
+    // Install the A rows whose tail the new window streams in as the B fibers of the same rows.
+    fn share_streamed_rows(&mut self, pe_idx: usize) {
+        let task = self.pes[pe_idx].task.as_ref().unwrap();
+        // The outer product streams the columns of A, which are not B fibers.
+        if task.merge_mode || self.scheduler.accelerator == Accelerator::Op {
+            return;
+        }
+        let window_tracker = &self.scheduler.window_tracker[&task.window_token];
+        let [row_s, col_s] = window_tracker.anchor;
+        let [row_num, col_num] = window_tracker.shape;
+        for rowid in row_s..min(row_s + row_num, self.a_matrix.row_num()) {
+            let row_len = self.a_matrix.get_ele_num(rowid, rowid + 1);
+            if col_s < row_len && col_s + col_num >= row_len {
+                let csrrow = self.a_matrix.peek_row(rowid);
+                let addr = csrrow.rowptr;
+                self.fiber_cache.install_row(csrrow, [rowid, addr]);
+            }
+        }
+    }
+
+    // Append a row per PE and adder tree with its task, the tokens are left empty while the unit
+    // has none. The merge blocks only run on the adder trees.
+    fn log_pes(&mut self) {
+        let exec_cycle = self.exec_cycle;
+        if let Some((workload, pe_log)) = self.pe_log.as_mut() {
+            let units = self.pes.iter().map(|pe| ("pe", pe.pe_idx, &pe.task)).chain(
+                self.adder_trees
+                    .iter()
+                    .enumerate()
+                    .map(|(idx, at)| ("adder_tree", idx, &at.task)),
+            );
+            for (unit, idx, task) in units {
+                match task.as_ref() {
+                    Some(task) => writeln!(
+                        pe_log,
+                        "{},{},{},{},{},{},{},{}",
+                        workload,
+                        exec_cycle,
+                        unit,
+                        idx,
+                        task.block_token,
+                        task.window_token,
+                        task.merge_mode,
+                        task.a_eles.iter().filter(|e| e.is_some()).count()
+                    ),
+                    None => writeln!(pe_log, "{},{},{},{},,,,0", workload, exec_cycle, unit, idx),
+                }
+                .unwrap();
+            }
+        }
+    }
+
+    // Update the occupancy peaks with the cache state at the end of the cycle, and append it to the
+    // occupancy CSV.
+    fn record_occupancy(&mut self) {
+        let occupancy = [
+            self.fiber_cache.cur_num,
+            self.fiber_cache.b_occp,
+            self.fiber_cache.psum_occp,
+        ];
+        for (peak, occp) in self.peak_occupancy.iter_mut().zip(occupancy.iter()) {
+            *peak = max(*peak, *occp);
+        }
+        if let Some((workload, occupancy_out)) = self.occupancy_out.as_mut() {
+            writeln!(
+                occupancy_out,
+                "{},{},{},{},{},{}",
+                workload,
+                self.exec_cycle,
+                occupancy[0],
+                occupancy[1],
+                occupancy[2],
+                self.fiber_cache.capability
+            )
+            .unwrap();
+        }
+    }
+
+    // Append the bytes of every channel in the current epoch, and start the next one.
+    fn log_channel_epoch(&mut self) {
+        let epoch_bytes = self.dram_channels.take_epoch_bytes();
+        if let Some((workload, channel_util_out)) = self.channel_util_out.as_mut() {
+            for (channel, bytes) in epoch_bytes.iter().enumerate() {
+                writeln!(
+                    channel_util_out,
+                    "{},{},{},{}",
+                    workload, self.channel_epoch_start, channel, bytes
+                )
+                .unwrap();
+            }
+        }
+        self.channel_epoch_start = self.exec_cycle + 1;
+    }
+
+    // The psum bookkeeping invariants: the rows of the merge tasks in flight are still tracked,
+    // the finished rows have no psum or task left, and no psum addr belongs to two rows.
+    pub fn check_psum_trackers(&self) -> Result<(), String> {
+        let output_tracker = &self.scheduler.output_tracker;
+        let row_rgstr_task = &self.scheduler.row_rgstr_task;
+        let a_row_finished = &self.scheduler.a_row_finished;
+        let row_states = |row: usize| {
+            format!(
+                "row {}: output_tracker {:?}, registered tasks {:?}, finished {:?}",
+                row,
+                output_tracker.get(&row),
+                row_rgstr_task.get(&row),
+                a_row_finished.get(&row)
+            )
+        };
+        let merge_tasks = self
+            .pes
+            .iter()
+            .map(|pe| &pe.task)
+            .chain(self.adder_trees.iter().map(|at| &at.task))
+            .filter_map(|task| task.as_ref().filter(|t| t.merge_mode));
+        let mut owners: HashMap<usize, usize> = HashMap::new();
+        for task in merge_tasks {
+            for [row, addr] in task.a_eles.iter().flatten().map(|e| e.idx) {
+                if !output_tracker.contains_key(&row) {
+                    return Err(format!(
+                        "merge task {} is not tracked, {}",
+                        task.window_token,
+                        row_states(row)
+                    ));
+                }
+                if let Some(owner) = owners.insert(addr, row).filter(|o| *o != row) {
+                    return Err(format!(
+                        "psum {} is merged for two rows, {}; {}",
+                        addr,
+                        row_states(owner),
+                        row_states(row)
+                    ));
+                }
+            }
+        }
+        let mut rows = output_tracker.keys().cloned().collect::<Vec<usize>>();
+        rows.sort_unstable();
+        for row in rows {
+            if a_row_finished.contains_key(&row) {
+                return Err(format!("finished with psums left, {}", row_states(row)));
+            }
+            for addr in output_tracker[&row].iter() {
+                if let Some(owner) = owners.insert(*addr, row) {
+                    return Err(format!(
+                        "psum {} is referenced twice, {}; {}",
+                        addr,
+                        row_states(owner),
+                        row_states(row)
+                    ));
+                }
+            }
+        }
+        let mut finished = a_row_finished.iter().collect::<Vec<_>>();
+        finished.sort_unstable();
+        for (row, addr) in finished {
+            if row_rgstr_task.get(row).map_or(false, |r| *r > 0) {
+                return Err(format!("finished with tasks left, {}", row_states(*row)));
+            }
+            if let Some(owner) = owners.insert(*addr, *row) {
+                return Err(format!(
+                    "psum {} is referenced twice, {}; {}",
+                    addr,
+                    row_states(owner),
+                    row_states(*row)
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // Append an event to the trace, tagged with the current cycle.
+    fn trace_event(&mut self, mut event: Value) {
+        let exec_cycle = self.exec_cycle;
+        if let Some(trace_out) = self.trace_out.as_mut() {
+            event["cycle"] = json!(exec_cycle);
+            writeln!(trace_out, "{}", event).unwrap();
+        }
+    }
+
+    // Count a drain cycle under the work that kept it busy: merging, flushing psums to memory,
+    // writing back finished rows, or still multiplying.
+    fn classify_drain_cycle(&mut self, prev_writes: [usize; 2]) {
+        let writeback = self.result_writeback_words - prev_writes[1];
+        let flush =
+            (self.fiber_cache.psum_mem.write_count - prev_writes[0]).saturating_sub(writeback);
+        let merging = self
+            .pes
+            .iter()
+            .any(|p| p.task.as_ref().map_or(false, |t| t.merge_mode))
+            || self.adder_trees.iter().any(|a| a.task.is_some());
+        if merging {
+            self.drain_report.merge_cycles += 1;
+        } else if flush > 0 {
+            self.drain_report.flush_cycles += 1;
+        } else if writeback > 0 {
+            self.drain_report.writeback_cycles += 1;
+        } else {
+            self.drain_report.multiply_cycles += 1;
+        }
+    }
+
+    fn record_drain_cycle(&mut self) {
+        self.drain_report.cycles += 1;
+        self.drain_report.active_pe_cycles += self.pes.iter().filter(|p| p.task.is_some()).count();
+    }
+
+    // Merge the psums left after the last task, so that every row ends with a single psum in
+    // psum memory. The PEs merge the residual rows in parallel, each taking lane_num psum elements
+    // a cycle, and the cycles are charged to the run and its drain phase.
+    pub fn final_merge(&mut self) {
+        let mut merged_len = 0;
+        let mut rows = self
+            .scheduler
+            .output_tracker
+            .keys()
+            .cloned()
+            .collect::<Vec<usize>>();
+        rows.sort_unstable();
+        for row in rows {
+            let addrs = self.scheduler.output_tracker.remove(&row).unwrap();
+            let addr = addrs[0];
+            if addrs.len() > 1 {
+                trace_println!("final merge: row {} psums {:?}", row, &addrs);
+                let mut merged: BTreeMap<usize, T> = BTreeMap::new();
+                let mut row_len = 0;
+                for psum_addr in addrs.iter() {
+                    let csrrow = self.fiber_cache.take_psum(*psum_addr);
+                    row_len += csrrow.len();
+                    for (col, value) in csrrow.indptr.iter().zip(csrrow.data.iter()) {
+                        let psum = merged.entry(*col).or_insert(T::zero());
+                        *psum = psum.plus(*value);
+                    }
+                }
+                merged_len += row_len;
+                self.final_merge_add_count += row_len - merged.len();
+                let csrrow = CsrRow::new_from_data(
+                    addr,
+                    merged.values().cloned().collect(),
+                    merged.keys().cloned().collect(),
+                );
+                self.fiber_cache.psum_mem.write(&mut vec![csrrow]).unwrap();
+            } else if self.fiber_cache.rowmap.contains_key(&addr) {
+                self.fiber_cache.swapout(addr);
+            }
+            for psum_addr in addrs[1..].iter() {
+                self.scheduler.psum_addrs.release(*psum_addr);
+            }
+            self.scheduler.psum_addrs.finish();
+            self.scheduler.a_tail_produced.remove(&row);
+            self.scheduler.a_row_finished.insert(row, addr);
+        }
+        let merge_width = self.pe_num * self.lane_num;
+        let cycles = (merged_len + merge_width - 1) / merge_width;
+        self.exec_cycle += cycles;
+        self.drain_report.cycles += cycles;
+        self.drain_report.merge_cycles += cycles;
+        for pe_stats in self.pe_stats.iter_mut() {
+            pe_stats.merge_cycle += cycles;
         }
     }
 
@@ -895,7 +1803,7 @@ impl<'a> Simulator<'a> {
         lane_idx: usize,
         rb_num: usize,
         cur_cycle: usize,
-    ) -> Option<Vec<Element>> {
+    ) -> Option<Vec<Element<T>>> {
         if self.pes[pe_idx].task.is_none() {
             return None;
         }
@@ -913,11 +1821,44 @@ impl<'a> Simulator<'a> {
 
         let scalar_idx = scalar_idx.unwrap();
         let b_col_idx = window_tracker.b_cols_assigned[lane_idx];
-        if !self.fiber_cache.contains_row(&scalar_idx[1]) && b_col_idx == 0 {
-            task.memory_traffic +=
-                (self.fiber_cache.mem_latency as f32 * self.word_cycle_chan_bw) as usize
+        let merge_mode = task.merge_mode;
+        // Fibers fitting in the private L1 are filled as a whole from the shared cache.
+        let b_row_len = self.scheduler.b_row_lens[&scalar_idx[1]];
+        let l1_served = !merge_mode
+            && self.l1_caches.len() > 0
+            && b_row_len > 0
+            && self.l1_caches[pe_idx].fits(b_row_len * 2);
+        // MultiRow broadcasts a fiber fetched once to every row of the block referencing it, and
+        // Op the B row of a column to every element of the column.
+        let block_token = task.block_token;
+        let broadcast_served = !merge_mode
+            && !l1_served
+            && matches!(
+                self.scheduler.accelerator,
+                Accelerator::MultiRow | Accelerator::Op
+            )
+            && b_row_len > 0;
+        let broadcast_hit = broadcast_served
+            && self
+                .broadcast_buffers
+                .get(&block_token)
+                .map_or(false, |buffer| buffer.contains_key(&scalar_idx[1]));
+        if !self.fiber_cache.contains_row(&scalar_idx[1])
+            && b_col_idx == 0
+            && !(l1_served && self.l1_caches[pe_idx].contains_row(&scalar_idx[1]))
+            && !broadcast_hit
+        {
+            // The DRAM model adds channel conflicts and row buffer effects to the latency.
+            let mem_latency = if rb_num > 0 {
+                let b_mem = &self.fiber_cache.b_mem;
+                let bytes = fiber_bytes(b_row_len, b_mem.word_byte, b_mem.index_byte, true);
+                self.dram_channels.access(scalar_idx[1], bytes, cur_cycle)
+            } else {
+                self.fiber_cache.mem_latency
+            };
+            task.memory_traffic += (mem_latency as f32 * self.word_cycle_chan_bw) as usize
         }
-        let elements = if self.pes[pe_idx].task.as_ref().unwrap().merge_mode {
+        let elements = if merge_mode {
             match self
                 .fiber_cache
                 .request_consume_scalars(scalar_idx, b_col_idx, rb_num, cur_cycle, true)
@@ -926,12 +1867,75 @@ impl<'a> Simulator<'a> {
                     if es.len() == 0 {
                         None
                     } else {
+                        // The psum read waits for its bank.
+                        if let Some(psum_banks) = self.psum_banks.as_mut() {
+                            self.a_pending_cycle[pe_idx] +=
+                                psum_banks.access(scalar_idx[1], cur_cycle);
+                        }
                         window_tracker.b_cols_assigned[lane_idx] += es.len();
                         Some(es)
                     }
                 }
                 None => Some(vec![]), // Pending cycle, not drained.
             }
+        } else if l1_served {
+            // Read 0 elements should not mean the end of the row.
+            if rb_num == 0 {
+                return Some(vec![]);
+            }
+            if !self.l1_caches[pe_idx].contains_row(&scalar_idx[1]) {
+                match self
+                    .fiber_cache
+                    .request_read_scalars(scalar_idx, 0, b_row_len, cur_cycle, true)
+                {
+                    Some(es) => self.l1_caches[pe_idx].fill(
+                        scalar_idx[1],
+                        sorted_element_vec_to_csr_row(es),
+                        cur_cycle,
+                    ),
+                    None => return Some(vec![]), // Pending cycle, not drained.
+                }
+            }
+            let es =
+                self.l1_caches[pe_idx].read_scalars(scalar_idx[1], b_col_idx, rb_num, cur_cycle);
+            if es.len() == 0 {
+                None
+            } else {
+                window_tracker.b_cols_assigned[lane_idx] += es.len();
+                Some(es)
+            }
+        } else if broadcast_served {
+            if rb_num == 0 {
+                return Some(vec![]);
+            }
+            // Count each reuse of a fiber once.
+            if broadcast_hit && b_col_idx == 0 {
+                self.broadcast_hit_count += 1;
+            }
+            if !broadcast_hit {
+                match self
+                    .fiber_cache
+                    .request_read_scalars(scalar_idx, 0, b_row_len, cur_cycle, true)
+                {
+                    Some(es) => {
+                        self.broadcast_buffers
+                            .entry(block_token)
+                            .or_insert_with(HashMap::new)
+                            .insert(scalar_idx[1], sorted_element_vec_to_csr_row(es));
+                    }
+                    None => return Some(vec![]), // Pending cycle, not drained.
+                }
+            }
+            let elements = self.broadcast_buffers[&block_token][&scalar_idx[1]]
+                .clone()
+                .as_element_vec();
+            let col_t = min(b_col_idx + rb_num, elements.len());
+            if b_col_idx >= col_t {
+                None
+            } else {
+                window_tracker.b_cols_assigned[lane_idx] += col_t - b_col_idx;
+                Some(elements[b_col_idx..col_t].to_vec())
+            }
         } else {
             match self
                 .fiber_cache
@@ -952,11 +1956,39 @@ impl<'a> Simulator<'a> {
         return elements;
     }
 
-    pub fn write_psums(&mut self, pe_idx: usize, psums: Vec<Vec<Element>>) {
+    pub fn write_psums(&mut self, pe_idx: usize, psums: Vec<Vec<Element<T>>>) {
         if self.pes[pe_idx].task.is_none() {
             return;
         }
         let task = self.pes[pe_idx].task.as_ref().unwrap();
+        if !task.merge_mode {
+            self.scheduler
+                .block_tracker
+                .get_mut(&task.block_token)
+                .unwrap()
+                .output_fiber_size += psums.iter().map(|ps| ps.len()).sum::<usize>();
+        }
+
+        // Write the partial rows to the dense output or the column-major psums by their A row.
+        if self.dense_output.is_some() || self.csc_psums.is_some() {
+            let arow_addr_pairs =
+                &self.scheduler.window_tracker[&task.window_token].arow_addr_pairs;
+            let mut csrrows = vec![];
+            for (gidx, ps) in psums.into_iter().enumerate() {
+                if ps.len() == 0 {
+                    continue;
+                }
+                let mut csrrow = sorted_element_vec_to_csr_row(ps);
+                csrrow.rowptr = arow_addr_pairs[gidx][0];
+                trace_println!("-write_psum: {:?}", &csrrow);
+                csrrows.push(csrrow);
+            }
+            match self.dense_output.as_mut() {
+                Some(dense_output) => dense_output.write_rows(csrrows),
+                None => self.csc_psums.as_mut().unwrap().write_rows(csrrows),
+            }
+            return;
+        }
 
         // Write psums to cache.
         for (gidx, ps) in psums.into_iter().enumerate() {
@@ -973,6 +2005,10 @@ impl<'a> Simulator<'a> {
             // Assign the output address.
             csrrow.rowptr = arow_addr[1];
             trace_println!("-write_psum: {:?}", &csrrow);
+            // The PE stalls until its psum writes get their banks.
+            if let Some(psum_banks) = self.psum_banks.as_mut() {
+                self.a_pending_cycle[pe_idx] += psum_banks.access(arow_addr[1], self.exec_cycle);
+            }
             self.scheduler
                 .b_row_lens
                 .entry(arow_addr[1])
@@ -985,7 +2021,7 @@ impl<'a> Simulator<'a> {
     pub fn swapout_finished_psums(&mut self) {
         let output_tracker = &mut self.scheduler.output_tracker;
         let row_rgstr_task = &self.scheduler.row_rgstr_task;
-        let swapable_rows = self
+        let mut swapable_rows = self
             .scheduler
             .a_tail_produced
             .drain_filter(|row| {
@@ -993,16 +2029,20 @@ impl<'a> Simulator<'a> {
                     && output_tracker.get(row).map_or(true, |ps| ps.len() == 1)
             })
             .collect::<Vec<usize>>();
+        swapable_rows.sort_unstable();
+        let prev_write_count = self.fiber_cache.psum_mem.write_count;
         for row in swapable_rows {
             if output_tracker.contains_key(&row) {
                 let addr = output_tracker[&row][0];
                 self.scheduler.a_row_finished.insert(row, addr);
+                self.scheduler.psum_addrs.finish();
                 output_tracker.remove(&row);
                 if self.fiber_cache.rowmap.contains_key(&addr) {
                     self.fiber_cache.swapout(addr);
                 }
             }
         }
+        self.result_writeback_words += self.fiber_cache.psum_mem.write_count - prev_write_count;
     }
 
     pub fn get_a_mat_stat(&self) -> [usize; 2] {
@@ -1017,12 +2057,53 @@ impl<'a> Simulator<'a> {
     }
 
     pub fn get_c_mat_stat(&self) -> [usize; 2] {
+        let csc_count = self
+            .csc_psums
+            .as_ref()
+            .map_or([0, 0], |c| [c.read_count, c.write_count]);
+        let dense_count = self.dense_output.as_ref().map_or(0, |d| d.write_count);
         [
-            self.fiber_cache.psum_mem.read_count,
-            self.fiber_cache.psum_mem.write_count,
+            self.fiber_cache.psum_mem.read_count + csc_count[0],
+            self.fiber_cache.psum_mem.write_count + csc_count[1] + dense_count,
         ]
     }
 
+    pub fn get_a_mat_bytes(&self) -> [usize; 2] {
+        [self.a_matrix.read_bytes, self.a_matrix.write_bytes]
+    }
+
+    pub fn get_b_mat_bytes(&self) -> [usize; 2] {
+        [
+            self.fiber_cache.b_mem.read_bytes,
+            self.fiber_cache.b_mem.write_bytes,
+        ]
+    }
+
+    pub fn get_c_mat_bytes(&self) -> [usize; 2] {
+        let csc_bytes = self
+            .csc_psums
+            .as_ref()
+            .map_or([0, 0], |c| [c.read_bytes, c.write_bytes]);
+        let dense_bytes = self.dense_output.as_ref().map_or(0, |d| d.write_bytes);
+        [
+            self.fiber_cache.psum_mem.read_bytes + csc_bytes[0],
+            self.fiber_cache.psum_mem.write_bytes + csc_bytes[1] + dense_bytes,
+        ]
+    }
+
+    pub fn get_offchip_bytes(&self) -> usize {
+        let [a, b, c] = [
+            self.get_a_mat_bytes(),
+            self.get_b_mat_bytes(),
+            self.get_c_mat_bytes(),
+        ];
+        a[0] + a[1] + b[0] + b[1] + c[0] + c[1]
+    }
+
+    pub fn get_drain_report(&self) -> &DrainReport {
+        &self.drain_report
+    }
+
     pub fn get_exec_cycle(&self) -> usize {
         self.exec_cycle - self.drain_cycles.iter().min().unwrap()
     }
@@ -1031,34 +2112,509 @@ impl<'a> Simulator<'a> {
         [self.fiber_cache.read_count, self.fiber_cache.write_count]
     }
 
-    pub fn get_exec_result(&mut self) -> Vec<CsrRow> {
-        let mut c = vec![];
-        for rowid in 0..self.a_matrix.row_num() {
-            let mut csrrow = CsrRow::new(rowid);
-            if self.a_matrix.get_ele_num(rowid, rowid + 1) > 0 {
-                let raw_rowid = if self.a_matrix.remapped {
-                    self.a_matrix.row_remap[&rowid]
-                } else {
-                    rowid
-                };
-                if self.scheduler.a_row_finished.contains_key(&rowid) {
-                    let addr = self.scheduler.a_row_finished.get(&rowid).unwrap();
-                    trace_println!(
-                        "Get result: row: {} row len: {}",
-                        raw_rowid,
-                        self.fiber_cache.psum_mem.data[&addr].size() / 2
-                    );
-                    csrrow = match self.fiber_cache.psum_mem.data.get(&addr) {
-                        Some(row) => row.clone(),
-                        None => self.fiber_cache.rowmap.get(&addr).unwrap().clone(),
-                    };
-                    csrrow.rowptr = raw_rowid;
+    pub fn get_channel_conflict_cycle(&self) -> usize {
+        self.dram_channels.conflict_cycles
+    }
+
+    pub fn get_row_buffer_stat(&self) -> [usize; 2] {
+        [
+            self.dram_channels.row_buffer_hits,
+            self.dram_channels.row_buffer_misses,
+        ]
+    }
+
+    // Min, max and mean latency of the off-chip accesses.
+    pub fn get_mem_latency_stat(&self) -> Option<[f64; 3]> {
+        self.dram_channels.latency_range.map(|[min, max]| {
+            [
+                min as f64,
+                max as f64,
+                self.dram_channels.latency_sum as f64 / self.dram_channels.access_num as f64,
+            ]
+        })
+    }
+
+    pub fn get_l1_stat(&self) -> [usize; 2] {
+        [
+            self.l1_caches.iter().map(|c| c.read_count).sum(),
+            self.l1_caches.iter().map(|c| c.miss_count).sum(),
+        ]
+    }
+
+    pub fn get_prefetch_stat(&self) -> [usize; 2] {
+        [
+            self.fiber_cache.prefetch_count,
+            self.fiber_cache.prefetch_hit_count,
+        ]
+    }
+
+    pub fn get_merge_stat(&self) -> [usize; 3] {
+        [
+            self.scheduler.merge_task_count,
+            self.pe_stats.iter().map(|p| p.merge_cycle).sum(),
+            self.pe_stats.iter().map(|p| p.compute_cycle).sum(),
+        ]
+    }
+
+    pub fn get_stats(&self, workload: &str) -> SimulationStats {
+        SimulationStats {
+            workload: workload.to_string(),
+            accelerator: self.scheduler.accelerator.to_string(),
+            seed: self.scheduler.seed,
+            exec_count: self.get_exec_cycle(),
+            a_count: self.get_a_mat_stat(),
+            b_count: self.get_b_mat_stat(),
+            c_count: self.get_c_mat_stat(),
+            a_bytes: self.get_a_mat_bytes(),
+            b_bytes: self.get_b_mat_bytes(),
+            c_bytes: self.get_c_mat_bytes(),
+            merge_count: self.get_merge_stat(),
+            op_count: self.get_op_stat(),
+            dense_mac_count: self.get_dense_mac_count(),
+            steal_count: self.get_steal_count(),
+            cache_count: self.get_cache_stat(),
+            cache_miss_count: self.get_cache_miss_stat(),
+            prefetch_count: if self.fiber_cache.prefetch {
+                Some(self.get_prefetch_stat())
+            } else {
+                None
+            },
+            channel_conflict_cycles: if self.dram_channels.serialize {
+                Some(self.get_channel_conflict_cycle())
+            } else {
+                None
+            },
+            psum_bank_conflict_cycles: self.psum_banks.as_ref().map(|b| b.conflict_cycles),
+            bandwidth_stall_cycles: self.byte_cycle_bw.map(|_| self.bandwidth_stall_cycles),
+            row_buffer_count: if self.dram_channels.row_fibers > 0 {
+                Some(self.get_row_buffer_stat())
+            } else {
+                None
+            },
+            l1_count: if self.l1_caches.len() > 0 {
+                Some(self.get_l1_stat())
+            } else {
+                None
+            },
+            mem_latency_stat: match self.dram_channels.mem_latency {
+                MemLatency::Fixed(_) => None,
+                _ => self.get_mem_latency_stat(),
+            },
+            line_miss_count: self
+                .fiber_cache
+                .line_words
+                .map(|_| self.fiber_cache.line_miss_count),
+            truncated: self.truncated,
+            reuse: self
+                .fiber_cache
+                .reuse_tracker
+                .as_ref()
+                .map(|tracker| tracker.report(self.fiber_cache.word_byte)),
+            broadcast_hit_count: if self.scheduler.accelerator == Accelerator::MultiRow {
+                Some(self.broadcast_hit_count)
+            } else {
+                None
+            },
+            warm_b_fibers: self.warm_b_fibers,
+            pinned_fibers: self.pinned_fibers,
+            peak_occupancy: Some(self.peak_occupancy),
+            channel_bytes: Some(self.dram_channels.channel_bytes.clone()),
+            adder_tree_busy: if self.adder_tree_num > 0 {
+                Some(self.adder_tree_busy.clone())
+            } else {
+                None
+            },
+            symmetric_storage_bytes: if self.a_matrix.mirror.is_some()
+                || self.fiber_cache.b_mem.mirror.is_some()
+            {
+                let [a, b] = [
+                    self.a_matrix.storage_bytes(),
+                    self.fiber_cache.b_mem.storage_bytes(),
+                ];
+                Some([a[0] + b[0], a[1] + b[1]])
+            } else {
+                None
+            },
+            energy: self.energy_config.as_ref().map(|energy_config| {
+                let [a, b, c] = [
+                    self.get_a_mat_stat(),
+                    self.get_b_mat_stat(),
+                    self.get_c_mat_stat(),
+                ];
+                EnergyStats::new(
+                    energy_config,
+                    [a[0] + b[0] + c[0], a[1] + b[1] + c[1]],
+                    self.get_cache_stat(),
+                    self.get_op_stat(),
+                )
+            }),
+            drain_cycles: self.drain_cycles.clone(),
+            drain: Some(self.drain_report.clone()),
+            psum_addr_count: Some([
+                self.scheduler.psum_addrs.peak_live,
+                self.scheduler.psum_addrs.reused,
+            ]),
+            pe_stats: self.get_pe_stats(),
+        }
+    }
+
+    // Multiplications of the PEs, and merge additions of the PEs and in-cache adder trees. The
+    // adder trees only merge psums, so their multipliers are not counted.
+    pub fn get_op_stat(&self) -> [usize; 2] {
+        let mac_count = self.get_mac_count();
+        let add_count = self
+            .pes
+            .iter()
+            .map(|pe| pe.merge_tree.add_count)
+            .sum::<usize>()
+            + self
+                .adder_trees
+                .iter()
+                .map(|at| at.adder.add_count)
+                .sum::<usize>()
+            + self.final_merge_add_count;
+        [mac_count, add_count]
+    }
+
+    // The scalar times fiber element products issued to the multipliers.
+    pub fn get_mac_count(&self) -> usize {
+        self.pes.iter().map(|pe| pe.mac_count).sum::<usize>()
+    }
+
+    // MACs of the same GEMM with dense operands. The B shape is stored as [cols, rows].
+    pub fn get_dense_mac_count(&self) -> usize {
+        let [b_cols, b_rows] = self.fiber_cache.b_mem.mat_shape;
+        self.c_row_num * b_rows * b_cols
+    }
+
+    pub fn get_pe_stats(&self) -> Vec<PeStats> {
+        self.pe_stats.clone()
+    }
+
+    pub fn get_steal_count(&self) -> usize {
+        self.scheduler.steal_count
+    }
+
+    // Save the full state, including the contents of the A, B and psum memories.
+    pub fn save_state(&self, path: &str) -> Result<(), Box<dyn Error>> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        serde_json::to_writer(&mut writer, self)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    // Load the state saved by `save_state`. The memories stay attached to the same storages,
+    // only their contents are replaced.
+    pub fn load_state(&mut self, path: &str) -> Result<(), Box<dyn Error>> {
+        let mut state: Value = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+        self.fiber_cache.load_state(state["fiber_cache"].take())?;
+        *self.a_matrix = Deserialize::deserialize(state["a_matrix"].take())?;
+        load_state_fields!(
+            self,
+            state,
+            pe_num,
+            adder_tree_num,
+            lane_num,
+            csc_psums,
+            dense_output,
+            l1_caches,
+            broadcast_buffers,
+            broadcast_hit_count,
+            dram_channels,
+            psum_banks,
+            pes,
+            c_row_num,
+            exec_cycle,
+            scheduler,
+            adder_trees,
+            adder_tree_busy,
+            a_pending_cycle,
+            channel,
+            word_cycle_chan_bw,
+            drain_cycles,
+            drain_report,
+            result_writeback_words,
+            final_merge_add_count,
+            mult_util,
+            active_cycle,
+            pe_stats,
+            energy_config,
+            max_cycles,
+            truncated,
+            byte_cycle_bw,
+            bandwidth_backlog,
+            bandwidth_stall_cycles,
+            macs_per_cycle,
+            shared_operand,
+            warm_b_fibers,
+            pinned_fibers,
+            pin_budget,
+            peak_occupancy,
+        );
+        self.stable_products = pair_map::deserialize(state["stable_products"].take())?;
+        Ok(())
+    }
+
+    pub fn set_victim_policy(&mut self, victim_policy: Box<dyn VictimPolicy>) {
+        self.fiber_cache.set_victim_policy(victim_policy);
+    }
+
+    pub fn enable_reuse_analysis(&mut self) {
+        self.fiber_cache.reuse_tracker = Some(ReuseTracker::new());
+    }
+
+    // Start with the most reused B fibers that fit in the cache, or all of B.
+    pub fn warm_b(&mut self, all: bool) {
+        let a_transposed = self.scheduler.accelerator == Accelerator::Op;
+        let preload_num = self.fiber_cache.preload_b(self.a_matrix, a_transposed, all);
+        self.warm_b_fibers = Some(preload_num);
+    }
+
+    // The B fibers referenced by the most A rows.
+    pub fn hot_b_fibers(&self, num: usize) -> Vec<usize> {
+        let a_transposed = self.scheduler.accelerator == Accelerator::Op;
+        let (mut b_rows, _) = self
+            .fiber_cache
+            .rank_b_by_reuse(self.a_matrix, a_transposed);
+        b_rows.truncate(num);
+        b_rows
+    }
+
+    // Never evict the given B fibers once loaded, in their order while they fit in the budget.
+    pub fn pin_b(&mut self, rowids: &[usize]) {
+        let budget = (self.fiber_cache.capability as f32 * self.pin_budget) as usize;
+        let a_transposed = self.scheduler.accelerator == Accelerator::Op;
+        let pinned_num = self
+            .fiber_cache
+            .pin_b(rowids, budget, self.a_matrix, a_transposed);
+        self.pinned_fibers = Some([pinned_num, rowids.len()]);
+    }
+
+    pub fn record_schedule(&mut self) {
+        self.scheduler.schedule_record = Some(vec![]);
+    }
+
+    // Write the recorded shape decisions as JSON lines.
+    pub fn save_schedule(&self, path: &str) -> Result<(), Box<dyn Error>> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        for decision in self.scheduler.schedule_record.iter().flatten() {
+            writeln!(writer, "{}", serde_json::to_string(decision)?)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    // Write one CSV line per allocated block with its shapes, the fiber sizes it touched and
+    // produced, its reuse ratios and the cost the rowwise adjust scheme ranks it by.
+    pub fn save_block_report(&self, path: &str) -> Result<(), Box<dyn Error>> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writeln!(
+            writer,
+            "token,merge,anchor_row,anchor_col,block_rows,block_cols,window_rows,window_cols,\
+             touched_fiber_size,dedup_fiber_size,output_fiber_size,c_reuse,b_reuse,miss_size,\
+             psum_rw_size,cache_rw_size,cost"
+        )?;
+        for token in self.scheduler.block_tracker.keys().sorted() {
+            let block = &self.scheduler.block_tracker[token];
+            let window_shape = block
+                .window_tokens
+                .first()
+                .map_or([0, 0], |w| self.scheduler.window_tracker[w].shape);
+            // Merge blocks are not tracked by the adjust schemes.
+            let [miss_size, psum_rw_size, cache_rw_size] = self
+                .scheduler
+                .rowwise_adjust_tracker
+                .block_info
+                .get(token)
+                .map_or([0; 3], |b| {
+                    [b.miss_size, b.psum_rw_size[0], b.psum_rw_size[1]]
+                });
+            writeln!(
+                writer,
+                "{},{},{},{},{},{},{},{},{},{},{},{:.4},{:.4},{},{},{},{}",
+                token,
+                block.is_merge_block,
+                block.anchor[0],
+                block.anchor[1],
+                block.shape[0],
+                block.shape[1],
+                window_shape[0],
+                window_shape[1],
+                block.touched_fiber_size,
+                block.dedup_fiber_size,
+                block.output_fiber_size,
+                block.c_reuse(window_shape),
+                block.b_reuse(window_shape),
+                miss_size,
+                psum_rw_size,
+                cache_rw_size,
+                (miss_size + psum_rw_size) * 100 + cache_rw_size
+            )?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    // Take the block and window shapes from a recorded schedule instead of adjusting them. The
+    // shapes are adjusted again once the schedule runs out.
+    pub fn replay_schedule(&mut self, path: &str) -> Result<(), Box<dyn Error>> {
+        let mut decisions = VecDeque::new();
+        for line in BufReader::new(File::open(path)?).lines() {
+            decisions.push_back(serde_json::from_str(&line?)?);
+        }
+        self.scheduler.schedule_replay = Some(decisions);
+        Ok(())
+    }
+
+    pub fn get_cache_miss_stat(&self) -> [usize; 3] {
+        [
+            self.fiber_cache.miss_count,
+            self.fiber_cache.conflict_miss_count,
+            self.fiber_cache.compulsory_miss_count,
+        ]
+    }
+
+    // The raw row id of a stored A row, the result row its products go to.
+    fn raw_rowid(&self, rowid: usize) -> usize {
+        // A transposed A keeps its row order only in row_remap.
+        if self.a_matrix.row_remap.is_empty() {
+            rowid
+        } else {
+            self.a_matrix.row_remap[&rowid]
+        }
+    }
+
+    // The raw A column of a B element, i.e. the position of its product in the reference order.
+    fn raw_colid(&self, b: &Element<T>) -> usize {
+        self.a_matrix
+            .col_remap
+            .get(&b.idx[0])
+            .cloned()
+            .unwrap_or(b.idx[0])
+    }
+
+    // Keep the products of the lanes just multiplied, each lane group writes one output row.
+    fn log_lane_products(&mut self, pe_idx: usize, group_size: usize) {
+        let window_token = self.pes[pe_idx].task.as_ref().unwrap().window_token;
+        let arow_addrs = &self.scheduler.window_tracker[&window_token].arow_addr_pairs;
+        let multiplier_array = &self.pes[pe_idx].multiplier_array;
+        let products = multiplier_array
+            .b_eles
+            .iter()
+            .zip(multiplier_array.c_eles.iter())
+            .enumerate()
+            .filter_map(|(lane_idx, (b, c))| match (b, c) {
+                (Some(b), Some(c)) => Some((
+                    [
+                        self.raw_rowid(arow_addrs[lane_idx / group_size][0]),
+                        c.idx[1],
+                    ],
+                    self.raw_colid(b),
+                    c.value,
+                )),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        for (idx, colid, value) in products {
+            self.stable_products
+                .entry(idx)
+                .or_default()
+                .push((colid, value));
+        }
+    }
+
+    // Keep the product an adder tree just multiplied, unless it only passes a psum through.
+    fn log_tree_product(&mut self, idx: usize) {
+        let task = match self.adder_trees[idx].task.as_ref() {
+            Some(task) if !task.merge_mode => task,
+            _ => return,
+        };
+        let (b, c) = match self.adder_trees[idx].multiplier.last_product() {
+            Some(product) => product,
+            None => return,
+        };
+        let arow = self.scheduler.window_tracker[&task.window_token].arow_addr_pairs[0][0];
+        let product = ([self.raw_rowid(arow), c.idx[1]], self.raw_colid(b), c.value);
+        self.stable_products
+            .entry(product.0)
+            .or_default()
+            .push((product.1, product.2));
+    }
+
+    // Replace the value of each result element with the sum of its products in ascending raw A
+    // column order, the order of the reference product.
+    pub fn apply_stable_reduce(&self, result: &mut [CsrRow<T>]) {
+        for csrrow in result.iter_mut() {
+            for (col, value) in csrrow.indptr.iter().zip(csrrow.data.iter_mut()) {
+                if let Some(products) = self.stable_products.get(&[csrrow.rowptr, *col]) {
+                    let mut products = products.clone();
+                    products.sort_by_key(|(colid, _)| *colid);
+                    *value = products
+                        .iter()
+                        .fold(T::zero(), |psum, (_, product)| psum.plus(*product));
                 }
             }
-            c.push(csrrow);
         }
-        c.sort_by(|a, b| a.rowptr.cmp(&b.rowptr));
-        return c;
+    }
+
+    pub fn get_exec_result(&mut self) -> Vec<CsrRow<T>> {
+        self.result_rows().collect()
+    }
+
+    // The result rows in raw row order. The rows of the fiber cache or psum memory are cloned one
+    // at a time as they are pulled, only the in-place outputs are converted as a whole.
+    pub fn result_rows(&self) -> Box<dyn Iterator<Item = CsrRow<T>> + '_> {
+        let in_place = match (self.dense_output.as_ref(), self.csc_psums.as_ref()) {
+            (Some(dense_output), _) => Some(dense_output.to_csr_rows()),
+            (None, Some(csc_psums)) => Some(csc_psums.to_csr_rows(self.c_row_num)),
+            (None, None) => None,
+        };
+        if let Some(mut c) = in_place {
+            // A transposed A keeps its row order only in row_remap.
+            if !self.a_matrix.row_remap.is_empty() {
+                for csrrow in c.iter_mut() {
+                    csrrow.rowptr = self.a_matrix.row_remap[&csrrow.rowptr];
+                }
+            }
+            c.sort_by(|a, b| a.rowptr.cmp(&b.rowptr));
+            return Box::new(c.into_iter());
+        }
+        // Raw row id -> stored row id.
+        let row_num = self.a_matrix.row_num();
+        let mut rowids = (0..row_num).collect::<Vec<usize>>();
+        if self.a_matrix.remapped {
+            for rowid in 0..row_num {
+                rowids[self.a_matrix.row_remap[&rowid]] = rowid;
+            }
+        }
+        Box::new(
+            rowids
+                .into_iter()
+                .enumerate()
+                .map(move |(raw_rowid, rowid)| self.result_row(rowid, raw_rowid)),
+        )
+    }
+
+    fn result_row(&self, rowid: usize, raw_rowid: usize) -> CsrRow<T> {
+        if self.a_matrix.get_ele_num(rowid, rowid + 1) == 0 {
+            return CsrRow::new(raw_rowid);
+        }
+        match self.scheduler.a_row_finished.get(&rowid) {
+            Some(addr) => {
+                trace_println!(
+                    "Get result: row: {} row len: {}",
+                    raw_rowid,
+                    self.fiber_cache.psum_mem.data[&addr].size() / 2
+                );
+                let mut csrrow = match self.fiber_cache.psum_mem.data.get(&addr) {
+                    Some(row) => row.clone(),
+                    None => self.fiber_cache.rowmap.get(&addr).unwrap().clone(),
+                };
+                csrrow.rowptr = raw_rowid;
+                csrrow
+            }
+            None => CsrRow::new(raw_rowid),
+        }
     }
 
     pub fn update_energy_adjust_tracker(
@@ -1100,6 +2656,8 @@ impl<'a> Simulator<'a> {
         {
             // Collect output psums.
             if self.adder_trees[idx].task.is_some() {
+                let event = task_event("finish", "adder_tree", idx, &self.adder_trees[idx].task);
+                self.trace_event(event);
                 let prev_win_token = self.adder_trees[idx].task.as_ref().unwrap().window_token;
                 for arow_addr in self.scheduler.window_tracker[&prev_win_token]
                     .arow_addr_pairs
@@ -1121,6 +2679,9 @@ impl<'a> Simulator<'a> {
                             .or_insert(vec![arow_addr[1]]);
                     }
                 }
+                for addr in self.scheduler.release_task_addrs(prev_win_token, true) {
+                    self.fiber_cache.purge_psum(addr);
+                }
             }
             // Collect stats of the prev finished task.
             if self.adder_trees[idx].task.is_some()
@@ -1141,6 +2702,10 @@ impl<'a> Simulator<'a> {
                 self.exec_cycle,
             );
             self.adder_trees[idx].set_task(task);
+            if self.adder_trees[idx].task.is_some() {
+                let event = task_event("assign", "adder_tree", idx, &self.adder_trees[idx].task);
+                self.trace_event(event);
+            }
             // trace_println!("new task: {:?}", &self.adder_trees[idx].task);
         }
         if self.adder_trees[idx].task.is_some() {
@@ -1174,13 +2739,16 @@ impl<'a> Simulator<'a> {
         let prod = self.adder_trees[idx].multiplier.retrieve_c();
         self.adder_trees[idx].multiplier.set_b(sb);
         self.adder_trees[idx].multiplier.multiply();
+        if self.stable_reduce {
+            self.log_tree_product(idx);
+        }
         // Push prod to the adder.
         let psum = self.adder_trees[idx].adder.add(prod);
         // Write back.
         self.adder_tree_write_psum(idx, psum);
     }
 
-    pub fn adder_tree_write_psum(&mut self, idx: usize, mut element: Option<Element>) {
+    pub fn adder_tree_write_psum(&mut self, idx: usize, mut element: Option<Element<T>>) {
         if self.adder_trees[idx].task.is_none() || element.is_none() {
             return;
         }
@@ -1206,9 +2774,9 @@ impl<'a> Simulator<'a> {
         idx: usize,
         lane_idx: usize,
         cur_cycle: usize,
-    ) -> Option<Element> {
+    ) -> Option<Element<T>> {
         if self.adder_trees[idx].task.is_none() {
-            return Some(Element::new([usize::MAX; 2], 0.0));
+            return Some(Element::new([usize::MAX; 2], T::zero()));
         }
         let task = self.adder_trees[idx].task.as_mut().unwrap();
         let window_tracker = self
@@ -1218,7 +2786,7 @@ impl<'a> Simulator<'a> {
             .unwrap();
         let scalar_idx = window_tracker.lane2idx[lane_idx];
         if scalar_idx.is_none() {
-            return Some(Element::new([usize::MAX; 2], 0.0));
+            return Some(Element::new([usize::MAX; 2], T::zero()));
         }
         let scalar_idx = scalar_idx.unwrap();
         let b_col_idx = window_tracker.b_cols_assigned[lane_idx];
@@ -1227,7 +2795,7 @@ impl<'a> Simulator<'a> {
                 .request_consume_scalars(scalar_idx, b_col_idx, 1, cur_cycle, true)
                 .map(|mut es| {
                     if es.len() == 0 {
-                        Element::new([usize::MAX; 2], 0.0)
+                        Element::new([usize::MAX; 2], T::zero())
                     } else {
                         window_tracker.b_cols_assigned[lane_idx] += 1;
                         es.pop().unwrap()
@@ -1238,7 +2806,7 @@ impl<'a> Simulator<'a> {
                 .request_read_scalars(scalar_idx, b_col_idx, 1, cur_cycle, true)
                 .map(|mut es| {
                     if es.len() == 0 {
-                        Element::new([usize::MAX; 2], 0.0)
+                        Element::new([usize::MAX; 2], T::zero())
                     } else {
                         window_tracker.b_cols_assigned[lane_idx] += 1;
                         es.pop().unwrap()
@@ -1249,3 +2817,799 @@ impl<'a> Simulator<'a> {
         return element;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gemm::GEMM;
+    use crate::preprocessing::sort_by_length;
+    use crate::storage::{EvictionScope, VictimCandidate};
+    use crate::test_util::*;
+    use crate::util::{compare_results, reference_spgemm};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn merge_urgency_counts_psum_words() {
+        with_simulator(
+            &test_config(),
+            test_gemm(16, 16, 0.2, 10),
+            Accelerator::Spada,
+            |simulator| {
+                let addr = simulator.fiber_cache.output_base_addr;
+                simulator
+                    .scheduler
+                    .output_tracker
+                    .insert(0, vec![addr, addr + 1]);
+                simulator.scheduler.b_row_lens.insert(addr, 3);
+                simulator.scheduler.b_row_lens.insert(addr + 1, 3);
+                // The 6 pending elements take 12 words.
+                let capability = simulator.fiber_cache.capability;
+                simulator.fiber_cache.cur_num = capability - 8;
+                assert!(simulator.scheduler.is_merge_urgent(&simulator.fiber_cache));
+                simulator.fiber_cache.cur_num = capability - 12;
+                assert!(!simulator.scheduler.is_merge_urgent(&simulator.fiber_cache));
+            },
+        );
+    }
+
+    #[test]
+    fn longest_first_drains_the_largest_backlog() {
+        let cases = [
+            ("RowId", vec![0, 1, 1, 1]),
+            ("LongestFirst", vec![1, 1, 1, 2]),
+        ];
+        for (merge_order, group_rows) in cases.iter() {
+            let config = config_with(serde_json::json!({ "merge_order": merge_order }));
+            let gemm = test_gemm(16, 16, 0.2, 10);
+            with_simulator(&config, gemm, Accelerator::Spada, |simulator| {
+                let scheduler = &mut simulator.scheduler;
+                let addr = simulator.fiber_cache.output_base_addr;
+                // Uneven psum backlogs, the middle row has the most.
+                for (row, psum_num) in [(0, 2), (1, 6), (2, 4)].iter() {
+                    let psums = (0..*psum_num).map(|p| addr + row * 8 + p).collect();
+                    scheduler.output_tracker.insert(*row, psums);
+                }
+                scheduler.a_traversed = true;
+                let task = scheduler.merge_task(0, false).unwrap();
+                // Each group of merge_radix lanes merges the psums of one row.
+                let rows = task
+                    .a_eles
+                    .chunks(config.merge_radix)
+                    .map(|g| g[0].as_ref().unwrap().idx[0])
+                    .collect::<Vec<usize>>();
+                assert_eq!(&rows, group_rows, "{}", merge_order);
+            });
+        }
+    }
+
+    #[test]
+    fn leftover_psums_form_a_partial_group() {
+        let config = config_with(serde_json::json!({"merge_radix": 4}));
+        with_simulator(
+            &config,
+            test_gemm(16, 16, 0.2, 10),
+            Accelerator::Spada,
+            |simulator| {
+                let scheduler = &mut simulator.scheduler;
+                let addr = simulator.fiber_cache.output_base_addr;
+                scheduler
+                    .output_tracker
+                    .insert(0, (addr..addr + 6).collect());
+                scheduler.a_traversed = true;
+                let task = scheduler.merge_task(0, false).unwrap();
+                // The 6 psums fill a group of 4 and half of the second group.
+                let group_sizes = task
+                    .a_eles
+                    .chunks(4)
+                    .map(|g| g.iter().filter(|e| e.is_some()).count())
+                    .collect::<Vec<usize>>();
+                assert_eq!(group_sizes, vec![4, 2]);
+                assert!(scheduler.output_tracker[&0].is_empty());
+            },
+        );
+    }
+
+    #[test]
+    fn merge_cycles_scale_with_adder_tree_depth() {
+        // Cycles until a group of 16 psums of one column leaves the tree.
+        let merge_cycles = |fan_in: usize| {
+            let mut merge_tree = MergeTree::new(4, Some(fan_in), 2);
+            let group = (0..16).map(|_| Element::new([0, 0], 1.0)).collect();
+            merge_tree.push_elements(vec![group]);
+            let mut cycle = 0;
+            while merge_tree.pop_elements().is_empty() {
+                cycle += 1;
+            }
+            assert_eq!(merge_tree.add_count, 15);
+            cycle
+        };
+        // 4, 2 and 1 stages of 2 cycles each.
+        assert_eq!(merge_cycles(2), 8);
+        assert_eq!(merge_cycles(4), 4);
+        assert_eq!(merge_cycles(16), 2);
+    }
+
+    #[test]
+    fn shared_column_is_broadcast_once() {
+        // Both A rows hold only column 0, so the second row reuses the fiber of the first.
+        let a = csr_with_row_lens(&[1, 1], 4);
+        let b = csr_with_row_lens(&[4; 4], 4);
+        let gemm = GEMM::try_new("shared_column", a, b).unwrap();
+        let config = config_with(serde_json::json!({"block_shape": [2, 10000000]}));
+        with_simulator(&config, gemm, Accelerator::MultiRow, |simulator| {
+            simulator.execute();
+            assert_eq!(simulator.broadcast_hit_count, 1);
+        });
+    }
+
+    #[test]
+    fn multirow_reads_shared_fibers_once_per_block() {
+        // All 8 A rows hold columns 0..4.
+        let a = csr_with_row_lens(&[4; 8], 8);
+        let b = csr_with_row_lens(&[4; 8], 8);
+        let config = config_with(serde_json::json!({"block_shape": [8, 10000000]}));
+        let run = |accelerator: Accelerator| {
+            let gemm = GEMM::try_new("shared_columns", a.clone(), b.clone()).unwrap();
+            with_simulator(&config, gemm, accelerator, |simulator| {
+                simulator.execute();
+                (
+                    simulator.get_cache_stat()[0],
+                    simulator.get_b_mat_stat()[0],
+                    simulator.broadcast_hit_count,
+                )
+            })
+        };
+        let (cache_reads, b_reads, broadcast_hits) = run(Accelerator::MultiRow);
+        let (ip_cache_reads, ip_b_reads, _) = run(Accelerator::Ip);
+        // The 7 rows after the first reuse each of the 4 fibers, which take 8 words.
+        assert_eq!(broadcast_hits, 7 * 4);
+        assert_eq!(ip_cache_reads - cache_reads, 8 * broadcast_hits);
+        assert_eq!(b_reads, ip_b_reads);
+    }
+
+    #[test]
+    fn warm_b_turns_first_touches_into_hits() {
+        let config = config_with(serde_json::json!({"cache_size": 1024}));
+        let run = |warm: bool| {
+            with_simulator(
+                &config,
+                test_gemm(16, 16, 0.2, 5),
+                Accelerator::Spada,
+                |simulator| {
+                    if warm {
+                        simulator.warm_b(false);
+                    }
+                    simulator.execute();
+                    let cache = &simulator.fiber_cache;
+                    (cache.miss_count, cache.b_evict_count)
+                },
+            )
+        };
+        let (miss_count, b_evict_count) = run(false);
+        let (warm_miss_count, warm_b_evict_count) = run(true);
+        assert!(warm_miss_count < miss_count);
+        assert!(warm_b_evict_count < b_evict_count);
+        // The first read of the hottest fiber only misses without the preload.
+        for warm in [false, true].iter() {
+            with_simulator(
+                &config,
+                test_gemm(16, 16, 0.2, 5),
+                Accelerator::Spada,
+                |simulator| {
+                    if *warm {
+                        simulator.warm_b(false);
+                    }
+                    let addr = simulator.hot_b_fibers(1)[0];
+                    let len = simulator.fiber_cache.b_mem.get_ele_num(addr, addr + 1);
+                    simulator
+                        .fiber_cache
+                        .request_read_scalars([0, addr], 0, len, 0, true)
+                        .unwrap();
+                    assert_eq!(simulator.fiber_cache.miss_count == 0, *warm);
+                },
+            );
+        }
+    }
+
+    #[test]
+    fn merge_watermark_lowers_psum_evictions() {
+        // The psums of the PEs overflow the 2 KB cache.
+        let gemm = test_gemm(24, 24, 0.3, 0);
+        let reference = reference_spgemm(&gemm.a, &gemm.b);
+        let run = |merge_watermark: Option<f32>| {
+            let config = config_with(serde_json::json!({
+                "cache_size": 2048,
+                "at_num": 0,
+                "merge_watermark": merge_watermark,
+            }));
+            with_simulator(&config, gemm.clone(), Accelerator::Spada, |simulator| {
+                simulator.execute();
+                let result = simulator.get_exec_result();
+                assert!(compare_results(&reference, &result, 1e-9).is_empty());
+                simulator.fiber_cache.psum_evict_count
+            })
+        };
+        let psum_evict_count = run(None);
+        assert!(psum_evict_count > 0);
+        assert!(run(Some(0.5)) < psum_evict_count);
+    }
+
+    // A GEMM whose B fibers 0..16 all take 8 words.
+    fn equal_fiber_gemm() -> GEMM {
+        let a = csr_with_row_lens(&[1; 16], 16);
+        let b = csr_with_row_lens(&[4; 16], 16);
+        GEMM::try_new("equal_fibers", a, b).unwrap()
+    }
+
+    // Read the whole B fibers in order, one cycle apart.
+    fn read_fibers(simulator: &mut Simulator, addrs: &[usize]) {
+        for (cycle, addr) in addrs.iter().enumerate() {
+            simulator
+                .fiber_cache
+                .request_read_scalars([cycle, *addr], 0, 4, cycle, true)
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn same_set_fibers_evict_each_other_in_one_way() {
+        // 64 words, so 8 sets of one fiber in the 1-way cache and fibers 0 and 8 share a set.
+        let cases = [(Some(1), 24, 8), (None, 16, 0)];
+        for (ways, miss_count, conflict_count) in cases.iter() {
+            let config = config_with(serde_json::json!({
+                "cache_size": 512,
+                "set_associative": ways,
+            }));
+            with_simulator(
+                &config,
+                equal_fiber_gemm(),
+                Accelerator::Spada,
+                |simulator| {
+                    assert_eq!(
+                        simulator.fiber_cache.set_num,
+                        if ways.is_some() { 8 } else { 1 }
+                    );
+                    read_fibers(simulator, &[0, 8, 0]);
+                    let fiber_cache = &simulator.fiber_cache;
+                    assert_eq!(fiber_cache.miss_count, *miss_count, "{:?}", ways);
+                    assert_eq!(
+                        fiber_cache.conflict_miss_count, *conflict_count,
+                        "{:?}",
+                        ways
+                    );
+                    assert_eq!(fiber_cache.contains_row(&8), ways.is_none());
+                },
+            );
+        }
+    }
+
+    #[test]
+    fn misses_are_classified() {
+        // The 1-way cache holds one 8 word fiber in each of its 8 sets.
+        let config = config_with(serde_json::json!({"cache_size": 512, "set_associative": 1}));
+        with_simulator(
+            &config,
+            equal_fiber_gemm(),
+            Accelerator::Spada,
+            |simulator| {
+                // First touches of 9 fibers, fiber 8 replaces fiber 0 of the full cache.
+                read_fibers(simulator, &[0, 1, 2, 3, 4, 5, 6, 7, 8]);
+                // Fiber 0 replaces fiber 8 of the still full cache.
+                read_fibers(simulator, &[0]);
+                // With fiber 7 gone, fiber 8 misses again for capacity and replaces fiber 0 although
+                // there is space left. Fiber 0 then misses for the conflict.
+                simulator.fiber_cache.swapout(7);
+                read_fibers(simulator, &[8, 0]);
+                // The other 16 missed words are the capacity misses of fibers 0 and 8.
+                assert_eq!(simulator.get_cache_miss_stat(), [96, 8, 72]);
+                // A hit adds no miss.
+                read_fibers(simulator, &[0]);
+                assert_eq!(simulator.get_cache_miss_stat(), [96, 8, 72]);
+            },
+        );
+    }
+
+    // Split every finished row into two psums of half its values, as if its merges never ran.
+    // Returns the elements of all the psums.
+    fn split_finished_rows(simulator: &mut Simulator) -> usize {
+        let mut psum_len = 0;
+        let finished = simulator
+            .scheduler
+            .a_row_finished
+            .drain()
+            .collect::<Vec<(usize, usize)>>();
+        for (row, addr) in finished {
+            let csrrow = simulator.fiber_cache.take_psum(addr);
+            let halves = csrrow.data.iter().map(|v| v / 2.0).collect::<Vec<f64>>();
+            let half_addr = simulator.scheduler.psum_addrs.alloc();
+            simulator.scheduler.psum_addrs.live += 1;
+            for psum_addr in [addr, half_addr].iter() {
+                let psum = CsrRow::new_from_data(*psum_addr, halves.clone(), csrrow.indptr.clone());
+                simulator
+                    .fiber_cache
+                    .psum_mem
+                    .write(&mut vec![psum])
+                    .unwrap();
+            }
+            simulator
+                .scheduler
+                .output_tracker
+                .insert(row, vec![addr, half_addr]);
+            psum_len += 2 * csrrow.len();
+        }
+        psum_len
+    }
+
+    #[test]
+    fn residual_psums_are_merged_and_charged() {
+        let gemm = test_gemm(16, 16, 0.3, 11);
+        let reference = reference_spgemm(&gemm.a, &gemm.b);
+        with_simulator(&test_config(), gemm, Accelerator::Spada, |simulator| {
+            simulator.execute();
+            let psum_len = split_finished_rows(simulator);
+            let exec_cycle = simulator.exec_cycle;
+            let add_count = simulator.get_op_stat()[1];
+            let merge_cycles = simulator.drain_report.merge_cycles;
+            simulator.final_merge();
+            let result = simulator.get_exec_result();
+            assert!(compare_results(&reference, &result, 1e-9).is_empty());
+            assert!(simulator.scheduler.output_tracker.is_empty());
+            // Every element is added to its other half, 8 lanes of 2 PEs taking 16 a cycle.
+            let cycles = (psum_len + 15) / 16;
+            assert_eq!(simulator.exec_cycle, exec_cycle + cycles);
+            assert_eq!(simulator.get_op_stat()[1], add_count + psum_len / 2);
+            assert_eq!(simulator.drain_report.merge_cycles, merge_cycles + cycles);
+        });
+    }
+
+    #[test]
+    fn drain_components_sum_to_drain_cycles() {
+        with_simulator(
+            &test_config(),
+            test_gemm(64, 64, 0.2, 12),
+            Accelerator::Spada,
+            |simulator| {
+                simulator.execute();
+                assert!(split_finished_rows(simulator) > 0);
+                simulator.final_merge();
+                let drain = simulator.get_drain_report();
+                assert!(drain.merge_cycles > 0);
+                assert_eq!(
+                    drain.merge_cycles
+                        + drain.flush_cycles
+                        + drain.writeback_cycles
+                        + drain.multiply_cycles
+                        + drain.stall_cycles,
+                    drain.cycles
+                );
+            },
+        );
+    }
+
+    #[test]
+    fn burst_over_the_bandwidth_stalls_the_idle_pes() {
+        let config = config_with(serde_json::json!({"bandwidth_limit": true}));
+        let run = |backlog: f32| {
+            with_simulator(
+                &config,
+                test_gemm(16, 16, 0.2, 13),
+                Accelerator::Spada,
+                |simulator| {
+                    simulator.bandwidth_backlog = backlog;
+                    simulator.execute();
+                    (
+                        simulator.exec_cycle,
+                        simulator.bandwidth_stall_cycles,
+                        simulator.pe_stats.clone(),
+                    )
+                },
+            )
+        };
+        let (exec_cycle, stall_cycles, pe_stats) = run(0.0);
+        // 16 channels of 8 bytes drain 128 bytes a cycle, so 3.5 cycles of bytes stall 3 cycles
+        // before any PE holds a task.
+        let (burst_exec_cycle, burst_stall_cycles, burst_pe_stats) = run(448.0);
+        assert_eq!(burst_exec_cycle, exec_cycle + 3);
+        assert_eq!(burst_stall_cycles, stall_cycles + 3);
+        for (stats, burst_stats) in pe_stats.iter().zip(burst_pe_stats.iter()) {
+            assert_eq!(burst_stats.idle_cycle, stats.idle_cycle + 3);
+            assert_eq!(burst_stats.stall_cycle, stats.stall_cycle);
+        }
+    }
+
+    #[test]
+    fn write_back_skips_clean_psums() {
+        let psum_writes = |write_back: bool| {
+            let config = config_with(serde_json::json!({ "write_back": write_back }));
+            with_simulator(
+                &config,
+                equal_fiber_gemm(),
+                Accelerator::Spada,
+                |simulator| {
+                    let cache = &mut simulator.fiber_cache;
+                    let addr = cache.output_base_addr;
+                    // The row accumulates the psums of three blocks before it is swapped out.
+                    for col in 0..3 {
+                        cache.append_psum_to(
+                            addr,
+                            CsrRow::new_from_data(addr, vec![1.0], vec![col]),
+                        );
+                    }
+                    cache.swapout(addr);
+                    // Read back for a merge, then evicted again unchanged.
+                    cache
+                        .request_read_scalars([0, addr], 0, 3, 0, true)
+                        .unwrap();
+                    cache.swapout(addr);
+                    cache.psum_mem.write_count
+                },
+            )
+        };
+        assert_eq!(psum_writes(false), 12);
+        assert_eq!(psum_writes(true), 6);
+    }
+
+    #[test]
+    fn single_row_leaves_one_pe_idle() {
+        // One window of 8 elements, so only one of the two PEs ever holds a task.
+        let a = csr_with_row_lens(&[8], 16);
+        let b = csr_with_row_lens(&[4; 16], 16);
+        let gemm = GEMM::try_new("single_row", a, b).unwrap();
+        with_simulator(&test_config(), gemm, Accelerator::Spada, |simulator| {
+            simulator.execute();
+            let mut pe_stats = simulator.pe_stats.clone();
+            pe_stats.sort_by_key(|s| s.idle_cycle);
+            let (busy, idle) = (&pe_stats[0], &pe_stats[1]);
+            assert!(busy.compute_cycle > 0);
+            assert_eq!(idle.compute_cycle, 0);
+            assert!(idle.idle_cycle > busy.idle_cycle);
+            // Both PEs account for every cycle of the run.
+            let total =
+                |s: &PeStats| s.compute_cycle + s.merge_cycle + s.stall_cycle + s.idle_cycle;
+            assert_eq!(total(busy), total(idle));
+        });
+    }
+
+    #[test]
+    fn l1_cache_lowers_shared_cache_reads() {
+        let run = |l1_cache_size: usize| {
+            let config = config_with(serde_json::json!({ "l1_cache_size": l1_cache_size }));
+            with_simulator(
+                &config,
+                test_gemm(32, 32, 0.3, 17),
+                Accelerator::Spada,
+                |simulator| {
+                    simulator.execute();
+                    (simulator.get_cache_stat()[0], simulator.get_l1_stat())
+                },
+            )
+        };
+        let (shared_reads, _) = run(0);
+        let (l1_shared_reads, [l1_reads, l1_misses]) = run(4096);
+        assert!(l1_reads > l1_misses);
+        assert!(l1_shared_reads < shared_reads);
+    }
+
+    #[test]
+    fn sequential_sweep_hits_prefetched_fibers() {
+        let sweep = |prefetch: bool| {
+            let config = config_with(serde_json::json!({ "prefetch": prefetch }));
+            with_simulator(
+                &config,
+                equal_fiber_gemm(),
+                Accelerator::Spada,
+                |simulator| {
+                    read_fibers(simulator, &(0..16).collect::<Vec<usize>>());
+                    let cache = &simulator.fiber_cache;
+                    (cache.miss_count, cache.prefetch_hit_count)
+                },
+            )
+        };
+        let (miss_count, _) = sweep(false);
+        let (prefetch_miss_count, prefetch_hit_count) = sweep(true);
+        assert!(prefetch_hit_count > 0);
+        // Each prefetch hit is a miss of the fiber's 8 words saved.
+        assert_eq!(prefetch_miss_count, miss_count - 8 * prefetch_hit_count);
+    }
+
+    #[test]
+    fn block_report_has_a_row_per_block() {
+        let path = temp_path("block_report.csv");
+        let tokens = with_simulator(
+            &test_config(),
+            test_gemm(16, 16, 0.2, 3),
+            Accelerator::Spada,
+            |simulator| {
+                simulator.execute();
+                simulator.save_block_report(&path).unwrap();
+                simulator
+                    .scheduler
+                    .block_tracker
+                    .keys()
+                    .cloned()
+                    .sorted()
+                    .collect::<Vec<_>>()
+            },
+        );
+        let report = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let rows = report
+            .lines()
+            .skip(1)
+            .map(|line| line.split(',').next().unwrap().parse::<usize>().unwrap())
+            .collect::<Vec<_>>();
+        assert!(!tokens.is_empty());
+        assert_eq!(rows, tokens);
+    }
+
+    #[test]
+    fn long_fiber_misses_line_by_line() {
+        // B fiber 0 takes 64 words and fiber 1 takes 2, the 64 byte lines hold 8 words.
+        let gemm = || {
+            let a = csr_with_row_lens(&[2; 4], 2);
+            let b = csr_with_row_lens(&[32, 1], 32);
+            GEMM::try_new("long_fiber", a, b).unwrap()
+        };
+        // Without lines the long fiber is one miss of its 64 words.
+        let cases = [(Some(64), [64, 8], [72, 9]), (None, [64, 0], [66, 0])];
+        for (line_size, long_misses, short_misses) in cases.iter() {
+            let config = config_with(serde_json::json!({ "line_size": line_size }));
+            with_simulator(&config, gemm(), Accelerator::Spada, |simulator| {
+                let cache = &mut simulator.fiber_cache;
+                cache.request_read_scalars([0, 0], 0, 32, 0, true).unwrap();
+                assert_eq!([cache.miss_count, cache.line_miss_count], *long_misses);
+                // A short fiber still costs a whole line.
+                cache.request_read_scalars([1, 1], 0, 1, 1, true).unwrap();
+                assert_eq!([cache.miss_count, cache.line_miss_count], *short_misses);
+            });
+        }
+    }
+
+    // Evicts the largest resident line and keeps the victims.
+    struct EvictLargest(Rc<RefCell<Vec<usize>>>);
+
+    impl VictimPolicy for EvictLargest {
+        fn select_victim(
+            &mut self,
+            _scope: EvictionScope,
+            candidates: &[VictimCandidate],
+            _space_missing: usize,
+        ) -> usize {
+            let victim = candidates.iter().max_by_key(|c| c.size).unwrap().addr;
+            self.0.borrow_mut().push(victim);
+            victim
+        }
+    }
+
+    #[test]
+    fn custom_policy_evicts_the_largest_fiber() {
+        // The fibers take 8, 24, 12, 16, 4 and 20 words, the first five fill the 64 word cache.
+        let row_lens = [4, 12, 6, 8, 2, 10];
+        let a = csr_with_row_lens(&[1; 6], 6);
+        let b = csr_with_row_lens(&row_lens, 12);
+        let gemm = GEMM::try_new("fiber_sizes", a, b).unwrap();
+        let config = config_with(serde_json::json!({"cache_size": 512}));
+        let victims = Rc::new(RefCell::new(vec![]));
+        with_simulator(&config, gemm, Accelerator::Spada, |simulator| {
+            let cache = &mut simulator.fiber_cache;
+            cache.set_victim_policy(Box::new(EvictLargest(victims.clone())));
+            for (addr, len) in row_lens.iter().enumerate() {
+                cache
+                    .request_read_scalars([addr, addr], 0, *len, addr, true)
+                    .unwrap();
+            }
+            assert_eq!(*victims.borrow(), vec![1]);
+            assert!(!cache.rowmap.contains_key(&1));
+            for addr in [0, 2, 3, 4, 5].iter() {
+                assert!(cache.rowmap.contains_key(addr));
+            }
+            assert_eq!(cache.b_evict_count, 24);
+        });
+    }
+
+    #[test]
+    fn psum_checks_catch_a_bad_output_tracker() {
+        with_simulator(
+            &test_config(),
+            test_gemm(16, 16, 0.2, 8),
+            Accelerator::Spada,
+            |simulator| {
+                // Two rows share a psum.
+                let scheduler = &mut simulator.scheduler;
+                scheduler.output_tracker.insert(3, vec![100]);
+                scheduler.output_tracker.insert(5, vec![100]);
+                let err = simulator.check_psum_trackers().unwrap_err();
+                assert!(err.starts_with("psum 100 is referenced twice"), "{}", err);
+                // A finished row still has a psum.
+                let scheduler = &mut simulator.scheduler;
+                scheduler.output_tracker.remove(&5);
+                scheduler.a_row_finished.insert(3, 101);
+                let err = simulator.check_psum_trackers().unwrap_err();
+                assert!(
+                    err.starts_with("finished with psums left, row 3"),
+                    "{}",
+                    err
+                );
+            },
+        );
+        // A consistent run passes the checks of every cycle.
+        with_simulator(
+            &test_config(),
+            test_gemm(16, 16, 0.2, 8),
+            Accelerator::Spada,
+            |simulator| {
+                simulator.debug_checks = true;
+                simulator.execute();
+                assert_eq!(simulator.check_psum_trackers(), Ok(()));
+            },
+        );
+    }
+
+    #[test]
+    fn mac_count_is_the_issued_products() {
+        let gemm = test_gemm(20, 16, 0.2, 21);
+        // Each A element multiplies the fiber of B it selects.
+        let product_num = gemm
+            .a
+            .indices()
+            .iter()
+            .map(|col| gemm.b.outer_view(*col).unwrap().nnz())
+            .sum::<usize>();
+        for accelerator in [Accelerator::Ip, Accelerator::Spada].iter() {
+            with_simulator(
+                &test_config(),
+                gemm.clone(),
+                accelerator.clone(),
+                |simulator| {
+                    simulator.execute();
+                    assert_eq!(simulator.get_mac_count(), product_num, "{}", accelerator);
+                },
+            );
+        }
+    }
+
+    // The A elements the windows of a block over all the rows of A read, in window order.
+    fn window_elements(row_lens: &[usize], window_rows: usize) -> Vec<[usize; 2]> {
+        // The fixed windows take the rows of the configured block shape and share the lanes.
+        let config = config_with(serde_json::json!({"block_shape": [window_rows, 8]}));
+        let a = csr_with_row_lens(row_lens, 8);
+        let gemm = GEMM::try_new("windows", a, csr_with_row_lens(&[1; 8], 8)).unwrap();
+        with_simulator(&config, gemm, Accelerator::Ip, |simulator| {
+            let scheduler = &mut simulator.scheduler;
+            let row_num = row_lens.len();
+            scheduler.set_block(
+                0,
+                [0, 0],
+                [row_num, 8],
+                false,
+                row_lens.to_vec(),
+                vec![true; row_num],
+            );
+            let mut elements = vec![];
+            while let Some((_, task)) = scheduler.next_window(0, simulator.a_matrix, 0) {
+                let window = &scheduler.window_tracker[&task.window_token];
+                assert_eq!(window.shape, [window_rows, 8 / window_rows]);
+                elements.extend(window.lane2idx.iter().flatten());
+            }
+            elements
+        })
+    }
+
+    #[test]
+    fn windows_read_each_a_element_once() {
+        // 7 rows, including an empty one, for windows of 3 rows and of 1 row.
+        let row_lens = [5, 0, 3, 8, 1, 4, 6];
+        let mut expected = row_lens
+            .iter()
+            .enumerate()
+            .flat_map(|(row, len)| (0..*len).map(move |col| [row, col]))
+            .collect::<Vec<_>>();
+        expected.sort_unstable();
+        for window_rows in [3, 1].iter() {
+            let mut elements = window_elements(&row_lens, *window_rows);
+            elements.sort_unstable();
+            assert_eq!(elements, expected, "{} rows", window_rows);
+        }
+    }
+
+    #[test]
+    fn adder_trees_take_fewer_cycles_than_merging_on_pes() {
+        // The 4 column blocks leave many psums of each row to merge.
+        let gemm = test_gemm(32, 32, 0.3, 3);
+        let reference = reference_spgemm(&gemm.a, &gemm.b);
+        let run = |at_num: usize| {
+            let config = config_with(serde_json::json!({"at_num": at_num, "block_shape": [1, 4]}));
+            with_simulator(&config, gemm.clone(), Accelerator::Spada, |simulator| {
+                simulator.execute();
+                let result = simulator.get_exec_result();
+                assert!(compare_results(&reference, &result, 1e-9).is_empty());
+                simulator.exec_cycle
+            })
+        };
+        assert!(run(4) < run(0));
+    }
+
+    #[test]
+    fn result_rows_stream_the_exec_result() {
+        let config = test_config();
+        let gemm = test_gemm(24, 24, 0.2, 17);
+        let reference = reference_spgemm(&gemm.a, &gemm.b);
+        // With the stored rows in A order and sorted by length.
+        for reorder in [false, true].iter() {
+            let (mut dram_a, mut dram_b) =
+                CsrMatStorage::init_with_gemm(gemm.clone(), config.word_byte, config.index_byte);
+            if *reorder {
+                let rowmap = sort_by_length(&mut dram_a, true);
+                dram_a.reorder_row(rowmap);
+            }
+            let mut dram_psum = VectorStorage::new(config.word_byte, config.index_byte);
+            let output_base_addr = dram_b.indptr.len();
+            let mut simulator = Simulator::new(
+                &config,
+                output_base_addr,
+                config.block_shape,
+                &mut dram_a,
+                &mut dram_b,
+                &mut dram_psum,
+                Accelerator::Spada,
+                BlockStrategy::Rowwise,
+                0,
+            );
+            simulator.execute();
+            let rows = simulator.result_rows().collect::<Vec<_>>();
+            let result = simulator.get_exec_result();
+            assert_eq!(rows.len(), result.len());
+            for (raw_rowid, (row, result_row)) in rows.iter().zip(result.iter()).enumerate() {
+                assert_eq!(row.rowptr, raw_rowid);
+                assert_eq!(row.rowptr, result_row.rowptr);
+                assert_eq!(row.indptr, result_row.indptr);
+                assert_eq!(row.data, result_row.data);
+            }
+            assert!(compare_results(&reference, &rows, 1e-9).is_empty());
+        }
+    }
+
+    #[test]
+    fn block_clamp_keeps_the_floor_width() {
+        // The configured blocks are 1 wide and the rowwise scheme never widens them.
+        let gemm = test_gemm(32, 32, 0.3, 19);
+        let reference = reference_spgemm(&gemm.a, &gemm.b);
+        let row_lens = gemm
+            .a
+            .outer_iterator()
+            .map(|row| row.nnz())
+            .collect::<Vec<usize>>();
+        let run = |min_block_width: Option<f32>| {
+            let config = config_with(serde_json::json!({
+                "block_shape": [1, 1],
+                "min_block_width": min_block_width,
+            }));
+            with_simulator(&config, gemm.clone(), Accelerator::Spada, |simulator| {
+                simulator.execute();
+                let result = simulator.get_exec_result();
+                assert!(compare_results(&reference, &result, 1e-9).is_empty());
+                simulator
+                    .scheduler
+                    .block_tracker
+                    .values()
+                    .filter(|block| !block.is_merge_block)
+                    .map(|block| (block.anchor, block.shape))
+                    .collect::<Vec<_>>()
+            })
+        };
+        assert!(run(None).iter().all(|(_, shape)| shape[1] == 1));
+        let blocks = run(Some(0.5));
+        for (anchor, shape) in blocks.iter() {
+            // Half the mean length left in the block rows.
+            let rows = anchor[0]..min(anchor[0] + shape[0], row_lens.len());
+            let remaining = row_lens[rows.clone()]
+                .iter()
+                .map(|len| len.saturating_sub(anchor[1]))
+                .sum::<usize>();
+            let floor = (remaining as f32 / 2.0 / rows.len() as f32).ceil() as usize;
+            assert!(shape[1] >= floor, "{:?} {:?}", anchor, shape);
+        }
+        assert!(blocks.iter().any(|(_, shape)| shape[1] > 1));
+    }
+}
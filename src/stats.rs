@@ -0,0 +1,822 @@
+use crate::frontend::{EnergyConfig, StatsFormat};
+use crate::simulator::{DrainReport, PeStats};
+use serde::Serialize;
+use serde_json::Value;
+use std::cmp::max;
+use std::fmt;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SimulationStats {
+    pub workload: String,
+    pub accelerator: String,
+    pub seed: u64,
+    // Stopped by the cycle limit, the counts only cover the executed part.
+    pub truncated: bool,
+    pub exec_count: usize,
+    pub a_count: [usize; 2],
+    pub b_count: [usize; 2],
+    pub c_count: [usize; 2],
+    pub a_bytes: [usize; 2],
+    pub b_bytes: [usize; 2],
+    pub c_bytes: [usize; 2],
+    pub merge_count: [usize; 3],
+    pub op_count: [usize; 2],
+    // MACs of the GEMM with dense operands.
+    pub dense_mac_count: usize,
+    pub steal_count: usize,
+    pub cache_count: [usize; 2],
+    // Misses in total, the conflict and the compulsory ones, the rest are capacity misses.
+    pub cache_miss_count: [usize; 3],
+    // Only collected when the feature is enabled.
+    pub prefetch_count: Option<[usize; 2]>,
+    pub channel_conflict_cycles: Option<usize>,
+    pub psum_bank_conflict_cycles: Option<usize>,
+    pub bandwidth_stall_cycles: Option<usize>,
+    pub row_buffer_count: Option<[usize; 2]>,
+    pub l1_count: Option<[usize; 2]>,
+    // Min, max and mean off-chip access latency if it is sampled from a distribution.
+    pub mem_latency_stat: Option<[f64; 3]>,
+    pub line_miss_count: Option<usize>,
+    pub broadcast_hit_count: Option<usize>,
+    pub warm_b_fibers: Option<usize>,
+    // Pinned B fibers that fit in the pinning budget, and the requested ones.
+    pub pinned_fibers: Option<[usize; 2]>,
+    // Peak words of the fiber cache in total, of the B fibers and of the psums. Only the cycle
+    // accurate simulation models the cache.
+    pub peak_occupancy: Option<[usize; 3]>,
+    // Bytes fetched through each DRAM channel.
+    pub channel_bytes: Option<Vec<usize>>,
+    // Busy cycles of each in-cache adder tree, if there are any.
+    pub adder_tree_busy: Option<Vec<usize>>,
+    // Stored and fully materialized bytes of A and B.
+    pub symmetric_storage_bytes: Option<[usize; 2]>,
+    pub reuse: Option<Vec<ReusePoint>>,
+    pub energy: Option<EnergyStats>,
+    pub drain_cycles: Vec<usize>,
+    // Only the cycle accurate simulation has a drain phase.
+    pub drain: Option<DrainReport>,
+    // Peak psum addresses live at once, and the addresses handed out again.
+    pub psum_addr_count: Option<[usize; 2]>,
+    pub pe_stats: Vec<PeStats>,
+}
+
+// Predicted hit rates of a fully associative LRU cache of cache_size bytes.
+#[derive(Debug, Clone, Serialize)]
+pub struct ReusePoint {
+    pub cache_size: usize,
+    pub b_hit_rate: f64,
+    pub psum_hit_rate: f64,
+}
+
+// Energy breakdown in pJ.
+#[derive(Debug, Clone, Serialize)]
+pub struct EnergyStats {
+    pub dram_read: f64,
+    pub dram_write: f64,
+    pub cache_read: f64,
+    pub cache_write: f64,
+    pub mac: f64,
+    pub merge_add: f64,
+    pub total: f64,
+}
+
+impl EnergyStats {
+    pub fn new(
+        energy_config: &EnergyConfig,
+        dram_count: [usize; 2],
+        cache_count: [usize; 2],
+        op_count: [usize; 2],
+    ) -> EnergyStats {
+        let dram_read = dram_count[0] as f64 * energy_config.dram_read;
+        let dram_write = dram_count[1] as f64 * energy_config.dram_write;
+        let cache_read = cache_count[0] as f64 * energy_config.cache_read;
+        let cache_write = cache_count[1] as f64 * energy_config.cache_write;
+        let mac = op_count[0] as f64 * energy_config.mac;
+        let merge_add = op_count[1] as f64 * energy_config.merge_add;
+        EnergyStats {
+            dram_read,
+            dram_write,
+            cache_read,
+            cache_write,
+            mac,
+            merge_add,
+            total: dram_read + dram_write + cache_read + cache_write + mac + merge_add,
+        }
+    }
+}
+
+// Row length profile of a matrix. Bin 0 of the histogram counts the empty rows and bin i the
+// rows with a length in [2^(i-1), 2^i).
+#[derive(Debug, Clone, Serialize)]
+pub struct MatrixProfile {
+    pub rows: usize,
+    pub cols: usize,
+    pub nnz: usize,
+    pub empty_rows: usize,
+    pub min_row_len: usize,
+    pub max_row_len: usize,
+    pub median_row_len: usize,
+    pub histogram: Vec<usize>,
+}
+
+impl MatrixProfile {
+    pub fn new(indptr: &[usize], cols: usize) -> MatrixProfile {
+        let mut row_lens = indptr
+            .windows(2)
+            .map(|w| w[1] - w[0])
+            .collect::<Vec<usize>>();
+        row_lens.sort();
+        let mut histogram = vec![0; 1];
+        for row_len in row_lens.iter() {
+            // Bits needed for the length, 0 for an empty row.
+            let bin = (usize::BITS - row_len.leading_zeros()) as usize;
+            if bin >= histogram.len() {
+                histogram.resize(bin + 1, 0);
+            }
+            histogram[bin] += 1;
+        }
+        MatrixProfile {
+            rows: row_lens.len(),
+            cols,
+            nnz: row_lens.iter().sum(),
+            empty_rows: histogram[0],
+            min_row_len: row_lens.first().cloned().unwrap_or(0),
+            max_row_len: row_lens.last().cloned().unwrap_or(0),
+            median_row_len: if row_lens.len() > 0 {
+                row_lens[(row_lens.len() - 1) / 2]
+            } else {
+                0
+            },
+            histogram,
+        }
+    }
+}
+
+impl fmt::Display for MatrixProfile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "Rows: {} cols: {} nnz: {}",
+            self.rows, self.cols, self.nnz
+        )?;
+        writeln!(
+            f,
+            "Row length: min {} max {} median {} avg {:.2}",
+            self.min_row_len,
+            self.max_row_len,
+            self.median_row_len,
+            self.nnz as f64 / max(self.rows, 1) as f64
+        )?;
+        writeln!(
+            f,
+            "Empty rows: {} ({:.2}%)",
+            self.empty_rows,
+            self.empty_rows as f64 * 100.0 / max(self.rows, 1) as f64
+        )?;
+        writeln!(f, "Row length histogram:")?;
+        for (bin, count) in self.histogram.iter().enumerate() {
+            if bin == 0 {
+                writeln!(f, "0: {}", count)?;
+            } else {
+                writeln!(f, "[{}, {}): {}", 1usize << (bin - 1), 1usize << bin, count)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl SimulationStats {
+    // Dense MACs per MAC performed, the speedup from skipping the zeros alone.
+    pub fn sparsity_speedup(&self) -> Option<f64> {
+        match self.op_count[0] {
+            0 => None,
+            mac_count => Some(self.dense_mac_count as f64 / mac_count as f64),
+        }
+    }
+
+    // Bytes of the busiest channel over the mean of the channels, from 1 for an even interleave
+    // to the channel num when a single channel serves every access.
+    pub fn channel_imbalance(&self) -> Option<f64> {
+        let channel_bytes = self.channel_bytes.as_ref()?;
+        let total = channel_bytes.iter().sum::<usize>();
+        if total == 0 {
+            return None;
+        }
+        let busiest = *channel_bytes.iter().max().unwrap();
+        Some(busiest as f64 * channel_bytes.len() as f64 / total as f64)
+    }
+
+    // Sum the counts of the stages of a chained product. The peaks are the peaks of the
+    // stages and the mean memory latency is weighted by their cycles. The reuse analysis is only
+    // reported per stage.
+    pub fn chain(workload: &str, stages: &[SimulationStats]) -> SimulationStats {
+        let mut total = stages[0].clone();
+        total.workload = workload.to_owned();
+        total.reuse = None;
+        for stage in stages[1..].iter() {
+            let prev_exec_count = total.exec_count;
+            total.truncated |= stage.truncated;
+            total.exec_count += stage.exec_count;
+            add_counts(&mut total.a_count, &stage.a_count);
+            add_counts(&mut total.b_count, &stage.b_count);
+            add_counts(&mut total.c_count, &stage.c_count);
+            add_counts(&mut total.a_bytes, &stage.a_bytes);
+            add_counts(&mut total.b_bytes, &stage.b_bytes);
+            add_counts(&mut total.c_bytes, &stage.c_bytes);
+            add_counts(&mut total.merge_count, &stage.merge_count);
+            add_counts(&mut total.op_count, &stage.op_count);
+            total.dense_mac_count += stage.dense_mac_count;
+            total.steal_count += stage.steal_count;
+            add_counts(&mut total.cache_count, &stage.cache_count);
+            add_counts(&mut total.cache_miss_count, &stage.cache_miss_count);
+            add_option_counts(&mut total.prefetch_count, &stage.prefetch_count);
+            add_option_count(
+                &mut total.channel_conflict_cycles,
+                stage.channel_conflict_cycles,
+            );
+            add_option_count(
+                &mut total.psum_bank_conflict_cycles,
+                stage.psum_bank_conflict_cycles,
+            );
+            add_option_count(
+                &mut total.bandwidth_stall_cycles,
+                stage.bandwidth_stall_cycles,
+            );
+            add_option_counts(&mut total.row_buffer_count, &stage.row_buffer_count);
+            add_option_counts(&mut total.l1_count, &stage.l1_count);
+            if let (Some(latency), Some(stage_latency)) =
+                (total.mem_latency_stat.as_mut(), stage.mem_latency_stat)
+            {
+                let cycles = max(total.exec_count, 1) as f64;
+                latency[0] = latency[0].min(stage_latency[0]);
+                latency[1] = latency[1].max(stage_latency[1]);
+                latency[2] = (latency[2] * prev_exec_count as f64
+                    + stage_latency[2] * stage.exec_count as f64)
+                    / cycles;
+            }
+            add_option_count(&mut total.line_miss_count, stage.line_miss_count);
+            add_option_count(&mut total.broadcast_hit_count, stage.broadcast_hit_count);
+            add_option_count(&mut total.warm_b_fibers, stage.warm_b_fibers);
+            add_option_counts(&mut total.pinned_fibers, &stage.pinned_fibers);
+            if let (Some(peak), Some(stage_peak)) =
+                (total.peak_occupancy.as_mut(), stage.peak_occupancy)
+            {
+                for (p, s) in peak.iter_mut().zip(stage_peak.iter()) {
+                    *p = max(*p, *s);
+                }
+            }
+            add_option_counts(&mut total.channel_bytes, &stage.channel_bytes);
+            add_option_counts(&mut total.adder_tree_busy, &stage.adder_tree_busy);
+            add_option_counts(
+                &mut total.symmetric_storage_bytes,
+                &stage.symmetric_storage_bytes,
+            );
+            if let (Some(energy), Some(stage_energy)) = (total.energy.as_mut(), &stage.energy) {
+                energy.dram_read += stage_energy.dram_read;
+                energy.dram_write += stage_energy.dram_write;
+                energy.cache_read += stage_energy.cache_read;
+                energy.cache_write += stage_energy.cache_write;
+                energy.mac += stage_energy.mac;
+                energy.merge_add += stage_energy.merge_add;
+                energy.total += stage_energy.total;
+            }
+            if let (Some(count), Some(stage_count)) =
+                (total.psum_addr_count.as_mut(), stage.psum_addr_count)
+            {
+                count[0] = max(count[0], stage_count[0]);
+                count[1] += stage_count[1];
+            }
+            add_counts(&mut total.drain_cycles, &stage.drain_cycles);
+            if let (Some(drain), Some(stage_drain)) = (total.drain.as_mut(), &stage.drain) {
+                drain.cycles += stage_drain.cycles;
+                drain.merge_cycles += stage_drain.merge_cycles;
+                drain.flush_cycles += stage_drain.flush_cycles;
+                drain.writeback_cycles += stage_drain.writeback_cycles;
+                drain.multiply_cycles += stage_drain.multiply_cycles;
+                drain.stall_cycles += stage_drain.stall_cycles;
+                drain.active_pe_cycles += stage_drain.active_pe_cycles;
+            }
+            for (pe_stats, stage_pe_stats) in total.pe_stats.iter_mut().zip(stage.pe_stats.iter()) {
+                pe_stats.compute_cycle += stage_pe_stats.compute_cycle;
+                pe_stats.merge_cycle += stage_pe_stats.merge_cycle;
+                pe_stats.stall_cycle += stage_pe_stats.stall_cycle;
+                pe_stats.idle_cycle += stage_pe_stats.idle_cycle;
+            }
+        }
+        total
+    }
+
+    pub fn capacity_miss_count(&self) -> usize {
+        let [miss, conflict, compulsory] = self.cache_miss_count;
+        miss.saturating_sub(conflict + compulsory)
+    }
+
+    // Mean fraction of the cycles the adder trees hold a merge task.
+    pub fn adder_tree_util(&self) -> Option<f64> {
+        let busy = self.adder_tree_busy.as_ref()?;
+        if busy.is_empty() || self.exec_count == 0 {
+            return None;
+        }
+        Some(busy.iter().sum::<usize>() as f64 / (busy.len() * self.exec_count) as f64)
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    // Nested values are flattened into `name_idx` columns.
+    fn csv_columns(&self) -> Vec<(String, String)> {
+        let mut columns = vec![];
+        flatten_value("", &serde_json::to_value(self).unwrap(), &mut columns);
+        columns
+    }
+}
+
+// Add the counts of a stage elementwise.
+fn add_counts<C: AsMut<[usize]> + ?Sized, S: AsRef<[usize]> + ?Sized>(count: &mut C, stage: &S) {
+    for (c, s) in count.as_mut().iter_mut().zip(stage.as_ref().iter()) {
+        *c += s;
+    }
+}
+
+fn add_option_count(count: &mut Option<usize>, stage: Option<usize>) {
+    if let (Some(count), Some(stage)) = (count.as_mut(), stage) {
+        *count += stage;
+    }
+}
+
+fn add_option_counts<C: AsMut<[usize]>, S: AsRef<[usize]>>(
+    count: &mut Option<C>,
+    stage: &Option<S>,
+) {
+    if let (Some(count), Some(stage)) = (count.as_mut(), stage.as_ref()) {
+        add_counts(count, stage);
+    }
+}
+
+// Format the records of a batch run. A single JSON record stays an object, while several become
+// an array. CSV records share one header line.
+pub fn format_batch(stats: &[SimulationStats], stats_format: &StatsFormat) -> String {
+    match stats_format {
+        StatsFormat::Human => stats
+            .iter()
+            .map(|s| s.to_string())
+            .collect::<Vec<String>>()
+            .join("\n"),
+        StatsFormat::Json if stats.len() == 1 => stats[0].to_json() + "\n",
+        StatsFormat::Json => serde_json::to_string_pretty(stats).unwrap() + "\n",
+        StatsFormat::Csv => {
+            let mut lines = vec![];
+            for (idx, s) in stats.iter().enumerate() {
+                let columns = s.csv_columns();
+                if idx == 0 {
+                    lines.push(
+                        columns
+                            .iter()
+                            .map(|(name, _)| name.clone())
+                            .collect::<Vec<String>>()
+                            .join(","),
+                    );
+                }
+                lines.push(
+                    columns
+                        .into_iter()
+                        .map(|(_, value)| value)
+                        .collect::<Vec<String>>()
+                        .join(","),
+                );
+            }
+            lines.join("\n") + "\n"
+        }
+    }
+}
+
+// Side by side table of the runs of one GEMM on several accelerators.
+pub fn format_comparison(stats: &[SimulationStats]) -> String {
+    let header = [
+        "accelerator",
+        "cycles",
+        "a_read",
+        "a_write",
+        "b_read",
+        "b_write",
+        "c_read",
+        "c_write",
+        "cache_read",
+        "cache_write",
+    ];
+    let mut rows = vec![header
+        .iter()
+        .map(|h| h.to_string())
+        .collect::<Vec<String>>()];
+    for s in stats.iter() {
+        let mut row = vec![s.accelerator.clone(), s.exec_count.to_string()];
+        for count in [s.a_count, s.b_count, s.c_count, s.cache_count].iter() {
+            row.extend(count.iter().map(|c| c.to_string()));
+        }
+        rows.push(row);
+    }
+    let widths = (0..header.len())
+        .map(|col| rows.iter().map(|row| row[col].len()).max().unwrap_or(0))
+        .collect::<Vec<usize>>();
+    let mut output = String::from("-----Comparison\n");
+    for row in rows.iter() {
+        // The accelerator names are left aligned, the counts right aligned.
+        let cells = row
+            .iter()
+            .zip(widths.iter())
+            .enumerate()
+            .map(|(col, (cell, width))| match col {
+                0 => format!("{:<1$}", cell, width),
+                _ => format!("{:>1$}", cell, width),
+            })
+            .collect::<Vec<String>>();
+        output += &(cells.join(" ") + "\n");
+    }
+    output
+}
+
+fn flatten_value(name: &str, value: &Value, columns: &mut Vec<(String, String)>) {
+    let join = |key: &str| {
+        if name.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}_{}", name, key)
+        }
+    };
+    match value {
+        Value::Object(map) => {
+            for (key, v) in map.iter() {
+                flatten_value(&join(key), v, columns);
+            }
+        }
+        Value::Array(vec) => {
+            for (idx, v) in vec.iter().enumerate() {
+                flatten_value(&join(&idx.to_string()), v, columns);
+            }
+        }
+        Value::Null => columns.push((name.to_string(), String::new())),
+        Value::String(s) if s.contains(',') || s.contains('"') => {
+            columns.push((name.to_string(), format!("\"{}\"", s.replace('"', "\"\""))))
+        }
+        Value::String(s) => columns.push((name.to_string(), s.clone())),
+        v => columns.push((name.to_string(), v.to_string())),
+    }
+}
+
+impl fmt::Display for SimulationStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "-----Result-----")?;
+        writeln!(f, "-----Access count")?;
+        writeln!(f, "Execution count: {}", self.exec_count)?;
+        if self.truncated {
+            writeln!(f, "Truncated by the cycle limit")?;
+        }
+        writeln!(
+            f,
+            "A matrix count: read {} write {}",
+            self.a_count[0], self.a_count[1]
+        )?;
+        writeln!(
+            f,
+            "B matrix count: read {} write {}",
+            self.b_count[0], self.b_count[1]
+        )?;
+        writeln!(
+            f,
+            "C matrix count: read {} write {}",
+            self.c_count[0], self.c_count[1]
+        )?;
+        writeln!(
+            f,
+            "A matrix bytes: read {} write {}",
+            self.a_bytes[0], self.a_bytes[1]
+        )?;
+        writeln!(
+            f,
+            "B matrix bytes: read {} write {}",
+            self.b_bytes[0], self.b_bytes[1]
+        )?;
+        writeln!(
+            f,
+            "C matrix bytes: read {} write {}",
+            self.c_bytes[0], self.c_bytes[1]
+        )?;
+        writeln!(
+            f,
+            "Merge count: task {} cycle {} multiply cycle {}",
+            self.merge_count[0], self.merge_count[1], self.merge_count[2]
+        )?;
+        writeln!(f, "Steal count: {}", self.steal_count)?;
+        writeln!(
+            f,
+            "Op count: mac {} merge add {}",
+            self.op_count[0], self.op_count[1]
+        )?;
+        match self.sparsity_speedup() {
+            Some(speedup) => writeln!(
+                f,
+                "Sparsity speedup: {:.2} over {} dense macs",
+                speedup, self.dense_mac_count
+            )?,
+            None => writeln!(
+                f,
+                "Sparsity speedup: n/a over {} dense macs",
+                self.dense_mac_count
+            )?,
+        }
+        writeln!(
+            f,
+            "Cache count: read {} write {}",
+            self.cache_count[0], self.cache_count[1]
+        )?;
+        writeln!(
+            f,
+            "Cache miss count: {} conflict {} compulsory {} capacity {}",
+            self.cache_miss_count[0],
+            self.cache_miss_count[1],
+            self.cache_miss_count[2],
+            self.capacity_miss_count()
+        )?;
+        if let Some(prefetch_count) = self.prefetch_count {
+            writeln!(
+                f,
+                "Prefetch count: issued {} hit {}",
+                prefetch_count[0], prefetch_count[1]
+            )?;
+        }
+        if let Some(conflict_cycles) = self.channel_conflict_cycles {
+            writeln!(f, "Channel conflict cycles: {}", conflict_cycles)?;
+        }
+        if let Some(conflict_cycles) = self.psum_bank_conflict_cycles {
+            writeln!(f, "Psum bank conflict cycles: {}", conflict_cycles)?;
+        }
+        if let Some(stall_cycles) = self.bandwidth_stall_cycles {
+            writeln!(f, "Bandwidth stall cycles: {}", stall_cycles)?;
+        }
+        if let Some(row_buffer_count) = self.row_buffer_count {
+            writeln!(
+                f,
+                "Row buffer count: hit {} miss {}",
+                row_buffer_count[0], row_buffer_count[1]
+            )?;
+        }
+        if let Some(l1_count) = self.l1_count {
+            writeln!(
+                f,
+                "L1 cache count: read {} miss {}",
+                l1_count[0], l1_count[1]
+            )?;
+        }
+        if let Some([min, max, mean]) = self.mem_latency_stat {
+            writeln!(
+                f,
+                "Memory access latency: min {} max {} mean {:.2}",
+                min, max, mean
+            )?;
+        }
+        if let Some(line_miss_count) = self.line_miss_count {
+            writeln!(f, "Line miss count: {}", line_miss_count)?;
+        }
+        if let Some(broadcast_hit_count) = self.broadcast_hit_count {
+            writeln!(f, "Broadcast hit count: {}", broadcast_hit_count)?;
+        }
+        if let Some(warm_b_fibers) = self.warm_b_fibers {
+            writeln!(f, "Warm B fibers: {}", warm_b_fibers)?;
+        }
+        if let Some([pinned, requested]) = self.pinned_fibers {
+            writeln!(f, "Pinned B fibers: {} of {}", pinned, requested)?;
+        }
+        if let Some([total, b, psum]) = self.peak_occupancy {
+            writeln!(
+                f,
+                "Peak cache occupancy: {} words, B {} psum {}",
+                total, b, psum
+            )?;
+        }
+        if let (Some(channel_bytes), Some(imbalance)) =
+            (self.channel_bytes.as_ref(), self.channel_imbalance())
+        {
+            writeln!(
+                f,
+                "Channel bytes: max {} min {} imbalance {:.2}",
+                channel_bytes.iter().max().unwrap(),
+                channel_bytes.iter().min().unwrap(),
+                imbalance
+            )?;
+        }
+        if let Some(util) = self.adder_tree_util() {
+            writeln!(f, "Adder tree utilization: {:.2}", util)?;
+        }
+        if let Some([peak_live, reused]) = self.psum_addr_count {
+            writeln!(
+                f,
+                "Psum addresses: peak live {} reused {}",
+                peak_live, reused
+            )?;
+        }
+        if let Some(storage_bytes) = self.symmetric_storage_bytes {
+            writeln!(
+                f,
+                "Symmetric storage bytes: stored {} full {}",
+                storage_bytes[0], storage_bytes[1]
+            )?;
+        }
+        if let Some(energy) = self.energy.as_ref() {
+            writeln!(f, "-----Energy (pJ)")?;
+            writeln!(
+                f,
+                "DRAM: read {:.1} write {:.1}",
+                energy.dram_read, energy.dram_write
+            )?;
+            writeln!(
+                f,
+                "Cache: read {:.1} write {:.1}",
+                energy.cache_read, energy.cache_write
+            )?;
+            writeln!(
+                f,
+                "Compute: mac {:.1} merge add {:.1}",
+                energy.mac, energy.merge_add
+            )?;
+            writeln!(f, "Total: {:.1}", energy.total)?;
+        }
+
+        if let Some(reuse) = self.reuse.as_ref() {
+            writeln!(f, "-----Reuse analysis")?;
+            for point in reuse.iter() {
+                writeln!(
+                    f,
+                    "Cache size {} B: hit rate B {:.3} psum {:.3}",
+                    point.cache_size, point.b_hit_rate, point.psum_hit_rate
+                )?;
+            }
+        }
+
+        if let Some(drain) = self.drain.as_ref() {
+            writeln!(f, "-----Drain")?;
+            writeln!(
+                f,
+                "Drain cycles: {} merge {} flush {} writeback {} multiply {} stall {}",
+                drain.cycles,
+                drain.merge_cycles,
+                drain.flush_cycles,
+                drain.writeback_cycles,
+                drain.multiply_cycles,
+                drain.stall_cycles
+            )?;
+            writeln!(f, "Drain active PEs: mean {:.2}", drain.mean_active_pes())?;
+        }
+
+        writeln!(f, "-----PE utilization")?;
+        for (pe_idx, pe_stats) in self.pe_stats.iter().enumerate() {
+            writeln!(
+                f,
+                "PE {} cycles: compute {} merge {} stall {} idle {}",
+                pe_idx,
+                pe_stats.compute_cycle,
+                pe_stats.merge_cycle,
+                pe_stats.stall_cycle,
+                pe_stats.idle_cycle
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frontend::{Accelerator, MemLatency};
+    use crate::storage::DramChannels;
+    use crate::test_util::*;
+    use crate::{run_dense_model, run_simulation};
+
+    #[test]
+    fn json_record_holds_the_run_stats() {
+        let report = run_simulation(
+            &test_config(),
+            test_gemm(8, 8, 0.3, 0),
+            &test_options(Accelerator::Spada),
+        )
+        .unwrap();
+        let stats = report.stats;
+        let record: Value =
+            serde_json::from_str(&format_batch(&[stats.clone()], &StatsFormat::Json)).unwrap();
+        assert_eq!(record["accelerator"], "Spada");
+        assert_eq!(record["exec_count"], stats.exec_count);
+        assert_eq!(record["op_count"][0], stats.op_count[0]);
+        assert_eq!(record["drain"]["cycles"], stats.drain.unwrap().cycles);
+        assert_eq!(record["pe_stats"].as_array().unwrap().len(), 2);
+        assert!(record["prefetch_count"].is_null());
+    }
+
+    #[test]
+    fn energy_is_the_counts_times_the_costs() {
+        let config = config_with(serde_json::json!({"energy": {
+            "dram_read": 6.0,
+            "dram_write": 5.0,
+            "cache_read": 4.0,
+            "cache_write": 3.0,
+            "mac": 2.0,
+            "merge_add": 1.0,
+        }}));
+        let report = run_simulation(
+            &config,
+            test_gemm(16, 16, 0.3, 2),
+            &test_options(Accelerator::Spada),
+        )
+        .unwrap();
+        let stats = report.stats;
+        let energy = stats.energy.as_ref().unwrap();
+        let counts = [
+            stats.a_count[0] + stats.b_count[0] + stats.c_count[0],
+            stats.a_count[1] + stats.b_count[1] + stats.c_count[1],
+            stats.cache_count[0],
+            stats.cache_count[1],
+            stats.op_count[0],
+            stats.op_count[1],
+        ];
+        let costs = [6.0, 5.0, 4.0, 3.0, 2.0, 1.0];
+        let expected = counts
+            .iter()
+            .zip(costs.iter())
+            .map(|(count, cost)| *count as f64 * cost)
+            .sum::<f64>();
+        assert!(counts.iter().all(|count| *count > 0));
+        assert_eq!(energy.total, expected);
+        assert_eq!(energy.mac, stats.op_count[0] as f64 * 2.0);
+    }
+
+    #[test]
+    fn profile_bins_the_row_lengths() {
+        let mat = csr_with_row_lens(&[4, 0, 8, 2, 7, 1, 3], 8);
+        let profile = MatrixProfile::new(mat.indptr().as_slice().unwrap(), mat.cols());
+        assert_eq!(profile.nnz, 25);
+        assert_eq!(profile.empty_rows, 1);
+        assert_eq!([profile.min_row_len, profile.max_row_len], [0, 8]);
+        assert_eq!(profile.median_row_len, 3);
+        // Empty, 1, 2..4, 4..8 and 8..16.
+        assert_eq!(profile.histogram, vec![1, 1, 2, 2, 1]);
+    }
+
+    #[test]
+    fn single_channel_traffic_is_maximally_skewed() {
+        let mut stats = run_dense_model(&test_config(), &test_gemm(4, 4, 0.5, 0));
+        let mut dram = DramChannels::new(4, MemLatency::Fixed(30), 0, false, 0, 0, 0, 0);
+        for addr in (0..32).step_by(4) {
+            dram.access(addr, 8, 0);
+        }
+        stats.channel_bytes = Some(dram.channel_bytes.clone());
+        assert_eq!(stats.channel_imbalance(), Some(4.0));
+        // An even interleave is balanced.
+        for addr in (0..32).filter(|addr| addr % 4 != 0) {
+            dram.access(addr, 8, 0);
+        }
+        stats.channel_bytes = Some(dram.channel_bytes);
+        assert_eq!(stats.channel_imbalance(), Some(1.0));
+    }
+
+    #[test]
+    fn human_block_is_stable() {
+        let mut stats = run_dense_model(&test_config(), &test_gemm(4, 4, 0.5, 0));
+        stats.drain = Some(DrainReport {
+            cycles: 10,
+            merge_cycles: 4,
+            flush_cycles: 3,
+            writeback_cycles: 2,
+            multiply_cycles: 1,
+            stall_cycles: 0,
+            active_pe_cycles: 15,
+        });
+        stats.pe_stats = vec![PeStats {
+            compute_cycle: 5,
+            merge_cycle: 4,
+            stall_cycle: 3,
+            idle_cycle: 2,
+        }];
+        let expected = "\
+-----Result-----
+-----Access count
+Execution count: 4
+A matrix count: read 16 write 0
+B matrix count: read 16 write 0
+C matrix count: read 0 write 16
+A matrix bytes: read 128 write 0
+B matrix bytes: read 128 write 0
+C matrix bytes: read 0 write 128
+Merge count: task 0 cycle 0 multiply cycle 0
+Steal count: 0
+Op count: mac 64 merge add 0
+Sparsity speedup: 1.00 over 64 dense macs
+Cache count: read 0 write 0
+Cache miss count: 0 conflict 0 compulsory 0 capacity 0
+-----Drain
+Drain cycles: 10 merge 4 flush 3 writeback 2 multiply 1 stall 0
+Drain active PEs: mean 1.50
+-----PE utilization
+PE 0 cycles: compute 5 merge 4 stall 3 idle 2
+";
+        assert_eq!(stats.to_string(), expected);
+    }
+}
@@ -1,9 +1,19 @@
+use crate::frontend::{MemLatency, ReplacementPolicy};
 use crate::gemm::GEMM;
-use crate::trace_println;
+use crate::scalar::Scalar;
+use crate::stats::{MatrixProfile, ReusePoint};
+use crate::util::pair_map;
+use crate::{load_state_fields, trace_println};
 use itertools::izip;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha12Rng;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sprs::CsMat;
 use std::{
+    borrow::Cow,
     cmp::{max, min, Reverse},
-    collections::{BinaryHeap, HashMap},
+    collections::{BTreeMap, HashMap, HashSet},
     fmt, usize,
 };
 
@@ -19,28 +29,28 @@ impl fmt::Display for StorageError {
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct Element {
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Element<T = f64> {
     pub idx: [usize; 2],
-    pub value: f64,
+    pub value: T,
 }
 
-impl Element {
-    pub fn new(idx: [usize; 2], value: f64) -> Element {
+impl<T: Scalar> Element<T> {
+    pub fn new(idx: [usize; 2], value: T) -> Element<T> {
         Element { idx, value }
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct CsrRow {
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CsrRow<T = f64> {
     pub rowptr: usize,
-    pub data: Vec<f64>,
+    pub data: Vec<T>,
     pub indptr: Vec<usize>,
     consumed: usize,
 }
 
-impl CsrRow {
-    pub fn new(rowptr: usize) -> CsrRow {
+impl<T: Scalar> CsrRow<T> {
+    pub fn new(rowptr: usize) -> CsrRow<T> {
         CsrRow {
             rowptr: rowptr,
             data: vec![],
@@ -49,7 +59,7 @@ impl CsrRow {
         }
     }
 
-    pub fn new_from_data(rowptr: usize, data: Vec<f64>, indptr: Vec<usize>) -> CsrRow {
+    pub fn new_from_data(rowptr: usize, data: Vec<T>, indptr: Vec<usize>) -> CsrRow<T> {
         CsrRow {
             rowptr,
             data,
@@ -58,7 +68,7 @@ impl CsrRow {
         }
     }
 
-    pub fn as_element_vec(self) -> Vec<Element> {
+    pub fn as_element_vec(self) -> Vec<Element<T>> {
         let mut result = vec![];
         for (d, col_idx) in izip!(self.data, self.indptr) {
             result.push(Element {
@@ -78,7 +88,7 @@ impl CsrRow {
         return self.indptr.len();
     }
 
-    pub fn append(&mut self, csrrow: CsrRow) {
+    pub fn append(&mut self, csrrow: CsrRow<T>) {
         assert!(
             self.rowptr == csrrow.rowptr,
             "Not the same row ({}, {}), cannot be combined!",
@@ -89,7 +99,7 @@ impl CsrRow {
         self.indptr.extend(csrrow.indptr.iter());
     }
 
-    pub fn append_element(&mut self, element: Element) {
+    pub fn append_element(&mut self, element: Element<T>) {
         assert!(
             self.rowptr == element.idx[0],
             "Not the same row ({}, {}), cannot be combined!",
@@ -112,7 +122,7 @@ impl CsrRow {
     }
 }
 
-impl fmt::Display for CsrRow {
+impl<T: Scalar> fmt::Display for CsrRow<T> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let display_len = min(self.data.len(), 5);
         write!(
@@ -125,9 +135,9 @@ impl fmt::Display for CsrRow {
     }
 }
 
-pub fn sorted_element_vec_to_csr_row(srt_ele_vec: Vec<Element>) -> CsrRow {
+pub fn sorted_element_vec_to_csr_row<T: Scalar>(srt_ele_vec: Vec<Element<T>>) -> CsrRow<T> {
     let rowptr = srt_ele_vec[0].idx[0];
-    let data = srt_ele_vec.iter().map(|e| e.value).collect::<Vec<f64>>();
+    let data = srt_ele_vec.iter().map(|e| e.value).collect::<Vec<T>>();
     let indptr = srt_ele_vec.iter().map(|e| e.idx[1]).collect::<Vec<usize>>();
     return CsrRow {
         rowptr,
@@ -137,30 +147,58 @@ pub fn sorted_element_vec_to_csr_row(srt_ele_vec: Vec<Element>) -> CsrRow {
     };
 }
 
-pub trait StorageAPI {
+// Bytes of a fiber slice, the row pointer is charged when the fiber head is touched.
+pub fn fiber_bytes(ele_num: usize, word_byte: usize, index_byte: usize, rowptr: bool) -> usize {
+    ele_num * (word_byte + index_byte) + if rowptr { index_byte } else { 0 }
+}
+
+pub trait StorageAPI<T> {
     fn read(
         &mut self,
         row_ptr: usize,
         col_s: usize,
         ele_num: usize,
-    ) -> Result<CsrRow, StorageError>;
-    fn write(&mut self, rows: &mut Vec<CsrRow>) -> Result<Vec<usize>, StorageError>;
+    ) -> Result<CsrRow<T>, StorageError>;
+    fn write(&mut self, rows: &mut Vec<CsrRow<T>>) -> Result<Vec<usize>, StorageError>;
 }
 
-pub struct CsrMatStorage {
-    pub data: Vec<f64>,
+#[derive(Serialize, Deserialize)]
+pub struct CsrMatStorage<T = f64> {
+    pub data: Vec<T>,
     pub indptr: Vec<usize>,
     pub indices: Vec<usize>,
     pub read_count: usize,
     pub write_count: usize,
     pub remapped: bool,
     pub row_remap: HashMap<usize, usize>,
+    // New -> raw column. The product columns come from B, so the result needs no unmapping.
+    pub col_remap: HashMap<usize, usize>,
     pub track_count: bool,
     pub mat_shape: [usize; 2],
+    // Byte accounting related.
+    pub word_byte: usize,
+    pub index_byte: usize,
+    pub read_bytes: usize,
+    pub write_bytes: usize,
+    // A symmetric matrix only stores its lower triangle and diagonal, the rest is mirrored.
+    pub mirror: Option<SymmetricMirror>,
+}
+
+// The upper part of each row of a half stored symmetric matrix, i.e. the positions of the entries
+// below the diagonal in the same column, by row.
+#[derive(Serialize, Deserialize)]
+pub struct SymmetricMirror {
+    pub indptr: Vec<usize>,
+    pub pos: Vec<usize>,
 }
 
-impl StorageAPI for CsrMatStorage {
-    fn read(&mut self, rawp: usize, col_s: usize, ele_num: usize) -> Result<CsrRow, StorageError> {
+impl<T: Scalar> StorageAPI<T> for CsrMatStorage<T> {
+    fn read(
+        &mut self,
+        rawp: usize,
+        col_s: usize,
+        ele_num: usize,
+    ) -> Result<CsrRow<T>, StorageError> {
         if rawp >= self.indptr.len() {
             return Err(StorageError::ReadOverBoundError(format!(
                 "Invalid row_ptr: {}",
@@ -174,15 +212,17 @@ impl StorageAPI for CsrMatStorage {
             rawp
         };
 
-        let cur_row_pos = self.indptr[row_ptr];
-        let end_row_pos = self.indptr[row_ptr + 1];
-        let s = cur_row_pos + col_s;
+        // A mirrored element costs the same access as a stored one.
+        let (indices, data) = self.row_entries(row_ptr);
+        let end_row_pos = indices.len();
+        let s = col_s;
         let t = s + ele_num;
-        if (s <= t) && (t <= end_row_pos) {
-            let csrrow =
-                CsrRow::new_from_data(rawp, self.data[s..t].to_vec(), self.indices[s..t].to_vec());
+        if t <= end_row_pos {
+            let csrrow = CsrRow::new_from_data(rawp, data[s..t].to_vec(), indices[s..t].to_vec());
             if self.track_count {
                 self.read_count += csrrow.size();
+                self.read_bytes +=
+                    fiber_bytes(csrrow.len(), self.word_byte, self.index_byte, col_s == 0);
             }
             return Ok(csrrow);
         } else {
@@ -193,13 +233,14 @@ impl StorageAPI for CsrMatStorage {
         }
     }
 
-    fn write(&mut self, rows: &mut Vec<CsrRow>) -> Result<Vec<usize>, StorageError> {
+    fn write(&mut self, rows: &mut Vec<CsrRow<T>>) -> Result<Vec<usize>, StorageError> {
         let mut indptrs = vec![];
         for row in rows.iter_mut() {
             let indptr = self.data.len();
             indptrs.push(indptr);
             if self.track_count {
                 self.write_count += 2 * row.data.len() + 1;
+                self.write_bytes += fiber_bytes(row.len(), self.word_byte, self.index_byte, true);
             }
             self.data.extend(row.data.iter());
             self.indices.extend(row.indptr.iter());
@@ -210,42 +251,173 @@ impl StorageAPI for CsrMatStorage {
     }
 }
 
-impl CsrMatStorage {
-    pub fn init_with_gemm(gemm: GEMM) -> (CsrMatStorage, CsrMatStorage) {
+impl<T: Scalar> CsrMatStorage<T> {
+    pub fn init_with_gemm(
+        gemm: GEMM<T>,
+        word_byte: usize,
+        index_byte: usize,
+    ) -> (CsrMatStorage<T>, CsrMatStorage<T>) {
+        // The arrays are moved out of the matrices, so that they are not held twice.
         (
-            CsrMatStorage {
-                data: gemm.a.data().to_vec(),
-                indptr: gemm.a.indptr().as_slice().unwrap().to_vec(),
-                indices: gemm.a.indices().to_vec(),
-                read_count: 0,
-                write_count: 0,
-                remapped: false,
-                row_remap: HashMap::new(),
-                track_count: true,
-                mat_shape: [gemm.a.shape().1, gemm.a.shape().0],
-            },
-            CsrMatStorage {
-                data: gemm.b.data().to_vec(),
-                indptr: gemm.b.indptr().as_slice().unwrap().to_vec(),
-                indices: gemm.b.indices().to_vec(),
-                read_count: 0,
-                write_count: 0,
-                remapped: false,
-                row_remap: HashMap::new(),
-                track_count: true,
-                mat_shape: [gemm.b.shape().1, gemm.b.shape().0],
-            },
+            CsrMatStorage::from_mat(gemm.a, word_byte, index_byte),
+            CsrMatStorage::from_mat(gemm.b, word_byte, index_byte),
         )
     }
 
-    pub fn read_row(&mut self, row_ptr: usize) -> Result<CsrRow, StorageError> {
+    fn from_mat(mat: CsMat<T>, word_byte: usize, index_byte: usize) -> CsrMatStorage<T> {
+        let mat_shape = [mat.shape().1, mat.shape().0];
+        let (indptr, indices, data) = mat.into_raw_storage();
+        CsrMatStorage {
+            data,
+            indptr,
+            indices,
+            read_count: 0,
+            write_count: 0,
+            remapped: false,
+            row_remap: HashMap::new(),
+            col_remap: HashMap::new(),
+            track_count: true,
+            mat_shape,
+            word_byte,
+            index_byte,
+            read_bytes: 0,
+            write_bytes: 0,
+            mirror: None,
+        }
+    }
+
+    // Keep only the lower triangle and diagonal if the matrix is symmetric.
+    pub fn to_half_storage(&mut self) -> bool {
+        let row_num = self.row_num();
+        if self.mirror.is_some() || self.mat_shape[0] != row_num || self.remapped {
+            return false;
+        }
+        let is_symmetric = (0..row_num).all(|rowid| {
+            (self.indptr[rowid]..self.indptr[rowid + 1]).all(|pos| {
+                let colid = self.indices[pos];
+                let (s, t) = (self.indptr[colid], self.indptr[colid + 1]);
+                match self.indices[s..t].binary_search(&rowid) {
+                    Ok(offset) => self.data[s + offset] == self.data[pos],
+                    Err(_) => false,
+                }
+            })
+        });
+        if !is_symmetric {
+            return false;
+        }
+        let mut data = vec![];
+        let mut indices = vec![];
+        let mut indptr = vec![0];
+        let mut col_pos: Vec<Vec<usize>> = vec![vec![]; row_num];
+        for rowid in 0..row_num {
+            for pos in self.indptr[rowid]..self.indptr[rowid + 1] {
+                let colid = self.indices[pos];
+                if colid > rowid {
+                    break;
+                } else if colid < rowid {
+                    col_pos[colid].push(data.len());
+                }
+                indices.push(colid);
+                data.push(self.data[pos]);
+            }
+            indptr.push(data.len());
+        }
+        let mut mirror = SymmetricMirror {
+            indptr: vec![0],
+            pos: vec![],
+        };
+        for pos in col_pos {
+            mirror.pos.extend(pos);
+            mirror.indptr.push(mirror.pos.len());
+        }
+        self.data = data;
+        self.indices = indices;
+        self.indptr = indptr;
+        self.mirror = Some(mirror);
+        true
+    }
+
+    // Store both triangles again, e.g. before the rows are moved around.
+    fn to_full_storage(&mut self) {
+        if self.mirror.is_none() {
+            return;
+        }
+        let mut data = vec![];
+        let mut indices = vec![];
+        let mut indptr = vec![0];
+        for rowid in 0..self.row_num() {
+            let (row_indices, row_data) = self.row_entries(rowid);
+            indices.extend_from_slice(&row_indices);
+            data.extend_from_slice(&row_data);
+            indptr.push(data.len());
+        }
+        self.data = data;
+        self.indices = indices;
+        self.indptr = indptr;
+        self.mirror = None;
+    }
+
+    // The column indices and values of a raw row, mirroring the upper part if half stored.
+    pub fn row_entries(&self, rawid: usize) -> (Cow<[usize]>, Cow<[T]>) {
+        let (s, t) = (self.indptr[rawid], self.indptr[rawid + 1]);
+        match self.mirror.as_ref() {
+            None => (
+                Cow::Borrowed(&self.indices[s..t]),
+                Cow::Borrowed(&self.data[s..t]),
+            ),
+            Some(mirror) => {
+                let mut indices = self.indices[s..t].to_vec();
+                let mut data = self.data[s..t].to_vec();
+                // The entries below the diagonal of column rawid are the row ones above it.
+                for pos in mirror.pos[mirror.indptr[rawid]..mirror.indptr[rawid + 1]].iter() {
+                    indices.push(self.row_of(*pos));
+                    data.push(self.data[*pos]);
+                }
+                (Cow::Owned(indices), Cow::Owned(data))
+            }
+        }
+    }
+
+    // The stored row of a data position.
+    fn row_of(&self, pos: usize) -> usize {
+        self.indptr.partition_point(|p| *p <= pos) - 1
+    }
+
+    pub fn row_len(&self, rawid: usize) -> usize {
+        self.indptr[rawid + 1] - self.indptr[rawid]
+            + self
+                .mirror
+                .as_ref()
+                .map_or(0, |m| m.indptr[rawid + 1] - m.indptr[rawid])
+    }
+
+    pub fn nnz(&self) -> usize {
+        self.data.len() + self.mirror.as_ref().map_or(0, |m| m.pos.len())
+    }
+
+    // DRAM bytes of the stored arrays, and of the matrix if it stored both triangles.
+    pub fn storage_bytes(&self) -> [usize; 2] {
+        let rowptr_bytes = (self.row_num() + 1) * self.index_byte;
+        let full = self.nnz() * (self.word_byte + self.index_byte) + rowptr_bytes;
+        match self.mirror.as_ref() {
+            None => [full, full],
+            Some(mirror) => [
+                self.data.len() * (self.word_byte + self.index_byte)
+                    + rowptr_bytes
+                    + (mirror.indptr.len() + mirror.pos.len()) * self.index_byte,
+                full,
+            ],
+        }
+    }
+
+    pub fn read_row(&mut self, row_ptr: usize) -> Result<CsrRow<T>, StorageError> {
         if row_ptr >= self.indptr.len() {
             return Err(StorageError::ReadEmptyRowError(format!(
                 "Invalid row_ptr: {}",
                 row_ptr
             )));
         }
-        let row_len = self.indptr[row_ptr + 1] - self.indptr[row_ptr];
+        let row_len = self.row_len(row_ptr);
         return self.read(row_ptr, 0, row_len);
     }
 
@@ -254,6 +426,100 @@ impl CsrMatStorage {
         self.row_remap = rowmap;
     }
 
+    // Renumber the columns of A and move the rows of B along, so that the product is unchanged.
+    pub fn reorder_col(&mut self, b_matrix: &mut CsrMatStorage<T>, colmap: HashMap<usize, usize>) {
+        self.to_full_storage();
+        b_matrix.to_full_storage();
+        let inv_colmap = colmap
+            .iter()
+            .map(|(new, raw)| (*raw, *new))
+            .collect::<HashMap<usize, usize>>();
+        // Keep the indices of each A row sorted.
+        for rowid in 0..self.row_num() {
+            let (s, t) = (self.indptr[rowid], self.indptr[rowid + 1]);
+            let mut entries = self.indices[s..t]
+                .iter()
+                .map(|colid| inv_colmap[colid])
+                .zip(self.data[s..t].iter().cloned())
+                .collect::<Vec<(usize, T)>>();
+            entries.sort_by_key(|(colid, _)| *colid);
+            for (offset, (colid, value)) in entries.into_iter().enumerate() {
+                self.indices[s + offset] = colid;
+                self.data[s + offset] = value;
+            }
+        }
+        let mut data = vec![];
+        let mut indices = vec![];
+        let mut indptr = vec![0];
+        for rowid in 0..b_matrix.row_num() {
+            let rawid = colmap[&rowid];
+            let (s, t) = (b_matrix.indptr[rawid], b_matrix.indptr[rawid + 1]);
+            data.extend_from_slice(&b_matrix.data[s..t]);
+            indices.extend_from_slice(&b_matrix.indices[s..t]);
+            indptr.push(data.len());
+        }
+        b_matrix.data = data;
+        b_matrix.indices = indices;
+        b_matrix.indptr = indptr;
+        self.col_remap = colmap;
+    }
+
+    // Store the matrix column-major, e.g. A of an outer product. The row order is applied to the
+    // stored indices, and row_remap is kept to map them back to the raw rows.
+    pub fn transpose(&mut self) {
+        // A symmetric matrix is its own transpose.
+        if self.mirror.is_some() && !self.remapped {
+            return;
+        }
+        self.to_full_storage();
+        let row_num = self.row_num();
+        let col_num = self.mat_shape[0];
+        let mut cols: Vec<Vec<(usize, T)>> = vec![vec![]; col_num];
+        for rowid in 0..row_num {
+            let csrrow = self.peek_row(rowid);
+            for (colid, value) in csrrow.indptr.iter().zip(csrrow.data.iter()) {
+                cols[*colid].push((rowid, *value));
+            }
+        }
+        self.data = vec![];
+        self.indices = vec![];
+        self.indptr = vec![0];
+        for col in cols {
+            for (rowid, value) in col {
+                self.indices.push(rowid);
+                self.data.push(value);
+            }
+            self.indptr.push(self.data.len());
+        }
+        self.mat_shape = [row_num, col_num];
+        self.remapped = false;
+    }
+
+    // Read a whole row without touching the access counts, keyed by its raw row id.
+    pub fn peek_row(&self, rowid: usize) -> CsrRow<T> {
+        let rawid = if self.remapped {
+            self.row_remap[&rowid]
+        } else {
+            rowid
+        };
+        let (indices, data) = self.row_entries(rawid);
+        CsrRow::new_from_data(rawid, data.into_owned(), indices.into_owned())
+    }
+
+    // Only reads the row pointers, so the access counts are not touched.
+    pub fn profile(&self) -> MatrixProfile {
+        match self.mirror.as_ref() {
+            None => MatrixProfile::new(&self.indptr, self.mat_shape[0]),
+            Some(_) => {
+                let mut indptr = vec![0];
+                for rowid in 0..self.row_num() {
+                    indptr.push(indptr[rowid] + self.row_len(rowid));
+                }
+                MatrixProfile::new(&indptr, self.mat_shape[0])
+            }
+        }
+    }
+
     pub fn rowptr(&self, rowid: usize) -> usize {
         if self.remapped {
             return self.indptr[self.row_remap[&rowid]];
@@ -270,7 +536,7 @@ impl CsrMatStorage {
         let mut ele_num = 0;
         for i in row_s..row_t {
             let rawidx = if self.remapped { self.row_remap[&i] } else { i };
-            ele_num += self.indptr[rawidx + 1] - self.indptr[rawidx];
+            ele_num += self.row_len(rawidx);
         }
 
         return ele_num;
@@ -281,7 +547,7 @@ impl CsrMatStorage {
         row_idx: usize,
         col_idx: usize,
         num: usize,
-    ) -> Result<Vec<Element>, StorageError> {
+    ) -> Result<Vec<Element<T>>, StorageError> {
         // trace_println!(
         //     "***storage read_scalars: row_idx {} col_idx {} num {}",
         //     row_idx,
@@ -303,15 +569,20 @@ impl CsrMatStorage {
             row_idx
         };
 
-        let cur_row_pos = self.indptr[row_idx];
-        let end_row_pos = self.indptr[row_idx + 1];
-        let s = cur_row_pos + col_idx;
-        if s < end_row_pos {
-            let elements = (s..min(s + num, end_row_pos))
-                .map(|idx| Element::new([row_idx, self.indices[idx]], self.data[idx]))
-                .collect::<Vec<Element>>();
+        let (indices, data) = self.row_entries(row_idx);
+        let s = col_idx;
+        if s < indices.len() {
+            let elements = (s..min(s + num, indices.len()))
+                .map(|idx| Element::new([row_idx, indices[idx]], data[idx]))
+                .collect::<Vec<Element<T>>>();
             if self.track_count {
                 self.read_count += elements.len() * 2;
+                self.read_bytes += fiber_bytes(
+                    elements.len(),
+                    self.word_byte,
+                    self.index_byte,
+                    col_idx == 0,
+                );
             }
             return Ok(elements);
         } else {
@@ -323,25 +594,33 @@ impl CsrMatStorage {
     }
 }
 
-pub struct VectorStorage {
-    pub data: HashMap<usize, CsrRow>,
+#[derive(Serialize, Deserialize)]
+pub struct VectorStorage<T = f64> {
+    pub data: HashMap<usize, CsrRow<T>>,
     pub read_count: usize,
     pub write_count: usize,
     pub track_count: bool,
+    // Byte accounting related.
+    pub word_byte: usize,
+    pub index_byte: usize,
+    pub read_bytes: usize,
+    pub write_bytes: usize,
 }
 
-impl StorageAPI for VectorStorage {
+impl<T: Scalar> StorageAPI<T> for VectorStorage<T> {
     fn read(
         &mut self,
         row_ptr: usize,
         col_s: usize,
         ele_num: usize,
-    ) -> Result<CsrRow, StorageError> {
+    ) -> Result<CsrRow<T>, StorageError> {
         match self.data.get(&row_ptr) {
             Some(csrrow) => {
                 if col_s + ele_num <= csrrow.data.len() {
                     if self.track_count {
                         self.read_count += csrrow.size();
+                        self.read_bytes +=
+                            fiber_bytes(ele_num, self.word_byte, self.index_byte, col_s == 0);
                     }
                     return Ok(CsrRow::new_from_data(
                         csrrow.rowptr,
@@ -366,7 +645,7 @@ impl StorageAPI for VectorStorage {
         }
     }
 
-    fn write(&mut self, rows: &mut Vec<CsrRow>) -> Result<Vec<usize>, StorageError> {
+    fn write(&mut self, rows: &mut Vec<CsrRow<T>>) -> Result<Vec<usize>, StorageError> {
         let mut indptrs = vec![];
         for row in rows.iter_mut() {
             let indptr = row.rowptr;
@@ -378,6 +657,7 @@ impl StorageAPI for VectorStorage {
                 .or_insert(row.to_owned());
             if self.track_count {
                 self.write_count += row.size();
+                self.write_bytes += fiber_bytes(row.len(), self.word_byte, self.index_byte, true);
             }
         }
 
@@ -385,21 +665,27 @@ impl StorageAPI for VectorStorage {
     }
 }
 
-impl VectorStorage {
-    pub fn new() -> VectorStorage {
+impl<T: Scalar> VectorStorage<T> {
+    pub fn new(word_byte: usize, index_byte: usize) -> VectorStorage<T> {
         VectorStorage {
             data: HashMap::new(),
             read_count: 0,
             write_count: 0,
             track_count: true,
+            word_byte,
+            index_byte,
+            read_bytes: 0,
+            write_bytes: 0,
         }
     }
 
-    pub fn read_row(&mut self, row_ptr: usize) -> Result<CsrRow, StorageError> {
+    pub fn read_row(&mut self, row_ptr: usize) -> Result<CsrRow<T>, StorageError> {
         match self.data.get(&row_ptr) {
             Some(csrrow) => {
                 if self.track_count {
                     self.read_count += csrrow.size();
+                    self.read_bytes +=
+                        fiber_bytes(csrrow.len(), self.word_byte, self.index_byte, true);
                 }
                 return Ok(csrrow.clone());
             }
@@ -417,7 +703,7 @@ impl VectorStorage {
         row_idx: usize,
         col_idx: usize,
         num: usize,
-    ) -> Result<Vec<Element>, StorageError> {
+    ) -> Result<Vec<Element<T>>, StorageError> {
         match self.data.get(&row_idx) {
             Some(_cr) => {
                 let elements = self.data.get(&row_idx).unwrap().clone().as_element_vec();
@@ -425,6 +711,12 @@ impl VectorStorage {
                 let ele_size = (col_t - col_idx) * 2;
                 if self.track_count {
                     self.read_count += ele_size;
+                    self.read_bytes += fiber_bytes(
+                        col_t - col_idx,
+                        self.word_byte,
+                        self.index_byte,
+                        col_idx == 0,
+                    );
                 }
                 if col_t == elements.len() {
                     self.data.remove(&row_idx);
@@ -444,7 +736,7 @@ impl VectorStorage {
         self.data.contains_key(row_idx)
     }
 
-    pub fn write_element(&mut self, element: Element) -> Result<usize, StorageError> {
+    pub fn write_element(&mut self, element: Element<T>) -> Result<usize, StorageError> {
         let indptr = element.idx[0];
         self.data
             .entry(indptr)
@@ -452,24 +744,572 @@ impl VectorStorage {
             .append_element(element);
         if self.track_count {
             self.write_count += 2;
+            self.write_bytes += fiber_bytes(1, self.word_byte, self.index_byte, false);
         }
         return Ok(indptr);
     }
 }
 
-pub struct LatencyPriorityCache<'a> {
+// Column-major psum storage for the outer-product dataflow. The partial columns are merged
+// into the stored ones as they are written, so no row-wise merge is needed.
+#[derive(Serialize, Deserialize)]
+pub struct CscPsumStorage<T = f64> {
+    pub data: BTreeMap<usize, BTreeMap<usize, T>>, // col -> row -> psum
+    pub read_count: usize,
+    pub write_count: usize,
+    // Byte accounting related.
+    pub word_byte: usize,
+    pub index_byte: usize,
+    pub read_bytes: usize,
+    pub write_bytes: usize,
+}
+
+impl<T: Scalar> CscPsumStorage<T> {
+    pub fn new(word_byte: usize, index_byte: usize) -> CscPsumStorage<T> {
+        CscPsumStorage {
+            data: BTreeMap::new(),
+            read_count: 0,
+            write_count: 0,
+            word_byte,
+            index_byte,
+            read_bytes: 0,
+            write_bytes: 0,
+        }
+    }
+
+    // Split the psum rows into partial columns and accumulate them. Psums already stored at the
+    // same position are read back for the accumulation.
+    pub fn write_rows(&mut self, rows: Vec<CsrRow<T>>) {
+        let mut partial_cols: BTreeMap<usize, Vec<(usize, T)>> = BTreeMap::new();
+        for csrrow in rows.iter() {
+            for (col, value) in csrrow.indptr.iter().zip(csrrow.data.iter()) {
+                partial_cols
+                    .entry(*col)
+                    .or_default()
+                    .push((csrrow.rowptr, *value));
+            }
+        }
+        for (col, partial_col) in partial_cols {
+            let is_new_col = !self.data.contains_key(&col);
+            let stored_col = self.data.entry(col).or_default();
+            let mut merged_num = 0;
+            for (row, value) in partial_col.iter() {
+                match stored_col.get_mut(row) {
+                    Some(psum) => {
+                        *psum = psum.plus(*value);
+                        merged_num += 1;
+                    }
+                    None => {
+                        stored_col.insert(*row, *value);
+                    }
+                }
+            }
+            if merged_num > 0 {
+                self.read_count += merged_num * 2;
+                self.read_bytes += fiber_bytes(merged_num, self.word_byte, self.index_byte, false);
+            }
+            self.write_count += partial_col.len() * 2;
+            self.write_bytes += fiber_bytes(
+                partial_col.len(),
+                self.word_byte,
+                self.index_byte,
+                is_new_col,
+            );
+        }
+    }
+
+    // Transpose the stored columns back to rows, indexed by row.
+    pub fn to_csr_rows(&self, row_num: usize) -> Vec<CsrRow<T>> {
+        let mut csrrows = (0..row_num).map(CsrRow::new).collect::<Vec<CsrRow<T>>>();
+        for (col, stored_col) in self.data.iter() {
+            for (row, value) in stored_col.iter() {
+                csrrows[*row].data.push(*value);
+                csrrows[*row].indptr.push(*col);
+            }
+        }
+        csrrows
+    }
+}
+
+// Dense output of a GEMM with a few B columns, e.g. SpMV. The psums of a row accumulate in
+// place on chip by their A row, and only the final rows are charged as written back.
+#[derive(Serialize, Deserialize)]
+pub struct DenseOutputStorage<T = f64> {
+    pub col_num: usize,
+    pub data: Vec<Option<T>>, // row * col_num + col -> psum
+    pub accumulate_count: usize,
+    pub write_count: usize,
+    // Byte accounting related.
+    pub word_byte: usize,
+    pub index_byte: usize,
+    pub write_bytes: usize,
+}
+
+impl<T: Scalar> DenseOutputStorage<T> {
+    pub fn new(
+        row_num: usize,
+        col_num: usize,
+        word_byte: usize,
+        index_byte: usize,
+    ) -> DenseOutputStorage<T> {
+        DenseOutputStorage {
+            col_num,
+            data: vec![None; row_num * col_num],
+            accumulate_count: 0,
+            write_count: 0,
+            word_byte,
+            index_byte,
+            write_bytes: 0,
+        }
+    }
+
+    pub fn write_rows(&mut self, rows: Vec<CsrRow<T>>) {
+        for csrrow in rows.iter() {
+            let row_s = csrrow.rowptr * self.col_num;
+            for (col, value) in csrrow.indptr.iter().zip(csrrow.data.iter()) {
+                let is_new_row = self.data[row_s..row_s + self.col_num]
+                    .iter()
+                    .all(|psum| psum.is_none());
+                match self.data[row_s + col].as_mut() {
+                    Some(psum) => {
+                        *psum = psum.plus(*value);
+                        self.accumulate_count += 1;
+                    }
+                    None => {
+                        self.data[row_s + col] = Some(*value);
+                        self.write_count += 2;
+                        self.write_bytes +=
+                            fiber_bytes(1, self.word_byte, self.index_byte, is_new_row);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn to_csr_rows(&self) -> Vec<CsrRow<T>> {
+        self.data
+            .chunks(max(self.col_num, 1))
+            .enumerate()
+            .map(|(rowid, psums)| {
+                let mut csrrow = CsrRow::new(rowid);
+                for (col, psum) in psums.iter().enumerate() {
+                    if let Some(psum) = psum {
+                        csrrow.data.push(*psum);
+                        csrrow.indptr.push(col);
+                    }
+                }
+                csrrow
+            })
+            .collect()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct L1Cache<T = f64> {
+    pub capability: usize,
+    pub cur_num: usize,
+    pub rowmap: HashMap<usize, CsrRow<T>>,
+    pub last_use: HashMap<usize, usize>, // addr -> last access cycle
+    pub read_count: usize,
+    pub miss_count: usize,
+}
+
+impl<T: Scalar> L1Cache<T> {
+    pub fn new(cache_size: usize, word_byte: usize) -> L1Cache<T> {
+        L1Cache {
+            capability: cache_size / word_byte,
+            cur_num: 0,
+            rowmap: HashMap::new(),
+            last_use: HashMap::new(),
+            read_count: 0,
+            miss_count: 0,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.cur_num = 0;
+        self.rowmap.clear();
+        self.last_use.clear();
+        self.read_count = 0;
+        self.miss_count = 0;
+    }
+
+    pub fn contains_row(&self, row_idx: &usize) -> bool {
+        self.rowmap.contains_key(row_idx)
+    }
+
+    pub fn fits(&self, row_size: usize) -> bool {
+        row_size <= self.capability
+    }
+
+    pub fn fill(&mut self, addr: usize, csrrow: CsrRow<T>, cur_cycle: usize) {
+        let row_size = csrrow.size();
+        self.miss_count += row_size;
+        // Evict the least recently used fibers.
+        while self.cur_num + row_size > self.capability {
+            let victim = *self
+                .last_use
+                .iter()
+                .min_by_key(|(addr, cycle)| (**cycle, **addr))
+                .unwrap()
+                .0;
+            self.last_use.remove(&victim);
+            self.cur_num -= self.rowmap.remove(&victim).unwrap().size();
+        }
+        self.cur_num += row_size;
+        self.last_use.insert(addr, cur_cycle);
+        self.rowmap.insert(addr, csrrow);
+    }
+
+    pub fn read_scalars(
+        &mut self,
+        addr: usize,
+        col_s: usize,
+        num: usize,
+        cur_cycle: usize,
+    ) -> Vec<Element<T>> {
+        let elements = self.rowmap.get(&addr).unwrap().clone().as_element_vec();
+        let col_t = min(col_s + num, elements.len());
+        self.read_count += (col_t - col_s) * 2;
+        self.last_use.insert(addr, cur_cycle);
+        elements[col_s..col_t].to_vec()
+    }
+}
+
+// Stack distance profile of the fiber accesses, independent of the configured cache. An access
+// hits in a fully associative LRU cache once the fibers touched since the previous access to the
+// same fiber fit along with it.
+#[derive(Serialize, Deserialize)]
+pub struct ReuseTracker {
+    // LRU stack of (addr, size), the most recent at the end.
+    pub stack: Vec<(usize, usize)>,
+    // Words required to hit rounded up to a power of two -> access count, for [B, psum].
+    pub histograms: [BTreeMap<usize, usize>; 2],
+    pub cold_count: [usize; 2],
+}
+
+impl ReuseTracker {
+    pub fn new() -> ReuseTracker {
+        ReuseTracker {
+            stack: vec![],
+            histograms: [BTreeMap::new(), BTreeMap::new()],
+            cold_count: [0; 2],
+        }
+    }
+
+    pub fn access(&mut self, addr: usize, size: usize, is_psum: bool) {
+        let kind = is_psum as usize;
+        match self.stack.iter().rposition(|(a, _)| *a == addr) {
+            Some(pos) => {
+                let required = self.stack[pos + 1..].iter().map(|(_, s)| s).sum::<usize>() + size;
+                *self.histograms[kind]
+                    .entry(max(required, 1).next_power_of_two())
+                    .or_insert(0) += 1;
+                self.stack.remove(pos);
+            }
+            None => self.cold_count[kind] += 1,
+        }
+        self.stack.push((addr, size));
+    }
+
+    // Predicted hit rates of every power of two capacity up to the one that captures all reuse.
+    pub fn report(&self, word_byte: usize) -> Vec<ReusePoint> {
+        let max_words = self
+            .histograms
+            .iter()
+            .filter_map(|h| h.keys().last().cloned())
+            .max()
+            .unwrap_or(1);
+        let totals = [0, 1]
+            .map(|kind| self.histograms[kind].values().sum::<usize>() + self.cold_count[kind]);
+        let hit_rate = |kind: usize, words: usize| {
+            let hits = self.histograms[kind]
+                .range(..=words)
+                .map(|(_, c)| c)
+                .sum::<usize>();
+            hits as f64 / max(totals[kind], 1) as f64
+        };
+        let mut points = vec![];
+        let mut words = 1;
+        while words <= max_words {
+            points.push(ReusePoint {
+                cache_size: words * word_byte,
+                b_hit_rate: hit_rate(0, words),
+                psum_hit_rate: hit_rate(1, words),
+            });
+            words *= 2;
+        }
+        points
+    }
+}
+
+// Latency model of the DRAM channels behind the fiber cache.
+#[derive(Serialize, Deserialize)]
+pub struct DramChannels {
+    pub mem_latency: MemLatency,
+    rng: ChaCha12Rng,
+    // Observed access latencies, including the channel waits.
+    pub latency_range: Option<[usize; 2]>,
+    pub latency_sum: usize,
+    pub access_num: usize,
+    // Channel conflict related.
+    pub serialize: bool,
+    pub busy_until: Vec<usize>, // channel -> cycle it becomes free
+    pub conflict_cycles: usize,
+    // Open-page row buffer related, disabled if row_fibers is 0.
+    pub bank_num: usize,
+    pub row_fibers: usize,
+    pub hit_latency: usize,
+    pub precharge_latency: usize,
+    pub open_rows: Vec<Option<usize>>, // channel * bank_num + bank -> open DRAM row
+    pub row_buffer_hits: usize,
+    pub row_buffer_misses: usize,
+    // Bytes fetched through each channel, in total and since the current epoch started.
+    pub channel_bytes: Vec<usize>,
+    pub epoch_bytes: Vec<usize>,
+}
+
+impl DramChannels {
+    pub fn new(
+        channel_num: usize,
+        mem_latency: MemLatency,
+        seed: u64,
+        serialize: bool,
+        bank_num: usize,
+        row_fibers: usize,
+        hit_latency: usize,
+        precharge_latency: usize,
+    ) -> DramChannels {
+        DramChannels {
+            mem_latency,
+            rng: ChaCha12Rng::seed_from_u64(seed),
+            latency_range: None,
+            latency_sum: 0,
+            access_num: 0,
+            serialize,
+            busy_until: vec![0; channel_num],
+            conflict_cycles: 0,
+            bank_num,
+            row_fibers,
+            hit_latency,
+            precharge_latency,
+            open_rows: vec![None; channel_num * bank_num],
+            row_buffer_hits: 0,
+            row_buffer_misses: 0,
+            channel_bytes: vec![0; channel_num],
+            epoch_bytes: vec![0; channel_num],
+        }
+    }
+
+    // Fibers are interleaved across channels by their address.
+    pub fn channel_of(&self, addr: usize) -> usize {
+        addr % self.busy_until.len()
+    }
+
+    // The bytes of each channel in the current epoch, which starts over.
+    pub fn take_epoch_bytes(&mut self) -> Vec<usize> {
+        let channel_num = self.epoch_bytes.len();
+        std::mem::replace(&mut self.epoch_bytes, vec![0; channel_num])
+    }
+
+    // Consecutive fibers of a channel share a DRAM row, and DRAM rows are interleaved across banks.
+    pub fn bank_row_of(&self, addr: usize) -> [usize; 2] {
+        let dram_row = addr / self.busy_until.len() / self.row_fibers;
+        [dram_row % self.bank_num, dram_row / self.bank_num]
+    }
+
+    // A fixed latency does not draw from the RNG.
+    fn sample_latency(&mut self) -> usize {
+        match self.mem_latency {
+            MemLatency::Fixed(latency) => latency,
+            MemLatency::Uniform { min, max } => self.rng.gen_range(min..=max),
+            MemLatency::TwoPoint {
+                open,
+                closed,
+                open_rate,
+            } => {
+                if self.rng.gen::<f32>() < open_rate {
+                    open
+                } else {
+                    closed
+                }
+            }
+        }
+    }
+
+    // Issue an access and return its latency, including the wait for earlier ones on its channel.
+    pub fn access(&mut self, addr: usize, bytes: usize, cur_cycle: usize) -> usize {
+        let channel = self.channel_of(addr);
+        self.channel_bytes[channel] += bytes;
+        self.epoch_bytes[channel] += bytes;
+        let mem_latency = self.sample_latency();
+        let service_latency = if self.row_fibers == 0 {
+            mem_latency
+        } else {
+            let [bank, row] = self.bank_row_of(addr);
+            let open_row = &mut self.open_rows[channel * self.bank_num + bank];
+            let latency = match *open_row {
+                Some(r) if r == row => {
+                    self.row_buffer_hits += 1;
+                    self.hit_latency
+                }
+                Some(_) => {
+                    self.row_buffer_misses += 1;
+                    mem_latency + self.precharge_latency
+                }
+                None => {
+                    self.row_buffer_misses += 1;
+                    mem_latency
+                }
+            };
+            *open_row = Some(row);
+            latency
+        };
+        let mut wait_cycle = 0;
+        if self.serialize {
+            let start_cycle = max(cur_cycle, self.busy_until[channel]);
+            self.busy_until[channel] = start_cycle + service_latency;
+            wait_cycle = start_cycle - cur_cycle;
+            self.conflict_cycles += wait_cycle;
+        }
+        let latency = wait_cycle + service_latency;
+        self.latency_range = Some(match self.latency_range {
+            Some([min_latency, max_latency]) => {
+                [min(min_latency, latency), max(max_latency, latency)]
+            }
+            None => [latency, latency],
+        });
+        self.latency_sum += latency;
+        self.access_num += 1;
+        return latency;
+    }
+}
+
+// Banked psum SRAM, accesses to the same bank in a cycle are serialized.
+#[derive(Serialize, Deserialize)]
+pub struct PsumBanks {
+    pub busy_until: Vec<usize>, // bank -> cycle it becomes free
+    pub conflict_cycles: usize,
+}
+
+impl PsumBanks {
+    pub fn new(bank_num: usize) -> PsumBanks {
+        PsumBanks {
+            busy_until: vec![0; bank_num],
+            conflict_cycles: 0,
+        }
+    }
+
+    // Psum rows are interleaved across banks by their output address.
+    pub fn bank_of(&self, addr: usize) -> usize {
+        addr % self.busy_until.len()
+    }
+
+    // Occupy the bank for a cycle and return the cycles waited for the earlier accesses.
+    pub fn access(&mut self, addr: usize, cur_cycle: usize) -> usize {
+        let bank = self.bank_of(addr);
+        let start_cycle = max(cur_cycle, self.busy_until[bank]);
+        self.busy_until[bank] = start_cycle + 1;
+        let wait_cycle = start_cycle - cur_cycle;
+        self.conflict_cycles += wait_cycle;
+        wait_cycle
+    }
+}
+
+// Why the cache needs a victim: room in the whole cache, a free way in the set of the new line,
+// or room that only the psums can make because the B fibers are too small.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EvictionScope {
+    Cache,
+    Set,
+    Psums,
+}
+
+// A resident fiber offered for eviction.
+#[derive(Debug, Clone, Copy)]
+pub struct VictimCandidate {
+    pub addr: usize,
+    // The A row of its latest use, the smaller the colder.
+    pub last_use: usize,
+    // Resident words.
+    pub size: usize,
+    pub is_psum: bool,
+    // Evicting it writes it back to psum memory.
+    pub dirty: bool,
+}
+
+// Chooses the address of the line to evict. The candidates are never empty and come sorted by
+// address, space_missing is the number of words still to free.
+pub trait VictimPolicy {
+    fn select_victim(
+        &mut self,
+        scope: EvictionScope,
+        candidates: &[VictimCandidate],
+        space_missing: usize,
+    ) -> usize;
+}
+
+// The line whose last use is the earliest A row. When only the psums can make room, the oldest
+// allocated one.
+pub struct LruVictim;
+
+impl VictimPolicy for LruVictim {
+    fn select_victim(
+        &mut self,
+        scope: EvictionScope,
+        candidates: &[VictimCandidate],
+        _space_missing: usize,
+    ) -> usize {
+        match scope {
+            EvictionScope::Psums => candidates[0].addr,
+            _ => {
+                candidates
+                    .iter()
+                    .min_by_key(|c| (c.last_use, c.addr))
+                    .unwrap()
+                    .addr
+            }
+        }
+    }
+}
+
+// The coldest line large enough to make room alone, instead of several smaller ones.
+pub struct SizeAwareVictim;
+
+impl VictimPolicy for SizeAwareVictim {
+    fn select_victim(
+        &mut self,
+        scope: EvictionScope,
+        candidates: &[VictimCandidate],
+        space_missing: usize,
+    ) -> usize {
+        if scope == EvictionScope::Cache {
+            if let Some(c) = candidates
+                .iter()
+                .filter(|c| c.size >= space_missing)
+                .min_by_key(|c| (c.last_use, c.addr))
+            {
+                return c.addr;
+            }
+        }
+        LruVictim.select_victim(scope, candidates, space_missing)
+    }
+}
+
+#[derive(Serialize)]
+pub struct LatencyPriorityCache<'a, T = f64> {
     pub cache_size: usize,
     pub word_byte: usize,
     pub capability: usize,
     pub cur_num: usize,
     pub read_count: usize,
     pub write_count: usize,
-    pub rowmap: HashMap<usize, CsrRow>,
-    pub priority_queue: BinaryHeap<Reverse<[usize; 2]>>,
+    pub rowmap: HashMap<usize, CsrRow<T>>,
+    // The A row of the latest use of each fiber.
     pub valid_pq_row_dict: HashMap<usize, usize>,
     pub output_base_addr: usize,
-    pub b_mem: &'a mut CsrMatStorage,
-    pub psum_mem: &'a mut VectorStorage,
+    pub b_mem: &'a mut CsrMatStorage<T>,
+    pub psum_mem: &'a mut VectorStorage<T>,
     pub miss_count: usize,
     pub b_evict_count: usize,
     pub psum_evict_count: usize,
@@ -479,28 +1319,87 @@ pub struct LatencyPriorityCache<'a> {
     // Latency related.
     pub mem_latency: usize,
     pub cache_latency: usize,
+    #[serde(with = "crate::util::pair_map")]
     pub pending_request: HashMap<[usize; 2], usize>, // addr -> finish cycle
+    // Set associativity related.
+    pub ways: Option<usize>,
+    pub set_num: usize,
+    pub set_lines: Vec<usize>,
+    pub conflict_evicted: HashSet<usize>,
+    pub conflict_miss_count: usize,
+    // Miss classification related. The words of each touched B fiber not missed yet, the first
+    // misses of a fiber up to its footprint are compulsory.
+    pub unmissed: HashMap<usize, usize>,
+    pub compulsory_miss_count: usize,
+    // Line granularity related, in words.
+    pub line_words: Option<usize>,
+    // The B fibers with only their leading lines resident, and how many.
+    pub resident_lines: HashMap<usize, usize>,
+    pub line_miss_count: usize,
+    // Write back related.
+    pub write_back: bool,
+    pub dirty: HashSet<usize>,
+    pub replacement_policy: ReplacementPolicy,
+    #[serde(skip)]
+    pub victim_policy: Box<dyn VictimPolicy>,
+    // The B fibers never offered as victims once loaded.
+    pub pinned: HashSet<usize>,
+    // Prefetch related.
+    pub prefetch: bool,
+    pub prefetched: HashSet<usize>,
+    pub last_miss: Option<usize>,
+    pub miss_stride: usize,
+    pub prefetch_count: usize,
+    pub prefetch_hit_count: usize,
+    // Every access goes to memory when there is no cache.
+    pub bypass: bool,
+    // Only recorded for the reuse analysis.
+    pub reuse_tracker: Option<ReuseTracker>,
+}
+
+// Derive the set number so that each set holds `ways` average sized B fibers.
+fn set_num<T: Scalar>(capability: usize, ways: Option<usize>, b_mem: &CsrMatStorage<T>) -> usize {
+    match ways {
+        Some(ways) => {
+            let b_avg_size = max(1, 2 * b_mem.nnz() / max(1, b_mem.row_num()));
+            max(1, capability / (max(1, ways) * b_avg_size))
+        }
+        None => 1,
+    }
 }
 
-impl<'a> LatencyPriorityCache<'a> {
+impl<'a, T: Scalar> LatencyPriorityCache<'a, T> {
     pub fn new(
         cache_size: usize,
         word_byte: usize,
         output_base_addr: usize,
-        b_mem: &'a mut CsrMatStorage,
-        psum_mem: &'a mut VectorStorage,
+        b_mem: &'a mut CsrMatStorage<T>,
+        psum_mem: &'a mut VectorStorage<T>,
         mem_latency: usize,
         cache_latency: usize,
-    ) -> LatencyPriorityCache<'a> {
+        ways: Option<usize>,
+        line_size: Option<usize>,
+        write_back: bool,
+        prefetch: bool,
+        infinite: bool,
+        replacement_policy: ReplacementPolicy,
+    ) -> LatencyPriorityCache<'a, T> {
+        // An infinite cache never has to evict, so it is also fully associative.
+        let capability = if infinite {
+            usize::MAX
+        } else {
+            cache_size / word_byte
+        };
+        let ways = if infinite { None } else { ways };
+        let set_num = set_num(capability, ways, b_mem);
         LatencyPriorityCache {
             cache_size: cache_size,
             word_byte: word_byte,
-            capability: cache_size / word_byte,
+            capability,
             cur_num: 0,
             read_count: 0,
             write_count: 0,
             rowmap: HashMap::new(),
-            priority_queue: BinaryHeap::new(),
             valid_pq_row_dict: HashMap::new(),
             output_base_addr: output_base_addr,
             b_mem: b_mem,
@@ -514,15 +1413,204 @@ impl<'a> LatencyPriorityCache<'a> {
             mem_latency,
             cache_latency,
             pending_request: HashMap::new(),
+            ways,
+            set_num,
+            set_lines: vec![0; set_num],
+            conflict_evicted: HashSet::new(),
+            conflict_miss_count: 0,
+            unmissed: HashMap::new(),
+            compulsory_miss_count: 0,
+            line_words: line_size.map(|line_size| max(1, line_size / word_byte)),
+            resident_lines: HashMap::new(),
+            line_miss_count: 0,
+            write_back,
+            dirty: HashSet::new(),
+            replacement_policy,
+            victim_policy: match replacement_policy {
+                ReplacementPolicy::Lru => Box::new(LruVictim),
+                ReplacementPolicy::SizeAware => Box::new(SizeAwareVictim),
+            },
+            pinned: HashSet::new(),
+            prefetch,
+            prefetched: HashSet::new(),
+            last_miss: None,
+            miss_stride: 1,
+            prefetch_count: 0,
+            prefetch_hit_count: 0,
+            bypass: capability == 0,
+            reuse_tracker: None,
+        }
+    }
+
+    // Empty the cache for the B and psum memories of another GEMM, which are already in place
+    // behind the references. The sets are sized again by the new average B fiber.
+    pub fn reset(&mut self, output_base_addr: usize) {
+        self.cur_num = 0;
+        self.read_count = 0;
+        self.write_count = 0;
+        self.rowmap.clear();
+        self.valid_pq_row_dict.clear();
+        self.output_base_addr = output_base_addr;
+        self.miss_count = 0;
+        self.b_evict_count = 0;
+        self.psum_evict_count = 0;
+        self.b_occp = 0;
+        self.psum_occp = 0;
+        self.track_count = true;
+        self.pending_request.clear();
+        self.set_num = set_num(self.capability, self.ways, self.b_mem);
+        self.set_lines.clear();
+        self.set_lines.resize(self.set_num, 0);
+        self.conflict_evicted.clear();
+        self.conflict_miss_count = 0;
+        self.unmissed.clear();
+        self.compulsory_miss_count = 0;
+        self.resident_lines.clear();
+        self.line_miss_count = 0;
+        self.dirty.clear();
+        self.pinned.clear();
+        self.prefetched.clear();
+        self.last_miss = None;
+        self.miss_stride = 1;
+        self.prefetch_count = 0;
+        self.prefetch_hit_count = 0;
+        self.reuse_tracker = None;
+    }
+
+    // Record a fiber access for the reuse analysis.
+    fn record_reuse(&mut self, addr: usize) {
+        if self.reuse_tracker.is_none() || !self.track_count {
+            return;
+        }
+        let is_psum = self.is_psum_row(addr);
+        let size = match self.rowmap.get(&addr) {
+            Some(csrrow) => csrrow.size(),
+            None if is_psum => self.psum_mem.data.get(&addr).map_or(0, |r| r.size()),
+            None => self.b_mem.get_ele_num(addr, addr + 1) * 2,
+        };
+        self.reuse_tracker
+            .as_mut()
+            .unwrap()
+            .access(addr, size, is_psum);
+    }
+
+    // Restore the state serialized from a cache. The B and psum memories are restored in place
+    // behind the references.
+    pub fn load_state(&mut self, mut state: Value) -> Result<(), serde_json::Error> {
+        *self.b_mem = Deserialize::deserialize(state["b_mem"].take())?;
+        *self.psum_mem = Deserialize::deserialize(state["psum_mem"].take())?;
+        self.pending_request = pair_map::deserialize(state["pending_request"].take())?;
+        load_state_fields!(
+            self,
+            state,
+            cache_size,
+            word_byte,
+            capability,
+            cur_num,
+            read_count,
+            write_count,
+            rowmap,
+            valid_pq_row_dict,
+            output_base_addr,
+            miss_count,
+            b_evict_count,
+            psum_evict_count,
+            b_occp,
+            psum_occp,
+            track_count,
+            mem_latency,
+            cache_latency,
+            ways,
+            set_num,
+            set_lines,
+            conflict_evicted,
+            conflict_miss_count,
+            unmissed,
+            compulsory_miss_count,
+            line_words,
+            resident_lines,
+            line_miss_count,
+            write_back,
+            dirty,
+            replacement_policy,
+            pinned,
+            prefetch,
+            prefetched,
+            last_miss,
+            miss_stride,
+            prefetch_count,
+            prefetch_hit_count,
+            bypass,
+            reuse_tracker,
+        );
+        Ok(())
+    }
+
+    pub fn set_of(&self, addr: usize) -> usize {
+        addr % self.set_num
+    }
+
+    // Words taken by a fiber of the given size, rounded up to whole lines.
+    pub fn footprint(&self, size: usize) -> usize {
+        match self.line_words {
+            Some(line_words) => (size + line_words - 1) / line_words * line_words,
+            None => size,
+        }
+    }
+
+    // Words of a cached fiber that are actually resident.
+    fn resident_space(&self, addr: usize) -> usize {
+        match self.resident_lines.get(&addr) {
+            Some(lines) => lines * self.line_words.unwrap(),
+            None => self.footprint(self.rowmap[&addr].size()),
+        }
+    }
+
+    // Whether the elements before col_t reach the evicted tail lines of a fiber.
+    fn touches_evicted(&self, addr: usize, col_t: usize) -> bool {
+        match self.resident_lines.get(&addr) {
+            Some(lines) => {
+                let col_t = min(col_t, self.rowmap[&addr].len());
+                2 * col_t > lines * self.line_words.unwrap()
+            }
+            None => false,
         }
     }
 
-    fn rowmap_insert(&mut self, rowptr: usize, csrrow: CsrRow) {
-        self.rowmap.insert(rowptr, csrrow);
+    // Fetch the evicted tail lines of a fiber back from B memory.
+    fn refill_lines(&mut self, addr: usize) {
+        let lines = match self.resident_lines.remove(&addr) {
+            Some(lines) => lines,
+            None => return,
+        };
+        let resident = lines * self.line_words.unwrap();
+        let missing = self.footprint(self.rowmap[&addr].size()) - resident;
+        // Charge the memory for the elements that are not entirely resident.
+        let col_s = resident / 2;
+        let ele_num = self.rowmap[&addr].len() - col_s;
+        self.b_mem.read_scalars(addr, col_s, ele_num).unwrap();
+        self.record_miss(addr, missing);
+        self.freeup_space(addr, missing).unwrap();
+        self.cur_num += missing;
+        self.b_occp += missing;
+        if self.track_count {
+            self.write_count += missing;
+        }
     }
 
-    fn rowmap_remove(&mut self, rowptr: &usize) -> Option<CsrRow> {
+    fn rowmap_insert(&mut self, rowptr: usize, csrrow: CsrRow<T>) {
+        if self.rowmap.insert(rowptr, csrrow).is_none() {
+            let set = self.set_of(rowptr);
+            self.set_lines[set] += 1;
+        }
+    }
+
+    fn rowmap_remove(&mut self, rowptr: &usize) -> Option<CsrRow<T>> {
         let csrrow = self.rowmap.remove(rowptr);
+        if csrrow.is_some() {
+            let set = self.set_of(*rowptr);
+            self.set_lines[set] -= 1;
+        }
         csrrow
     }
 
@@ -536,50 +1624,29 @@ impl<'a> LatencyPriorityCache<'a> {
         });
         if consumed {
             trace_println!("*{} released after consume.", rowptr);
-            self.rowmap.remove(rowptr);
-        }
-    }
-
-    fn priority_queue_push(&mut self, a_loc: [usize; 2]) {
-        self.priority_queue.push(Reverse(a_loc));
-    }
-
-    fn priority_queue_pop(&mut self, pinned_addrs: Vec<usize>) -> Option<[usize; 2]> {
-        let mut popped = vec![];
-        loop {
-            let p = self.priority_queue.pop().map(|s| s.0);
-            popped.push(p);
-            if p.is_none() || !pinned_addrs.contains(&p.unwrap()[1]) {
-                break;
-            }
-        }
-        let result = popped.pop().unwrap();
-        for p in popped {
-            if p.is_some() {
-                self.priority_queue_push(p.unwrap());
-            }
+            self.rowmap_remove(rowptr);
+            self.dirty.remove(rowptr);
         }
-        return result;
     }
 
-    pub fn write(&mut self, csrrow: CsrRow, a_loc: [usize; 2]) {
+    pub fn write(&mut self, csrrow: CsrRow<T>, a_loc: [usize; 2]) {
         let row_size = csrrow.size();
-        // Freeup space first if necessary.
-        if self.cur_num + row_size <= self.capability {
-            self.cur_num += row_size;
-        } else {
-            if let Err(err) = self.freeup_space(csrrow.rowptr, row_size) {
+        let space = self.footprint(row_size);
+        // Freeup space first if necessary, a set-associative cache may also have to free a line
+        // of its set while there is space left.
+        if self.ways.is_some() || self.cur_num + space > self.capability {
+            if let Err(err) = self.freeup_space(a_loc[1], space) {
                 panic!("{}", err);
             }
-            self.cur_num += row_size;
         }
+        self.cur_num += space;
 
         // println!("*cache write invoked with count {} row {}", self.write_count, row_size);
         if self.is_psum_row(csrrow.rowptr) {
-            self.psum_occp += row_size;
-            trace_println!("*psum write: + {} -> {}", row_size, self.psum_occp);
+            self.psum_occp += space;
+            trace_println!("*psum write: + {} -> {}", space, self.psum_occp);
         } else {
-            self.b_occp += row_size;
+            self.b_occp += space;
         }
 
         // Update priority status.
@@ -587,64 +1654,56 @@ impl<'a> LatencyPriorityCache<'a> {
             .entry(a_loc[1])
             .and_modify(|x| *x = max(*x, a_loc[0]))
             .or_insert(a_loc[0]);
-        self.priority_queue_push([self.valid_pq_row_dict[&a_loc[1]], a_loc[1]]);
 
         if self.track_count {
             self.write_count += row_size;
         }
 
+        // B fibers filled without a miss, e.g. prefetched, leave no compulsory miss.
+        if !self.is_psum_row(a_loc[1]) {
+            self.unmissed.entry(a_loc[1]).or_insert(0);
+        }
+        // Lines filled from memory are clean.
+        self.dirty.remove(&a_loc[1]);
+        self.resident_lines.remove(&a_loc[1]);
         self.rowmap_insert(a_loc[1], csrrow);
     }
 
     pub fn freeup_space(&mut self, addr: usize, space_required: usize) -> Result<(), String> {
-        while self.priority_queue.len() > 0 && (self.cur_num + space_required > self.capability) {
+        // A new line in a set-associative cache can only replace lines of its own set.
+        if let Some(ways) = self.ways {
+            let set = self.set_of(addr);
+            while !self.rowmap.contains_key(&addr) && self.set_lines[set] >= ways {
+                let space_missing = (self.cur_num + space_required).saturating_sub(self.capability);
+                let poprow = match self.select_victim(EvictionScope::Set, addr, space_missing) {
+                    Some(poprow) => poprow,
+                    None => break,
+                };
+                // The victim would have survived in a fully associative cache.
+                if self.cur_num + space_required <= self.capability {
+                    self.conflict_evicted.insert(poprow);
+                }
+                self.evict_row(poprow);
+            }
+        }
+
+        while self.cur_num + space_required > self.capability {
             // trace_println!(
             //     "freeup_space: space_required: {} by {}",
             //     space_required,
             //     addr
             // );
-            let poprow: usize;
-            if self.b_occp < space_required {
-                poprow = *self
-                    .rowmap
-                    .keys()
-                    .filter(|&&rowid| self.is_psum_row(rowid) && rowid != addr)
-                    .next()
-                    .unwrap();
-            } else {
-                loop {
-                    let popid = self.priority_queue_pop(vec![addr]).unwrap();
-                    // trace_println!("freeup_space: popid: {:?}", popid);
-                    if self.valid_pq_row_dict[&popid[1]] == popid[0]
-                        && self.rowmap.contains_key(&popid[1])
-                    {
-                        poprow = popid[1];
-                        break;
-                    }
-                }
-            }
-            if self.is_psum_row(poprow) {
-                let popped_csrrow = self.rowmap_remove(&poprow).unwrap();
-                // trace_println!("*freerow {:?} and get {}", poprow, popped_csrrow.size());
-                self.cur_num -= popped_csrrow.size();
-                if self.track_count {
-                    self.psum_evict_count += popped_csrrow.size();
-                }
-                self.psum_occp -= popped_csrrow.size();
-                trace_println!(
-                    "*psum freeup: - {} -> {}",
-                    popped_csrrow.size(),
-                    self.psum_occp
-                );
-                self.psum_mem.write(&mut vec![popped_csrrow]).unwrap();
+            let space_missing = self.cur_num + space_required - self.capability;
+            // Fall back to the unpinned B fibers when no other psum is left to evict.
+            let poprow = if self.b_occp - self.pinned_occp() < space_required {
+                self.select_victim(EvictionScope::Psums, addr, space_missing)
+                    .or_else(|| self.select_victim(EvictionScope::Cache, addr, space_missing))
             } else {
-                let evict_size = self.rowmap_remove(&poprow).unwrap().size();
-                // trace_println!("*freerow {:?} and get {}", poprow, evict_size);
-                self.cur_num -= evict_size;
-                self.b_occp -= evict_size;
-                if self.track_count {
-                    self.b_evict_count += evict_size;
-                }
+                self.select_victim(EvictionScope::Cache, addr, space_missing)
+            };
+            match poprow {
+                Some(poprow) => self.evict_lines(poprow, space_missing),
+                None => break,
             }
         }
         if self.cur_num + space_required > self.capability {
@@ -657,44 +1716,257 @@ impl<'a> LatencyPriorityCache<'a> {
         }
     }
 
+    // Words of the pinned B fibers in the cache.
+    fn pinned_occp(&self) -> usize {
+        self.pinned
+            .iter()
+            .filter(|rowid| self.rowmap.contains_key(rowid))
+            .map(|rowid| self.resident_space(*rowid))
+            .sum()
+    }
+
+    // Replace the victim chooser, e.g. for eviction experiments.
+    pub fn set_victim_policy(&mut self, victim_policy: Box<dyn VictimPolicy>) {
+        self.victim_policy = victim_policy;
+    }
+
+    // Offer the resident lines of the scope other than addr to the victim policy.
+    fn select_victim(
+        &mut self,
+        scope: EvictionScope,
+        addr: usize,
+        space_missing: usize,
+    ) -> Option<usize> {
+        let set = self.set_of(addr);
+        let mut candidates = self
+            .rowmap
+            .keys()
+            .filter(|&&rowid| rowid != addr && !self.pinned.contains(&rowid))
+            .filter(|&&rowid| match scope {
+                EvictionScope::Cache => true,
+                EvictionScope::Set => self.set_of(rowid) == set,
+                EvictionScope::Psums => self.is_psum_row(rowid),
+            })
+            .filter_map(|&rowid| {
+                let is_psum = self.is_psum_row(rowid);
+                self.valid_pq_row_dict
+                    .get(&rowid)
+                    .map(|&last_use| VictimCandidate {
+                        addr: rowid,
+                        last_use,
+                        size: self.resident_space(rowid),
+                        is_psum,
+                        dirty: is_psum && (!self.write_back || self.dirty.contains(&rowid)),
+                    })
+            })
+            .collect::<Vec<VictimCandidate>>();
+        if candidates.is_empty() {
+            return None;
+        }
+        candidates.sort_by_key(|c| c.addr);
+        let victim = self
+            .victim_policy
+            .select_victim(scope, &candidates, space_missing);
+        assert!(
+            candidates.binary_search_by_key(&victim, |c| c.addr).is_ok(),
+            "The victim policy chose {}, which is not a candidate",
+            victim
+        );
+        Some(victim)
+    }
+
+    // Only drop the tail lines of a B fiber that make the missing space, its leading lines can
+    // still hit.
+    fn evict_lines(&mut self, poprow: usize, space_missing: usize) {
+        let line_words = match self.line_words {
+            Some(line_words) if !self.is_psum_row(poprow) => line_words,
+            _ => return self.evict_row(poprow),
+        };
+        let lines = self.resident_space(poprow) / line_words;
+        let evict_lines = (space_missing + line_words - 1) / line_words;
+        if evict_lines >= lines {
+            return self.evict_row(poprow);
+        }
+        self.resident_lines.insert(poprow, lines - evict_lines);
+        let evict_size = evict_lines * line_words;
+        self.cur_num -= evict_size;
+        self.b_occp -= evict_size;
+        if self.track_count {
+            self.b_evict_count += evict_size;
+        }
+    }
+
+    fn evict_row(&mut self, poprow: usize) {
+        self.prefetched.remove(&poprow);
+        if self.is_psum_row(poprow) {
+            let popped_csrrow = self.rowmap_remove(&poprow).unwrap();
+            let evict_size = self.footprint(popped_csrrow.size());
+            // trace_println!("*freerow {:?} and get {}", poprow, popped_csrrow.size());
+            self.cur_num -= evict_size;
+            if self.track_count {
+                self.psum_evict_count += evict_size;
+            }
+            self.psum_occp -= evict_size;
+            trace_println!("*psum freeup: - {} -> {}", evict_size, self.psum_occp);
+            self.write_back_psum(poprow, popped_csrrow);
+        } else {
+            let evict_size = self.resident_space(poprow);
+            self.rowmap_remove(&poprow);
+            self.resident_lines.remove(&poprow);
+            // trace_println!("*freerow {:?} and get {}", poprow, evict_size);
+            self.cur_num -= evict_size;
+            self.b_occp -= evict_size;
+            if self.track_count {
+                self.b_evict_count += evict_size;
+            }
+        }
+    }
+
+    fn prefetch_after_miss(&mut self, a_loc: [usize; 2]) {
+        // Follow the stride between consecutive misses, fall back to the next line.
+        let stride = match self.last_miss {
+            Some(last_miss) if a_loc[1] > last_miss => a_loc[1] - last_miss,
+            _ => 1,
+        };
+        let target = if stride == self.miss_stride {
+            a_loc[1] + stride
+        } else {
+            a_loc[1] + 1
+        };
+        self.miss_stride = stride;
+        self.last_miss = Some(a_loc[1]);
+
+        if target >= self.b_mem.row_num() || self.rowmap.contains_key(&target) {
+            return;
+        }
+        let row_size = self.b_mem.get_ele_num(target, target + 1) * 2;
+        // Only prefetch into free space so that no line about to be used is evicted.
+        if row_size == 0 || self.cur_num + self.footprint(row_size) > self.capability {
+            return;
+        }
+        if let Some(ways) = self.ways {
+            if self.set_lines[self.set_of(target)] >= ways {
+                return;
+            }
+        }
+        let csrrow = self.b_mem.read_row(target).unwrap();
+        self.write(csrrow, [a_loc[0], target]);
+        self.prefetched.insert(target);
+        if self.track_count {
+            self.prefetch_count += 1;
+        }
+    }
+
+    fn write_back_psum(&mut self, addr: usize, csrrow: CsrRow<T>) {
+        if self.write_back {
+            // Clean lines still have a valid copy in psum memory.
+            if !self.dirty.remove(&addr) {
+                return;
+            }
+            // Replace the stale copy the dirty line was filled from.
+            self.psum_mem.data.remove(&addr);
+        }
+        self.psum_mem.write(&mut vec![csrrow]).unwrap();
+    }
+
+    fn record_miss(&mut self, addr: usize, miss_size: usize) {
+        if self.track_count {
+            // Whole lines are fetched.
+            let miss_size = self.footprint(miss_size);
+            if let Some(line_words) = self.line_words {
+                self.line_miss_count += miss_size / line_words;
+            }
+            self.miss_count += miss_size;
+            // The psums are produced on chip, so only the B fibers have compulsory misses. The
+            // rest are capacity misses.
+            if self.conflict_evicted.remove(&addr) {
+                self.conflict_miss_count += miss_size;
+            } else if !self.is_psum_row(addr) {
+                let footprint = self.footprint(self.b_mem.get_ele_num(addr, addr + 1) * 2);
+                let unmissed = self.unmissed.entry(addr).or_insert(footprint);
+                let compulsory = min(*unmissed, miss_size);
+                *unmissed -= compulsory;
+                self.compulsory_miss_count += compulsory;
+            }
+        }
+    }
+
     pub fn swapout(&mut self, rowid: usize) {
         if self.rowmap.contains_key(&rowid) {
+            let space = self.resident_space(rowid);
+            self.resident_lines.remove(&rowid);
             let popped_csrrow = self.rowmap_remove(&rowid).unwrap();
             trace_println!("swapout {} with size {}", rowid, popped_csrrow.size());
-            self.cur_num -= popped_csrrow.size();
+            self.cur_num -= space;
             if self.is_psum_row(rowid) {
-                self.psum_occp -= popped_csrrow.size();
-                trace_println!(
-                    "*psum swapout: - {} -> {}",
-                    popped_csrrow.size(),
-                    self.psum_occp
-                );
+                self.psum_occp -= space;
+                trace_println!("*psum swapout: - {} -> {}", space, self.psum_occp);
             } else {
-                self.b_occp -= popped_csrrow.size();
+                self.b_occp -= space;
             }
-            self.psum_mem.write(&mut vec![popped_csrrow]).unwrap();
+            self.write_back_psum(rowid, popped_csrrow);
         } else {
             panic!("Swapout non-exist row: {}", rowid);
         }
     }
 
+    // Read out and release a psum from the cache or psum memory.
+    pub fn take_psum(&mut self, addr: usize) -> CsrRow<T> {
+        match self.rowmap_remove(&addr) {
+            Some(csrrow) => {
+                let space = self.footprint(csrrow.size());
+                self.cur_num -= space;
+                self.psum_occp -= space;
+                if self.track_count {
+                    self.read_count += csrrow.size();
+                }
+                self.dirty.remove(&addr);
+                // Drop the copy the line was filled from.
+                self.psum_mem.data.remove(&addr);
+                csrrow
+            }
+            None => {
+                let csrrow = self.psum_mem.read_row(addr).unwrap();
+                self.psum_mem.data.remove(&addr);
+                csrrow
+            }
+        }
+    }
+
+    // Drop what a merged psum left behind, before its address is handed out again.
+    pub fn purge_psum(&mut self, addr: usize) {
+        if let Some(csrrow) = self.rowmap_remove(&addr) {
+            let space = self.footprint(csrrow.size());
+            self.cur_num -= space;
+            self.psum_occp -= space;
+        }
+        self.psum_mem.data.remove(&addr);
+        self.dirty.remove(&addr);
+        self.valid_pq_row_dict.remove(&addr);
+        self.prefetched.remove(&addr);
+        self.conflict_evicted.remove(&addr);
+        self.pending_request.retain(|a_loc, _| a_loc[1] != addr);
+    }
+
     pub fn is_psum_row(&self, rowid: usize) -> bool {
         return rowid >= self.output_base_addr;
     }
 
-    pub fn append_psum_to(&mut self, addr: usize, csrrow: CsrRow) {
+    pub fn append_psum_to(&mut self, addr: usize, csrrow: CsrRow<T>) {
         let row_size = csrrow.size();
 
         // If the same addr psum is in the cache, append to current one.
         if self.rowmap.contains_key(&addr) {
             // Update occp.
-            self.freeup_space(addr, row_size).unwrap();
-            self.cur_num += row_size;
+            let cur_size = self.rowmap[&addr].size();
+            let space = self.footprint(cur_size + row_size) - self.footprint(cur_size);
+            self.freeup_space(addr, space).unwrap();
+            self.cur_num += space;
             if self.is_psum_row(addr) {
-                self.psum_occp += row_size;
-                trace_println!("*psum append: + {} -> {}", row_size, self.psum_occp);
+                self.psum_occp += space;
+                trace_println!("*psum append: + {} -> {}", space, self.psum_occp);
             } else {
-                self.b_occp += row_size;
+                self.b_occp += space;
             }
             // Update write count.
             if self.track_count {
@@ -703,9 +1975,10 @@ impl<'a> LatencyPriorityCache<'a> {
             // Update data.
             let psum = self.rowmap.get_mut(&addr).unwrap();
             psum.append(csrrow);
+            self.dirty.insert(addr);
 
-        // If swapped out, direct write the partial psum into psum memory.
-        } else if self.psum_mem.contains_row(&addr) {
+        // If swapped out or without a cache, direct write the partial psum into psum memory.
+        } else if self.bypass || self.psum_mem.contains_row(&addr) {
             self.psum_mem.write(&mut vec![csrrow]).unwrap();
 
         // Otherwise, alloc in cache.
@@ -715,15 +1988,15 @@ impl<'a> LatencyPriorityCache<'a> {
                 .entry(addr)
                 .and_modify(|x| *x = max(*x, addr))
                 .or_insert(addr);
-            self.priority_queue_push([self.valid_pq_row_dict[&addr], addr]);
             // Update occp.
-            self.freeup_space(addr, row_size).unwrap();
-            self.cur_num += row_size;
+            let space = self.footprint(row_size);
+            self.freeup_space(addr, space).unwrap();
+            self.cur_num += space;
             if self.is_psum_row(addr) {
-                self.psum_occp += row_size;
-                trace_println!("*psum append: + {} -> {}", row_size, self.psum_occp);
+                self.psum_occp += space;
+                trace_println!("*psum append: + {} -> {}", space, self.psum_occp);
             } else {
-                self.b_occp += row_size;
+                self.b_occp += space;
             }
             // Update write_count.
             if self.track_count {
@@ -731,7 +2004,9 @@ impl<'a> LatencyPriorityCache<'a> {
             }
             // Update data.
             self.rowmap_insert(addr, csrrow);
+            self.dirty.insert(addr);
         }
+        self.record_reuse(addr);
     }
 
     pub fn request_read_scalars(
@@ -741,14 +2016,15 @@ impl<'a> LatencyPriorityCache<'a> {
         num: usize,
         cur_cycle: usize,
         no_delay: bool,
-    ) -> Option<Vec<Element>> {
+    ) -> Option<Vec<Element<T>>> {
         // Read 0 elements should not mean the end of the row, thus return None.
         if num == 0 {
             return None;
         }
         // Pending the request.
         if !self.pending_request.contains_key(&a_loc) {
-            if self.rowmap.contains_key(&a_loc[1]) {
+            // A request that reaches evicted tail lines waits for the memory.
+            if self.rowmap.contains_key(&a_loc[1]) && !self.touches_evicted(a_loc[1], col_s + num) {
                 let ele_row = self.rowmap.get(&a_loc[1]).unwrap().len();
                 let mut b_latency = if col_s == 0 && ele_row > 0 {
                     self.cache_latency
@@ -772,15 +2048,23 @@ impl<'a> LatencyPriorityCache<'a> {
             return None;
         }
         self.pending_request.remove(&a_loc);
+        if col_s == 0 {
+            self.record_reuse(a_loc[1]);
+        }
 
         if self.rowmap.contains_key(&a_loc[1]) {
+            if self.touches_evicted(a_loc[1], col_s + num) {
+                self.refill_lines(a_loc[1]);
+            }
             // Only update when col_s is 0.
             if col_s == 0 {
                 self.valid_pq_row_dict
                     .entry(a_loc[1])
                     .and_modify(|x| *x = max(*x, a_loc[0]))
                     .or_insert(a_loc[0]);
-                self.priority_queue_push([self.valid_pq_row_dict[&a_loc[1]], a_loc[1]]);
+                if self.prefetched.remove(&a_loc[1]) && self.track_count {
+                    self.prefetch_hit_count += 1;
+                }
             }
             let elements = self.rowmap.get(&a_loc[1]).unwrap().clone().as_element_vec();
             let col_t = min(col_s + num, elements.len());
@@ -793,22 +2077,32 @@ impl<'a> LatencyPriorityCache<'a> {
             if self.is_psum_row(a_loc[1]) {
                 match self.psum_mem.read_row(a_loc[1]) {
                     Ok(csrrow) => {
-                        if self.track_count {
-                            self.miss_count += csrrow.size();
+                        self.record_miss(a_loc[1], csrrow.size());
+                        if !self.bypass {
+                            self.write(csrrow.clone(), a_loc);
                         }
-                        self.write(csrrow.clone(), a_loc);
                         let elements = csrrow.as_element_vec();
                         return Some(elements[col_s..min(col_s + num, elements.len())].to_vec());
                     }
                     Err(_) => panic!("{} should be in psum mem but not found!", a_loc[1]),
                 }
+            } else if self.bypass {
+                // Only read the requested part as nothing is kept for the next request.
+                match self.b_mem.read_scalars(a_loc[1], col_s, num) {
+                    Ok(eles) => {
+                        self.record_miss(a_loc[1], eles.len() * 2);
+                        return Some(eles);
+                    }
+                    Err(_) => return Some(vec![]),
+                }
             } else {
                 match self.b_mem.read_row(a_loc[1]) {
                     Ok(csrrow) => {
-                        if self.track_count {
-                            self.miss_count += csrrow.size();
-                        }
+                        self.record_miss(a_loc[1], csrrow.size());
                         self.write(csrrow.clone(), a_loc);
+                        if self.prefetch {
+                            self.prefetch_after_miss(a_loc);
+                        }
                         let elements = csrrow.as_element_vec();
                         return Some(elements[col_s..min(col_s + num, elements.len())].to_vec());
                     }
@@ -825,14 +2119,15 @@ impl<'a> LatencyPriorityCache<'a> {
         num: usize,
         cur_cycle: usize,
         no_delay: bool,
-    ) -> Option<Vec<Element>> {
+    ) -> Option<Vec<Element<T>>> {
         // Read 0 elements should not mean the end of the row, thus return None.
         if num == 0 {
             return None;
         }
         // Pending the request.
         if !self.pending_request.contains_key(&a_loc) {
-            if self.rowmap.contains_key(&a_loc[1]) {
+            // A request that reaches evicted tail lines waits for the memory.
+            if self.rowmap.contains_key(&a_loc[1]) && !self.touches_evicted(a_loc[1], col_s + num) {
                 let ele_row = self.rowmap.get(&a_loc[1]).unwrap().len();
                 let mut b_latency = if col_s == 0 && ele_row > 0 {
                     self.cache_latency
@@ -856,8 +2151,12 @@ impl<'a> LatencyPriorityCache<'a> {
             return None;
         }
         self.pending_request.remove(&a_loc);
+        if col_s == 0 {
+            self.record_reuse(a_loc[1]);
+        }
 
         if self.rowmap.contains_key(&a_loc[1]) {
+            self.refill_lines(a_loc[1]);
             // Convert the csrrow to element vector.
             let elements = self.rowmap.get(&a_loc[1]).unwrap().clone().as_element_vec();
             // Track the tail of the readout.
@@ -869,17 +2168,19 @@ impl<'a> LatencyPriorityCache<'a> {
                 self.read_count += ele_size;
             }
             // Update the occupation.
-            self.cur_num -= ele_size;
+            let cur_size = self.rowmap[&a_loc[1]].size();
+            let space = self.footprint(cur_size) - self.footprint(cur_size - ele_size);
+            self.cur_num -= space;
             if self.is_psum_row(a_loc[1]) {
-                self.psum_occp -= ele_size;
+                self.psum_occp -= space;
                 trace_println!(
                     "*psum {} consume: - {} -> {}",
                     a_loc[1],
-                    ele_size,
+                    space,
                     self.psum_occp
                 );
             } else {
-                self.b_occp -= ele_size;
+                self.b_occp -= space;
             }
             // Release the consumed row after traversing it.
             // if col_t == elements.len() {
@@ -894,8 +2195,8 @@ impl<'a> LatencyPriorityCache<'a> {
                     Ok(eles) => {
                         if self.track_count {
                             self.read_count += eles.len() * 2;
-                            self.miss_count += eles.len() * 2;
                         }
+                        self.record_miss(a_loc[1], eles.len() * 2);
                         Some(eles)
                     }
                     Err(_) => Some(vec![]),
@@ -905,8 +2206,8 @@ impl<'a> LatencyPriorityCache<'a> {
                     Ok(eles) => {
                         if self.track_count {
                             self.read_count += eles.len() * 2;
-                            self.miss_count += eles.len() * 2;
                         }
+                        self.record_miss(a_loc[1], eles.len() * 2);
                         Some(eles)
                     }
                     Err(_) => Some(vec![]),
@@ -919,22 +2220,162 @@ impl<'a> LatencyPriorityCache<'a> {
         self.rowmap.contains_key(row_idx)
     }
 
-    pub fn append_element_to(&mut self, addr: usize, element: Element) {
+    // Keep a B fiber that is already on chip without fetching it. Only free space is used, so
+    // nothing is evicted for it.
+    pub fn install_row(&mut self, csrrow: CsrRow<T>, a_loc: [usize; 2]) {
+        if self.bypass
+            || self.rowmap.contains_key(&a_loc[1])
+            || self.cur_num + self.footprint(csrrow.size()) > self.capability
+        {
+            return;
+        }
+        self.write(csrrow, a_loc);
+    }
+
+    // The non-empty B fibers referenced by A, the most referenced first, and the first A row
+    // referencing each B fiber. The outer product walks the transposed A, whose rows are the
+    // columns of A and so each touch the B fiber of the same id.
+    pub fn rank_b_by_reuse(
+        &self,
+        a_matrix: &CsrMatStorage<T>,
+        a_transposed: bool,
+    ) -> (Vec<usize>, Vec<usize>) {
+        let b_row_num = self.b_mem.row_num();
+        let mut reuse = vec![0; b_row_num];
+        let mut first_use = vec![usize::MAX; b_row_num];
+        for rowid in 0..a_matrix.row_num() {
+            let csrrow = a_matrix.peek_row(rowid);
+            if a_transposed {
+                reuse[rowid] += csrrow.len();
+                first_use[rowid] = min(first_use[rowid], rowid);
+                continue;
+            }
+            for col in csrrow.indptr {
+                reuse[col] += 1;
+                first_use[col] = min(first_use[col], rowid);
+            }
+        }
+        let mut b_rows = (0..b_row_num)
+            .filter(|rowid| reuse[*rowid] > 0 && self.b_mem.get_ele_num(*rowid, *rowid + 1) > 0)
+            .collect::<Vec<usize>>();
+        b_rows.sort_by_key(|rowid| (Reverse(reuse[*rowid]), *rowid));
+        (b_rows, first_use)
+    }
+
+    // Load the B fibers referenced by A before the run, the most reused first. Each fiber is
+    // ranked by its first A row, so the eviction order follows the A traversal. With `all` the
+    // cache grows by the size of B, so that all of it stays resident next to the psums.
+    pub fn preload_b(
+        &mut self,
+        a_matrix: &CsrMatStorage<T>,
+        a_transposed: bool,
+        all: bool,
+    ) -> usize {
+        if self.bypass {
+            return 0;
+        }
+        let (b_rows, first_use) = self.rank_b_by_reuse(a_matrix, a_transposed);
+        if all {
+            let b_size = b_rows
+                .iter()
+                .map(|rowid| 2 * self.b_mem.get_ele_num(*rowid, *rowid + 1))
+                .sum::<usize>();
+            self.capability = self.capability.saturating_add(b_size);
+        }
+
+        // The preload is not counted as cache traffic.
+        let track_count = self.track_count;
+        self.track_count = false;
+        let mut preload_num = 0;
+        for rowid in b_rows {
+            let mut csrrow = self.b_mem.peek_row(rowid);
+            let set_full = self
+                .ways
+                .map_or(false, |ways| self.set_lines[self.set_of(rowid)] >= ways);
+            if set_full || self.cur_num + self.footprint(csrrow.size()) > self.capability {
+                continue;
+            }
+            csrrow.rowptr = rowid;
+            self.write(csrrow, [first_use[rowid], rowid]);
+            preload_num += 1;
+        }
+        self.track_count = track_count;
+        preload_num
+    }
+
+    // Pin the B fibers in order while they fit in the budget. A B fiber and a psum row must always
+    // find room next to the pinned ones, so the budget is capped at the capability minus the
+    // largest of them.
+    pub fn pin_b(
+        &mut self,
+        rowids: &[usize],
+        budget: usize,
+        a_matrix: &CsrMatStorage<T>,
+        a_transposed: bool,
+    ) -> usize {
+        if self.bypass {
+            return 0;
+        }
+        let b_row_num = self.b_mem.row_num();
+        let max_fiber = (0..b_row_num)
+            .map(|rowid| self.footprint(2 * self.b_mem.get_ele_num(rowid, rowid + 1)))
+            .max()
+            .unwrap_or(0);
+        let max_psum = self.footprint(2 * self.max_output_row_len(a_matrix, a_transposed));
+        let budget = min(
+            budget,
+            self.capability.saturating_sub(max(max_fiber, max_psum)),
+        );
+        let mut pinned_size = 0;
+        for &rowid in rowids {
+            if rowid >= b_row_num || self.pinned.contains(&rowid) {
+                continue;
+            }
+            let size = self.footprint(2 * self.b_mem.get_ele_num(rowid, rowid + 1));
+            if size == 0 || pinned_size + size > budget {
+                continue;
+            }
+            pinned_size += size;
+            self.pinned.insert(rowid);
+        }
+        self.pinned.len()
+    }
+
+    // An upper bound of the elements of a psum row, i.e. the size of the B fibers touched by its
+    // A row. The psums of a row are appended unmerged, so it is not capped by the width of B.
+    fn max_output_row_len(&self, a_matrix: &CsrMatStorage<T>, a_transposed: bool) -> usize {
+        let out_row_num = if a_transposed {
+            a_matrix.mat_shape[0]
+        } else {
+            a_matrix.row_num()
+        };
+        let mut row_lens = vec![0; out_row_num];
+        for rowid in 0..a_matrix.row_num() {
+            for col in a_matrix.peek_row(rowid).indptr {
+                if a_transposed {
+                    row_lens[col] += self.b_mem.get_ele_num(rowid, rowid + 1);
+                } else {
+                    row_lens[rowid] += self.b_mem.get_ele_num(col, col + 1);
+                }
+            }
+        }
+        row_lens.into_iter().max().unwrap_or(0)
+    }
+
+    pub fn append_element_to(&mut self, addr: usize, element: Element<T>) {
         let element_size = 2;
         // If the same addr psum is in the cache, append to current one.
         if self.rowmap.contains_key(&addr) {
             // Update occp.
-            self.freeup_space(addr, element_size).unwrap();
-            self.cur_num += element_size;
+            let cur_size = self.rowmap[&addr].size();
+            let space = self.footprint(cur_size + element_size) - self.footprint(cur_size);
+            self.freeup_space(addr, space).unwrap();
+            self.cur_num += space;
             if self.is_psum_row(addr) {
-                self.psum_occp += element_size;
-                trace_println!(
-                    "*psum append element: + {} -> {}",
-                    element_size,
-                    self.psum_occp
-                );
+                self.psum_occp += space;
+                trace_println!("*psum append element: + {} -> {}", space, self.psum_occp);
             } else {
-                self.b_occp += element_size;
+                self.b_occp += space;
             }
             // Update write count.
             if self.track_count {
@@ -943,8 +2384,9 @@ impl<'a> LatencyPriorityCache<'a> {
             // Update data.
             let psum = self.rowmap.get_mut(&addr).unwrap();
             psum.append_element(element);
-        // If swapped out, direct write the partial psum into psum memory.
-        } else if self.psum_mem.contains_row(&addr) {
+            self.dirty.insert(addr);
+        // If swapped out or without a cache, direct write the partial psum into psum memory.
+        } else if self.bypass || self.psum_mem.contains_row(&addr) {
             self.psum_mem.write_element(element).unwrap();
         // Otherwise, alloc in cache.
         } else {
@@ -953,19 +2395,15 @@ impl<'a> LatencyPriorityCache<'a> {
                 .entry(addr)
                 .and_modify(|x| *x = max(*x, addr))
                 .or_insert(addr);
-            self.priority_queue_push([self.valid_pq_row_dict[&addr], addr]);
             // Update occp.
-            self.freeup_space(addr, element_size).unwrap();
-            self.cur_num += element_size;
+            let space = self.footprint(element_size);
+            self.freeup_space(addr, space).unwrap();
+            self.cur_num += space;
             if self.is_psum_row(addr) {
-                self.psum_occp += element_size;
-                trace_println!(
-                    "*psum append element: + {} -> {}",
-                    element_size,
-                    self.psum_occp
-                );
+                self.psum_occp += space;
+                trace_println!("*psum append element: + {} -> {}", space, self.psum_occp);
             } else {
-                self.b_occp += element_size;
+                self.b_occp += space;
             }
             // Update write_count.
             if self.track_count {
@@ -975,6 +2413,7 @@ impl<'a> LatencyPriorityCache<'a> {
             let mut csrrow = CsrRow::new(element.idx[0]);
             csrrow.append_element(element);
             self.rowmap_insert(addr, csrrow);
+            self.dirty.insert(addr);
         }
 
         if self.rowmap.contains_key(&addr) {
@@ -992,7 +2431,7 @@ impl<'a> LatencyPriorityCache<'a> {
         for (id, csrrow) in self.rowmap.iter() {
             if self.is_psum_row(*id) {
                 trace_println!("psum {}: {}", id, csrrow.size());
-                psum_sum += csrrow.size();
+                psum_sum += self.footprint(csrrow.size());
             }
         }
         trace_println!("");
@@ -1005,3 +2444,186 @@ impl<'a> LatencyPriorityCache<'a> {
         );
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::*;
+    use crate::util::{compare_results, reference_spgemm};
+
+    #[test]
+    fn bytes_count_the_indices_and_the_rowptr() {
+        let mut psum_mem = VectorStorage::<f64>::new(8, 4);
+        let csrrow = CsrRow::new_from_data(0, vec![1.0, 2.0, 3.0], vec![0, 4, 7]);
+        psum_mem.write(&mut vec![csrrow]).unwrap();
+        // 3 values with their column indices, and the row pointer.
+        assert_eq!(psum_mem.write_bytes, 3 * (8 + 4) + 4);
+        psum_mem.read_row(0).unwrap();
+        assert_eq!(psum_mem.read_bytes, 40);
+        // A read from the middle of the fiber does not fetch the row pointer again.
+        psum_mem.read(0, 1, 2).unwrap();
+        assert_eq!(psum_mem.read_bytes, 40 + 2 * (8 + 4));
+    }
+
+    #[test]
+    fn same_channel_reads_wait_for_each_other() {
+        let mut dram = DramChannels::new(4, MemLatency::Fixed(30), 0, true, 0, 0, 0, 0);
+        // Fibers 0, 4, 8 and 12 share channel 0, so each waits for all the earlier ones.
+        let latencies = [0, 4, 8, 12]
+            .iter()
+            .map(|addr| dram.access(*addr, 8, 0))
+            .collect::<Vec<usize>>();
+        assert_eq!(latencies, vec![30, 60, 90, 120]);
+        assert_eq!(dram.conflict_cycles, 30 + 60 + 90);
+        // Another channel is free.
+        assert_eq!(dram.access(1, 8, 0), 30);
+    }
+
+    #[test]
+    fn sequential_fibers_hit_the_open_row() {
+        // 4 fibers per DRAM row of one channel, the rows alternate between 2 banks.
+        let row_buffer_count = |addrs: Vec<usize>| {
+            let mut dram = DramChannels::new(1, MemLatency::Fixed(30), 0, false, 2, 4, 10, 5);
+            for addr in addrs.iter() {
+                dram.access(*addr, 8, 0);
+            }
+            [dram.row_buffer_hits, dram.row_buffer_misses]
+        };
+        // Only the first fiber of each of the 4 DRAM rows misses.
+        assert_eq!(row_buffer_count((0..16).collect()), [12, 4]);
+        // Jumping between the rows of bank 0 closes the open row every time.
+        assert_eq!(row_buffer_count(vec![0, 8, 1, 9, 2, 10, 3, 11]), [0, 8]);
+    }
+
+    #[test]
+    fn same_bank_psum_writes_conflict() {
+        let mut psum_banks = PsumBanks::new(4);
+        // Psums 0, 4 and 8 share bank 0 and are written in the same cycle.
+        let waits = [0, 4, 8]
+            .iter()
+            .map(|addr| psum_banks.access(*addr, 0))
+            .collect::<Vec<usize>>();
+        assert_eq!(waits, vec![0, 1, 2]);
+        assert_eq!(psum_banks.conflict_cycles, 3);
+        // The other banks take a write each in that cycle.
+        for addr in [1, 2, 3].iter() {
+            assert_eq!(psum_banks.access(*addr, 0), 0);
+        }
+        assert_eq!(psum_banks.conflict_cycles, 3);
+    }
+
+    #[test]
+    fn sampled_latency_stays_within_bounds() {
+        let latencies = |mem_latency: MemLatency| {
+            // No channel waits, so every latency is a sample.
+            let mut dram = DramChannels::new(4, mem_latency, 0, false, 0, 0, 0, 0);
+            (0..200)
+                .map(|addr| dram.access(addr, 8, 0))
+                .collect::<Vec<usize>>()
+        };
+        let uniform = latencies(MemLatency::Uniform { min: 10, max: 20 });
+        assert!(uniform.iter().all(|l| (10..=20).contains(l)));
+        assert_eq!(uniform.iter().min(), Some(&10));
+        assert_eq!(uniform.iter().max(), Some(&20));
+        assert_eq!(latencies(MemLatency::Fixed(30)), vec![30; 200]);
+    }
+
+    #[test]
+    fn column_permutation_keeps_the_product() {
+        let gemm = test_gemm(12, 10, 0.4, 5);
+        let reference = reference_spgemm(&gemm.a, &gemm.b);
+        let raw_a = gemm.a.clone();
+        let (mut a, mut b) = CsrMatStorage::init_with_gemm(gemm, 8, 4);
+        // Reverse the columns of A and the rows of B.
+        let colmap = (0..10)
+            .map(|colid| (colid, 9 - colid))
+            .collect::<HashMap<usize, usize>>();
+        a.reorder_col(&mut b, colmap);
+        let to_mat = |storage: &CsrMatStorage<f64>, shape: (usize, usize)| {
+            CsMat::new(
+                shape,
+                storage.indptr.clone(),
+                storage.indices.clone(),
+                storage.data.clone(),
+            )
+        };
+        let a = to_mat(&a, (12, 10));
+        let b = to_mat(&b, (10, 12));
+        assert_ne!(a, raw_a);
+        let result = reference_spgemm(&a, &b);
+        assert!(compare_results(&reference, &result, 1e-12).is_empty());
+    }
+
+    // Hits of a fully associative LRU cache of the given words, evicting the oldest fibers.
+    fn lru_hits(accesses: &[(usize, usize)], capacity: usize) -> usize {
+        let mut stack: Vec<(usize, usize)> = vec![];
+        let mut hits = 0;
+        for (addr, size) in accesses.iter() {
+            if let Some(pos) = stack.iter().position(|(a, _)| a == addr) {
+                stack.remove(pos);
+                hits += 1;
+            }
+            stack.push((*addr, *size));
+            while stack.iter().map(|(_, s)| s).sum::<usize>() > capacity {
+                stack.remove(0);
+            }
+        }
+        hits
+    }
+
+    #[test]
+    fn reuse_profile_matches_lru_simulation() {
+        let mut rng = ChaCha12Rng::seed_from_u64(0);
+        // 500 accesses to 20 fibers of 1 to 12 words.
+        let accesses = (0..500)
+            .map(|_| {
+                let addr = rng.gen_range(0..20);
+                (addr, addr % 12 + 1)
+            })
+            .collect::<Vec<(usize, usize)>>();
+        let mut tracker = ReuseTracker::new();
+        for (addr, size) in accesses.iter() {
+            tracker.access(*addr, *size, false);
+        }
+        assert_eq!(tracker.cold_count, [20, 0]);
+        for words in (0..9).map(|exp| 1 << exp) {
+            let predicted = tracker.histograms[0]
+                .range(..=words)
+                .map(|(_, c)| c)
+                .sum::<usize>();
+            assert_eq!(predicted, lru_hits(&accesses, words), "{}", words);
+        }
+    }
+
+    #[test]
+    fn size_aware_victim_frees_room_in_one_eviction() {
+        let line = |addr: usize, last_use: usize, size: usize| VictimCandidate {
+            addr,
+            last_use,
+            size,
+            is_psum: false,
+            dirty: false,
+        };
+        // Small lines used since the big line, and one used before it.
+        let candidates = [
+            line(0, 1, 2),
+            line(1, 8, 2),
+            line(2, 9, 2),
+            line(3, 3, 12),
+            line(4, 10, 2),
+        ];
+        assert_eq!(
+            LruVictim.select_victim(EvictionScope::Cache, &candidates, 8),
+            0
+        );
+        assert_eq!(
+            SizeAwareVictim.select_victim(EvictionScope::Cache, &candidates, 8),
+            3
+        );
+        // Without a line large enough, the policy falls back to LRU.
+        assert_eq!(
+            SizeAwareVictim.select_victim(EvictionScope::Cache, &candidates, 16),
+            0
+        );
+    }
+}
@@ -671,7 +671,7 @@ impl<'a> TrafficModel<'a> {
                     match self.get_next_block() {
                         Some(block) => {
                             trace_println!("Assign block {:?} to {}", block.get_idx(), pe_no);
-                            println!("row_s: {} block_shape: {:?}", self.row_s, self.block_shape);
+                            trace_println!("row_s: {} block_shape: {:?}", self.row_s, self.block_shape);
                             let reduction_window =
                                 self.adjust_window(block.get_idx(), block.get_shape());
                             self.pes[pe_no].assign_block(block);
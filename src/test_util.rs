@@ -0,0 +1,121 @@
+// Small configs, options and GEMMs shared by the unit tests.
+use crate::frontend::{Accelerator, BlockStrategy, OmegaConfig, RowDistribution};
+use crate::gemm::GEMM;
+use crate::simulator::Simulator;
+use crate::storage::{CsrMatStorage, VectorStorage};
+use crate::SimulationOptions;
+use serde_json::json;
+use sprs::CsMat;
+use std::{env, process};
+
+// Two PEs of 8 lanes and a 64 KB fiber cache, with the defaults of the sample config otherwise.
+pub fn test_config() -> OmegaConfig {
+    config_with(json!({}))
+}
+
+// The test config with some fields replaced, and the data type applied as parse_config does.
+pub fn config_with(fields: serde_json::Value) -> OmegaConfig {
+    let mut config = json!({
+        "ss_filepath": "./matrices",
+        "nn_filepath": "./matrices/nn_gemm.pkl",
+        "pe_num": 2,
+        "at_num": 16,
+        "lane_num": 8,
+        "cache_size": 65536,
+        "word_byte": 8,
+        "block_shape": [1, 10000000],
+        "mem_latency": 30,
+        "cache_latency": 0,
+        "freq": 1.0,
+        "channel": 16,
+        "bandwidth_per_channel": 8.0
+    });
+    for (key, value) in fields.as_object().unwrap() {
+        config[key] = value.clone();
+    }
+    let mut config: OmegaConfig = serde_json::from_value(config).unwrap();
+    config.apply_data_type();
+    config
+}
+
+pub fn test_options(accelerator: Accelerator) -> SimulationOptions {
+    SimulationOptions {
+        accelerator,
+        block_strategy: BlockStrategy::Rowwise,
+        verify: true,
+        channel_epoch: 1000,
+        ..Default::default()
+    }
+}
+
+// A reproducible random A (rows x cols) and B (cols x rows).
+pub fn test_gemm(rows: usize, cols: usize, density: f64, seed: u64) -> GEMM {
+    GEMM::random(rows, cols, density, seed, RowDistribution::Uniform).unwrap()
+}
+
+// A matrix with the given row lengths, each row filled from column 0 with ones.
+pub fn csr_with_row_lens(row_lens: &[usize], cols: usize) -> CsMat<f64> {
+    let mut indptr = vec![0];
+    let mut indices = vec![];
+    for len in row_lens.iter() {
+        indices.extend(0..*len);
+        indptr.push(indices.len());
+    }
+    let data = vec![1.0; indices.len()];
+    CsMat::new((row_lens.len(), cols), indptr, indices, data)
+}
+
+// The storage of A for a matrix, multiplied by an identity B.
+pub fn a_storage(a: CsMat<f64>) -> CsrMatStorage<f64> {
+    let b = CsMat::eye(a.cols());
+    let gemm = GEMM::try_new("a_storage", a, b).unwrap();
+    CsrMatStorage::init_with_gemm(gemm, 8, 4).0
+}
+
+// The matrix with each value converted to another value type.
+pub fn map_values<T: Copy, U>(mat: &CsMat<T>, f: impl Fn(T) -> U) -> CsMat<U> {
+    CsMat::new(
+        mat.shape(),
+        mat.indptr().as_slice().unwrap().to_vec(),
+        mat.indices().to_vec(),
+        mat.data().iter().map(|v| f(*v)).collect(),
+    )
+}
+
+pub const ACCELERATORS: [Accelerator; 4] = [
+    Accelerator::Ip,
+    Accelerator::Op,
+    Accelerator::MultiRow,
+    Accelerator::Spada,
+];
+
+// Run f on a simulator of the GEMM that has not started yet, for the tests of its parts.
+pub fn with_simulator<R>(
+    config: &OmegaConfig,
+    gemm: GEMM,
+    accelerator: Accelerator,
+    f: impl FnOnce(&mut Simulator) -> R,
+) -> R {
+    let (mut dram_a, mut dram_b) =
+        CsrMatStorage::init_with_gemm(gemm, config.word_byte, config.index_byte);
+    let mut dram_psum = VectorStorage::new(config.word_byte, config.index_byte);
+    let output_base_addr = dram_b.indptr.len();
+    let mut simulator = Simulator::new(
+        config,
+        output_base_addr,
+        config.block_shape,
+        &mut dram_a,
+        &mut dram_b,
+        &mut dram_psum,
+        accelerator,
+        BlockStrategy::Rowwise,
+        0,
+    );
+    f(&mut simulator)
+}
+
+// A path in the temp dir unique to the test process, for the files a run writes.
+pub fn temp_path(name: &str) -> String {
+    let file_name = format!("spada_{}_{}", process::id(), name);
+    env::temp_dir().join(file_name).to_str().unwrap().to_owned()
+}
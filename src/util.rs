@@ -1,13 +1,58 @@
+use crate::frontend::LogLevel;
+use crate::scalar::Scalar;
+use crate::storage::CsrRow;
+use sprs::CsMat;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Runtime log level. The trace macros are compiled out without the trace_exec feature.
+static LOG_LEVEL: AtomicUsize = AtomicUsize::new(0);
+
+pub fn set_log_level(log_level: LogLevel) {
+    LOG_LEVEL.store(log_level as usize, Ordering::Relaxed);
+}
+
+pub fn log_enabled(log_level: LogLevel) -> bool {
+    LOG_LEVEL.load(Ordering::Relaxed) >= log_level as usize
+}
+
+#[macro_export]
+macro_rules! info_println {
+    ($( $args:expr ),*) => {
+        if $crate::util::log_enabled($crate::frontend::LogLevel::Info) {
+            println!( $( $args ),* );
+        }
+    }
+}
+
+// Move the named fields out of a checkpoint object into the struct.
+#[macro_export]
+macro_rules! load_state_fields {
+    ($target:expr, $state:expr, $( $field:ident ),* $(,)?) => {
+        $(
+            $target.$field = serde::Deserialize::deserialize($state[stringify!($field)].take())?;
+        )*
+    };
+}
+
 #[cfg(feature = "trace_exec")]
 #[macro_export]
 macro_rules! trace_println {
-    ($( $args:expr ),*) => { println!( $( $args ),* ); }
+    ($( $args:expr ),*) => {
+        if $crate::util::log_enabled($crate::frontend::LogLevel::Trace) {
+            println!( $( $args ),* );
+        }
+    }
 }
 
 #[cfg(feature = "trace_exec")]
 #[macro_export]
 macro_rules! trace_print {
-    ($( $args:expr ),*) => { print!( $( $args ),* ); }
+    ($( $args:expr ),*) => {
+        if $crate::util::log_enabled($crate::frontend::LogLevel::Trace) {
+            print!( $( $args ),* );
+        }
+    }
 }
 
 // Non-debug version
@@ -22,3 +67,198 @@ macro_rules! trace_print {
 macro_rules! trace_println {
     ($( $args:expr ),*) => {};
 }
+
+// Reference row-wise product to check the simulated result against.
+pub fn reference_spgemm<T: Scalar>(a: &CsMat<T>, b: &CsMat<T>) -> Vec<CsrRow<T>> {
+    let mut c = vec![];
+    for (rowid, a_row) in a.outer_iterator().enumerate() {
+        let mut acc: BTreeMap<usize, T> = BTreeMap::new();
+        for (k, a_val) in a_row.iter() {
+            if let Some(b_row) = b.outer_view(k) {
+                for (col, b_val) in b_row.iter() {
+                    let psum = acc.entry(col).or_insert(T::zero());
+                    *psum = psum.plus(a_val.times(*b_val));
+                }
+            }
+        }
+        c.push(CsrRow::new_from_data(
+            rowid,
+            acc.values().cloned().collect(),
+            acc.keys().cloned().collect(),
+        ));
+    }
+    c
+}
+
+// Return the (row, col, expected, got) tuples that differ beyond the relative epsilon.
+pub fn compare_results<T: Scalar>(
+    reference: &Vec<CsrRow<T>>,
+    result: &Vec<CsrRow<T>>,
+    epsilon: f64,
+) -> Vec<(usize, usize, T, T)> {
+    let expected = csr_rows_to_entries(reference);
+    let got = csr_rows_to_entries(result);
+    let mut mismatches = vec![];
+    let idxs = expected
+        .keys()
+        .chain(got.keys())
+        .cloned()
+        .collect::<BTreeSet<_>>();
+    for idx in idxs {
+        let e = expected.get(&idx).cloned().unwrap_or(T::zero());
+        let g = got.get(&idx).cloned().unwrap_or(T::zero());
+        if e.distance(g) > epsilon * e.distance(T::zero()).max(1.0) {
+            mismatches.push((idx[0], idx[1], e, g));
+        }
+    }
+    mismatches
+}
+
+// Build a CSR operand from the rows of a result, e.g. the intermediate of a chained product.
+// The rows may come in any order and repeat elements, which are summed.
+pub fn csr_rows_to_mat<T: Scalar>(csrrows: &Vec<CsrRow<T>>, shape: [usize; 2]) -> CsMat<T> {
+    let mut indptr = vec![0; shape[0] + 1];
+    let mut indices = vec![];
+    let mut data = vec![];
+    for ([row, col], value) in csr_rows_to_entries(csrrows) {
+        indptr[row + 1] += 1;
+        indices.push(col);
+        data.push(value);
+    }
+    for row in 0..shape[0] {
+        indptr[row + 1] += indptr[row];
+    }
+    CsMat::new((shape[0], shape[1]), indptr, indices, data)
+}
+
+fn csr_rows_to_entries<T: Scalar>(csrrows: &Vec<CsrRow<T>>) -> BTreeMap<[usize; 2], T> {
+    let mut entries = BTreeMap::new();
+    for csrrow in csrrows.iter() {
+        for (col, value) in csrrow.indptr.iter().zip(csrrow.data.iter()) {
+            let entry = entries.entry([csrrow.rowptr, *col]).or_insert(T::zero());
+            *entry = entry.plus(*value);
+        }
+    }
+    entries
+}
+
+// Rows of a CSR matrix whose indices are not strictly increasing. The binary searches over the
+// fibers and the merge of the psums rely on sorted rows without repeated columns.
+pub fn unsorted_csr_rows(indptr: &[usize], indices: &[usize]) -> usize {
+    indptr
+        .windows(2)
+        .filter(|p| indices[p[0]..p[1]].windows(2).any(|c| c[0] >= c[1]))
+        .count()
+}
+
+// Sort the indices of the unsorted rows of a CSR matrix, moving their data along. Returns the
+// number of rows sorted.
+pub fn sort_csr_indices<T: Copy>(indptr: &[usize], indices: &mut [usize], data: &mut [T]) -> usize {
+    let mut sorted_num = 0;
+    for p in indptr.windows(2) {
+        let (s, t) = (p[0], p[1]);
+        if indices[s..t].windows(2).all(|c| c[0] < c[1]) {
+            continue;
+        }
+        let mut row = indices[s..t]
+            .iter()
+            .cloned()
+            .zip(data[s..t].iter().cloned())
+            .collect::<Vec<(usize, T)>>();
+        row.sort_by_key(|e| e.0);
+        for (offset, (col, value)) in row.into_iter().enumerate() {
+            indices[s + offset] = col;
+            data[s + offset] = value;
+        }
+        sorted_num += 1;
+    }
+    sorted_num
+}
+
+// Serde helpers of the checkpoint state, JSON only takes strings as map keys.
+pub mod pair_map {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::collections::HashMap;
+    use std::hash::Hash;
+
+    pub fn serialize<K, V, S>(map: &HashMap<K, V>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        K: Serialize,
+        V: Serialize,
+        S: Serializer,
+    {
+        serializer.collect_seq(map.iter())
+    }
+
+    pub fn deserialize<'de, K, V, D>(deserializer: D) -> Result<HashMap<K, V>, D::Error>
+    where
+        K: Deserialize<'de> + Eq + Hash,
+        V: Deserialize<'de>,
+        D: Deserializer<'de>,
+    {
+        Ok(Vec::<(K, V)>::deserialize(deserializer)?
+            .into_iter()
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::Element;
+    use crate::test_util::test_gemm;
+
+    #[test]
+    fn unsorted_rows_are_counted() {
+        let indptr = [0, 3, 3, 5, 7];
+        let indices = [0, 2, 5, 4, 1, 3, 3];
+        // The second row is empty, the third is reversed and the last repeats a column.
+        assert_eq!(unsorted_csr_rows(&indptr, &indices), 2);
+        assert_eq!(unsorted_csr_rows(&indptr[..3], &indices), 0);
+    }
+
+    #[test]
+    fn sorting_moves_the_data_along() {
+        let indptr = [0, 2, 5];
+        let mut indices = [0, 3, 6, 1, 4];
+        let mut data = [0.0, 3.0, 6.0, 1.0, 4.0];
+        assert_eq!(sort_csr_indices(&indptr, &mut indices, &mut data), 1);
+        assert_eq!(indices, [0, 3, 1, 4, 6]);
+        assert_eq!(data, [0.0, 3.0, 1.0, 4.0, 6.0]);
+        assert_eq!(unsorted_csr_rows(&indptr, &indices), 0);
+    }
+
+    #[test]
+    fn repeated_indices_stay_unsorted() {
+        let indptr = [0, 3];
+        let mut indices = [2, 0, 2];
+        let mut data = [1.0, 2.0, 3.0];
+        assert_eq!(sort_csr_indices(&indptr, &mut indices, &mut data), 1);
+        assert_eq!(indices, [0, 2, 2]);
+        // The loader rejects the matrices whose rows are still unsorted once sorted.
+        assert_eq!(unsorted_csr_rows(&indptr, &indices), 1);
+    }
+
+    #[test]
+    fn wrong_accumulation_is_reported() {
+        let gemm = test_gemm(16, 16, 0.3, 15);
+        let reference = reference_spgemm(&gemm.a, &gemm.b);
+        let mut result = reference.clone();
+        assert!(compare_results(&reference, &result, 1e-9).is_empty());
+        // A psum added twice to the first element of a row, and a psum dropped from another.
+        let row = result.iter().position(|r| r.len() > 0).unwrap();
+        let col = result[row].indptr[0];
+        let value = result[row].data[0];
+        result[row].append_element(Element::new([row, col], 0.5));
+        let last = result.iter().rposition(|r| r.len() > 0).unwrap();
+        let dropped_col = result[last].indptr.pop().unwrap();
+        let dropped = result[last].data.pop().unwrap();
+        assert_eq!(
+            compare_results(&reference, &result, 1e-9),
+            vec![
+                (row, col, value, value + 0.5),
+                (last, dropped_col, dropped, 0.0)
+            ]
+        );
+    }
+}